@@ -0,0 +1,197 @@
+use super::{Data, Error, Hive, KeyPath, RegistryBackend};
+use indexmap::IndexMap;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Default)]
+struct Node {
+    path: String,
+    values: IndexMap<String, (String, Data)>,
+}
+
+/// A registry held entirely in memory. Hive roots always exist.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryRegistry {
+    keys: RefCell<BTreeMap<(Hive, String), Node>>,
+}
+
+fn lookup_key(key: &KeyPath) -> (Hive, String) {
+    (key.hive, key.path.to_lowercase())
+}
+
+fn is_child_of(parent: &str, candidate: &str) -> bool {
+    if parent.is_empty() {
+        return !candidate.is_empty() && !candidate.contains('\\');
+    }
+
+    match candidate.strip_prefix(parent) {
+        Some(rest) => rest.starts_with('\\') && !rest[1..].contains('\\'),
+        None => false,
+    }
+}
+
+fn is_descendant_of(parent: &str, candidate: &str) -> bool {
+    match candidate.strip_prefix(parent) {
+        Some(rest) => rest.starts_with('\\'),
+        None => false,
+    }
+}
+
+impl MemoryRegistry {
+    pub fn new() -> MemoryRegistry {
+        MemoryRegistry::default()
+    }
+
+    fn with_node<T>(&self, key: &KeyPath, f: impl FnOnce(&mut Node) -> T) -> Result<T, Error> {
+        let mut keys = self.keys.borrow_mut();
+
+        if key.path.is_empty() {
+            let node = keys.entry(lookup_key(key)).or_default();
+            return Ok(f(node));
+        }
+
+        match keys.get_mut(&lookup_key(key)) {
+            Some(node) => Ok(f(node)),
+            None => Err(Error::KeyNotFound(key.clone())),
+        }
+    }
+}
+
+impl RegistryBackend for MemoryRegistry {
+    fn key_exists(&self, key: &KeyPath) -> bool {
+        key.path.is_empty() || self.keys.borrow().contains_key(&lookup_key(key))
+    }
+
+    fn create_key(&self, key: &KeyPath) -> Result<(), Error> {
+        let mut current = Some(key.clone());
+        let mut keys = self.keys.borrow_mut();
+
+        while let Some(k) = current {
+            if k.path.is_empty() {
+                break;
+            }
+
+            keys.entry(lookup_key(&k)).or_insert_with(|| Node {
+                path: k.path.clone(),
+                values: IndexMap::new(),
+            });
+            current = k.parent();
+        }
+
+        Ok(())
+    }
+
+    fn delete_key(&self, key: &KeyPath) -> Result<(), Error> {
+        if key.path.is_empty() || !self.key_exists(key) {
+            return Err(Error::KeyNotFound(key.clone()));
+        }
+
+        let (hive, path) = lookup_key(key);
+        self.keys
+            .borrow_mut()
+            .retain(|(h, p), _| !(*h == hive && (*p == path || is_descendant_of(&path, p))));
+
+        Ok(())
+    }
+
+    fn subkeys(&self, key: &KeyPath) -> Result<Vec<String>, Error> {
+        if !self.key_exists(key) {
+            return Err(Error::KeyNotFound(key.clone()));
+        }
+
+        let (hive, path) = lookup_key(key);
+        Ok(self
+            .keys
+            .borrow()
+            .iter()
+            .filter(|((h, p), _)| *h == hive && is_child_of(&path, p))
+            .map(|(_, node)| node.path.rsplit('\\').next().unwrap_or("").to_string())
+            .collect())
+    }
+
+    fn values(&self, key: &KeyPath) -> Result<Vec<(String, Data)>, Error> {
+        self.with_node(key, |node| node.values.values().cloned().collect())
+    }
+
+    fn value(&self, key: &KeyPath, name: &str) -> Result<Data, Error> {
+        self.with_node(key, |node| {
+            node.values
+                .get(&name.to_lowercase())
+                .map(|(_, data)| data.clone())
+        })?
+        .ok_or_else(|| Error::ValueNotFound(key.clone(), name.to_string()))
+    }
+
+    fn set_value(&self, key: &KeyPath, name: &str, data: &Data) -> Result<(), Error> {
+        self.with_node(key, |node| {
+            let entry = node
+                .values
+                .entry(name.to_lowercase())
+                .or_insert_with(|| (name.to_string(), Data::None));
+            entry.1 = data.clone();
+        })
+    }
+
+    fn delete_value(&self, key: &KeyPath, name: &str) -> Result<(), Error> {
+        self.with_node(key, |node| node.values.shift_remove(&name.to_lowercase()))?
+            .map(|_| ())
+            .ok_or_else(|| Error::ValueNotFound(key.clone(), name.to_string()))
+    }
+}
+
+#[test]
+fn test_memory_registry_keys() {
+    let reg = MemoryRegistry::new();
+    let key = KeyPath::new(Hive::LocalMachine, r"SYSTEM\Keyboard Layouts\a0000409");
+
+    reg.create_key(&key).unwrap();
+    assert!(reg.key_exists(&KeyPath::new(
+        Hive::LocalMachine,
+        r"system\keyboard layouts"
+    )));
+    assert!(!reg.key_exists(&KeyPath::new(Hive::CurrentUser, "SYSTEM")));
+    assert_eq!(
+        reg.subkeys(&KeyPath::new(
+            Hive::LocalMachine,
+            r"SYSTEM\Keyboard Layouts"
+        ))
+        .unwrap(),
+        vec!["a0000409".to_string()]
+    );
+
+    reg.delete_key(&KeyPath::new(
+        Hive::LocalMachine,
+        r"SYSTEM\KEYBOARD LAYOUTS",
+    ))
+    .unwrap();
+    assert!(!reg.key_exists(&key));
+    assert!(reg.key_exists(&KeyPath::new(Hive::LocalMachine, "SYSTEM")));
+}
+
+#[test]
+fn test_memory_registry_values() {
+    let reg = MemoryRegistry::new();
+    let key = KeyPath::new(Hive::CurrentUser, r"Keyboard Layout\Preload");
+
+    assert!(reg.set_value(&key, "1", &Data::U32(1)).is_err());
+
+    reg.create_key(&key).unwrap();
+    reg.set_value(&key, "2", &Data::String("00000409".into()))
+        .unwrap();
+    reg.set_value(&key, "1", &Data::String("00000407".into()))
+        .unwrap();
+    assert_eq!(
+        reg.values(&key).unwrap(),
+        vec![
+            ("2".to_string(), Data::String("00000409".into())),
+            ("1".to_string(), Data::String("00000407".into())),
+        ]
+    );
+
+    reg.delete_value(&key, "2").unwrap();
+    assert!(reg.delete_value(&key, "2").is_err());
+    assert_eq!(
+        reg.value(&key, "1").unwrap(),
+        Data::String("00000407".into())
+    );
+}
@@ -0,0 +1,169 @@
+//! Registry access used by the keyboard and language logic.
+//!
+//! Everything that reads or writes the registry goes through [`RegistryBackend`], so the same
+//! code can run against the live Windows registry or an in-memory tree in tests.
+
+mod memory;
+#[cfg(windows)]
+mod windows;
+
+pub use self::memory::MemoryRegistry;
+#[cfg(windows)]
+pub use self::windows::{HiveFile, WindowsRegistry};
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Hive {
+    LocalMachine,
+    CurrentUser,
+    Users,
+}
+
+impl Hive {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Hive::LocalMachine => "HKEY_LOCAL_MACHINE",
+            Hive::CurrentUser => "HKEY_CURRENT_USER",
+            Hive::Users => "HKEY_USERS",
+        }
+    }
+}
+
+/// A key in a hive, with path components separated by backslashes. The hive root is the empty path.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct KeyPath {
+    pub hive: Hive,
+    pub path: String,
+}
+
+impl KeyPath {
+    pub fn new<S: Into<String>>(hive: Hive, path: S) -> KeyPath {
+        KeyPath {
+            hive,
+            path: path.into(),
+        }
+    }
+
+    pub fn join(&self, child: &str) -> KeyPath {
+        if self.path.is_empty() {
+            KeyPath::new(self.hive, child)
+        } else {
+            KeyPath::new(self.hive, format!(r"{}\{}", self.path, child))
+        }
+    }
+
+    pub fn parent(&self) -> Option<KeyPath> {
+        if self.path.is_empty() {
+            return None;
+        }
+
+        match self.path.rfind('\\') {
+            Some(i) => Some(KeyPath::new(self.hive, &self.path[..i])),
+            None => Some(KeyPath::new(self.hive, "")),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.path.rsplit('\\').next().unwrap_or("")
+    }
+}
+
+impl fmt::Display for KeyPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.path.is_empty() {
+            f.write_str(self.hive.name())
+        } else {
+            write!(f, r"{}\{}", self.hive.name(), self.path)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum Data {
+    None,
+    String(String),
+    ExpandString(String),
+    MultiString(Vec<String>),
+    U32(u32),
+    U64(u64),
+    Binary(Vec<u8>),
+}
+
+impl Data {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Data::String(v) | Data::ExpandString(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_u32(&self) -> Option<u32> {
+        match self {
+            Data::U32(v) => Some(*v),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Data {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Data::None => Ok(()),
+            Data::String(v) | Data::ExpandString(v) => f.write_str(v),
+            Data::MultiString(v) => f.write_str(&v.join("\n")),
+            Data::U32(v) => write!(f, "{}", v),
+            Data::U64(v) => write!(f, "{}", v),
+            Data::Binary(v) => {
+                for b in v.iter() {
+                    write!(f, "{:02x}", b)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Registry key not found: {0}")]
+    KeyNotFound(KeyPath),
+
+    #[error("Registry value not found: {0} -> {1:?}")]
+    ValueNotFound(KeyPath, String),
+
+    #[error("Registry access failed for {0}: {1}")]
+    Access(KeyPath, String),
+}
+
+/// Key and value operations on a registry. Key and value names are matched case-insensitively,
+/// as Windows does.
+pub trait RegistryBackend {
+    fn key_exists(&self, key: &KeyPath) -> bool;
+
+    /// Creates the key and any missing parents. Succeeds if the key already exists.
+    fn create_key(&self, key: &KeyPath) -> Result<(), Error>;
+
+    /// Deletes the key and all of its subkeys.
+    fn delete_key(&self, key: &KeyPath) -> Result<(), Error>;
+
+    fn subkeys(&self, key: &KeyPath) -> Result<Vec<String>, Error>;
+
+    fn values(&self, key: &KeyPath) -> Result<Vec<(String, Data)>, Error>;
+
+    fn value(&self, key: &KeyPath, name: &str) -> Result<Data, Error>;
+
+    fn set_value(&self, key: &KeyPath, name: &str, data: &Data) -> Result<(), Error>;
+
+    fn delete_value(&self, key: &KeyPath, name: &str) -> Result<(), Error>;
+}
+
+/// Reads a string value, treating a missing key or value, or any other type, as absent.
+pub fn string_value(reg: &dyn RegistryBackend, key: &KeyPath, name: &str) -> Option<String> {
+    match reg.value(key, name) {
+        Ok(Data::String(v)) => Some(v),
+        _ => None,
+    }
+}
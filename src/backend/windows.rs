@@ -0,0 +1,241 @@
+use super::{Data, Error, Hive, KeyPath, RegistryBackend};
+use registry::{RegKey, Security};
+use std::convert::TryInto;
+use std::path::Path;
+
+/// The live registry of the running system.
+pub struct WindowsRegistry;
+
+/// A hive file loaded with `RegLoadAppKey`. Every [`KeyPath`] is resolved relative to the root of
+/// the file, whichever hive it names. The hive is unloaded when this is dropped.
+pub struct HiveFile {
+    root: RegKey,
+}
+
+impl HiveFile {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<HiveFile, Error> {
+        let display = path.as_ref().display().to_string();
+        let root = registry::Hive::load_file(path.as_ref(), Security::Read | Security::Write)
+            .map_err(|e| Error::Access(KeyPath::new(Hive::Users, display), e.to_string()))?;
+
+        Ok(HiveFile { root })
+    }
+}
+
+enum Root<'a> {
+    Hive(registry::Hive),
+    Key(&'a RegKey),
+}
+
+impl<'a> Root<'a> {
+    fn open(&self, path: &str, security: Security) -> Result<RegKey, registry::key::Error> {
+        match self {
+            Root::Hive(hive) => hive.open(path, security),
+            Root::Key(key) => key.open(path, security),
+        }
+    }
+
+    fn create(&self, path: &str, security: Security) -> Result<RegKey, registry::key::Error> {
+        match self {
+            Root::Hive(hive) => hive.create(path, security),
+            Root::Key(key) => key.create(path, security),
+        }
+    }
+}
+
+fn key_error(key: &KeyPath, e: registry::key::Error) -> Error {
+    match e {
+        registry::key::Error::NotFound(..) => Error::KeyNotFound(key.clone()),
+        e => Error::Access(key.clone(), e.to_string()),
+    }
+}
+
+fn from_registry_data(data: registry::Data) -> Data {
+    match data {
+        registry::Data::None => Data::None,
+        registry::Data::String(v) => Data::String(v.to_string_lossy()),
+        registry::Data::ExpandString(v) => Data::ExpandString(v.to_string_lossy()),
+        registry::Data::MultiString(v) => {
+            Data::MultiString(v.iter().map(|x| x.to_string_lossy()).collect())
+        }
+        registry::Data::U32(v) | registry::Data::U32BE(v) => Data::U32(v),
+        registry::Data::U64(v) => Data::U64(v),
+        registry::Data::Binary(v) => Data::Binary(v),
+        other => {
+            log::warn!("Unsupported registry data type: {:?}", other);
+            Data::None
+        }
+    }
+}
+
+fn to_registry_data(key: &KeyPath, data: &Data) -> Result<registry::Data, Error> {
+    let invalid = |e| Error::Access(key.clone(), format!("{:?}", e));
+
+    Ok(match data {
+        Data::None => registry::Data::None,
+        Data::String(v) => registry::Data::String(v.as_str().try_into().map_err(invalid)?),
+        Data::ExpandString(v) => {
+            registry::Data::ExpandString(v.as_str().try_into().map_err(invalid)?)
+        }
+        Data::MultiString(v) => registry::Data::MultiString(
+            v.iter()
+                .map(|x| x.as_str().try_into().map_err(invalid))
+                .collect::<Result<Vec<_>, _>>()?,
+        ),
+        Data::U32(v) => registry::Data::U32(*v),
+        Data::U64(v) => registry::Data::U64(*v),
+        Data::Binary(v) => registry::Data::Binary(v.clone()),
+    })
+}
+
+fn open(root: Root, key: &KeyPath, security: Security) -> Result<RegKey, Error> {
+    root.open(&key.path, security)
+        .map_err(|e| key_error(key, e))
+}
+
+fn create_key(root: Root, key: &KeyPath) -> Result<(), Error> {
+    root.create(&key.path, Security::Read | Security::Write)
+        .map(|_| ())
+        .map_err(|e| key_error(key, e))
+}
+
+fn delete_key(root: Root, key: &KeyPath) -> Result<(), Error> {
+    let parent = match key.parent() {
+        Some(v) => v,
+        None => {
+            return Err(Error::Access(
+                key.clone(),
+                "cannot delete a hive root".into(),
+            ))
+        }
+    };
+
+    open(root, &parent, Security::Read | Security::Write)?
+        .delete(key.name(), true)
+        .map_err(|e| key_error(key, e))
+}
+
+fn subkeys(root: Root, key: &KeyPath) -> Result<Vec<String>, Error> {
+    open(root, key, Security::Read)?
+        .keys()
+        .map(|x| {
+            x.map(|x| x.to_string())
+                .map_err(|e| Error::Access(key.clone(), e.to_string()))
+        })
+        .collect()
+}
+
+fn values(root: Root, key: &KeyPath) -> Result<Vec<(String, Data)>, Error> {
+    open(root, key, Security::Read)?
+        .values()
+        .map(|x| {
+            let (name, data) = x
+                .map_err(|e| Error::Access(key.clone(), e.to_string()))?
+                .into_inner();
+            Ok((name.to_string_lossy(), from_registry_data(data)))
+        })
+        .collect()
+}
+
+fn value(root: Root, key: &KeyPath, name: &str) -> Result<Data, Error> {
+    match open(root, key, Security::Read)?.value(name) {
+        Ok(v) => Ok(from_registry_data(v)),
+        Err(registry::value::Error::NotFound(..)) => {
+            Err(Error::ValueNotFound(key.clone(), name.to_string()))
+        }
+        Err(e) => Err(Error::Access(key.clone(), e.to_string())),
+    }
+}
+
+fn set_value(root: Root, key: &KeyPath, name: &str, data: &Data) -> Result<(), Error> {
+    let data = to_registry_data(key, data)?;
+    open(root, key, Security::Read | Security::Write)?
+        .set_value(name, &data)
+        .map_err(|e| Error::Access(key.clone(), e.to_string()))
+}
+
+fn delete_value(root: Root, key: &KeyPath, name: &str) -> Result<(), Error> {
+    match open(root, key, Security::Read | Security::Write)?.delete_value(name) {
+        Ok(_) => Ok(()),
+        Err(registry::value::Error::NotFound(..)) => {
+            Err(Error::ValueNotFound(key.clone(), name.to_string()))
+        }
+        Err(e) => Err(Error::Access(key.clone(), e.to_string())),
+    }
+}
+
+fn hive(hive: Hive) -> Root<'static> {
+    Root::Hive(match hive {
+        Hive::LocalMachine => registry::Hive::LocalMachine,
+        Hive::CurrentUser => registry::Hive::CurrentUser,
+        Hive::Users => registry::Hive::Users,
+    })
+}
+
+impl RegistryBackend for WindowsRegistry {
+    fn key_exists(&self, key: &KeyPath) -> bool {
+        open(hive(key.hive), key, Security::Read).is_ok()
+    }
+
+    fn create_key(&self, key: &KeyPath) -> Result<(), Error> {
+        create_key(hive(key.hive), key)
+    }
+
+    fn delete_key(&self, key: &KeyPath) -> Result<(), Error> {
+        delete_key(hive(key.hive), key)
+    }
+
+    fn subkeys(&self, key: &KeyPath) -> Result<Vec<String>, Error> {
+        subkeys(hive(key.hive), key)
+    }
+
+    fn values(&self, key: &KeyPath) -> Result<Vec<(String, Data)>, Error> {
+        values(hive(key.hive), key)
+    }
+
+    fn value(&self, key: &KeyPath, name: &str) -> Result<Data, Error> {
+        value(hive(key.hive), key, name)
+    }
+
+    fn set_value(&self, key: &KeyPath, name: &str, data: &Data) -> Result<(), Error> {
+        set_value(hive(key.hive), key, name, data)
+    }
+
+    fn delete_value(&self, key: &KeyPath, name: &str) -> Result<(), Error> {
+        delete_value(hive(key.hive), key, name)
+    }
+}
+
+impl RegistryBackend for HiveFile {
+    fn key_exists(&self, key: &KeyPath) -> bool {
+        open(Root::Key(&self.root), key, Security::Read).is_ok()
+    }
+
+    fn create_key(&self, key: &KeyPath) -> Result<(), Error> {
+        create_key(Root::Key(&self.root), key)
+    }
+
+    fn delete_key(&self, key: &KeyPath) -> Result<(), Error> {
+        delete_key(Root::Key(&self.root), key)
+    }
+
+    fn subkeys(&self, key: &KeyPath) -> Result<Vec<String>, Error> {
+        subkeys(Root::Key(&self.root), key)
+    }
+
+    fn values(&self, key: &KeyPath) -> Result<Vec<(String, Data)>, Error> {
+        values(Root::Key(&self.root), key)
+    }
+
+    fn value(&self, key: &KeyPath, name: &str) -> Result<Data, Error> {
+        value(Root::Key(&self.root), key, name)
+    }
+
+    fn set_value(&self, key: &KeyPath, name: &str, data: &Data) -> Result<(), Error> {
+        set_value(Root::Key(&self.root), key, name, data)
+    }
+
+    fn delete_value(&self, key: &KeyPath, name: &str) -> Result<(), Error> {
+        delete_value(Root::Key(&self.root), key, name)
+    }
+}
@@ -1,11 +1,28 @@
+use kbdi::text_service::Guid;
+#[cfg(windows)]
 use kbdi::*;
+use kbdi::{output, pe, LanguageTag};
 use structopt::StructOpt;
 
 #[derive(StructOpt)]
+#[cfg_attr(not(windows), allow(dead_code))]
 #[structopt(
     about = "Configure Windows registry values for keyboards",
     author = "Brendan Molloy <brendan@bbqsrc.net>"
 )]
+struct Args {
+    /// Print the registry changes and API calls that would be made, without making them
+    #[structopt(long, global = true)]
+    dry_run: bool,
+    /// Output format of query commands: text or json
+    #[structopt(long, global = true, default_value = "text")]
+    format: output::Format,
+    #[structopt(subcommand)]
+    command: Opt,
+}
+
+#[derive(StructOpt)]
+#[cfg_attr(not(windows), allow(dead_code))]
 enum Opt {
     #[structopt(
         name = "keyboard_install",
@@ -14,14 +31,14 @@ enum Opt {
     KeyboardInstall {
         /// Language tag in BCP 47 format (eg: sma-Latn-NO)
         #[structopt(short, long)]
-        tag: String,
-        /// Layout name (eg: Skolt Sami (Norway))
+        tag: LanguageTag,
+        /// Layout name (eg: Skolt Sami (Norway)), if not taken from the DLL
         #[structopt(short = "n", long)]
-        layout: String,
+        layout: Option<String>,
         /// Product code GUID (eg: {42c3de12-28...})
         #[structopt(short, long)]
         guid: String,
-        /// Name of keyboard DLL (eg: kbdfoo01.dll)
+        /// Name of keyboard DLL (eg: kbdfoo01.dll), or path to a DLL or directory of builds to deploy
         #[structopt(short, long)]
         dll: String,
         /// Native language name, if required (eg: Norsk)
@@ -39,35 +56,146 @@ enum Opt {
         /// Product code GUID (eg: {42c3de12-28...})
         guid: String,
     },
+    #[structopt(
+        name = "keyboard_upgrade",
+        about = "Replaces the DLL and names of an installed keyboard, keeping its KLID"
+    )]
+    KeyboardUpgrade {
+        /// Product code GUID (eg: {42c3de12-28...})
+        #[structopt(short, long)]
+        guid: String,
+        /// Layout name (eg: Skolt Sami (Norway)), if not taken from the DLL
+        #[structopt(short = "n", long)]
+        layout: Option<String>,
+        /// Name of keyboard DLL (eg: kbdfoo01.dll), or path to a DLL or directory of builds to deploy
+        #[structopt(short, long)]
+        dll: String,
+        /// Native language name, if not taken from the DLL (eg: Norsk)
+        #[structopt(short, long)]
+        lang: Option<String>,
+    },
+    #[structopt(
+        name = "keyboard_show",
+        about = "Shows the keys of a keyboard layout DLL, without installing it"
+    )]
+    KeyboardShow {
+        /// Path to the keyboard DLL (eg: kbdfoo01.dll)
+        dll: std::path::PathBuf,
+        /// Draw the keyboard as SVG, instead of the output format
+        #[structopt(long)]
+        svg: bool,
+    },
     #[structopt(name = "keyboard_enable", about = "Enables a keyboard for a user")]
     KeyboardEnable {
         /// Language tag in BCP 47 format (eg: sma-Latn-NO)
         #[structopt(short, long)]
-        tag: String,
+        tag: LanguageTag,
         /// Product code GUID (eg: {42c3de12-28...})
         #[structopt(short, long)]
         guid: String,
         /// Native language name, if required (eg: Norsk)
         #[structopt(short, long)]
         lang: Option<String>,
-        /// Enable keyboard for the default user (requires admin)
-        #[structopt(short, long)]
+        /// Enable keyboard for accounts created from now on instead (requires admin)
+        #[structopt(short, long, conflicts_with = "all_users")]
         default_user: bool,
+        /// Enable keyboard for every logged in user instead (requires admin)
+        #[structopt(long)]
+        all_users: bool,
+    },
+    #[structopt(name = "keyboard_disable", about = "Disables a keyboard for a user")]
+    KeyboardDisable {
+        /// Product code GUID (eg: {42c3de12-28...})
+        #[structopt(short, long)]
+        guid: String,
+        /// Disable keyboard for every logged in user instead (requires admin)
+        #[structopt(long)]
+        all_users: bool,
+    },
+    #[structopt(
+        name = "text_service_install",
+        about = "Registers a TSF text service (IME) profile for a language"
+    )]
+    TextServiceInstall {
+        /// Language tag in BCP 47 format (eg: sma-Latn-NO)
+        #[structopt(short, long)]
+        tag: LanguageTag,
+        /// CLSID of the text service (eg: {03B5835F-F03C-...})
+        #[structopt(short, long)]
+        clsid: Guid,
+        /// GUID of the language profile (eg: {A76C93D9-5523-...})
+        #[structopt(short, long)]
+        profile: Guid,
+        /// Description shown in the language bar (eg: Skolt Sami IME)
+        #[structopt(short = "n", long)]
+        description: String,
+        /// Path of the file holding the icon
+        #[structopt(long)]
+        icon_file: Option<String>,
+        /// Index of the icon in the icon file
+        #[structopt(long, default_value = "0")]
+        icon_index: u32,
+        /// Enable the text service immediately after registering
+        #[structopt(short, long)]
+        enable: bool,
+    },
+    #[structopt(
+        name = "text_service_uninstall",
+        about = "Unregisters a TSF text service profile from every language"
+    )]
+    TextServiceUninstall {
+        /// CLSID of the text service (eg: {03B5835F-F03C-...})
+        #[structopt(short, long)]
+        clsid: Guid,
+        /// GUID of the language profile (eg: {A76C93D9-5523-...})
+        #[structopt(short, long)]
+        profile: Guid,
+    },
+    #[structopt(
+        name = "text_service_enable",
+        about = "Enables a registered TSF text service for a user"
+    )]
+    TextServiceEnable {
+        /// Language tag in BCP 47 format (eg: sma-Latn-NO)
+        #[structopt(short, long)]
+        tag: LanguageTag,
+        /// CLSID of the text service (eg: {03B5835F-F03C-...})
+        #[structopt(short, long)]
+        clsid: Guid,
+        /// GUID of the language profile (eg: {A76C93D9-5523-...})
+        #[structopt(short, long)]
+        profile: Guid,
+    },
+    #[structopt(
+        name = "text_service_list",
+        about = "Lists the registered TSF text service profiles"
+    )]
+    TextServiceList {
+        /// Only list profiles for this language tag (eg: sma-Latn-NO)
+        #[structopt(short, long)]
+        tag: Option<LanguageTag>,
+    },
+    #[structopt(
+        name = "registry_regen",
+        about = "Rebuilds the user's Preload and Substitutes keys from their input methods"
+    )]
+    RegistryRegen {
+        /// Print the changes that would be made, without making them
+        #[structopt(long)]
+        show: bool,
     },
-    #[structopt(name = "registry_regen", about = "Enable a language with provided tag")]
-    RegistryRegen,
     #[structopt(
         name = "language_enable",
         about = "Enable a language with provided tag"
     )]
     LanguageEnable {
         /// Language tag in BCP 47 format (eg: sma-Latn-NO)
-        tag: String,
+        tag: LanguageTag,
     },
     #[structopt(name = "language_query", about = "Get data about language tag")]
     LanguageQuery {
         /// Language tag in BCP 47 format (eg: sma-Latn-NO)
-        tag: String,
+        tag: LanguageTag,
     },
     #[structopt(
         name = "language_list",
@@ -85,17 +213,175 @@ enum Opt {
     )]
     KeyboardEnabled,
     #[structopt(about = "Remove empty languages and invalid keyboards")]
-    Clean,
+    Clean {
+        /// Also remove invalid keyboards kbdi did not install, and prune enabled input methods
+        #[structopt(long)]
+        include_foreign: bool,
+    },
+    #[structopt(
+        about = "Takes ownership of keyboards kbdi did not install, so clean manages them"
+    )]
+    Adopt {
+        /// Product code GUIDs of the keyboards to adopt
+        guids: Vec<String>,
+        /// Adopt every installed keyboard kbdi does not own
+        #[structopt(long, conflicts_with = "guids", required_unless = "guids")]
+        all: bool,
+    },
+    #[structopt(about = "Checks the keyboard registry for problems, without changing anything")]
+    Doctor,
+    #[structopt(about = "Installs and enables the keyboards listed in a TOML or JSON manifest")]
+    Apply {
+        /// Path to the manifest (eg: keyboards.toml)
+        manifest: std::path::PathBuf,
+    },
+    #[structopt(about = "Exports the keyboard and language keys managed by kbdi")]
+    Export {
+        /// Path of the .reg file to write (eg: kbdi.reg)
+        #[structopt(long)]
+        reg: std::path::PathBuf,
+    },
+    #[structopt(about = "Installs and enables the keyboards listed in an exported .reg file")]
+    Import {
+        /// Path to the .reg file (eg: kbdi.reg)
+        file: std::path::PathBuf,
+    },
+    #[structopt(about = "Saves or restores the user's languages, input methods and keyboard keys")]
+    Snapshot(SnapshotOpt),
+    #[structopt(
+        about = "Compares two snapshots, or a snapshot with the user's current configuration"
+    )]
+    Diff {
+        /// Path to the earlier snapshot (eg: before.json)
+        a: std::path::PathBuf,
+        /// Path to the later snapshot; the current configuration if not given
+        b: Option<std::path::PathBuf>,
+    },
+}
+
+#[derive(StructOpt)]
+#[cfg_attr(not(windows), allow(dead_code))]
+enum SnapshotOpt {
+    #[structopt(about = "Saves the user's input configuration to a JSON file")]
+    Save {
+        /// Path of the snapshot to write (eg: before.json)
+        file: std::path::PathBuf,
+    },
+    #[structopt(about = "Puts the user back into the input configuration saved in a snapshot")]
+    Restore {
+        /// Path to the snapshot (eg: before.json)
+        file: std::path::PathBuf,
+    },
 }
 
+#[cfg(windows)]
 fn main() {
     kbdi::setup_logger().unwrap_or_else(|_| eprintln!("Logger failed to init."));
     log::info!("Starting Divvun Keyboard Installer...");
 
     let _guard = option_env!("SENTRY_DSN").map(|var| sentry::init(var));
-    let opt = Opt::from_args();
+    let args = Args::from_args();
+    if args.dry_run {
+        dry_run::start();
+    } else {
+        recover_journal();
+    }
+
+    let result = run(args.command, args.format);
 
-    match opt {
+    if args.dry_run {
+        for change in dry_run::finish() {
+            println!("{}", change);
+        }
+    }
+
+    if let Err(err) = result {
+        log::error!("{:?}", err);
+        eprintln!("Error: {}", err);
+        std::process::exit(err.exit_code());
+    }
+}
+
+/// Undoes the registry changes a run that crashed or was killed left half done, then journals this
+/// run's transactions to the same file so that the next run can do the same for it.
+#[cfg(windows)]
+fn recover_journal() {
+    let path = match kbdi::journal_path() {
+        Ok(v) => v,
+        Err(e) => {
+            log::warn!("Not journaling to a file: {}", e);
+            return;
+        }
+    };
+
+    match journal::recover(&backend::WindowsRegistry, &path) {
+        Ok(errors) => {
+            for e in errors {
+                eprintln!(
+                    "Warning: could not undo a change of an unfinished run: {}",
+                    e
+                );
+            }
+            journal::persist_to(path);
+        }
+        Err(e) => eprintln!("Warning: could not recover {}: {}", path.display(), e),
+    }
+}
+
+fn show_keyboard(
+    dll: &std::path::Path,
+    svg: bool,
+    format: output::Format,
+) -> Result<(), kbdi::Error> {
+    let invalid = |source| kbdi::Error::LayoutDll {
+        file: dll.display().to_string(),
+        source,
+    };
+    let layout = pe::LayoutDll::open(dll).map_err(invalid)?;
+    let map = pe::kbdtables::KeyMap::read(&layout).map_err(invalid)?;
+
+    if svg {
+        let title = layout
+            .layout_display_name()
+            .unwrap_or_else(|| dll.display().to_string());
+        print!("{}", map.to_svg(&title));
+        return Ok(());
+    }
+
+    match format {
+        output::Format::Text => print!("{}", map),
+        output::Format::Json => println!("{}", output::to_json(&map)?),
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn print_user_results(
+    results: &[profiles::UserResult],
+    format: output::Format,
+) -> Result<(), Error> {
+    match format {
+        output::Format::Text => {
+            for r in results.iter() {
+                println!("{}", r);
+            }
+        }
+        output::Format::Json => println!("{}", output::to_json(&results)?),
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn run(command: Opt, format: output::Format) -> Result<(), Error> {
+    let live = backend::WindowsRegistry;
+    let dry_run_reg = dry_run::DryRunRegistry::new(&live);
+    let reg: &dyn backend::RegistryBackend = if dry_run::is_active() {
+        &dry_run_reg
+    } else {
+        &live
+    };
+
+    match command {
         Opt::KeyboardInstall {
             tag,
             layout,
@@ -105,56 +391,267 @@ fn main() {
             enable,
         } => {
             log::info!("Installing keyboard...");
-            match keyboard::install(&tag, &layout, &guid, &dll, lang.as_deref()) {
+            match keyboard::install(
+                reg,
+                &tag,
+                layout.as_deref(),
+                &guid,
+                &dll,
+                lang.as_deref(),
+                None,
+            ) {
                 Ok(_) => (),
-                Err(err) => match err {
-                    keyboard::Error::AlreadyExists => {
-                        log::info!("Keyboard already installed.");
-                    }
-                    _ => panic!(err),
-                },
+                Err(Error::AlreadyInstalled(_)) => {
+                    log::info!("Keyboard already installed.");
+                }
+                Err(err) => return Err(err),
             }
             if enable {
                 log::info!("Enabling keyboard...");
-                keyboard::enable(&tag, &guid, lang.as_deref()).unwrap();
+                keyboard::enable(reg, &tag, &guid, lang.as_deref())?;
             }
         }
+        Opt::KeyboardUpgrade {
+            guid,
+            layout,
+            dll,
+            lang,
+        } => {
+            keyboard::upgrade(reg, &guid, layout.as_deref(), &dll, lang.as_deref())?;
+        }
         Opt::KeyboardUninstall { guid } => {
-            keyboard::uninstall(&guid).unwrap();
+            let purged = keyboard::uninstall(reg, &guid)?;
+            match format {
+                output::Format::Text => {
+                    for p in purged.iter() {
+                        print!("{}", p);
+                    }
+                }
+                output::Format::Json => println!("{}", output::to_json(&purged)?),
+            }
         }
+        Opt::KeyboardShow { dll, svg } => show_keyboard(&dll, svg, format)?,
         Opt::KeyboardEnable {
             tag,
             guid,
             lang,
             default_user,
+            all_users,
         } => {
-            keyboard::enable(&tag, &guid, lang.as_deref()).unwrap();
+            if default_user {
+                let results = keyboard::enable_default_user(reg, &tag, &guid)?;
+                match format {
+                    output::Format::Text => {
+                        for r in results.iter() {
+                            println!("{}", r);
+                        }
+                    }
+                    output::Format::Json => println!("{}", output::to_json(&results)?),
+                }
+            } else if all_users {
+                let results = keyboard::enable_all_users(reg, &tag, &guid)?;
+                print_user_results(&results, format)?;
+            } else {
+                keyboard::enable(reg, &tag, &guid, lang.as_deref())?;
+            }
         }
-        Opt::RegistryRegen => {
-            keyboard::regenerate_registry();
+        Opt::KeyboardDisable { guid, all_users } => {
+            if all_users {
+                let results = keyboard::disable_all_users(reg, &guid)?;
+                print_user_results(&results, format)?;
+            } else {
+                keyboard::disable(reg, &guid)?;
+            }
         }
-        Opt::LanguageEnable { tag } => {
-            enable_language(&tag).unwrap();
+        Opt::TextServiceInstall {
+            tag,
+            clsid,
+            profile,
+            description,
+            icon_file,
+            icon_index,
+            enable,
+        } => {
+            text_service::install(
+                reg,
+                &tag,
+                clsid,
+                profile,
+                &description,
+                icon_file.as_deref(),
+                icon_index,
+            )?;
+            if enable {
+                keyboard::enable_text_service(reg, &tag, clsid, profile)?;
+            }
+        }
+        Opt::TextServiceUninstall { clsid, profile } => {
+            text_service::uninstall(reg, clsid, profile)?;
+        }
+        Opt::TextServiceEnable {
+            tag,
+            clsid,
+            profile,
+        } => {
+            keyboard::enable_text_service(reg, &tag, clsid, profile)?;
+        }
+        Opt::TextServiceList { tag } => {
+            let lcid = match tag {
+                Some(tag) => Some(lcid(reg, &tag)?),
+                None => None,
+            };
+            let profiles: Vec<_> = text_service::installed(reg)?
+                .into_iter()
+                .filter(|x| lcid.is_none() || lcid == Some(x.lang_id))
+                .collect();
+            match format {
+                output::Format::Text => {
+                    for p in profiles.iter() {
+                        println!("{}", p);
+                    }
+                }
+                output::Format::Json => {
+                    let records: Vec<output::TextServiceRecord> = profiles
+                        .iter()
+                        .map(output::TextServiceRecord::from)
+                        .collect();
+                    println!("{}", output::to_json(&records)?);
+                }
+            }
         }
-        Opt::LanguageQuery { tag } => {
-            println!("{}", query_language(&tag));
+        Opt::RegistryRegen { show: true } => {
+            let plan = keyboard::registry_regeneration(reg)?;
+            match format {
+                output::Format::Text => print!("{}", plan),
+                output::Format::Json => println!("{}", output::to_json(&plan)?),
+            }
         }
-        Opt::LanguageList => {
-            let languages = enabled_languages().unwrap().join(" ");
-            println!("{}", &languages);
+        Opt::RegistryRegen { show: false } => {
+            keyboard::regenerate_registry(reg)?;
         }
+        Opt::LanguageEnable { tag } => {
+            enable_language(reg, &tag)?;
+        }
+        Opt::LanguageQuery { tag } => match format {
+            output::Format::Text => println!("{}", query_language(&tag)?),
+            output::Format::Json => println!("{}", output::to_json(&language_info(&tag)?)?),
+        },
+        Opt::LanguageList => match format {
+            output::Format::Text => println!("{}", enabled_languages()?.join(" ")),
+            output::Format::Json => println!("{}", output::to_json(&enabled_languages()?)?),
+        },
         Opt::KeyboardList => {
-            for k in keyboard::installed().iter() {
-                println!("{}", k);
+            let keyboards = keyboard::installed(reg)?;
+            match format {
+                output::Format::Text => {
+                    for k in keyboards.iter() {
+                        println!("{}", k);
+                    }
+                }
+                output::Format::Json => {
+                    let records: Vec<output::KeyboardRecord> =
+                        keyboards.iter().map(output::KeyboardRecord::from).collect();
+                    println!("{}", output::to_json(&records)?);
+                }
             }
         }
         Opt::KeyboardEnabled => {
-            for k in enabled_keyboards().iter() {
-                println!("{:?}", k);
+            let keyboards = enabled_keyboards()?;
+            match format {
+                output::Format::Text => {
+                    for k in keyboards.iter() {
+                        println!("{:?}", k);
+                    }
+                }
+                output::Format::Json => {
+                    let records: Vec<output::EnabledLanguageRecord> = keyboards
+                        .into_iter()
+                        .map(output::EnabledLanguageRecord::from)
+                        .collect();
+                    println!("{}", output::to_json(&records)?);
+                }
             }
         }
-        Opt::Clean => {
-            clean().unwrap();
+        Opt::Clean { include_foreign } => {
+            clean(reg, include_foreign)?;
+        }
+        Opt::Adopt { guids, all } => {
+            let guids = if all { vec![] } else { guids };
+            let adopted = keyboard::adopt(reg, &guids)?;
+            match format {
+                output::Format::Text => {
+                    for k in adopted.iter() {
+                        println!("{}", k);
+                    }
+                }
+                output::Format::Json => {
+                    let records: Vec<output::KeyboardRecord> =
+                        adopted.iter().map(output::KeyboardRecord::from).collect();
+                    println!("{}", output::to_json(&records)?);
+                }
+            }
         }
+        Opt::Doctor => {
+            let problems = doctor::diagnose_system(reg)?;
+            match format {
+                output::Format::Text if problems.is_empty() => println!("No problems found."),
+                output::Format::Text => {
+                    for p in problems.iter() {
+                        println!("{}", p);
+                    }
+                }
+                output::Format::Json => println!("{}", output::to_json(&problems)?),
+            }
+        }
+        Opt::Apply { manifest } => {
+            let source = manifest.display().to_string();
+            let manifest = manifest::Manifest::load(&manifest)?;
+            keyboard::apply(reg, &manifest, Some(&source))?;
+        }
+        Opt::Export { reg: path } => {
+            reg_file::RegFile::export(reg)?.save(&path)?;
+        }
+        Opt::Import { file } => {
+            let manifest = reg_file::RegFile::load(&file)?.to_manifest()?;
+            keyboard::apply(reg, &manifest, Some(&file.display().to_string()))?;
+        }
+        Opt::Snapshot(SnapshotOpt::Save { file }) => {
+            snapshot::save(reg)?.save(&file)?;
+        }
+        Opt::Snapshot(SnapshotOpt::Restore { file }) => {
+            let snapshot = snapshot::Snapshot::load(&file)?;
+            snapshot::restore(reg, &snapshot)?;
+        }
+        Opt::Diff { a, b } => {
+            let a = snapshot::Snapshot::load(&a)?;
+            let b = match b {
+                Some(b) => snapshot::Snapshot::load(&b)?,
+                None => snapshot::save(reg)?,
+            };
+            let diff = diff::Diff::between(&a, &b);
+            match format {
+                output::Format::Text => print!("{}", diff),
+                output::Format::Json => println!("{}", output::to_json(&diff)?),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(windows))]
+fn main() {
+    let args = Args::from_args();
+    let result = match args.command {
+        Opt::KeyboardShow { dll, svg } => show_keyboard(&dll, svg, args.format),
+        _ => {
+            eprintln!("kbdi only supports Windows, apart from keyboard_show.");
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(err) = result {
+        eprintln!("Error: {}", err);
+        std::process::exit(err.exit_code());
     }
 }
@@ -1,11 +1,24 @@
+#[cfg(windows)]
 use kbdi::*;
+use kbdi::{output, LanguageTag};
 use structopt::StructOpt;
 
 #[derive(StructOpt)]
+#[cfg_attr(not(windows), allow(dead_code))]
 #[structopt(
     about = "Configure Windows registry values for keyboards",
     author = "Brendan Molloy <brendan@bbqsrc.net>"
 )]
+struct Args {
+    /// Output format of query commands: text or json
+    #[structopt(long, global = true, default_value = "text")]
+    format: output::Format,
+    #[structopt(subcommand)]
+    command: Opt,
+}
+
+#[derive(StructOpt)]
+#[cfg_attr(not(windows), allow(dead_code))]
 enum Opt {
     #[structopt(
         name = "keyboard_install",
@@ -14,14 +27,14 @@ enum Opt {
     KeyboardInstall {
         /// Language tag in BCP 47 format (eg: sma-Latn-NO)
         #[structopt(short, long)]
-        tag: String,
-        /// Layout name (eg: Skolt Sami (Norway))
+        tag: LanguageTag,
+        /// Layout name (eg: Skolt Sami (Norway)), if not taken from the DLL
         #[structopt(short = "n", long)]
-        layout: String,
+        layout: Option<String>,
         /// Product code GUID (eg: {42c3de12-28...})
         #[structopt(short, long)]
         guid: String,
-        /// Name of keyboard DLL (eg: kbdfoo01.dll)
+        /// Name of keyboard DLL (eg: kbdfoo01.dll), or path to a DLL or directory of builds to deploy
         #[structopt(short, long)]
         dll: String,
         /// Native language name, if required (eg: Norsk)
@@ -39,11 +52,29 @@ enum Opt {
         /// Product code GUID (eg: {42c3de12-28...})
         guid: String,
     },
+    #[structopt(
+        name = "keyboard_upgrade",
+        about = "Replaces the DLL and names of an installed keyboard, keeping its KLID"
+    )]
+    KeyboardUpgrade {
+        /// Product code GUID (eg: {42c3de12-28...})
+        #[structopt(short, long)]
+        guid: String,
+        /// Layout name (eg: Skolt Sami (Norway)), if not taken from the DLL
+        #[structopt(short = "n", long)]
+        layout: Option<String>,
+        /// Name of keyboard DLL (eg: kbdfoo01.dll), or path to a DLL or directory of builds to deploy
+        #[structopt(short, long)]
+        dll: String,
+        /// Native language name, if not taken from the DLL (eg: Norsk)
+        #[structopt(short, long)]
+        lang: Option<String>,
+    },
     #[structopt(name = "keyboard_enable", about = "Enables a keyboard for a user")]
     KeyboardEnable {
         /// Language tag in BCP 47 format (eg: sma-Latn-NO)
         #[structopt(short, long)]
-        tag: String,
+        tag: LanguageTag,
         /// Product code GUID (eg: {42c3de12-28...})
         #[structopt(short, long)]
         guid: String,
@@ -51,7 +82,7 @@ enum Opt {
     #[structopt(name = "language_query", about = "Get data about language tag")]
     LanguageQuery {
         /// Language tag in BCP 47 format (eg: sma-Latn-NO)
-        tag: String,
+        tag: LanguageTag,
     },
     #[structopt(
         name = "keyboard_list",
@@ -59,11 +90,63 @@ enum Opt {
     )]
     KeyboardList,
     #[structopt(about = "Remove empty languages and invalid keyboards")]
-    Clean,
+    Clean {
+        /// Also remove invalid keyboards kbdi did not install, and prune enabled input methods
+        #[structopt(long)]
+        include_foreign: bool,
+    },
+    #[structopt(
+        about = "Takes ownership of keyboards kbdi did not install, so clean manages them"
+    )]
+    Adopt {
+        /// Product code GUIDs of the keyboards to adopt
+        guids: Vec<String>,
+        /// Adopt every installed keyboard kbdi does not own
+        #[structopt(long, conflicts_with = "guids", required_unless = "guids")]
+        all: bool,
+    },
 }
 
+#[cfg(windows)]
 fn main() {
-    let opt = Opt::from_args();
+    let args = Args::from_args();
+    recover_journal();
+
+    if let Err(err) = run(args.command, args.format) {
+        eprintln!("Error: {}", err);
+        std::process::exit(err.exit_code());
+    }
+}
+
+/// Undoes the registry changes a run that crashed or was killed left half done, then journals this
+/// run's transactions to the same file so that the next run can do the same for it.
+#[cfg(windows)]
+fn recover_journal() {
+    let path = match kbdi::journal_path() {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("Warning: not journaling to a file: {}", e);
+            return;
+        }
+    };
+
+    match journal::recover(&backend::WindowsRegistry, &path) {
+        Ok(errors) => {
+            for e in errors {
+                eprintln!(
+                    "Warning: could not undo a change of an unfinished run: {}",
+                    e
+                );
+            }
+            journal::persist_to(path);
+        }
+        Err(e) => eprintln!("Warning: could not recover {}: {}", path.display(), e),
+    }
+}
+
+#[cfg(windows)]
+fn run(opt: Opt, format: output::Format) -> Result<(), Error> {
+    let reg = backend::WindowsRegistry;
 
     match opt {
         Opt::KeyboardInstall {
@@ -75,36 +158,93 @@ fn main() {
             enable,
         } => {
             println!("Installing keyboard...");
-            match keyboard::install(&tag, &layout, &guid, &dll, lang.as_deref()) {
+            match keyboard::install(
+                &reg,
+                &tag,
+                layout.as_deref(),
+                &guid,
+                &dll,
+                lang.as_deref(),
+                None,
+            ) {
                 Ok(_) => (),
-                Err(err) => match err {
-                    keyboard::Error::AlreadyExists => {
-                        println!("Keyboard already installed.");
-                    }
-                    _ => panic!(err),
-                },
+                Err(Error::AlreadyInstalled(_)) => {
+                    println!("Keyboard already installed.");
+                }
+                Err(err) => return Err(err),
             }
             if enable {
                 println!("Enabling keyboard...");
-                keyboard::enable(&tag, &guid).unwrap();
+                keyboard::enable(&reg, &tag, &guid)?;
             }
         }
+        Opt::KeyboardUpgrade {
+            guid,
+            layout,
+            dll,
+            lang,
+        } => {
+            keyboard::upgrade(&reg, &guid, layout.as_deref(), &dll, lang.as_deref())?;
+        }
         Opt::KeyboardUninstall { guid } => {
-            keyboard::uninstall(&guid).unwrap();
+            let purged = keyboard::uninstall(&reg, &guid)?;
+            match format {
+                output::Format::Text => {
+                    for p in purged.iter() {
+                        print!("{}", p);
+                    }
+                }
+                output::Format::Json => println!("{}", output::to_json(&purged)?),
+            }
         }
         Opt::KeyboardEnable { tag, guid } => {
-            keyboard::enable(&tag, &guid).unwrap();
-        }
-        Opt::LanguageQuery { tag } => {
-            println!("{}", query_language(&tag));
+            keyboard::enable(&reg, &tag, &guid)?;
         }
+        Opt::LanguageQuery { tag } => match format {
+            output::Format::Text => println!("{}", query_language(&tag)?),
+            output::Format::Json => println!("{}", output::to_json(&language_info(&tag)?)?),
+        },
         Opt::KeyboardList => {
-            for k in keyboard::installed().iter() {
-                println!("{}", k);
+            let keyboards = keyboard::installed(&reg)?;
+            match format {
+                output::Format::Text => {
+                    for k in keyboards.iter() {
+                        println!("{}", k);
+                    }
+                }
+                output::Format::Json => {
+                    let records: Vec<output::KeyboardRecord> =
+                        keyboards.iter().map(output::KeyboardRecord::from).collect();
+                    println!("{}", output::to_json(&records)?);
+                }
             }
         }
-        Opt::Clean => {
-            clean().unwrap();
+        Opt::Clean { include_foreign } => {
+            clean(&reg, include_foreign)?;
+        }
+        Opt::Adopt { guids, all } => {
+            let guids = if all { vec![] } else { guids };
+            let adopted = keyboard::adopt(&reg, &guids)?;
+            match format {
+                output::Format::Text => {
+                    for k in adopted.iter() {
+                        println!("{}", k);
+                    }
+                }
+                output::Format::Json => {
+                    let records: Vec<output::KeyboardRecord> =
+                        adopted.iter().map(output::KeyboardRecord::from).collect();
+                    println!("{}", output::to_json(&records)?);
+                }
+            }
         }
     }
+
+    Ok(())
+}
+
+#[cfg(not(windows))]
+fn main() {
+    eprintln!("kbdi-legacy only supports Windows.");
+    std::process::exit(1);
 }
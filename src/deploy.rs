@@ -0,0 +1,498 @@
+//! Copying layout DLLs into the Windows system directories, and removing them again.
+//!
+//! Every file operation is made under a [`SystemRoot`], which is `%SystemRoot%` on Windows but
+//! may be any directory, so this also works, and is tested, on other platforms.
+
+use crate::backend::{self, RegistryBackend};
+use crate::dry_run::{self, Change};
+use crate::journal::Transaction;
+use crate::keyboard::{keyboard_layouts_key, KeyboardRegKey};
+use crate::pe::{LayoutDll, Machine};
+use crate::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The registry value recording the files a keyboard's install copied, so uninstalling removes
+/// only those.
+pub const DEPLOYED_FILES: &str = "Layout Deployed Files";
+
+/// The subdirectories of a source directory holding the build for each machine.
+const BUILD_DIRS: [(&str, Machine); 3] = [
+    ("x86", Machine::X86),
+    ("amd64", Machine::X64),
+    ("arm64", Machine::Arm64),
+];
+
+/// A Windows directory, the machine its Windows runs on, and whether this process sees it through
+/// WOW64.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SystemRoot {
+    path: PathBuf,
+    machine: Machine,
+    wow64: bool,
+}
+
+impl SystemRoot {
+    pub fn new<P: Into<PathBuf>>(path: P, machine: Machine) -> SystemRoot {
+        SystemRoot {
+            path: path.into(),
+            machine,
+            wow64: false,
+        }
+    }
+
+    /// The same Windows, as a 32-bit process running under WOW64 sees it.
+    pub fn under_wow64(self) -> SystemRoot {
+        SystemRoot {
+            wow64: self.machine.is_64_bit(),
+            ..self
+        }
+    }
+
+    /// The running Windows, from `%SystemRoot%` and the processor architecture, which a 32-bit
+    /// process is told in `PROCESSOR_ARCHITEW6432`.
+    pub fn current() -> SystemRoot {
+        let path = std::env::var_os("SystemRoot")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(r"C:\Windows"));
+        let wow64 = std::env::var("PROCESSOR_ARCHITEW6432").ok();
+        let machine = match wow64
+            .clone()
+            .or_else(|| std::env::var("PROCESSOR_ARCHITECTURE").ok())
+            .as_deref()
+        {
+            Some("x86") => Machine::X86,
+            Some("ARM64") => Machine::Arm64,
+            _ => Machine::X64,
+        };
+        match wow64 {
+            Some(_) => SystemRoot::new(path, machine).under_wow64(),
+            None => SystemRoot::new(path, machine),
+        }
+    }
+
+    pub fn machine(&self) -> Machine {
+        self.machine
+    }
+
+    /// The native system directory, as it is recorded and shown. Open files in it through
+    /// [`SystemRoot::resolve`].
+    pub fn system32(&self) -> PathBuf {
+        self.path.join("System32")
+    }
+
+    /// The path this process opens to reach `path`. Under WOW64, `System32` is redirected to
+    /// `SysWOW64`, so the native one is reached through `Sysnative` instead.
+    pub fn resolve(&self, path: &Path) -> PathBuf {
+        match path.strip_prefix(self.system32()) {
+            Ok(rest) if self.wow64 => self.path.join("Sysnative").join(rest),
+            _ => path.to_owned(),
+        }
+    }
+
+    /// Where 32-bit processes find their DLLs on 64-bit Windows.
+    pub fn syswow64(&self) -> Option<PathBuf> {
+        match self.machine.is_64_bit() {
+            true => Some(self.path.join("SysWOW64")),
+            false => None,
+        }
+    }
+
+    /// The directories layout DLLs are deployed to, with the build each needs.
+    fn targets(&self) -> Vec<(PathBuf, Machine)> {
+        let mut targets = vec![(self.system32(), self.machine)];
+        targets.extend(self.syswow64().map(|x| (x, Machine::X86)));
+        targets
+    }
+}
+
+/// The builds of a layout DLL to deploy: a single DLL, or a directory with a DLL in some of its
+/// `x86`, `amd64` and `arm64` subdirectories.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Source {
+    name: String,
+    builds: Vec<(Machine, PathBuf)>,
+}
+
+impl Source {
+    pub fn open(path: &Path) -> Result<Source, Error> {
+        if !path.is_dir() {
+            let machine = open_build(path)?.machine();
+            return Ok(Source {
+                name: file_name(path)?,
+                builds: vec![(machine, path.to_owned())],
+            });
+        }
+
+        let mut name: Option<String> = None;
+        let mut builds = vec![];
+        for (dir, machine) in BUILD_DIRS.iter() {
+            let dir = path.join(dir);
+            if !dir.is_dir() {
+                continue;
+            }
+
+            let dlls: Vec<PathBuf> = fs::read_dir(&dir)?
+                .filter_map(|x| x.ok().map(|x| x.path()))
+                .filter(|x| matches!(x.extension(), Some(ext) if ext.eq_ignore_ascii_case("dll")))
+                .collect();
+            let dll = match &dlls[..] {
+                [dll] => dll.to_owned(),
+                _ => {
+                    return Err(Error::InvalidArgument(format!(
+                        "{} must hold exactly one DLL, but has {}",
+                        dir.display(),
+                        dlls.len()
+                    )))
+                }
+            };
+
+            let found = open_build(&dll)?.machine();
+            if found != *machine {
+                return Err(Error::InvalidArgument(format!(
+                    "{} is an {} DLL, not {}",
+                    dll.display(),
+                    found,
+                    machine
+                )));
+            }
+
+            let dll_name = file_name(&dll)?;
+            match &name {
+                Some(v) if !v.eq_ignore_ascii_case(&dll_name) => {
+                    return Err(Error::InvalidArgument(format!(
+                        "the builds in {} are named both {} and {}",
+                        path.display(),
+                        v,
+                        dll_name
+                    )))
+                }
+                _ => name = Some(dll_name),
+            }
+            builds.push((*machine, dll));
+        }
+
+        match name {
+            Some(name) => Ok(Source { name, builds }),
+            None => Err(Error::InvalidArgument(format!(
+                "{} has no x86, amd64 or arm64 build of a layout DLL",
+                path.display()
+            ))),
+        }
+    }
+
+    /// The file name the DLL is deployed as.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn build(&self, machine: Machine) -> Option<&Path> {
+        self.builds
+            .iter()
+            .find(|(x, _)| *x == machine)
+            .map(|(_, path)| &**path)
+    }
+}
+
+pub(crate) fn open_build(path: &Path) -> Result<LayoutDll, Error> {
+    LayoutDll::open(path).map_err(|source| Error::LayoutDll {
+        file: path.display().to_string(),
+        source,
+    })
+}
+
+fn file_name(path: &Path) -> Result<String, Error> {
+    path.file_name()
+        .and_then(|x| x.to_str())
+        .map(str::to_owned)
+        .ok_or_else(|| Error::InvalidArgument(format!("{} is not a file", path.display())))
+}
+
+/// The installed keyboards, other than those of `product_code`, whose `Layout File` is `file`.
+fn other_owners(
+    reg: &dyn RegistryBackend,
+    file: &str,
+    product_code: &str,
+) -> Result<Vec<String>, Error> {
+    let regkey = keyboard_layouts_key();
+    if !reg.key_exists(&regkey) {
+        return Ok(vec![]);
+    }
+
+    let mut owners = vec![];
+    for klid in reg.subkeys(&regkey)? {
+        let key = regkey.join(&klid);
+        match backend::string_value(reg, &key, "Layout File") {
+            Some(v) if v.eq_ignore_ascii_case(file) => {}
+            _ => continue,
+        }
+        match backend::string_value(reg, &key, "Layout Product Code") {
+            Some(v) if v == product_code => {}
+            Some(v) => owners.push(format!("product {}", v)),
+            None => owners.push(format!("keyboard layout {}", klid)),
+        }
+    }
+    Ok(owners)
+}
+
+/// The files an install of `product_code` recorded deploying.
+fn recorded_files(reg: &dyn RegistryBackend, product_code: &str) -> Result<Vec<PathBuf>, Error> {
+    if !reg.key_exists(&keyboard_layouts_key()) {
+        return Ok(vec![]);
+    }
+    Ok(KeyboardRegKey::find_by_product_code(reg, product_code)?
+        .map(|x| x.deployed_files())
+        .unwrap_or_default())
+}
+
+/// Copies the builds of `source` that `root` needs into its system directories, journaling in
+/// `tx` how to put back what was there, and returns the files written, as `root` records them.
+///
+/// Nothing is copied if a DLL of the same name is used by a keyboard other than those of
+/// `product_code`, if one already in place differs and was not deployed for `product_code`, or if
+/// there is no build for `System32`. A missing x86 build for `SysWOW64` is
+/// only warned about, as 64-bit processes can still use the layout.
+pub fn deploy(
+    tx: &Transaction,
+    root: &SystemRoot,
+    source: &Source,
+    product_code: &str,
+) -> Result<Vec<PathBuf>, Error> {
+    if let Some(owner) = other_owners(tx, source.name(), product_code)?
+        .into_iter()
+        .next()
+    {
+        return Err(Error::ForeignLayoutDll {
+            file: source.name().to_owned(),
+            owner,
+        });
+    }
+
+    let mut copies = vec![];
+    for (dir, machine) in root.targets() {
+        match source.build(machine) {
+            Some(build) => copies.push((build, dir.join(source.name()))),
+            None if dir == root.system32() => {
+                return Err(Error::InvalidArgument(format!(
+                    "there is no {} build of {}, which this Windows needs",
+                    machine,
+                    source.name()
+                )))
+            }
+            None => log::warn!(
+                "There is no x86 build of {}, so 32-bit programs cannot use it",
+                source.name()
+            ),
+        }
+    }
+
+    // A file no keyboard records is still another program's, unless it is the same DLL.
+    let recorded = recorded_files(tx, product_code)?;
+    for (from, to) in copies.iter() {
+        let existing = root.resolve(to);
+        if existing.exists() && !recorded.contains(to) && fs::read(&existing)? != fs::read(from)? {
+            return Err(Error::ForeignLayoutDll {
+                file: source.name().to_owned(),
+                owner: format!(
+                    "another program, which put a different copy in {}",
+                    to.parent().unwrap_or(to).display()
+                ),
+            });
+        }
+    }
+
+    let mut written = vec![];
+    for (from, to) in copies {
+        written.push(to.clone());
+        if dry_run::is_active() {
+            dry_run::record(Change::CopyFile(from.to_owned(), to));
+            continue;
+        }
+
+        log::info!("Copying {} to {}", from.display(), to.display());
+        let to = root.resolve(&to);
+        let previous = match to.exists() {
+            true => Some(fs::read(&to)?),
+            false => None,
+        };
+        fs::copy(from, &to)?;
+
+        let description = format!("restore {}", to.display());
+        tx.on_rollback(&description, move || {
+            match previous {
+                Some(bytes) => fs::write(&to, bytes),
+                None => fs::remove_file(&to),
+            }
+            .map_err(|e| e.to_string())
+        });
+    }
+
+    Ok(written)
+}
+
+/// Deletes the `files` an install of `product_code` deployed, except those another installed
+/// keyboard still uses, and returns the files deleted. Files that cannot be deleted, as when
+/// a program has the DLL loaded, are warned about and left.
+pub fn remove(
+    reg: &dyn RegistryBackend,
+    root: &SystemRoot,
+    files: &[PathBuf],
+    product_code: &str,
+) -> Result<Vec<PathBuf>, Error> {
+    let mut removed = vec![];
+    for file in files {
+        let owners = other_owners(reg, &file_name(file)?, product_code)?;
+        if let Some(owner) = owners.first() {
+            log::info!("Keeping {}, which {} uses", file.display(), owner);
+            continue;
+        }
+
+        if dry_run::is_active() {
+            dry_run::record(Change::DeleteFile(file.to_owned()));
+            removed.push(file.to_owned());
+            continue;
+        }
+
+        match fs::remove_file(root.resolve(file)) {
+            Ok(()) => removed.push(file.to_owned()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => log::warn!("Could not delete {}: {}", file.display(), e),
+        }
+    }
+    Ok(removed)
+}
+
+#[test]
+fn test_deploy() {
+    use crate::backend::{Data, MemoryRegistry};
+    use crate::pe::fixture;
+
+    let dir = std::env::temp_dir().join(format!("kbdi-deploy-{}", std::process::id()));
+    let root = SystemRoot::new(dir.join("Windows"), Machine::X64);
+    let builds = dir.join("builds");
+    let (x86, _) = fixture(Machine::X86, &["KbdLayerDescriptor"], &[], &[], &[]);
+    let (x64, _) = fixture(Machine::X64, &["KbdLayerDescriptor"], &[], &[], &[]);
+    for (subdir, bytes) in [("x86", &x86), ("amd64", &x64)].iter() {
+        fs::create_dir_all(builds.join(subdir)).unwrap();
+        fs::write(builds.join(subdir).join("kbdse01.dll"), bytes).unwrap();
+    }
+    fs::create_dir_all(root.system32()).unwrap();
+    fs::create_dir_all(root.syswow64().unwrap()).unwrap();
+    fs::write(root.system32().join("kbdse01.dll"), b"old").unwrap();
+
+    let source = Source::open(&builds).unwrap();
+    assert_eq!(source.name(), "kbdse01.dll");
+    assert_eq!(source.build(Machine::Arm64), None);
+    let system32 = root.system32().join("kbdse01.dll");
+    let syswow64 = root.syswow64().unwrap().join("kbdse01.dll");
+
+    let reg = MemoryRegistry::new();
+    let tx = Transaction::begin(&reg);
+    match deploy(&tx, &root, &source, "{1}") {
+        Err(Error::ForeignLayoutDll { owner, .. }) => assert!(owner.starts_with("another program")),
+        x => panic!("{:?}", x),
+    }
+    assert_eq!(fs::read(&system32).unwrap(), b"old");
+
+    // Once recorded as deployed for {1}, the file is its own to replace.
+    let deployed = keyboard_layouts_key().join("a000043c");
+    reg.create_key(&deployed).unwrap();
+    reg.set_value(
+        &deployed,
+        "Layout Product Code",
+        &Data::String("{1}".into()),
+    )
+    .unwrap();
+    let recorded = vec![system32.display().to_string()];
+    reg.set_value(
+        &deployed,
+        DEPLOYED_FILES,
+        &Data::MultiString(recorded.clone()),
+    )
+    .unwrap();
+    let files = deploy(&tx, &root, &source, "{1}").unwrap();
+    assert_eq!(files, vec![system32.clone(), syswow64.clone()]);
+    assert_eq!(fs::read(&system32).unwrap(), x64);
+    assert_eq!(fs::read(&syswow64).unwrap(), x86);
+    assert!(tx.rollback().is_empty());
+    assert_eq!(fs::read(&system32).unwrap(), b"old");
+    assert!(!syswow64.exists());
+
+    let arm64 = SystemRoot::new(dir.join("Windows"), Machine::Arm64);
+    let tx = Transaction::begin(&reg);
+    assert!(matches!(
+        deploy(&tx, &arm64, &source, "{1}"),
+        Err(Error::InvalidArgument(_))
+    ));
+
+    let klid = keyboard_layouts_key().join("a000043b");
+    reg.create_key(&klid).unwrap();
+    reg.set_value(&klid, "Layout File", &Data::String("KBDSE01.DLL".into()))
+        .unwrap();
+    reg.set_value(&klid, "Layout Product Code", &Data::String("{2}".into()))
+        .unwrap();
+    reg.set_value(&klid, DEPLOYED_FILES, &Data::MultiString(recorded))
+        .unwrap();
+    match deploy(&tx, &root, &source, "{1}") {
+        Err(Error::ForeignLayoutDll { owner, .. }) => assert_eq!(owner, "product {2}"),
+        x => panic!("{:?}", x),
+    }
+    assert_eq!(fs::read(&system32).unwrap(), b"old");
+    let files = deploy(&tx, &root, &source, "{2}").unwrap();
+    tx.commit();
+
+    assert!(remove(&reg, &root, &files, "{1}").unwrap().is_empty());
+    assert_eq!(remove(&reg, &root, &files, "{2}").unwrap(), files);
+    assert!(!system32.exists() && !syswow64.exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_deploy_under_wow64() {
+    use crate::backend::MemoryRegistry;
+    use crate::pe::fixture;
+
+    let dir = std::env::temp_dir().join(format!("kbdi-wow64-{}", std::process::id()));
+    let root = SystemRoot::new(dir.join("Windows"), Machine::X64).under_wow64();
+    let sysnative = dir.join("Windows").join("Sysnative");
+    assert_eq!(
+        root.resolve(&root.system32().join("kbdse01.dll")),
+        sysnative.join("kbdse01.dll")
+    );
+    assert_eq!(
+        SystemRoot::new(dir.join("Windows"), Machine::X86)
+            .under_wow64()
+            .resolve(&dir.join("Windows").join("System32")),
+        dir.join("Windows").join("System32")
+    );
+
+    let (x86, _) = fixture(Machine::X86, &["KbdLayerDescriptor"], &[], &[], &[]);
+    let (x64, _) = fixture(Machine::X64, &["KbdLayerDescriptor"], &[], &[], &[]);
+    for (subdir, bytes) in [("x86", &x86), ("amd64", &x64)].iter() {
+        fs::create_dir_all(dir.join("builds").join(subdir)).unwrap();
+        fs::write(dir.join("builds").join(subdir).join("kbdse01.dll"), bytes).unwrap();
+    }
+    fs::create_dir_all(&sysnative).unwrap();
+    fs::create_dir_all(root.syswow64().unwrap()).unwrap();
+    let source = Source::open(&dir.join("builds")).unwrap();
+
+    // The native build goes through Sysnative, but is recorded in System32.
+    let reg = MemoryRegistry::new();
+    let tx = Transaction::begin(&reg);
+    let files = deploy(&tx, &root, &source, "{1}").unwrap();
+    tx.commit();
+    let syswow64 = root.syswow64().unwrap().join("kbdse01.dll");
+    assert_eq!(
+        files,
+        vec![root.system32().join("kbdse01.dll"), syswow64.clone()]
+    );
+    assert_eq!(fs::read(sysnative.join("kbdse01.dll")).unwrap(), x64);
+    assert_eq!(fs::read(&syswow64).unwrap(), x86);
+    assert!(!root.system32().exists());
+
+    assert_eq!(remove(&reg, &root, &files, "{1}").unwrap(), files);
+    assert!(!sysnative.join("kbdse01.dll").exists() && !syswow64.exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,323 @@
+//! Differences between two snapshots of a user's input configuration, for `kbdi diff`.
+
+use crate::keyboard_win8::{preload_key, substitutes_key};
+use crate::snapshot::{KeySnapshot, Snapshot};
+use crate::types::InputListItem;
+use serde::Serialize;
+use std::convert::TryFrom;
+use std::fmt;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Diff {
+    pub added_languages: Vec<String>,
+    pub removed_languages: Vec<String>,
+    /// The languages in both snapshots, in each order, when the order differs.
+    pub reordered_languages: Option<Reorder>,
+    pub input_methods: Vec<InputMethodChanges>,
+    /// Values that differ in `Preload`, `Substitutes` or the installed keyboard layouts.
+    pub values: Vec<ValueChange>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Reorder {
+    pub before: Vec<String>,
+    pub after: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct InputMethodChanges {
+    pub tag: String,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+/// A value that was added, removed or changed. `before` or `after` is `null` when the value did
+/// not exist.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ValueChange {
+    pub key: String,
+    pub name: String,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+/// Input methods are compared in their canonical form, so `0x043b:a000043b` and
+/// `043B:A000043B` are the same.
+fn canonical_input_method(ime: &str) -> String {
+    InputListItem::try_from(ime)
+        .map(|x| x.to_string())
+        .unwrap_or_else(|_| ime.to_ascii_uppercase())
+}
+
+fn contains_tag(tags: &[&str], tag: &str) -> bool {
+    tags.iter().any(|x| x.eq_ignore_ascii_case(tag))
+}
+
+/// Every value of the keys `kbdi diff` compares, as key, name and data.
+fn compared_values(snapshot: &Snapshot) -> Vec<(&str, &str, String)> {
+    let compared_keys = [preload_key().path, substitutes_key().path];
+    let keys = snapshot
+        .keys
+        .iter()
+        .filter(|key| {
+            compared_keys
+                .iter()
+                .any(|x| x.eq_ignore_ascii_case(&key.path))
+        })
+        .chain(snapshot.layouts.iter());
+
+    keys.flat_map(|key: &KeySnapshot| {
+        key.values
+            .iter()
+            .map(move |(name, data)| (&*key.path, &**name, data.to_string()))
+    })
+    .collect()
+}
+
+impl Diff {
+    pub fn between(a: &Snapshot, b: &Snapshot) -> Diff {
+        let tags_a: Vec<&str> = a.languages.iter().map(|x| &*x.tag).collect();
+        let tags_b: Vec<&str> = b.languages.iter().map(|x| &*x.tag).collect();
+
+        let mut diff = Diff {
+            added_languages: tags_b
+                .iter()
+                .filter(|x| !contains_tag(&tags_a, x))
+                .map(|x| x.to_string())
+                .collect(),
+            removed_languages: tags_a
+                .iter()
+                .filter(|x| !contains_tag(&tags_b, x))
+                .map(|x| x.to_string())
+                .collect(),
+            ..Diff::default()
+        };
+
+        let before: Vec<String> = tags_a
+            .iter()
+            .filter(|x| contains_tag(&tags_b, x))
+            .map(|x| x.to_string())
+            .collect();
+        let after: Vec<String> = tags_b
+            .iter()
+            .filter(|x| contains_tag(&tags_a, x))
+            .map(|x| x.to_string())
+            .collect();
+        let same_order = before
+            .iter()
+            .zip(after.iter())
+            .all(|(x, y)| x.eq_ignore_ascii_case(y));
+        if !same_order {
+            diff.reordered_languages = Some(Reorder { before, after });
+        }
+
+        let input_methods = |snapshot: &Snapshot, tag: &str| -> Vec<String> {
+            snapshot
+                .languages
+                .iter()
+                .filter(|x| x.tag.eq_ignore_ascii_case(tag))
+                .flat_map(|x| x.input_methods.iter().map(|x| canonical_input_method(x)))
+                .collect()
+        };
+
+        for tag in tags_a
+            .iter()
+            .chain(tags_b.iter().filter(|x| !contains_tag(&tags_a, x)))
+        {
+            let imes_a = input_methods(a, tag);
+            let imes_b = input_methods(b, tag);
+            let changes = InputMethodChanges {
+                tag: tag.to_string(),
+                added: imes_b
+                    .iter()
+                    .filter(|x| !imes_a.contains(x))
+                    .cloned()
+                    .collect(),
+                removed: imes_a
+                    .iter()
+                    .filter(|x| !imes_b.contains(x))
+                    .cloned()
+                    .collect(),
+            };
+
+            if !changes.added.is_empty() || !changes.removed.is_empty() {
+                diff.input_methods.push(changes);
+            }
+        }
+
+        let values_a = compared_values(a);
+        let values_b = compared_values(b);
+        let find = |values: &[(&str, &str, String)], key: &str, name: &str| {
+            values
+                .iter()
+                .find(|(k, n, _)| k.eq_ignore_ascii_case(key) && n.eq_ignore_ascii_case(name))
+                .map(|(_, _, data)| data.to_owned())
+        };
+
+        for (key, name, data) in values_a.iter() {
+            let after = find(&values_b, key, name);
+            if after.as_ref() != Some(data) {
+                diff.values.push(ValueChange {
+                    key: key.to_string(),
+                    name: name.to_string(),
+                    before: Some(data.to_owned()),
+                    after,
+                });
+            }
+        }
+        for (key, name, data) in values_b.iter() {
+            if find(&values_a, key, name).is_none() {
+                diff.values.push(ValueChange {
+                    key: key.to_string(),
+                    name: name.to_string(),
+                    before: None,
+                    after: Some(data.to_owned()),
+                });
+            }
+        }
+
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self == &Diff::default()
+    }
+}
+
+impl fmt::Display for Diff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "No differences.");
+        }
+
+        if !self.added_languages.is_empty()
+            || !self.removed_languages.is_empty()
+            || self.reordered_languages.is_some()
+        {
+            writeln!(f, "Languages:")?;
+            for tag in self.added_languages.iter() {
+                writeln!(f, "  + {}", tag)?;
+            }
+            for tag in self.removed_languages.iter() {
+                writeln!(f, "  - {}", tag)?;
+            }
+            if let Some(reorder) = self.reordered_languages.as_ref() {
+                writeln!(
+                    f,
+                    "  ~ order {} -> {}",
+                    reorder.before.join(", "),
+                    reorder.after.join(", ")
+                )?;
+            }
+        }
+
+        if !self.input_methods.is_empty() {
+            writeln!(f, "Input methods:")?;
+            for changes in self.input_methods.iter() {
+                for ime in changes.added.iter() {
+                    writeln!(f, "  + {} {}", changes.tag, ime)?;
+                }
+                for ime in changes.removed.iter() {
+                    writeln!(f, "  - {} {}", changes.tag, ime)?;
+                }
+            }
+        }
+
+        if !self.values.is_empty() {
+            writeln!(f, "Registry:")?;
+            for change in self.values.iter() {
+                let name = format!(r"{}\{}", change.key, change.name);
+                match (&change.before, &change.after) {
+                    (Some(before), Some(after)) => {
+                        writeln!(f, "  ~ {} = {} -> {}", name, before, after)?
+                    }
+                    (None, Some(after)) => writeln!(f, "  + {} = {}", name, after)?,
+                    (Some(before), None) => writeln!(f, "  - {} = {}", name, before)?,
+                    (None, None) => {}
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_diff_snapshots() {
+    use crate::backend::Data;
+    use crate::snapshot::LanguageSnapshot;
+
+    let language = |tag: &str, imes: &[&str]| LanguageSnapshot {
+        tag: tag.into(),
+        input_methods: imes.iter().map(|x| x.to_string()).collect(),
+    };
+    let key = |path: &str, values: &[(&str, &str)]| KeySnapshot {
+        path: path.into(),
+        values: values
+            .iter()
+            .map(|(n, v)| (n.to_string(), Data::String(v.to_string())))
+            .collect(),
+    };
+    let layout = r"SYSTEM\CurrentControlSet\Control\Keyboard Layouts\a000043b";
+
+    let a = Snapshot {
+        languages: vec![
+            language("en-US", &["0409:00000409"]),
+            language("se-NO", &["043B:A000043B"]),
+            language("fi-FI", &["040B:0000040B"]),
+        ],
+        keys: vec![
+            key(
+                r"Keyboard Layout\Preload",
+                &[("1", "00000409"), ("2", "d001043b")],
+            ),
+            key(r"Keyboard Layout\Substitutes", &[("d001043b", "a000043b")]),
+            key(r"Control Panel\International\User Profile", &[]),
+        ],
+        layouts: vec![key(layout, &[("Layout File", "kbdse01.dll")])],
+    };
+    assert!(Diff::between(&a, &a).is_empty());
+    assert_eq!(Diff::between(&a, &a).to_string(), "No differences.\n");
+
+    let b = Snapshot {
+        languages: vec![
+            language("se-NO", &["0x043b:0xa000043b", "043B:0000043B"]),
+            language("EN-us", &[]),
+            language("sjd-RU", &["2000:A0002000"]),
+        ],
+        keys: vec![key(
+            r"Keyboard Layout\Preload",
+            &[("1", "00000409"), ("2", "0000043b")],
+        )],
+        layouts: vec![],
+    };
+
+    let diff = Diff::between(&a, &b);
+    assert_eq!(diff.added_languages, vec!["sjd-RU"]);
+    assert_eq!(diff.removed_languages, vec!["fi-FI"]);
+    assert_eq!(
+        diff.reordered_languages,
+        Some(Reorder {
+            before: vec!["en-US".into(), "se-NO".into()],
+            after: vec!["se-NO".into(), "EN-us".into()],
+        })
+    );
+    assert_eq!(
+        diff.to_string(),
+        concat!(
+            "Languages:\n",
+            "  + sjd-RU\n",
+            "  - fi-FI\n",
+            "  ~ order en-US, se-NO -> se-NO, EN-us\n",
+            "Input methods:\n",
+            "  - en-US 0409:00000409\n",
+            "  + se-NO 043B:0000043B\n",
+            "  - fi-FI 040B:0000040B\n",
+            "  + sjd-RU 2000:A0002000\n",
+            "Registry:\n",
+            "  ~ Keyboard Layout\\Preload\\2 = d001043b -> 0000043b\n",
+            "  - Keyboard Layout\\Substitutes\\d001043b = a000043b\n",
+            "  - SYSTEM\\CurrentControlSet\\Control\\Keyboard Layouts\\a000043b\\Layout File = kbdse01.dll\n",
+        )
+    );
+}
@@ -0,0 +1,459 @@
+//! Read-only health checks of the keyboard registry, for `kbdi doctor`.
+//!
+//! Unlike [`crate::keyboard::remove_invalid`], nothing here changes the registry. Each problem
+//! found comes with the command that would fix it.
+
+use crate::backend::{self, RegistryBackend};
+use crate::keyboard::{keyboard_layouts_key, KeyboardRegKey};
+use crate::keyboard_win8::{preload_key, substitutes_key, valid_keyboards};
+use crate::types::InputListItem;
+use crate::Error;
+use serde::Serialize;
+use std::convert::TryFrom;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// Something is likely to look wrong to the user, but keyboards still work.
+    Warning,
+    /// A keyboard or language does not work, or cannot be installed or uninstalled cleanly.
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Warning => f.write_str("warning"),
+            Severity::Error => f.write_str("error"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Problem {
+    pub severity: Severity,
+    /// Which check found the problem, eg. `duplicate_product_code`
+    pub check: &'static str,
+    pub message: String,
+    /// The command that would fix the problem
+    pub fix: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}: {}", self.severity, self.message)?;
+        write!(f, "  fix: {}", self.fix)
+    }
+}
+
+fn reinstall_fix(keyboard: &KeyboardRegKey) -> String {
+    match keyboard.product_code() {
+        Some(guid) => format!(
+            "kbdi keyboard_uninstall {}, then install the keyboard again",
+            guid
+        ),
+        None => format!(
+            r"delete HKEY_LOCAL_MACHINE\{}\{}, then install the keyboard again",
+            keyboard_layouts_key().path,
+            keyboard.regkey_id()
+        ),
+    }
+}
+
+/// Checks the installed keyboards, `Substitutes`, `Preload` and the user's `languages` with their
+/// input methods, as returned by `enabled_keyboards`. Each `Layout File` is looked for in every
+/// one of `system_dirs`, using `file_exists`.
+pub fn diagnose(
+    reg: &dyn RegistryBackend,
+    languages: &[(String, Vec<String>)],
+    system_dirs: &[PathBuf],
+    file_exists: &dyn Fn(&Path) -> bool,
+) -> Result<Vec<Problem>, Error> {
+    let mut problems = vec![];
+    let layouts_key = keyboard_layouts_key();
+    let all_klids: Vec<String> = if reg.key_exists(&layouts_key) {
+        reg.subkeys(&layouts_key)?
+    } else {
+        vec![]
+    };
+    let keyboards = if reg.key_exists(&layouts_key) {
+        KeyboardRegKey::installed(reg)?
+    } else {
+        vec![]
+    };
+    let is_installed = |klid: &str| all_klids.iter().any(|x| x.eq_ignore_ascii_case(klid));
+
+    // Product codes
+    for (i, keyboard) in keyboards.iter().enumerate() {
+        let guid = match keyboard.product_code() {
+            Some(v) => v,
+            None => continue,
+        };
+        if let Some(first) = keyboards[..i]
+            .iter()
+            .find(|x| x.product_code().as_deref() == Some(&guid))
+        {
+            problems.push(Problem {
+                severity: Severity::Error,
+                check: "duplicate_product_code",
+                message: format!(
+                    "{} has the same product code {} as {}",
+                    keyboard.regkey_id(),
+                    guid,
+                    first.regkey_id()
+                ),
+                // Cleaning up only compares the keyboards it may delete
+                fix: if keyboard.is_owned() && first.is_owned() {
+                    "kbdi clean".into()
+                } else {
+                    "kbdi clean --include-foreign".into()
+                },
+            });
+        }
+    }
+
+    // Layout files
+    for keyboard in keyboards.iter() {
+        let layout_file = match keyboard.layout_file() {
+            Some(v) => v,
+            None => {
+                problems.push(Problem {
+                    severity: Severity::Error,
+                    check: "missing_layout_file",
+                    message: format!("{} has no Layout File", keyboard.regkey_id()),
+                    fix: reinstall_fix(keyboard),
+                });
+                continue;
+            }
+        };
+
+        let missing: Vec<&PathBuf> = system_dirs
+            .iter()
+            .filter(|dir| !file_exists(&dir.join(&layout_file)))
+            .collect();
+        if missing.is_empty() {
+            continue;
+        }
+
+        let all_missing = missing.len() == system_dirs.len();
+        let dirs: Vec<String> = missing.iter().map(|x| x.display().to_string()).collect();
+        problems.push(Problem {
+            severity: if all_missing {
+                Severity::Error
+            } else {
+                Severity::Warning
+            },
+            check: "missing_layout_dll",
+            message: format!(
+                "{} uses {}, which is not in {}",
+                keyboard.regkey_id(),
+                layout_file,
+                dirs.join(" or ")
+            ),
+            fix: reinstall_fix(keyboard),
+        });
+    }
+
+    // Layout ids
+    let mut layout_ids: Vec<(String, String)> = vec![];
+    for klid in all_klids.iter() {
+        if let Some(id) = backend::string_value(reg, &layouts_key.join(klid), "Layout Id") {
+            if let Some((first, _)) = layout_ids.iter().find(|(_, x)| x.eq_ignore_ascii_case(&id)) {
+                problems.push(Problem {
+                    severity: Severity::Error,
+                    check: "duplicate_layout_id",
+                    message: format!("{} has the same Layout Id {} as {}", klid, id, first),
+                    fix: match keyboards.iter().find(|x| x.regkey_id() == klid) {
+                        Some(keyboard) => reinstall_fix(keyboard),
+                        None => format!("reinstall the keyboard using {}", klid),
+                    },
+                });
+            }
+            layout_ids.push((klid.to_owned(), id));
+        }
+    }
+    for keyboard in keyboards.iter().filter(|x| x.id().is_none()) {
+        problems.push(Problem {
+            severity: Severity::Error,
+            check: "missing_layout_id",
+            message: format!("{} has no Layout Id", keyboard.regkey_id()),
+            fix: reinstall_fix(keyboard),
+        });
+    }
+
+    // Substitutes
+    if reg.key_exists(&substitutes_key()) {
+        for (name, data) in reg.values(&substitutes_key())? {
+            let klid = data.to_string();
+            if !is_installed(&klid) {
+                problems.push(Problem {
+                    severity: Severity::Warning,
+                    check: "dangling_substitute",
+                    message: format!(
+                        "Substitute {} points at {}, which is not installed",
+                        name, klid
+                    ),
+                    fix: "kbdi registry_regen".into(),
+                });
+            }
+        }
+    }
+
+    // Preload
+    if reg.key_exists(&preload_key()) {
+        let mut names: Vec<u32> = vec![];
+        for (name, _) in reg.values(&preload_key())? {
+            match name.parse::<u32>() {
+                Ok(n) if n > 0 => names.push(n),
+                _ => problems.push(Problem {
+                    severity: Severity::Warning,
+                    check: "preload_gap",
+                    message: format!("Preload has a value named {:?}, not a number", name),
+                    fix: "kbdi registry_regen".into(),
+                }),
+            }
+        }
+        names.sort();
+        for (expected, n) in (1..).zip(names.iter()) {
+            if *n != expected {
+                problems.push(Problem {
+                    severity: Severity::Warning,
+                    check: "preload_gap",
+                    message: format!("Preload skips from {} to {}", expected - 1, n),
+                    fix: "kbdi registry_regen".into(),
+                });
+                break;
+            }
+        }
+    }
+
+    // Enabled input methods, which cleaning up drops unless `valid_keyboards` keeps them
+    let valid = valid_keyboards(reg, languages)?;
+    for (tag, imes) in languages.iter() {
+        if imes.is_empty() {
+            problems.push(Problem {
+                severity: Severity::Warning,
+                check: "language_without_input_methods",
+                message: format!("{} is enabled but has no input methods", tag),
+                fix: "kbdi clean".into(),
+            });
+        }
+
+        let kept = valid
+            .iter()
+            .find(|(x, _)| x == tag)
+            .map(|(_, imes)| &imes[..])
+            .unwrap_or_default();
+        for ime in imes.iter() {
+            let item = match InputListItem::try_from(&**ime) {
+                Ok(v) => v,
+                Err(e) => {
+                    problems.push(Problem {
+                        severity: Severity::Error,
+                        check: "invalid_input_method",
+                        message: format!("{}: {}", tag, e),
+                        fix: "kbdi clean".into(),
+                    });
+                    continue;
+                }
+            };
+
+            if !kept.contains(ime) {
+                problems.push(Problem {
+                    severity: Severity::Error,
+                    check: "uninstalled_input_method",
+                    message: format!("{} has input method {}, which is not installed", tag, item),
+                    fix: "kbdi clean".into(),
+                });
+            }
+        }
+    }
+
+    Ok(problems)
+}
+
+/// Checks the live system, looking for layout DLLs in `System32` and, on 64-bit Windows,
+/// `SysWOW64`.
+#[cfg(windows)]
+pub fn diagnose_system(reg: &dyn RegistryBackend) -> Result<Vec<Problem>, Error> {
+    let root = crate::deploy::SystemRoot::current();
+    let system_dirs: Vec<PathBuf> = std::iter::once(root.system32())
+        .chain(root.syswow64())
+        .filter(|x| root.resolve(x).is_dir())
+        .collect();
+
+    diagnose(reg, &crate::enabled_keyboards()?, &system_dirs, &|path| {
+        root.resolve(path).exists()
+    })
+}
+
+#[test]
+fn test_diagnose() {
+    use crate::backend::{Data, MemoryRegistry};
+    use crate::keyboard::Ownership;
+
+    let reg = MemoryRegistry::new();
+    let se = "se-NO".parse().unwrap();
+    reg.create_key(&keyboard_layouts_key()).unwrap();
+    reg.create_key(&keyboard_layouts_key().join("00000409"))
+        .unwrap();
+    KeyboardRegKey::create(
+        &reg,
+        &se,
+        0x043b,
+        "",
+        "{1}",
+        "kbdse01.dll",
+        "",
+        &Ownership::new(None),
+    )
+    .unwrap();
+    KeyboardRegKey::create(
+        &reg,
+        &se,
+        0x043b,
+        "",
+        "{1}",
+        "kbdse02.dll",
+        "",
+        &Ownership::new(None),
+    )
+    .unwrap();
+    reg.set_value(
+        &keyboard_layouts_key().join("00000409"),
+        "Layout Id",
+        &Data::String("0100".into()),
+    )
+    .unwrap();
+    let a001 = keyboard_layouts_key().join("a001043b");
+    reg.delete_value(&a001, "Layout Id").unwrap();
+
+    reg.create_key(&substitutes_key()).unwrap();
+    reg.set_value(
+        &substitutes_key(),
+        "d001043b",
+        &Data::String("a000043b".into()),
+    )
+    .unwrap();
+    reg.set_value(
+        &substitutes_key(),
+        "d0010407",
+        &Data::String("a0000407".into()),
+    )
+    .unwrap();
+    reg.create_key(&preload_key()).unwrap();
+    reg.set_value(&preload_key(), "1", &Data::String("00000409".into()))
+        .unwrap();
+    reg.set_value(&preload_key(), "3", &Data::String("d001043b".into()))
+        .unwrap();
+
+    let languages = vec![
+        (
+            "en-US".to_string(),
+            vec!["0409:00000409".to_string(), "0409:A0000409".to_string()],
+        ),
+        ("se-NO".to_string(), vec!["043B:A000043B".to_string()]),
+        ("fi-FI".to_string(), vec![]),
+    ];
+    let system_dirs = vec![PathBuf::from("System32"), PathBuf::from("SysWOW64")];
+    let problems = diagnose(&reg, &languages, &system_dirs, &|path| {
+        path == Path::new("System32").join("kbdse01.dll")
+            || path == Path::new("SysWOW64").join("kbdse01.dll")
+            || path == Path::new("System32").join("kbdse02.dll")
+    })
+    .unwrap();
+
+    let found: Vec<(Severity, &str)> = problems.iter().map(|x| (x.severity, x.check)).collect();
+    assert_eq!(
+        found,
+        vec![
+            (Severity::Error, "duplicate_product_code"),
+            (Severity::Warning, "missing_layout_dll"),
+            (Severity::Error, "duplicate_layout_id"),
+            (Severity::Error, "missing_layout_id"),
+            (Severity::Warning, "dangling_substitute"),
+            (Severity::Warning, "preload_gap"),
+            (Severity::Error, "uninstalled_input_method"),
+            (Severity::Warning, "language_without_input_methods"),
+        ]
+    );
+    assert_eq!(
+        problems[5].to_string(),
+        "warning: Preload skips from 1 to 3\n  fix: kbdi registry_regen"
+    );
+    assert_eq!(
+        problems[1].fix,
+        "kbdi keyboard_uninstall {1}, then install the keyboard again"
+    );
+
+    let problems = diagnose(&MemoryRegistry::new(), &[], &system_dirs, &|_| false).unwrap();
+    assert!(problems.is_empty());
+}
+
+#[test]
+fn test_fixes() {
+    use crate::backend::MemoryRegistry;
+    use crate::keyboard::{remove_duplicate_guids, Ownership};
+
+    let reg = MemoryRegistry::new();
+    let se = "se-NO".parse().unwrap();
+    reg.create_key(&keyboard_layouts_key()).unwrap();
+    let msklc = Ownership {
+        installer: "msklc".into(),
+        version: "1.4".into(),
+        installed: "".into(),
+        source: None,
+    };
+    KeyboardRegKey::create(&reg, &se, 0x043b, "", "{1}", "kbdse01.dll", "", &msklc).unwrap();
+    KeyboardRegKey::create(
+        &reg,
+        &se,
+        0x043b,
+        "",
+        "{1}",
+        "kbdse02.dll",
+        "",
+        &Ownership::new(None),
+    )
+    .unwrap();
+
+    let enabled = vec![
+        (
+            "se-NO".to_string(),
+            vec![
+                "043B:A000043B".to_string(),
+                "043B:A009043B".to_string(),
+                "nonsense".to_string(),
+            ],
+        ),
+        ("fi-FI".to_string(), vec![]),
+    ];
+    let problems = diagnose(&reg, &enabled, &[], &|_| true).unwrap();
+    let fixes: Vec<(&str, &str)> = problems.iter().map(|x| (x.check, &*x.fix)).collect();
+    assert_eq!(
+        fixes,
+        vec![
+            ("duplicate_product_code", "kbdi clean --include-foreign"),
+            ("uninstalled_input_method", "kbdi clean"),
+            ("invalid_input_method", "kbdi clean"),
+            ("language_without_input_methods", "kbdi clean"),
+        ]
+    );
+
+    // What `kbdi clean` does to the keyboards and input methods it found
+    let clean = |include_foreign: bool| {
+        remove_duplicate_guids(&reg, include_foreign).unwrap();
+        valid_keyboards(&reg, &enabled).unwrap()
+    };
+
+    let languages = clean(false);
+    let problems = diagnose(&reg, &languages, &[], &|_| true).unwrap();
+    assert_eq!(problems.len(), 1);
+    assert_eq!(problems[0].check, "duplicate_product_code");
+
+    let languages = clean(true);
+    let problems = diagnose(&reg, &languages, &[], &|_| true).unwrap();
+    assert_eq!(problems, vec![]);
+}
@@ -0,0 +1,389 @@
+//! Recording of the changes an operation would make, without making them.
+//!
+//! Registry writes are captured by [`DryRunRegistry`], which layers them over a live backend so
+//! later reads in the same run see them. Calls into Windows APIs that change user configuration
+//! check [`is_active`] and record themselves instead of running.
+
+use crate::backend::{Data, Error, Hive, KeyPath, RegistryBackend};
+use indexmap::IndexMap;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    CreateKey(KeyPath),
+    DeleteKey(KeyPath),
+    SetValue(KeyPath, String, Data),
+    DeleteValue(KeyPath, String),
+    InstallLayoutOrTip(String, i32),
+    SetUserLanguages(Vec<String>),
+    RemoveInputsForAllLanguages,
+    SyncLanguageDataToCloud,
+    CopyFile(PathBuf, PathBuf),
+    DeleteFile(PathBuf),
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Change::CreateKey(key) => write!(f, "create key    {}", key),
+            Change::DeleteKey(key) => write!(f, "delete key    {}", key),
+            Change::SetValue(key, name, data) => {
+                write!(f, "set value     {} [{}] = {:?}", key, name, data)
+            }
+            Change::DeleteValue(key, name) => write!(f, "delete value  {} [{}]", key, name),
+            Change::InstallLayoutOrTip(input, flag) => {
+                write!(f, "call          InstallLayoutOrTip({:?}, {})", input, flag)
+            }
+            Change::SetUserLanguages(tags) => {
+                write!(f, "call          SetUserLanguages({:?})", tags)
+            }
+            Change::RemoveInputsForAllLanguages => {
+                write!(f, "call          RemoveInputsForAllLanguagesInternal()")
+            }
+            Change::SyncLanguageDataToCloud => write!(f, "call          SyncLanguageDataToCloud()"),
+            Change::CopyFile(from, to) => {
+                write!(f, "copy file     {} -> {}", from.display(), to.display())
+            }
+            Change::DeleteFile(path) => write!(f, "delete file   {}", path.display()),
+        }
+    }
+}
+
+thread_local! {
+    static PLAN: RefCell<Option<Vec<Change>>> = const { RefCell::new(None) };
+}
+
+/// Starts recording changes on this thread.
+pub fn start() {
+    PLAN.with(|plan| *plan.borrow_mut() = Some(vec![]));
+}
+
+pub fn is_active() -> bool {
+    PLAN.with(|plan| plan.borrow().is_some())
+}
+
+pub fn record(change: Change) {
+    log::debug!("Dry run: {}", &change);
+    PLAN.with(|plan| {
+        if let Some(plan) = plan.borrow_mut().as_mut() {
+            plan.push(change);
+        }
+    });
+}
+
+/// Stops recording and returns the changes recorded since [`start`].
+pub fn finish() -> Vec<Change> {
+    PLAN.with(|plan| plan.borrow_mut().take().unwrap_or_default())
+}
+
+#[derive(Debug, Clone)]
+enum KeyState {
+    Deleted,
+    Present {
+        name: String,
+        /// The key was created by this run, so anything the live registry holds under it is gone.
+        shadow: bool,
+        values: IndexMap<String, Option<(String, Data)>>,
+    },
+}
+
+/// Reads from `inner`, but keeps every write in memory and records it as a [`Change`].
+pub struct DryRunRegistry<'a> {
+    inner: &'a dyn RegistryBackend,
+    keys: RefCell<BTreeMap<(Hive, String), KeyState>>,
+}
+
+fn lookup_key(key: &KeyPath) -> (Hive, String) {
+    (key.hive, key.path.to_lowercase())
+}
+
+impl<'a> DryRunRegistry<'a> {
+    pub fn new(inner: &'a dyn RegistryBackend) -> DryRunRegistry<'a> {
+        DryRunRegistry {
+            inner,
+            keys: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    /// Whether the live contents of the key can still be seen through this run's changes.
+    fn inner_visible(&self, key: &KeyPath) -> bool {
+        let keys = self.keys.borrow();
+        let mut current = Some(key.clone());
+
+        while let Some(k) = current {
+            match keys.get(&lookup_key(&k)) {
+                Some(KeyState::Deleted) | Some(KeyState::Present { shadow: true, .. }) => {
+                    return false
+                }
+                _ => {}
+            }
+            current = k.parent();
+        }
+
+        true
+    }
+
+    fn with_values<T>(
+        &self,
+        key: &KeyPath,
+        f: impl FnOnce(&mut IndexMap<String, Option<(String, Data)>>) -> T,
+    ) -> Result<T, Error> {
+        if !self.key_exists(key) {
+            return Err(Error::KeyNotFound(key.clone()));
+        }
+
+        let mut keys = self.keys.borrow_mut();
+        let state = keys
+            .entry(lookup_key(key))
+            .or_insert_with(|| KeyState::Present {
+                name: key.name().to_string(),
+                shadow: false,
+                values: IndexMap::new(),
+            });
+
+        match state {
+            KeyState::Present { values, .. } => Ok(f(values)),
+            KeyState::Deleted => Err(Error::KeyNotFound(key.clone())),
+        }
+    }
+}
+
+impl<'a> RegistryBackend for DryRunRegistry<'a> {
+    fn key_exists(&self, key: &KeyPath) -> bool {
+        if key.path.is_empty() {
+            return true;
+        }
+
+        match self.keys.borrow().get(&lookup_key(key)) {
+            Some(KeyState::Present { .. }) => return true,
+            Some(KeyState::Deleted) => return false,
+            None => {}
+        }
+
+        self.inner_visible(key) && self.inner.key_exists(key)
+    }
+
+    fn create_key(&self, key: &KeyPath) -> Result<(), Error> {
+        let mut chain = vec![];
+        let mut current = Some(key.clone());
+        while let Some(k) = current {
+            current = k.parent();
+            if !k.path.is_empty() {
+                chain.push(k);
+            }
+        }
+
+        for k in chain.into_iter().rev() {
+            if self.key_exists(&k) {
+                continue;
+            }
+
+            self.keys.borrow_mut().insert(
+                lookup_key(&k),
+                KeyState::Present {
+                    name: k.name().to_string(),
+                    shadow: true,
+                    values: IndexMap::new(),
+                },
+            );
+            record(Change::CreateKey(k));
+        }
+
+        Ok(())
+    }
+
+    fn delete_key(&self, key: &KeyPath) -> Result<(), Error> {
+        if key.path.is_empty() || !self.key_exists(key) {
+            return Err(Error::KeyNotFound(key.clone()));
+        }
+
+        let (hive, path) = lookup_key(key);
+        let mut keys = self.keys.borrow_mut();
+        keys.retain(|(h, p), _| {
+            !(*h == hive && matches!(p.strip_prefix(&path), Some(x) if x.starts_with('\\')))
+        });
+        keys.insert((hive, path), KeyState::Deleted);
+        record(Change::DeleteKey(key.clone()));
+
+        Ok(())
+    }
+
+    fn subkeys(&self, key: &KeyPath) -> Result<Vec<String>, Error> {
+        if !self.key_exists(key) {
+            return Err(Error::KeyNotFound(key.clone()));
+        }
+
+        let mut names = match self.inner_visible(key) {
+            true => self.inner.subkeys(key)?,
+            false => vec![],
+        };
+        names.retain(|x| self.key_exists(&key.join(x)));
+
+        let (hive, path) = lookup_key(key);
+        for ((h, p), state) in self.keys.borrow().iter() {
+            let rest = match p.strip_prefix(&path) {
+                Some(rest) if path.is_empty() => rest,
+                Some(rest) if rest.starts_with('\\') => &rest[1..],
+                _ => continue,
+            };
+
+            if *h != hive || rest.is_empty() || rest.contains('\\') {
+                continue;
+            }
+
+            if let KeyState::Present { name, .. } = state {
+                if !names.iter().any(|x| x.eq_ignore_ascii_case(name)) {
+                    names.push(name.clone());
+                }
+            }
+        }
+
+        Ok(names)
+    }
+
+    fn values(&self, key: &KeyPath) -> Result<Vec<(String, Data)>, Error> {
+        if !self.key_exists(key) {
+            return Err(Error::KeyNotFound(key.clone()));
+        }
+
+        let mut values: IndexMap<String, (String, Data)> = match self.inner_visible(key) {
+            true => self
+                .inner
+                .values(key)?
+                .into_iter()
+                .map(|(name, data)| (name.to_lowercase(), (name, data)))
+                .collect(),
+            false => IndexMap::new(),
+        };
+
+        if let Some(KeyState::Present {
+            values: changes, ..
+        }) = self.keys.borrow().get(&lookup_key(key))
+        {
+            for (lower, change) in changes.iter() {
+                match change {
+                    Some(v) => {
+                        values.insert(lower.clone(), v.clone());
+                    }
+                    None => {
+                        values.shift_remove(lower);
+                    }
+                }
+            }
+        }
+
+        Ok(values.into_iter().map(|(_, v)| v).collect())
+    }
+
+    fn value(&self, key: &KeyPath, name: &str) -> Result<Data, Error> {
+        self.values(key)?
+            .into_iter()
+            .find(|(x, _)| x.eq_ignore_ascii_case(name))
+            .map(|(_, data)| data)
+            .ok_or_else(|| Error::ValueNotFound(key.clone(), name.to_string()))
+    }
+
+    fn set_value(&self, key: &KeyPath, name: &str, data: &Data) -> Result<(), Error> {
+        self.with_values(key, |values| {
+            values.insert(name.to_lowercase(), Some((name.to_string(), data.clone())));
+        })?;
+        record(Change::SetValue(
+            key.clone(),
+            name.to_string(),
+            data.clone(),
+        ));
+        Ok(())
+    }
+
+    fn delete_value(&self, key: &KeyPath, name: &str) -> Result<(), Error> {
+        self.value(key, name)?;
+        self.with_values(key, |values| {
+            values.insert(name.to_lowercase(), None);
+        })?;
+        record(Change::DeleteValue(key.clone(), name.to_string()));
+        Ok(())
+    }
+}
+
+#[test]
+fn test_dry_run_registry() {
+    use crate::backend::MemoryRegistry;
+
+    let live = MemoryRegistry::new();
+    let layouts = KeyPath::new(Hive::LocalMachine, r"SYSTEM\Keyboard Layouts");
+    let preload = KeyPath::new(Hive::CurrentUser, r"Keyboard Layout\Preload");
+    live.create_key(&layouts.join("00000409")).unwrap();
+    live.create_key(&layouts.join("a000043b")).unwrap();
+    live.create_key(&preload).unwrap();
+    live.set_value(&preload, "1", &Data::String("00000409".into()))
+        .unwrap();
+
+    start();
+    let reg = DryRunRegistry::new(&live);
+    reg.delete_key(&layouts.join("A000043B")).unwrap();
+    reg.create_key(&layouts.join("a001043b")).unwrap();
+    reg.set_value(
+        &layouts.join("a001043b"),
+        "Layout Id",
+        &Data::String("0100".into()),
+    )
+    .unwrap();
+    reg.delete_value(&preload, "1").unwrap();
+    reg.set_value(&preload, "1", &Data::String("a001043b".into()))
+        .unwrap();
+
+    assert_eq!(
+        reg.subkeys(&layouts).unwrap(),
+        vec!["00000409".to_string(), "a001043b".to_string()]
+    );
+    assert_eq!(
+        reg.values(&preload).unwrap(),
+        vec![("1".to_string(), Data::String("a001043b".into()))]
+    );
+    assert_eq!(
+        live.subkeys(&layouts).unwrap(),
+        vec!["00000409".to_string(), "a000043b".to_string()]
+    );
+    assert_eq!(
+        live.value(&preload, "1").unwrap(),
+        Data::String("00000409".into())
+    );
+
+    assert_eq!(
+        finish(),
+        vec![
+            Change::DeleteKey(layouts.join("A000043B")),
+            Change::CreateKey(layouts.join("a001043b")),
+            Change::SetValue(
+                layouts.join("a001043b"),
+                "Layout Id".into(),
+                Data::String("0100".into())
+            ),
+            Change::DeleteValue(preload.clone(), "1".into()),
+            Change::SetValue(preload.clone(), "1".into(), Data::String("a001043b".into())),
+        ]
+    );
+    assert!(!is_active());
+}
+
+#[test]
+fn test_dry_run_recreated_key_hides_live_contents() {
+    use crate::backend::MemoryRegistry;
+
+    let live = MemoryRegistry::new();
+    let key = KeyPath::new(Hive::CurrentUser, r"Keyboard Layout\Substitutes");
+    live.create_key(&key).unwrap();
+    live.set_value(&key, "d001043b", &Data::String("a000043b".into()))
+        .unwrap();
+
+    let reg = DryRunRegistry::new(&live);
+    reg.delete_key(&key.parent().unwrap()).unwrap();
+    assert!(!reg.key_exists(&key));
+
+    reg.create_key(&key).unwrap();
+    assert_eq!(reg.values(&key).unwrap(), vec![]);
+    assert!(live.key_exists(&key));
+}
@@ -0,0 +1,183 @@
+use crate::{backend, klid, language_tag, pe, regf};
+use std::io;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("A keyboard with product code {0} is already installed")]
+    AlreadyInstalled(String),
+
+    #[error("No keyboard with product code {0} is installed")]
+    NotInstalled(String),
+
+    #[error("{file} is already used by {owner}, so it was not replaced")]
+    ForeignLayoutDll { file: String, owner: String },
+
+    #[error("Language tag {0:?} is not supported by Windows")]
+    UnsupportedLanguage(String),
+
+    #[error("Language tag {0:?} has no LCID")]
+    NoLcid(String),
+
+    #[error(transparent)]
+    InvalidLanguageTag(#[from] language_tag::Error),
+
+    #[error("Invalid input method list: {0}")]
+    InvalidInputMethods(String),
+
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+
+    #[error("Invalid manifest: {0}")]
+    InvalidManifest(String),
+
+    #[error("Invalid .reg file: {0}")]
+    InvalidRegFile(String),
+
+    #[error("Invalid snapshot: {0}")]
+    InvalidSnapshot(String),
+
+    #[error("Invalid keyboard layout DLL {file}: {source}")]
+    LayoutDll {
+        file: String,
+        #[source]
+        source: pe::Error,
+    },
+
+    #[error("Could not allocate a keyboard layout id: {0}")]
+    Allocation(#[from] klid::Error),
+
+    #[error("Registry error: {0}")]
+    Registry(#[from] backend::Error),
+
+    #[error("Invalid registry hive file: {0}")]
+    Hive(#[from] regf::Error),
+
+    #[error("{function} failed: {source}")]
+    Win32 {
+        function: &'static str,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("{function} failed with HRESULT 0x{code:08x}")]
+    Hresult { function: &'static str, code: i32 },
+
+    #[error("Could not load {library}: {message}")]
+    LibraryLoad {
+        library: &'static str,
+        message: String,
+    },
+
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Path error: {0}")]
+    Path(#[from] pathos::Error),
+
+    #[error("Set logger error: {0}")]
+    SetLoggerError(#[from] log::SetLoggerError),
+
+    #[error("Could not serialize output: {0}")]
+    Serialize(#[from] serde_json::Error),
+}
+
+impl Error {
+    /// The process exit code for this error. Installers check these, so existing codes must not
+    /// change; see the table in the README.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::AlreadyInstalled(_) => 10,
+            Error::NotInstalled(_) => 11,
+            Error::ForeignLayoutDll { .. } => 12,
+            Error::UnsupportedLanguage(_) | Error::NoLcid(_) | Error::InvalidLanguageTag(_) => 20,
+            Error::InvalidInputMethods(_)
+            | Error::InvalidArgument(_)
+            | Error::InvalidManifest(_)
+            | Error::InvalidRegFile(_)
+            | Error::InvalidSnapshot(_)
+            | Error::LayoutDll { .. } => 30,
+            Error::Allocation(_) => 31,
+            Error::Registry(_) | Error::Hive(_) => 40,
+            Error::Win32 { .. } | Error::Hresult { .. } | Error::LibraryLoad { .. } => 50,
+            Error::Io(_) | Error::Path(_) | Error::SetLoggerError(_) | Error::Serialize(_) => 60,
+        }
+    }
+
+    #[cfg(windows)]
+    pub(crate) fn win32(function: &'static str) -> Error {
+        Error::Win32 {
+            function,
+            source: io::Error::last_os_error(),
+        }
+    }
+}
+
+#[test]
+fn test_exit_codes() {
+    use crate::backend::{Hive, KeyPath};
+
+    let key = KeyPath::new(Hive::LocalMachine, "SYSTEM");
+    assert_eq!(Error::AlreadyInstalled("{1}".into()).exit_code(), 10);
+    assert_eq!(Error::NotInstalled("{1}".into()).exit_code(), 11);
+    assert_eq!(
+        Error::ForeignLayoutDll {
+            file: "kbdus.dll".into(),
+            owner: "keyboard layout 00000409".into(),
+        }
+        .exit_code(),
+        12
+    );
+    assert_eq!(Error::NoLcid("x-foo".into()).exit_code(), 20);
+    assert_eq!(Error::from(language_tag::Error::Empty).exit_code(), 20);
+    assert_eq!(
+        Error::LayoutDll {
+            file: "kbdse01.dll".into(),
+            source: pe::Error::MissingLayoutDescriptor
+        }
+        .exit_code(),
+        30
+    );
+    assert_eq!(Error::from(klid::Error::LayoutIdsExhausted).exit_code(), 31);
+    assert_eq!(
+        Error::from(backend::Error::KeyNotFound(key)).exit_code(),
+        40
+    );
+    assert_eq!(Error::from(regf::Error::Dirty).exit_code(), 40);
+    assert_eq!(
+        Error::Hresult {
+            function: "SetUserLanguages",
+            code: -2147024809
+        }
+        .exit_code(),
+        50
+    );
+}
+
+#[test]
+fn test_messages_include_source() {
+    use crate::backend::{Hive, KeyPath};
+
+    let key = KeyPath::new(Hive::LocalMachine, "SYSTEM");
+    let access_denied = || io::Error::new(io::ErrorKind::PermissionDenied, "Access is denied.");
+    let registry = Error::from(backend::Error::KeyNotFound(key.clone()));
+    assert_eq!(
+        registry.to_string(),
+        format!("Registry error: {}", backend::Error::KeyNotFound(key))
+    );
+    let win32 = Error::Win32 {
+        function: "RegLoadAppKeyW",
+        source: access_denied(),
+    };
+    assert_eq!(
+        win32.to_string(),
+        "RegLoadAppKeyW failed: Access is denied."
+    );
+    assert_eq!(
+        Error::from(access_denied()).to_string(),
+        "IO error: Access is denied."
+    );
+    assert_eq!(
+        Error::from(regf::Error::Io(access_denied())).to_string(),
+        "Invalid registry hive file: IO error: Access is denied."
+    );
+}
@@ -0,0 +1,554 @@
+//! Transactions over a registry backend.
+//!
+//! Each change made through [`Transaction`] is journaled with how to undo it once it has been
+//! made. Unless the transaction is committed, dropping it (including while unwinding from a panic)
+//! undoes every journaled change in reverse order.
+//!
+//! A transaction begun over another one journals its registry writes in both, but its
+//! [`Transaction::on_rollback`] calls only in itself, and those are dropped when it commits. Work
+//! that must be undone along with its caller's therefore takes the caller's transaction rather
+//! than beginning its own.
+//!
+//! After [`persist_to`], the journal is also written to a file before each change is made, so a
+//! process that crashes or is killed leaves behind what it had not finished. [`recover`] undoes
+//! its registry changes on the next run; changes made through Windows APIs can only be reported.
+
+use crate::backend::{Data, Error, KeyPath, RegistryBackend};
+use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+/// A key with all of its values and subkeys.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tree {
+    pub key: KeyPath,
+    pub values: Vec<(String, Data)>,
+    pub children: Vec<Tree>,
+}
+
+impl Tree {
+    pub fn read(reg: &dyn RegistryBackend, key: &KeyPath) -> Result<Tree, Error> {
+        Ok(Tree {
+            key: key.clone(),
+            values: reg.values(key)?,
+            children: reg
+                .subkeys(key)?
+                .iter()
+                .map(|x| Tree::read(reg, &key.join(x)))
+                .collect::<Result<Vec<_>, _>>()?,
+        })
+    }
+
+    pub fn write(&self, reg: &dyn RegistryBackend) -> Result<(), Error> {
+        reg.create_key(&self.key)?;
+        for (name, data) in self.values.iter() {
+            reg.set_value(&self.key, name, data)?;
+        }
+        for child in self.children.iter() {
+            child.write(reg)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Undo {
+    DeleteKey(KeyPath),
+    RestoreKey(Tree),
+    SetValue(KeyPath, String, Data),
+    DeleteValue(KeyPath, String),
+    /// A change made outside of the registry backend. Only the process that made it holds the
+    /// function reverting it.
+    Call(String),
+}
+
+impl Undo {
+    fn apply(&self, reg: &dyn RegistryBackend) -> Result<(), Error> {
+        match self {
+            Undo::DeleteKey(key) => reg.delete_key(key),
+            Undo::RestoreKey(tree) => tree.write(reg),
+            Undo::SetValue(key, name, data) => reg.set_value(key, name, data),
+            Undo::DeleteValue(key, name) => reg.delete_value(key, name),
+            // Journaled along with its function, which undoes it instead
+            Undo::Call(description) => unreachable!("{} has no registry undo", description),
+        }
+    }
+}
+
+impl fmt::Display for Undo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Undo::DeleteKey(key) => write!(f, "delete key {}", key),
+            Undo::RestoreKey(tree) => write!(f, "restore key {}", tree.key),
+            Undo::SetValue(key, name, data) => write!(f, "set {} [{}] = {:?}", key, name, data),
+            Undo::DeleteValue(key, name) => write!(f, "delete {} [{}]", key, name),
+            Undo::Call(description) => f.write_str(description),
+        }
+    }
+}
+
+/// A line of the journal file.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Record {
+    /// Written before transaction `tx` makes a change, with how to undo it.
+    Undo { tx: u64, undo: Undo },
+    /// Transaction `tx` was committed or rolled back, so nothing of it is left to undo.
+    Done { tx: u64 },
+}
+
+struct JournalFile {
+    path: PathBuf,
+    next_tx: u64,
+    open: usize,
+}
+
+thread_local! {
+    static FILE: RefCell<Option<JournalFile>> = const { RefCell::new(None) };
+}
+
+/// Writes the journal of each transaction begun on this thread from now on to `path` too, other
+/// than during a dry run. The file is removed once every such transaction has finished.
+pub fn persist_to(path: PathBuf) {
+    FILE.with(|file| {
+        *file.borrow_mut() = Some(JournalFile {
+            path,
+            next_tx: 1,
+            open: 0,
+        })
+    });
+}
+
+/// Stops writing journals to a file on this thread.
+pub fn stop_persisting() {
+    FILE.with(|file| *file.borrow_mut() = None);
+}
+
+fn append(path: &Path, record: &Record) -> io::Result<()> {
+    let mut line = serde_json::to_string(record)?;
+    line.push('\n');
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(line.as_bytes())?;
+    file.sync_data()
+}
+
+/// Undoes the changes of the transactions journaled in `path` that never finished, newest first,
+/// then removes the file. A change the crashed run journaled but did not get to make is already
+/// undone, so keys and values that are not found are skipped. Returns the changes that could not
+/// be undone, such as those made through Windows APIs.
+pub fn recover(reg: &dyn RegistryBackend, path: &Path) -> io::Result<Vec<String>> {
+    let file = match fs::File::open(path) {
+        Ok(v) => v,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+
+    let mut records = vec![];
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        // The last line is cut short if the run was killed while writing it
+        match serde_json::from_str::<Record>(&line) {
+            Ok(v) => records.push(v),
+            Err(e) => log::warn!("Skipping journal line {:?}: {}", line, e),
+        }
+    }
+
+    let done: Vec<u64> = records
+        .iter()
+        .filter_map(|x| match x {
+            Record::Done { tx } => Some(*tx),
+            Record::Undo { .. } => None,
+        })
+        .collect();
+
+    let mut errors = vec![];
+    for record in records.into_iter().rev() {
+        let undo = match record {
+            Record::Undo { tx, undo } if !done.contains(&tx) => undo,
+            _ => continue,
+        };
+
+        if let Undo::Call(description) = &undo {
+            log::error!("Cannot recover: {}", description);
+            errors.push(format!(
+                "{}: only the run that made this change could undo it",
+                undo
+            ));
+            continue;
+        }
+
+        log::info!("Recovering: {}", &undo);
+        match undo.apply(reg) {
+            Ok(()) | Err(Error::KeyNotFound(_)) | Err(Error::ValueNotFound(..)) => {}
+            Err(e) => {
+                log::error!("Failed to {}: {}", undo, e);
+                errors.push(format!("{}: {}", undo, e));
+            }
+        }
+    }
+
+    fs::remove_file(path)?;
+    Ok(errors)
+}
+
+/// The function undoing an [`Undo::Call`].
+type Revert<'a> = Box<dyn FnOnce() -> Result<(), String> + 'a>;
+
+pub struct Transaction<'a> {
+    inner: &'a dyn RegistryBackend,
+    journal: RefCell<Vec<(Undo, Option<Revert<'a>>)>>,
+    committed: Cell<bool>,
+    /// This transaction's id in the journal file, if it is written to one
+    tx: Option<u64>,
+}
+
+impl<'a> Transaction<'a> {
+    pub fn begin(inner: &'a dyn RegistryBackend) -> Transaction<'a> {
+        let tx = match crate::dry_run::is_active() {
+            true => None,
+            false => FILE.with(|file| {
+                file.borrow_mut().as_mut().map(|file| {
+                    file.open += 1;
+                    file.next_tx += 1;
+                    file.next_tx - 1
+                })
+            }),
+        };
+
+        Transaction {
+            inner,
+            journal: RefCell::new(vec![]),
+            committed: Cell::new(false),
+            tx,
+        }
+    }
+
+    /// The backend this transaction writes through to, for changes that need no undoing.
+    pub fn inner(&self) -> &'a dyn RegistryBackend {
+        self.inner
+    }
+
+    /// Writes `undo` to the journal file, before the change it undoes is made.
+    fn write_ahead(&self, undo: &Undo) -> io::Result<()> {
+        let tx = match self.tx {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        FILE.with(|file| match file.borrow().as_ref() {
+            Some(file) => append(
+                &file.path,
+                &Record::Undo {
+                    tx,
+                    undo: undo.clone(),
+                },
+            ),
+            None => Ok(()),
+        })
+    }
+
+    /// Journals `undo` in memory, once the change it undoes has been made.
+    fn push(&self, undo: Undo) {
+        log::info!("Journal: on rollback, {}", &undo);
+        self.journal.borrow_mut().push((undo, None));
+    }
+
+    /// Makes a registry change with `write`, journaling `undo` ahead of it on disk and, once it
+    /// has been made, in memory. A failed `write` returns whether it still changed anything, in
+    /// which case `undo` is journaled in memory all the same.
+    fn change(
+        &self,
+        key: &KeyPath,
+        undo: Undo,
+        write: impl FnOnce() -> Result<(), (Error, bool)>,
+    ) -> Result<(), Error> {
+        self.write_ahead(&undo).map_err(|e| {
+            Error::Access(key.clone(), format!("could not write the journal: {}", e))
+        })?;
+        match write() {
+            Ok(()) => {
+                self.push(undo);
+                Ok(())
+            }
+            Err((e, changed)) => {
+                if changed {
+                    self.push(undo);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Journals a change made outside of the registry backend, such as a Windows API call, with
+    /// the function that reverts it. Call this before making the change.
+    pub fn on_rollback<F>(&self, description: &str, f: F)
+    where
+        F: FnOnce() -> Result<(), String> + 'a,
+    {
+        let undo = Undo::Call(description.to_string());
+        if let Err(e) = self.write_ahead(&undo) {
+            log::warn!("Could not write {:?} to the journal: {}", description, e);
+        }
+        log::info!("Journal: on rollback, {}", &undo);
+        self.journal.borrow_mut().push((undo, Some(Box::new(f))));
+    }
+
+    pub fn commit(self) {
+        log::debug!("Committing transaction");
+        self.committed.set(true);
+        self.finish();
+    }
+
+    /// Undoes every journaled change, newest first. Failures are logged and skipped so that as
+    /// much as possible is restored; their messages are returned.
+    pub fn rollback(self) -> Vec<String> {
+        self.committed.set(true);
+        let errors = self.undo_all();
+        self.finish();
+        errors
+    }
+
+    fn undo_all(&self) -> Vec<String> {
+        let mut errors = vec![];
+        let journal = std::mem::take(&mut *self.journal.borrow_mut());
+
+        for (undo, f) in journal.into_iter().rev() {
+            log::info!("Rolling back: {}", &undo);
+            let result = match f {
+                Some(f) => f(),
+                None => undo.apply(self.inner).map_err(|e| e.to_string()),
+            };
+
+            if let Err(e) = result {
+                log::error!("Failed to {}: {}", undo, e);
+                errors.push(format!("{}: {}", undo, e));
+            }
+        }
+
+        errors
+    }
+
+    /// Records in the journal file that nothing of this transaction is left to undo, removing the
+    /// file if no other transaction is open.
+    fn finish(&self) {
+        let tx = match self.tx {
+            Some(v) => v,
+            None => return,
+        };
+
+        FILE.with(|file| {
+            let mut file = file.borrow_mut();
+            let file = match file.as_mut() {
+                Some(v) => v,
+                None => return,
+            };
+
+            file.open -= 1;
+            let result = match file.open {
+                0 => fs::remove_file(&file.path).or_else(|e| match e.kind() {
+                    io::ErrorKind::NotFound => Ok(()),
+                    _ => Err(e),
+                }),
+                _ => append(&file.path, &Record::Done { tx }),
+            };
+            if let Err(e) = result {
+                log::warn!("Could not update {}: {}", file.path.display(), e);
+            }
+        });
+    }
+}
+
+impl<'a> Drop for Transaction<'a> {
+    fn drop(&mut self) {
+        if !self.committed.get() {
+            log::warn!("Transaction was not committed; rolling back");
+            self.undo_all();
+            self.finish();
+        }
+    }
+}
+
+impl<'a> RegistryBackend for Transaction<'a> {
+    fn key_exists(&self, key: &KeyPath) -> bool {
+        self.inner.key_exists(key)
+    }
+
+    fn create_key(&self, key: &KeyPath) -> Result<(), Error> {
+        let mut created = None;
+        let mut current = Some(key.clone());
+        while let Some(k) = current {
+            if k.path.is_empty() || self.inner.key_exists(&k) {
+                break;
+            }
+            current = k.parent();
+            created = Some(k);
+        }
+
+        let created = match created {
+            Some(v) => v,
+            None => return self.inner.create_key(key),
+        };
+
+        // A failure partway through leaves the outermost new keys behind
+        let exists = |k: &KeyPath| self.inner.key_exists(k);
+        self.change(key, Undo::DeleteKey(created.clone()), || {
+            self.inner
+                .create_key(key)
+                .map_err(|e| (e, exists(&created)))
+        })
+    }
+
+    fn delete_key(&self, key: &KeyPath) -> Result<(), Error> {
+        let tree = Tree::read(self.inner, key)?;
+
+        // A failure partway through leaves only some of the subkeys deleted
+        let unchanged = |tree: &Tree| matches!(Tree::read(self.inner, key), Ok(ref x) if x == tree);
+        self.change(key, Undo::RestoreKey(tree.clone()), || {
+            self.inner
+                .delete_key(key)
+                .map_err(|e| (e, !unchanged(&tree)))
+        })
+    }
+
+    fn subkeys(&self, key: &KeyPath) -> Result<Vec<String>, Error> {
+        self.inner.subkeys(key)
+    }
+
+    fn values(&self, key: &KeyPath) -> Result<Vec<(String, Data)>, Error> {
+        self.inner.values(key)
+    }
+
+    fn value(&self, key: &KeyPath, name: &str) -> Result<Data, Error> {
+        self.inner.value(key, name)
+    }
+
+    fn set_value(&self, key: &KeyPath, name: &str, data: &Data) -> Result<(), Error> {
+        let undo = match self.inner.value(key, name) {
+            Ok(old) => Undo::SetValue(key.clone(), name.to_string(), old),
+            Err(Error::ValueNotFound(..)) => Undo::DeleteValue(key.clone(), name.to_string()),
+            Err(e) => return Err(e),
+        };
+
+        self.change(key, undo, || {
+            self.inner
+                .set_value(key, name, data)
+                .map_err(|e| (e, false))
+        })
+    }
+
+    fn delete_value(&self, key: &KeyPath, name: &str) -> Result<(), Error> {
+        let old = self.inner.value(key, name)?;
+        self.change(
+            key,
+            Undo::SetValue(key.clone(), name.to_string(), old),
+            || self.inner.delete_value(key, name).map_err(|e| (e, false)),
+        )
+    }
+}
+
+#[test]
+fn test_transaction_rollback() {
+    use crate::backend::{Hive, MemoryRegistry};
+
+    let reg = MemoryRegistry::new();
+    let layouts = KeyPath::new(Hive::LocalMachine, r"SYSTEM\Keyboard Layouts");
+    let old = layouts.join("a000043b");
+    reg.create_key(&old.join("Nested")).unwrap();
+    reg.set_value(&old, "Layout Id", &Data::String("0100".into()))
+        .unwrap();
+    reg.set_value(&old.join("Nested"), "x", &Data::U32(1))
+        .unwrap();
+    let before = Tree::read(&reg, &layouts).unwrap();
+
+    let called = Cell::new(false);
+    {
+        let tx = Transaction::begin(&reg);
+        tx.on_rollback("restore input methods", || {
+            called.set(true);
+            Ok(())
+        });
+        tx.create_key(&layouts.join(r"a001043b\Deep")).unwrap();
+        tx.set_value(
+            &layouts.join("a001043b"),
+            "Layout Id",
+            &Data::String("0101".into()),
+        )
+        .unwrap();
+        tx.set_value(&old, "Layout Id", &Data::String("0102".into()))
+            .unwrap();
+        tx.delete_key(&old).unwrap();
+        assert!(!reg.key_exists(&old));
+    }
+
+    assert!(called.get());
+    assert_eq!(Tree::read(&reg, &layouts).unwrap(), before);
+}
+
+#[test]
+fn test_transaction_commit() {
+    use crate::backend::{Hive, MemoryRegistry};
+
+    let reg = MemoryRegistry::new();
+    let key = KeyPath::new(Hive::CurrentUser, r"Keyboard Layout\Preload");
+
+    let tx = Transaction::begin(&reg);
+    tx.create_key(&key).unwrap();
+    tx.set_value(&key, "1", &Data::String("00000409".into()))
+        .unwrap();
+    tx.commit();
+
+    assert_eq!(
+        reg.value(&key, "1").unwrap(),
+        Data::String("00000409".into())
+    );
+}
+
+#[test]
+fn test_recover() {
+    use crate::backend::{Hive, MemoryRegistry};
+
+    let dir = std::env::temp_dir().join(format!("kbdi-journal-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("journal.jsonl");
+
+    let reg = MemoryRegistry::new();
+    let layouts = KeyPath::new(Hive::LocalMachine, r"SYSTEM\Keyboard Layouts");
+    let old = layouts.join("a000043b");
+    reg.create_key(&old.join("Nested")).unwrap();
+    reg.set_value(&old, "Layout Id", &Data::String("0100".into()))
+        .unwrap();
+    persist_to(path.clone());
+    let tx = Transaction::begin(&reg);
+    tx.create_key(&old.join("New")).unwrap();
+    tx.commit();
+    assert!(!path.exists());
+    let before = Tree::read(&reg, &old).unwrap();
+
+    // A run that is killed partway through leaves its journal behind
+    let tx = Transaction::begin(&reg);
+    tx.on_rollback("restore input methods", || Ok(()));
+    tx.set_value(&old, "Layout Id", &Data::String("0101".into()))
+        .unwrap();
+    tx.set_value(&old, "Layout File", &Data::String("kbdse01.dll".into()))
+        .unwrap();
+    tx.delete_key(&old.join("Nested")).unwrap();
+    tx.delete_key(&old.join("New")).unwrap();
+    std::mem::forget(tx);
+    stop_persisting();
+    assert!(path.exists());
+
+    let errors = recover(&reg, &path).unwrap();
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].starts_with("restore input methods"));
+    assert!(!path.exists());
+    let mut after = Tree::read(&reg, &old).unwrap();
+    after.children.sort_by(|a, b| a.key.path.cmp(&b.key.path));
+    assert_eq!(after, before);
+    assert!(recover(&reg, &path).unwrap().is_empty());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,111 +1,463 @@
+use crate::backend::{self, Data, Hive, KeyPath, RegistryBackend};
+use crate::deploy::{self, SystemRoot};
+#[cfg(any(windows, test))]
+use crate::journal::Transaction;
+use crate::klid;
+#[cfg(windows)]
+use crate::manifest::Manifest;
+use crate::pe::{LayoutDll, LANGUAGE_DISPLAY_NAME, LAYOUT_DISPLAY_NAME};
+#[cfg(windows)]
 use crate::platform::*;
+#[cfg(windows)]
+use crate::profiles::{self, PurgedProfile};
+#[cfg(feature = "legacy")]
 use crate::types::InputList;
-use registry::{Data, Hive, RegKey, Security};
-use std::convert::{TryFrom, TryInto};
+use crate::{Error, LanguageTag};
+use serde::Serialize;
 use std::fmt;
-use std::io;
+#[cfg(any(windows, test))]
 use std::path::Path;
+use std::path::PathBuf;
 
-#[cfg(feature = "legacy")]
+#[cfg(all(windows, feature = "legacy"))]
 pub use crate::keyboard_legacy::*;
 #[cfg(not(feature = "legacy"))]
 pub use crate::keyboard_win8::*;
 
 pub struct KeyboardRegKey {
     id: String,
-    regkey: RegKey,
+    values: Vec<(String, Data)>,
 }
 
-#[derive(Debug)]
-pub enum Error {
-    AlreadyExists,
-    NotFound,
-    IoError(io::Error),
-    RegErr(registry::key::Error),
+/// The installer name kbdi stamps on the keyboards it creates.
+pub const INSTALLER: &str = "kbdi";
+
+/// Who installed a keyboard, stamped on its key. Cleaning up only touches keyboards kbdi owns,
+/// so those of MSKLC and other installers are left alone.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Ownership {
+    pub installer: String,
+    pub version: String,
+    /// When the keyboard was installed or adopted, in RFC 3339.
+    pub installed: String,
+    /// The manifest or `.reg` file the keyboard was installed from, if any.
+    pub source: Option<String>,
+}
+
+impl Ownership {
+    /// Ownership by this version of kbdi, from now.
+    pub fn new(source: Option<&str>) -> Ownership {
+        Ownership {
+            installer: INSTALLER.to_owned(),
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            installed: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            source: source.map(str::to_owned),
+        }
+    }
+
+    fn values(&self) -> Vec<(&'static str, String)> {
+        let mut values = vec![
+            ("Installer", self.installer.to_owned()),
+            ("Installer Version", self.version.to_owned()),
+            ("Install Date", self.installed.to_owned()),
+        ];
+        values.extend(self.source.iter().map(|x| ("Install Source", x.to_owned())));
+        values
+    }
 }
 
+impl fmt::Display for Ownership {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} on {}",
+            self.installer, self.version, self.installed
+        )?;
+        if let Some(source) = &self.source {
+            write!(f, " from {}", source)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
 pub fn install(
-    tag: &str,
-    layout_name: &str,
+    reg: &dyn RegistryBackend,
+    tag: &LanguageTag,
+    layout_name: Option<&str>,
     product_code: &str,
     layout_file: &str,
     display_name: Option<&str>,
+    source: Option<&str>,
 ) -> Result<(), Error> {
+    let tx = Transaction::begin(reg);
+    install_in(
+        &tx,
+        tag,
+        layout_name,
+        product_code,
+        layout_file,
+        display_name,
+        source,
+    )?;
+    tx.commit();
+    Ok(())
+}
+
+/// Installs a keyboard as part of `tx`, so it is undone, deployed files included, if the caller
+/// rolls back after this has succeeded.
+#[cfg(windows)]
+fn install_in(
+    tx: &Transaction,
+    tag: &LanguageTag,
+    layout_name: Option<&str>,
+    product_code: &str,
+    layout_file: &str,
+    display_name: Option<&str>,
+    source: Option<&str>,
+) -> Result<(), Error> {
+    let reg: &dyn RegistryBackend = tx;
+
     log::info!("Checking if already installed");
-    if let Some(_) = KeyboardRegKey::find_by_product_code(product_code) {
-        return Err(Error::AlreadyExists);
+    if KeyboardRegKey::find_by_product_code(reg, product_code)?.is_some() {
+        return Err(Error::AlreadyInstalled(product_code.to_owned()));
     }
 
+    log::info!("Checking layout DLL");
+    let root = SystemRoot::current();
+    let (builds, dll) = resolve_layout_dll(&root, layout_file)?;
+    let layout_file = builds.as_ref().map_or(layout_file, |x| x.name());
+    log::info!("{} is an {} layout DLL", layout_file, dll.machine());
+
+    let layout_name = match layout_name
+        .map(str::to_owned)
+        .or_else(|| dll.layout_display_name())
+    {
+        Some(v) => v,
+        None => {
+            return Err(Error::InvalidArgument(format!(
+                "{} has no layout name; give one with --layout",
+                layout_file
+            )))
+        }
+    };
+
     log::info!("Checking language name is valid");
-    let lang_name = match display_name {
-        Some(v) => v.to_owned(),
+    let lang_name = match display_name
+        .map(str::to_owned)
+        .or_else(|| dll.language_display_name())
+    {
+        Some(v) => v,
         #[cfg(not(feature = "legacy"))]
-        None => winlangdb::get_language_names(tag).unwrap().name,
+        None => winlangdb::get_language_names(tag)?.name,
         #[cfg(feature = "legacy")]
         None => layout_name.to_owned(),
     };
 
+    info!("Locale name to lcid");
+    let lcid = crate::lcid(reg, tag)?;
+    info!("Using lcid '{:04x}'", lcid);
+
+    create_keyboard(
+        tx,
+        &root,
+        builds.as_ref(),
+        tag,
+        lcid,
+        &lang_name,
+        product_code,
+        layout_file,
+        &layout_name,
+        &Ownership::new(source),
+    )?;
+    Ok(())
+}
+
+/// Deploys `builds`, if given, and creates the keyboard's registry key, recording the files
+/// deployed, all as part of `tx`.
+#[cfg(any(windows, test))]
+#[allow(clippy::too_many_arguments)]
+fn create_keyboard(
+    tx: &Transaction,
+    root: &SystemRoot,
+    builds: Option<&deploy::Source>,
+    tag: &LanguageTag,
+    lcid: u16,
+    lang_name: &str,
+    product_code: &str,
+    layout_file: &str,
+    layout_name: &str,
+    owner: &Ownership,
+) -> Result<KeyboardRegKey, Error> {
+    let deployed = match builds {
+        Some(builds) => deploy::deploy(tx, root, builds, product_code)?,
+        None => vec![],
+    };
+
     log::info!("Creating registry key");
-    KeyboardRegKey::create(tag, &lang_name, product_code, layout_file, layout_name);
+    let record = KeyboardRegKey::create(
+        tx,
+        tag,
+        lcid,
+        lang_name,
+        product_code,
+        layout_file,
+        layout_name,
+        owner,
+    )?;
+    if !deployed.is_empty() {
+        let files = deployed.iter().map(|x| x.display().to_string()).collect();
+        tx.set_value(
+            &keyboard_layouts_key().join(record.regkey_id()),
+            deploy::DEPLOYED_FILES,
+            &Data::MultiString(files),
+        )?;
+    }
+    Ok(record)
+}
+
+/// Replaces the layout DLL and names of an installed keyboard, keeping its KLID and `Layout Id`.
+/// Names not given are taken from the new DLL, or else kept. Files the previous version deployed
+/// and this one does not are deleted.
+#[cfg(windows)]
+pub fn upgrade(
+    reg: &dyn RegistryBackend,
+    product_code: &str,
+    layout_name: Option<&str>,
+    layout_file: &str,
+    display_name: Option<&str>,
+) -> Result<(), Error> {
+    let record = match KeyboardRegKey::find_by_product_code(reg, product_code)? {
+        Some(v) => v,
+        None => return Err(Error::NotInstalled(product_code.to_owned())),
+    };
+
+    log::info!("Checking layout DLL");
+    let root = SystemRoot::current();
+    let (source, dll) = resolve_layout_dll(&root, layout_file)?;
+    let layout_file = source.as_ref().map_or(layout_file, |x| x.name());
+    log::info!("{} is an {} layout DLL", layout_file, dll.machine());
+
+    let layout_name = layout_name
+        .map(str::to_owned)
+        .or_else(|| dll.layout_display_name())
+        .or_else(|| record.layout_name())
+        .unwrap_or_default();
+    let lang_name = display_name
+        .map(str::to_owned)
+        .or_else(|| dll.language_display_name())
+        .or_else(|| record.language_name())
+        .unwrap_or_default();
+
+    let previous = record.deployed_files();
+    let tx = Transaction::begin(reg);
+    let deployed = match &source {
+        Some(source) => deploy::deploy(&tx, &root, source, product_code)?,
+        // The DLL was replaced by other means; files it still has the name of stay recorded.
+        None => previous
+            .iter()
+            .filter(|x| {
+                let name = x.file_name().and_then(|x| x.to_str()).unwrap_or_default();
+                name.eq_ignore_ascii_case(layout_file)
+            })
+            .cloned()
+            .collect(),
+    };
+
+    log::info!("Updating registry key {}", record.regkey_id());
+    record.update(&tx, &lang_name, layout_file, &layout_name)?;
+    let regkey = keyboard_layouts_key().join(record.regkey_id());
+    match deployed.is_empty() {
+        true if !previous.is_empty() => tx.delete_value(&regkey, deploy::DEPLOYED_FILES)?,
+        true => {}
+        false => {
+            let files = deployed.iter().map(|x| x.display().to_string()).collect();
+            tx.set_value(&regkey, deploy::DEPLOYED_FILES, &Data::MultiString(files))?;
+        }
+    }
+    tx.commit();
+
+    let stale: Vec<PathBuf> = previous
+        .into_iter()
+        .filter(|x| !deployed.contains(x))
+        .collect();
+    for file in deploy::remove(reg, &root, &stale, product_code)? {
+        log::info!("Deleted {}", file.display());
+    }
+    Ok(())
+}
+
+/// Installs every keyboard in the manifest, then enables those marked to be enabled all at once.
+/// Keyboards that are already installed are left as they are. `source` is the file the manifest
+/// was read from, recorded as the keyboards' install source.
+///
+/// Everything is done in one transaction, so if any keyboard fails, those installed before it are
+/// removed again and their deployed files restored.
+#[cfg(windows)]
+pub fn apply(
+    reg: &dyn RegistryBackend,
+    manifest: &Manifest,
+    source: Option<&str>,
+) -> Result<(), Error> {
+    let tx = Transaction::begin(reg);
+
+    for keyboard in manifest.keyboards.iter() {
+        log::info!("Installing {} ({})", &keyboard.layout, &keyboard.guid);
+        match install_in(
+            &tx,
+            &keyboard.tag,
+            Some(&keyboard.layout),
+            &keyboard.guid,
+            &keyboard.dll,
+            keyboard.lang.as_deref(),
+            source,
+        ) {
+            Ok(_) => {}
+            Err(Error::AlreadyInstalled(_)) => log::info!("Keyboard already installed."),
+            Err(e) => return Err(e),
+        }
+    }
+
+    let to_enable: Vec<(&LanguageTag, &str)> = manifest
+        .keyboards
+        .iter()
+        .filter(|x| x.enable)
+        .map(|x| (&x.tag, &*x.guid))
+        .collect();
+
+    if !to_enable.is_empty() {
+        log::info!("Enabling {} keyboards", to_enable.len());
+        enable_all(&tx, &to_enable)?;
+    }
+
+    tx.commit();
     Ok(())
 }
 
 #[cfg(feature = "legacy")]
 fn enabled_input_methods() -> InputList {
-    InputList::try_from("".to_owned()).unwrap()
+    InputList::from(vec![])
 }
 
-fn delete_keyboard_regkey(record: KeyboardRegKey) -> Result<(), Error> {
-    let klrk = keyboard_layouts_regkey_write();
-    match klrk.delete(record.regkey_id(), true) {
-        Ok(_) => Ok(()),
-        Err(e) => Err(Error::RegErr(e)),
-    }
+fn delete_keyboard_regkey(reg: &dyn RegistryBackend, record: KeyboardRegKey) -> Result<(), Error> {
+    reg.delete_key(&keyboard_layouts_key().join(record.regkey_id()))?;
+    Ok(())
 }
 
-pub fn uninstall(product_code: &str) -> Result<(), Error> {
-    if let Some(record) = KeyboardRegKey::find_by_product_code(product_code) {
-        delete_keyboard_regkey(record)?;
-        crate::clean().unwrap();
-        return Ok(());
+/// Removes the keyboard, then purges its KLID from the input methods of every user profile,
+/// returning the profiles that referenced it.
+#[cfg(windows)]
+pub fn uninstall(
+    reg: &dyn RegistryBackend,
+    product_code: &str,
+) -> Result<Vec<PurgedProfile>, Error> {
+    let record = match KeyboardRegKey::find_by_product_code(reg, product_code)? {
+        Some(v) => v,
+        None => return Err(Error::NotInstalled(product_code.to_owned())),
+    };
+
+    let tx = Transaction::begin(reg);
+    #[cfg(not(feature = "legacy"))]
+    {
+        let original_keyboards = crate::enabled_keyboards()?;
+        tx.on_rollback("restore enabled languages and input methods", move || {
+            crate::restore_keyboards(reg, &original_keyboards).map_err(|e| e.to_string())
+        });
+    }
+
+    let klid = record.regkey_id().to_owned();
+    let deployed = record.deployed_files();
+    delete_keyboard_regkey(&tx, record)?;
+    let mut purged = profiles::purge_loaded_profiles(&tx, &klid)?;
+    crate::clean(&tx, false)?;
+    tx.commit();
+
+    // Hive files cannot be rolled back, so they are only changed once the rest has succeeded.
+    purged.extend(profiles::purge_unloaded_profiles(reg, &klid)?);
+
+    for file in deploy::remove(reg, &SystemRoot::current(), &deployed, product_code)? {
+        log::info!("Deleted {}", file.display());
     }
+    Ok(purged)
+}
 
-    Err(Error::NotFound)
+pub fn installed(reg: &dyn RegistryBackend) -> Result<Vec<KeyboardRegKey>, Error> {
+    KeyboardRegKey::installed(reg)
 }
 
-pub fn installed() -> Vec<KeyboardRegKey> {
-    KeyboardRegKey::installed()
+/// Takes ownership of the keyboards with the given product codes, or of every installed keyboard
+/// if none are given, so cleaning up treats them as kbdi's own. Returns the keyboards adopted;
+/// those kbdi already owns are left as they are.
+pub fn adopt(
+    reg: &dyn RegistryBackend,
+    product_codes: &[String],
+) -> Result<Vec<KeyboardRegKey>, Error> {
+    let keys = match product_codes {
+        [] => KeyboardRegKey::installed(reg)?,
+        codes => codes
+            .iter()
+            .map(
+                |code| match KeyboardRegKey::find_by_product_code(reg, code)? {
+                    Some(v) => Ok(v),
+                    None => Err(Error::NotInstalled(code.to_owned())),
+                },
+            )
+            .collect::<Result<_, _>>()?,
+    };
+
+    let owner = Ownership::new(None);
+    let mut adopted = vec![];
+    for key in keys.into_iter().filter(|x| !x.is_owned()) {
+        log::info!("Adopting {}", key.regkey_id());
+        adopted.push(key.stamp(reg, &owner)?);
+    }
+    Ok(adopted)
 }
 
-fn keyboard_layouts_regkey_readonly() -> RegKey {
-    Hive::LocalMachine
-        .open(
-            r"SYSTEM\CurrentControlSet\Control\Keyboard Layouts",
-            Security::Read,
-        )
-        .unwrap()
+/// The machine-wide key holding one subkey per installed layout, named by its KLID.
+pub(crate) fn keyboard_layouts_key() -> KeyPath {
+    KeyPath::new(
+        Hive::LocalMachine,
+        r"SYSTEM\CurrentControlSet\Control\Keyboard Layouts",
+    )
 }
 
-fn keyboard_layouts_regkey_write() -> RegKey {
-    Hive::LocalMachine
-        .open(
-            r"SYSTEM\CurrentControlSet\Control\Keyboard Layouts",
-            Security::Write | Security::Read,
-        )
-        .unwrap()
+/// Removes duplicate and broken keyboards, then the input methods of keyboards and text services
+/// that are no longer installed. Only keyboards kbdi owns are deleted unless `include_foreign` is
+/// set.
+pub fn remove_invalid(reg: &dyn RegistryBackend, include_foreign: bool) -> Result<(), Error> {
+    remove_duplicate_guids(reg, include_foreign)?;
+    remove_invalid_dlls(reg, include_foreign)?;
+    #[cfg(all(windows, not(feature = "legacy")))]
+    remove_invalid_kbids(reg)?;
+    Ok(())
 }
 
-pub fn remove_invalid() {
-    remove_duplicate_guids();
-    remove_invalid_dlls();
-    #[cfg(not(feature = "legacy"))]
-    remove_invalid_kbids();
+/// The installed keyboards cleaning up may touch.
+fn cleanable(
+    reg: &dyn RegistryBackend,
+    include_foreign: bool,
+) -> Result<Vec<KeyboardRegKey>, Error> {
+    let keys = KeyboardRegKey::installed(reg)?;
+    Ok(keys
+        .into_iter()
+        .filter(|x| {
+            let owned = x.is_owned();
+            if !owned && !include_foreign {
+                log::debug!("Skipping {}, which kbdi does not own", x.regkey_id());
+            }
+            owned || include_foreign
+        })
+        .collect())
 }
 
-fn remove_duplicate_guids() {
+pub(crate) fn remove_duplicate_guids(
+    reg: &dyn RegistryBackend,
+    include_foreign: bool,
+) -> Result<(), Error> {
     // Find duplicate GUIDs, clear all but first
     let mut guids = vec![];
-    let keys = KeyboardRegKey::installed();
+    let keys = cleanable(reg, include_foreign)?;
     for key in keys {
         let guid = match key.product_code() {
             Some(v) => v,
@@ -113,15 +465,64 @@ fn remove_duplicate_guids() {
         };
 
         if guids.contains(&guid) {
-            delete_keyboard_regkey(key).unwrap();
+            delete_keyboard_regkey(reg, key)?;
         } else {
             guids.push(guid);
         }
     }
+
+    Ok(())
+}
+
+/// Whether `layout_file` is a path to deploy from, rather than the name of a DLL in `System32`.
+/// Only an absolute path, or one with a directory, is a path: a bare file name is never looked
+/// for in the current directory.
+#[cfg(any(windows, test))]
+fn is_deploy_source(layout_file: &str) -> bool {
+    let path = Path::new(layout_file);
+    path.is_absolute() || matches!(path.parent(), Some(x) if !x.as_os_str().is_empty())
 }
 
-fn remove_invalid_dlls() {
-    let keys = KeyboardRegKey::installed();
+/// Reads the layout DLL `layout_file` names. A path to a DLL, or to a directory of builds, is the
+/// source to deploy from, and is returned with the build this Windows needs; a bare file name
+/// names a DLL already in `System32`.
+#[cfg(windows)]
+fn resolve_layout_dll(
+    root: &SystemRoot,
+    layout_file: &str,
+) -> Result<(Option<deploy::Source>, LayoutDll), Error> {
+    if !is_deploy_source(layout_file) {
+        return Ok((None, open_layout_dll(root, layout_file)?));
+    }
+
+    let source = deploy::Source::open(Path::new(layout_file))?;
+    let dll = match source.build(root.machine()) {
+        Some(build) => deploy::open_build(build)?,
+        None => {
+            return Err(Error::InvalidArgument(format!(
+                "there is no {} build of {}, which this Windows needs",
+                root.machine(),
+                source.name()
+            )))
+        }
+    };
+    Ok((Some(source), dll))
+}
+
+/// Reads the installed layout DLL named `layout_file`, failing unless it is a valid layout.
+fn open_layout_dll(root: &SystemRoot, layout_file: &str) -> Result<LayoutDll, Error> {
+    LayoutDll::open(root.resolve(&root.system32().join(layout_file))).map_err(|source| {
+        Error::LayoutDll {
+            file: layout_file.to_owned(),
+            source,
+        }
+    })
+}
+
+/// Removes keyboards whose DLL is missing, or is not a keyboard layout.
+fn remove_invalid_dlls(reg: &dyn RegistryBackend, include_foreign: bool) -> Result<(), Error> {
+    let root = SystemRoot::current();
+    let keys = cleanable(reg, include_foreign)?;
 
     for key in keys {
         let layout_file = match key.layout_file() {
@@ -129,89 +530,101 @@ fn remove_invalid_dlls() {
             None => continue,
         };
 
-        if !Path::new(r"C:\Windows\System32").join(layout_file).exists() {
-            delete_keyboard_regkey(key).unwrap();
+        if let Err(e) = open_layout_dll(&root, &layout_file) {
+            log::warn!("Removing {}: {}", key.regkey_id(), e);
+            delete_keyboard_regkey(reg, key)?;
         }
     }
-}
 
-fn first_available_keyboard_regkey_id(lcid: &str) -> String {
-    let regkey = keyboard_layouts_regkey_readonly();
-    let mut kbd_keys: Vec<u16> = regkey
-        .keys()
-        .map(|x| x.unwrap().to_string())
-        .filter(|x| x.starts_with(&"a") && x.ends_with(&lcid))
-        .map(|x| {
-            let n = u32::from_str_radix(&x, 16).unwrap_or(0u32);
-            (n >> 16) as u16
-        })
-        .collect();
-
-    kbd_keys.sort();
-
-    if let Some(last) = kbd_keys.last() {
-        format!("{:04x}{}", last + 1, lcid)
-    } else {
-        format!("a000{}", lcid)
-    }
+    Ok(())
 }
 
-fn first_available_layout_id() -> String {
-    let regkey = keyboard_layouts_regkey_readonly();
-    let kbd_keys: Vec<String> = regkey.keys().map(|x| x.unwrap().to_string()).collect();
+fn installed_layouts(reg: &dyn RegistryBackend) -> Result<Vec<klid::InstalledLayout>, Error> {
+    let regkey = keyboard_layouts_key();
 
-    let mut layout_ids: Vec<u32> = kbd_keys
+    Ok(reg
+        .subkeys(&regkey)?
         .into_iter()
-        .map(|key| {
-            let kbdkey = &regkey.open(key, Security::Read | Security::Write).unwrap();
-            let layout_idstr: String = match kbdkey.value("Layout Id") {
-                Ok(Data::String(v)) => v.to_string_lossy(),
-                _ => "0".to_string(),
-            };
-
-            u32::from_str_radix(&layout_idstr, 16).unwrap_or(0u32)
+        .map(|key| klid::InstalledLayout {
+            layout_id: backend::string_value(reg, &regkey.join(&key), "Layout Id"),
+            klid: key,
         })
-        .collect();
+        .collect())
+}
 
-    layout_ids.sort();
+/// Languages using a transient LCID, either recorded under `User Profile` or in the KLID of an
+/// installed layout.
+#[cfg(any(windows, test))]
+pub(crate) fn transient_lcids(
+    reg: &dyn RegistryBackend,
+) -> Result<Vec<klid::TransientLcid>, Error> {
+    let mut used = vec![];
 
-    format!("{:04x}", layout_ids.last().unwrap() + 1)
+    let user_profile = KeyPath::new(
+        Hive::CurrentUser,
+        r"Control Panel\International\User Profile",
+    );
+    if reg.key_exists(&user_profile) {
+        for tag in reg.subkeys(&user_profile)? {
+            if let Ok(Data::U32(lcid)) = reg.value(&user_profile.join(&tag), "TransientLangId") {
+                if klid::is_transient_lcid(lcid) {
+                    used.push(klid::TransientLcid {
+                        tag,
+                        lcid: lcid as u16,
+                    });
+                }
+            }
+        }
+    }
+
+    if reg.key_exists(&keyboard_layouts_key()) {
+        for keyboard in KeyboardRegKey::installed(reg)? {
+            let lcid = u32::from_str_radix(keyboard.regkey_id(), 16).unwrap_or(0) & 0xffff;
+            if let (true, Some(tag)) = (
+                klid::is_transient_lcid(lcid),
+                keyboard.string_value("Layout Locale Name"),
+            ) {
+                used.push(klid::TransientLcid {
+                    tag,
+                    lcid: lcid as u16,
+                });
+            }
+        }
+    }
+
+    Ok(used)
 }
 
 impl KeyboardRegKey {
-    pub fn find_by_product_code(product_code: &str) -> Option<KeyboardRegKey> {
-        let regkey = keyboard_layouts_regkey_readonly();
-        let keys: Vec<String> = regkey.keys().map(|x| x.unwrap().to_string()).collect();
-        for key in keys.into_iter() {
-            let kl_key = regkey.open(&key, Security::Read).unwrap();
-            let ret: Result<Data, registry::value::Error> = kl_key.value("Layout Product Code");
-            match ret {
-                Ok(Data::String(s)) if s.to_string_lossy() == product_code => {
-                    return Some(KeyboardRegKey {
-                        id: key.clone(),
-                        regkey: kl_key,
-                    })
+    fn open(reg: &dyn RegistryBackend, id: &str) -> Result<KeyboardRegKey, Error> {
+        Ok(KeyboardRegKey {
+            id: id.to_owned(),
+            values: reg.values(&keyboard_layouts_key().join(id))?,
+        })
+    }
+
+    pub fn find_by_product_code(
+        reg: &dyn RegistryBackend,
+        product_code: &str,
+    ) -> Result<Option<KeyboardRegKey>, Error> {
+        let regkey = keyboard_layouts_key();
+        for key in reg.subkeys(&regkey)?.into_iter() {
+            match backend::string_value(reg, &regkey.join(&key), "Layout Product Code") {
+                Some(s) if s == product_code => {
+                    return Ok(Some(KeyboardRegKey::open(reg, &key)?));
                 }
                 _ => continue,
             }
         }
 
-        None
+        Ok(None)
     }
 
-    pub fn installed() -> Vec<KeyboardRegKey> {
-        let regkey = keyboard_layouts_regkey_readonly();
-        regkey
-            .keys()
-            .map(|x| x.unwrap().to_string())
+    pub fn installed(reg: &dyn RegistryBackend) -> Result<Vec<KeyboardRegKey>, Error> {
+        reg.subkeys(&keyboard_layouts_key())?
+            .into_iter()
             .filter(|x| x.starts_with("a"))
-            .map(|x| {
-                let k = regkey.open(&x, Security::Read | Security::Write).unwrap();
-                KeyboardRegKey {
-                    id: x.to_owned(),
-                    regkey: k,
-                }
-            })
+            .map(|x| KeyboardRegKey::open(reg, &x))
             .collect()
     }
 
@@ -219,119 +632,160 @@ impl KeyboardRegKey {
         &self.id
     }
 
+    fn string_value(&self, name: &str) -> Option<String> {
+        self.values
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .and_then(|(_, v)| match v {
+                Data::String(v) => Some(v.to_owned()),
+                _ => None,
+            })
+    }
+
     pub fn id(&self) -> Option<String> {
-        match self.regkey.value("Layout Id") {
-            Ok(Data::String(v)) => Some(v.to_string_lossy()),
-            _ => None,
-        }
+        self.string_value("Layout Id")
     }
 
     pub fn product_code(&self) -> Option<String> {
-        match self.regkey.value("Layout Product Code") {
-            Ok(Data::String(v)) => Some(v.to_string_lossy()),
-            _ => None,
-        }
+        self.string_value("Layout Product Code")
     }
 
     pub fn language_name(&self) -> Option<String> {
-        match self.regkey.value("Custom Language Name") {
-            Ok(Data::String(v)) => Some(v.to_string_lossy()),
-            _ => None,
-        }
+        self.string_value("Custom Language Name")
     }
 
     pub fn layout_file(&self) -> Option<String> {
-        match self.regkey.value("Layout File") {
-            Ok(Data::String(v)) => Some(v.to_string_lossy()),
-            _ => None,
-        }
+        self.string_value("Layout File")
     }
 
     pub fn layout_name(&self) -> Option<String> {
-        match self.regkey.value("Layout Text") {
-            Ok(Data::String(v)) => Some(v.to_string_lossy()),
-            _ => None,
+        self.string_value("Layout Text")
+    }
+
+    /// Who installed the keyboard, if it was stamped by kbdi or another installer that does.
+    pub fn owner(&self) -> Option<Ownership> {
+        Some(Ownership {
+            installer: self.string_value("Installer")?,
+            version: self.string_value("Installer Version").unwrap_or_default(),
+            installed: self.string_value("Install Date").unwrap_or_default(),
+            source: self.string_value("Install Source"),
+        })
+    }
+
+    pub fn is_owned(&self) -> bool {
+        matches!(self.owner(), Some(owner) if owner.installer == INSTALLER)
+    }
+
+    /// Records `owner` as who installed the keyboard.
+    pub fn stamp(
+        &self,
+        reg: &dyn RegistryBackend,
+        owner: &Ownership,
+    ) -> Result<KeyboardRegKey, Error> {
+        let regkey = keyboard_layouts_key().join(self.regkey_id());
+        if owner.source.is_none() && self.string_value("Install Source").is_some() {
+            reg.delete_value(&regkey, "Install Source")?;
+        }
+        for (name, value) in owner.values() {
+            reg.set_value(&regkey, name, &Data::String(value))?;
         }
+        KeyboardRegKey::open(reg, self.regkey_id())
     }
 
+    /// The files copied into the system directories when this keyboard was installed.
+    pub fn deployed_files(&self) -> Vec<PathBuf> {
+        self.values
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(deploy::DEPLOYED_FILES))
+            .map(|(_, v)| match v {
+                Data::MultiString(v) => v.iter().map(PathBuf::from).collect(),
+                _ => vec![],
+            })
+            .unwrap_or_default()
+    }
+
+    /// Creates the key for a keyboard under a newly allocated KLID, stamped with `owner`.
+    #[allow(clippy::too_many_arguments)]
     pub fn create(
-        tag: &str,
+        reg: &dyn RegistryBackend,
+        tag: &LanguageTag,
+        lcid: u16,
         display_name: &str,
         product_code: &str,
         layout_file: &str,
         layout_name: &str,
-    ) -> KeyboardRegKey {
-        info!("Locale name to lcid");
-        let lcid = format!("{:04x}", crate::lcid(&tag) as u16);
-
-        info!("Using lcid '{}'", lcid);
-
+        owner: &Ownership,
+    ) -> Result<KeyboardRegKey, Error> {
         info!("D: Get first available reg ids");
-        let key_name = first_available_keyboard_regkey_id(&lcid);
-        let layout_id = first_available_layout_id();
+        let klid::Allocation {
+            klid: key_name,
+            layout_id,
+        } = klid::allocate(&installed_layouts(reg)?, lcid)?;
 
-        info!("D: open regkey");
-        let regkey = keyboard_layouts_regkey_write()
-            .create(&key_name, Security::Read | Security::Write)
-            .unwrap();
+        info!("D: create regkey");
+        let regkey = keyboard_layouts_key().join(&key_name);
+        reg.create_key(&regkey)?;
 
         info!("D: set regkey vals");
-        regkey
-            .set_value(
+        let values = vec![
+            (
                 "Custom Language Display Name",
-                &Data::String(
-                    format!("@%SystemRoot%\\system32\\{},-1100", &layout_file)
-                        .try_into()
-                        .unwrap(),
-                ),
-            )
-            .unwrap();
-        regkey
-            .set_value(
-                "Custom Language Name",
-                &Data::String(display_name.try_into().unwrap()),
-            )
-            .unwrap();
-        regkey
-            .set_value(
+                string_resource(layout_file, LANGUAGE_DISPLAY_NAME),
+            ),
+            ("Custom Language Name", display_name.to_owned()),
+            (
                 "Layout Display Name",
-                &Data::String(
-                    format!("@%SystemRoot%\\system32\\{},-1000", &layout_file)
-                        .try_into()
-                        .unwrap(),
-                ),
-            )
-            .unwrap();
-        regkey
-            .set_value(
-                "Layout File",
-                &Data::String(layout_file.try_into().unwrap()),
-            )
-            .unwrap();
-        regkey
-            .set_value("Layout Id", &Data::String(layout_id.try_into().unwrap()))
-            .unwrap();
-        regkey
-            .set_value("Layout Locale Name", &Data::String(tag.try_into().unwrap()))
-            .unwrap();
-        regkey
-            .set_value(
-                "Layout Product Code",
-                &Data::String(product_code.try_into().unwrap()),
-            )
-            .unwrap();
-        regkey
-            .set_value(
-                "Layout Text",
-                &Data::String(layout_name.try_into().unwrap()),
-            )
-            .unwrap();
+                string_resource(layout_file, LAYOUT_DISPLAY_NAME),
+            ),
+            ("Layout File", layout_file.to_owned()),
+            ("Layout Id", layout_id),
+            ("Layout Locale Name", tag.to_string()),
+            ("Layout Product Code", product_code.to_owned()),
+            ("Layout Text", layout_name.to_owned()),
+        ];
 
-        KeyboardRegKey {
-            id: key_name.clone(),
-            regkey,
+        for (name, value) in values.into_iter().chain(owner.values()) {
+            reg.set_value(&regkey, name, &Data::String(value))?;
         }
+
+        KeyboardRegKey::open(reg, &key_name)
     }
+
+    /// Points the keyboard at another layout DLL and names, keeping its KLID and `Layout Id` so
+    /// the users who have it enabled keep it.
+    pub fn update(
+        &self,
+        reg: &dyn RegistryBackend,
+        display_name: &str,
+        layout_file: &str,
+        layout_name: &str,
+    ) -> Result<KeyboardRegKey, Error> {
+        let regkey = keyboard_layouts_key().join(self.regkey_id());
+        let values = vec![
+            (
+                "Custom Language Display Name",
+                string_resource(layout_file, LANGUAGE_DISPLAY_NAME),
+            ),
+            ("Custom Language Name", display_name.to_owned()),
+            (
+                "Layout Display Name",
+                string_resource(layout_file, LAYOUT_DISPLAY_NAME),
+            ),
+            ("Layout File", layout_file.to_owned()),
+            ("Layout Text", layout_name.to_owned()),
+        ];
+
+        for (name, value) in values {
+            reg.set_value(&regkey, name, &Data::String(value))?;
+        }
+
+        KeyboardRegKey::open(reg, self.regkey_id())
+    }
+}
+
+/// A reference to string resource `id` of a layout DLL in `System32`, as the registry takes it.
+fn string_resource(layout_file: &str, id: u16) -> String {
+    format!("@%SystemRoot%\\system32\\{},-{}", layout_file, id)
 }
 
 impl fmt::Display for KeyboardRegKey {
@@ -358,7 +812,252 @@ impl fmt::Display for KeyboardRegKey {
             "Product Code:   {}",
             self.product_code().unwrap_or("".to_string())
         )?;
+        if let Some(owner) = self.owner() {
+            writeln!(f, "Installed By:   {}", owner)?;
+        }
 
         Ok(())
     }
 }
+
+#[test]
+fn test_create_keyboards() {
+    let reg = backend::MemoryRegistry::new();
+    reg.create_key(&keyboard_layouts_key()).unwrap();
+
+    let se = "se-NO".parse().unwrap();
+    let first = KeyboardRegKey::create(
+        &reg,
+        &se,
+        0x043b,
+        "davvisámegiella",
+        "{1}",
+        "kbdse01.dll",
+        "Northern Sami",
+        &Ownership::new(None),
+    )
+    .unwrap();
+    let second = KeyboardRegKey::create(
+        &reg,
+        &se,
+        0x043b,
+        "davvisámegiella",
+        "{2}",
+        "kbdse02.dll",
+        "Northern Sami (2)",
+        &Ownership::new(None),
+    )
+    .unwrap();
+
+    assert_eq!(first.regkey_id(), "a000043b");
+    assert_eq!(first.id().as_deref(), Some("0100"));
+    assert_eq!(second.regkey_id(), "a001043b");
+    assert_eq!(second.id().as_deref(), Some("0101"));
+    assert_eq!(first.layout_file().as_deref(), Some("kbdse01.dll"));
+
+    let found = KeyboardRegKey::find_by_product_code(&reg, "{2}")
+        .unwrap()
+        .unwrap();
+    assert_eq!(found.regkey_id(), "a001043b");
+    assert!(KeyboardRegKey::find_by_product_code(&reg, "{3}")
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+fn test_update_keyboard() {
+    let reg = backend::MemoryRegistry::new();
+    reg.create_key(&keyboard_layouts_key()).unwrap();
+
+    let se = "se-NO".parse().unwrap();
+    let old = KeyboardRegKey::create(
+        &reg,
+        &se,
+        0x043b,
+        "",
+        "{1}",
+        "kbdse01.dll",
+        "",
+        &Ownership::new(None),
+    )
+    .unwrap();
+    let new = old
+        .update(&reg, "davvisámegiella", "kbdse02.dll", "Northern Sami")
+        .unwrap();
+
+    assert_eq!(new.regkey_id(), "a000043b");
+    assert_eq!(new.id(), old.id());
+    assert_eq!(new.product_code().as_deref(), Some("{1}"));
+    assert_eq!(new.layout_file().as_deref(), Some("kbdse02.dll"));
+    assert_eq!(new.layout_name().as_deref(), Some("Northern Sami"));
+    assert_eq!(new.language_name().as_deref(), Some("davvisámegiella"));
+    assert_eq!(
+        new.string_value("Layout Display Name").as_deref(),
+        Some(r"@%SystemRoot%\system32\kbdse02.dll,-1000")
+    );
+    assert_eq!(installed(&reg).unwrap().len(), 1);
+}
+
+#[test]
+fn test_is_deploy_source() {
+    assert!(!is_deploy_source("kbdse01.dll"));
+    assert!(is_deploy_source("./kbdse01.dll"));
+    assert!(is_deploy_source("build/kbdse"));
+    assert!(is_deploy_source(
+        &std::env::temp_dir().join("kbdse01.dll").to_string_lossy()
+    ));
+}
+
+#[test]
+fn test_create_keyboard_rollback() {
+    use crate::pe::{fixture, Machine};
+    use std::fs;
+
+    // As `apply` does: the second keyboard fails after the first has been created.
+    let dir = std::env::temp_dir().join(format!("kbdi-apply-{}", std::process::id()));
+    let root = SystemRoot::new(dir.join("Windows"), Machine::X64);
+    let (x64, _) = fixture(Machine::X64, &["KbdLayerDescriptor"], &[], &[], &[]);
+    fs::create_dir_all(root.system32()).unwrap();
+    fs::write(dir.join("kbdse01.dll"), &x64).unwrap();
+    let source = deploy::Source::open(&dir.join("kbdse01.dll")).unwrap();
+    let deployed = root.system32().join("kbdse01.dll");
+
+    let reg = backend::MemoryRegistry::new();
+    reg.create_key(&keyboard_layouts_key()).unwrap();
+    let se = "se-NO".parse().unwrap();
+    let tx = Transaction::begin(&reg);
+    for product_code in ["{1}", "{2}"].iter() {
+        let result = create_keyboard(
+            &tx,
+            &root,
+            Some(&source),
+            &se,
+            0x043b,
+            "",
+            product_code,
+            "kbdse01.dll",
+            "",
+            &Ownership::new(None),
+        );
+        if *product_code == "{1}" {
+            result.unwrap();
+            assert_eq!(fs::read(&deployed).unwrap(), x64);
+        } else {
+            assert!(matches!(result, Err(Error::ForeignLayoutDll { .. })));
+        }
+    }
+    assert!(tx.rollback().is_empty());
+
+    assert!(installed(&reg).unwrap().is_empty());
+    assert!(!deployed.exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_transient_lcids() {
+    let reg = backend::MemoryRegistry::new();
+    reg.create_key(&keyboard_layouts_key()).unwrap();
+    let sjd = "sjd-RU".parse().unwrap();
+    KeyboardRegKey::create(
+        &reg,
+        &sjd,
+        0x2400,
+        "",
+        "{1}",
+        "kbdsjd.dll",
+        "",
+        &Ownership::new(None),
+    )
+    .unwrap();
+
+    let sje = KeyPath::new(
+        Hive::CurrentUser,
+        r"Control Panel\International\User Profile\sje-SE",
+    );
+    reg.create_key(&sje).unwrap();
+    reg.set_value(&sje, "TransientLangId", &Data::U32(0x2000))
+        .unwrap();
+
+    let used = transient_lcids(&reg).unwrap();
+    assert_eq!(
+        used,
+        vec![
+            klid::TransientLcid {
+                tag: "sje-SE".into(),
+                lcid: 0x2000
+            },
+            klid::TransientLcid {
+                tag: "sjd-RU".into(),
+                lcid: 0x2400
+            },
+        ]
+    );
+    let sju = "sju-SE".parse().unwrap();
+    let sjd = "sjd-RU".parse().unwrap();
+    assert_eq!(klid::next_transient_lcid(&used, &sju), Ok(0x2800));
+    assert_eq!(klid::next_transient_lcid(&used, &sjd), Ok(0x2400));
+}
+
+#[test]
+fn test_remove_duplicate_guids() {
+    let reg = backend::MemoryRegistry::new();
+    let se = "se-NO".parse().unwrap();
+    reg.create_key(&keyboard_layouts_key()).unwrap();
+
+    let owner = Ownership::new(None);
+    for code in ["{1}", "{1}", "{2}", "{2}"].iter() {
+        KeyboardRegKey::create(&reg, &se, 0x043b, "", code, "kbdse01.dll", "", &owner).unwrap();
+    }
+    // Installed by another installer, which does not stamp its keys
+    for name in ["Installer", "Installer Version", "Install Date"].iter() {
+        for klid in ["a002043b", "a003043b"].iter() {
+            reg.delete_value(&keyboard_layouts_key().join(klid), name)
+                .unwrap();
+        }
+    }
+    assert_eq!(installed(&reg).unwrap().len(), 4);
+
+    remove_duplicate_guids(&reg, false).unwrap();
+    let remaining = installed(&reg).unwrap();
+    let ids: Vec<&str> = remaining.iter().map(|x| x.regkey_id()).collect();
+    assert_eq!(ids, vec!["a000043b", "a002043b", "a003043b"]);
+
+    remove_duplicate_guids(&reg, true).unwrap();
+    assert_eq!(installed(&reg).unwrap().len(), 2);
+}
+
+#[test]
+fn test_adopt() {
+    let reg = backend::MemoryRegistry::new();
+    let se = "se-NO".parse().unwrap();
+    reg.create_key(&keyboard_layouts_key()).unwrap();
+
+    let owner = Ownership {
+        installer: "msklc".into(),
+        version: "1.4".into(),
+        installed: "".into(),
+        source: Some("kbdse.klc".into()),
+    };
+    KeyboardRegKey::create(&reg, &se, 0x043b, "", "{1}", "kbdse01.dll", "", &owner).unwrap();
+    KeyboardRegKey::create(&reg, &se, 0x043b, "", "{2}", "kbdse02.dll", "", &owner).unwrap();
+    assert!(!installed(&reg).unwrap()[0].is_owned());
+
+    assert!(matches!(
+        adopt(&reg, &["{3}".to_string()]),
+        Err(Error::NotInstalled(_))
+    ));
+    let adopted = adopt(&reg, &["{2}".to_string()]).unwrap();
+    assert_eq!(adopted.len(), 1);
+    assert_eq!(adopted[0].regkey_id(), "a001043b");
+    assert!(adopted[0].is_owned());
+    assert_eq!(adopted[0].owner().unwrap().source, None);
+
+    let adopted: Vec<String> = adopt(&reg, &[])
+        .unwrap()
+        .iter()
+        .map(|x| x.regkey_id().to_owned())
+        .collect();
+    assert_eq!(adopted, vec!["a000043b"]);
+    assert!(adopt(&reg, &[]).unwrap().is_empty());
+}
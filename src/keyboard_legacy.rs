@@ -1,89 +1,92 @@
-use crate::keyboard::{Error, KeyboardRegKey};
+use crate::backend::{Hive, KeyPath, RegistryBackend};
+use crate::keyboard::KeyboardRegKey;
 use crate::platform::*;
 use crate::types::*;
-use registry::{Data, Hive, RegKey, Security};
+use crate::{Error, LanguageTag};
 use std::convert::TryFrom;
 
-pub fn enable(tag: &str, product_code: &str) -> Result<(), Error> {
-    let record = match KeyboardRegKey::find_by_product_code(product_code) {
-        Some(v) => v,
-        None => return Err(Error::NotFound),
-    };
+pub fn enable(
+    reg: &dyn RegistryBackend,
+    tag: &LanguageTag,
+    product_code: &str,
+) -> Result<(), Error> {
+    enable_all(reg, &[(tag, product_code)])
+}
+
+/// Enables each keyboard, given as a language tag and product code, with a single call to
+/// `InstallLayoutOrTip`.
+pub fn enable_all(
+    reg: &dyn RegistryBackend,
+    keyboards: &[(&LanguageTag, &str)],
+) -> Result<(), Error> {
+    let mut tips = vec![];
+    for (tag, product_code) in keyboards.iter() {
+        let record = match KeyboardRegKey::find_by_product_code(reg, product_code)? {
+            Some(v) => v,
+            None => return Err(Error::NotInstalled(product_code.to_string())),
+        };
+
+        // Generate input list item
+        let lcid = crate::lcid(reg, tag)?;
+        tips.push(format!("{:04X}:{}", lcid, record.regkey_id()));
+    }
 
-    // Generate input list item
-    let lcid = crate::lcid(tag);
-    let tip = InputList::try_from(format!("{:04X}:{}", lcid, record.regkey_id())).unwrap();
+    let tips = InputList::try_from(tips.clone())
+        .map_err(|_| Error::InvalidInputMethods(tips.join(";")))?;
 
     info!("D: Install layout, flag 0");
-    input::install_layout(tip, 0x0).unwrap();
+    input::install_layout(tips, 0x0)?;
     // info!("D: Enable keyboard layout");
     // winuser::load_keyboard_layout(record.regkey_id());
     Ok(())
 }
 
-fn base_regkey(is_all_users: bool) -> RegKey {
+fn base_regkey(is_all_users: bool) -> KeyPath {
     match is_all_users {
-        true => Hive::Users
-            .open(".DEFAULT", Security::Read | Security::Write)
-            .unwrap(),
-        false => Hive::CurrentUser
-            .open("", Security::Read | Security::Write)
-            .unwrap(),
+        true => KeyPath::new(Hive::Users, ".DEFAULT"),
+        false => KeyPath::new(Hive::CurrentUser, ""),
     }
 }
 
-fn kbd_layout_sub_regkey(is_all_users: bool) -> RegKey {
-    base_regkey(is_all_users)
-        .open(
-            r"Keyboard Layout\Substitutes",
-            Security::Read | Security::Write,
-        )
-        .unwrap()
+fn kbd_layout_sub_regkey(is_all_users: bool) -> KeyPath {
+    base_regkey(is_all_users).join(r"Keyboard Layout\Substitutes")
 }
 
-fn kbd_layout_preload_regkey(is_all_users: bool) -> RegKey {
-    base_regkey(is_all_users)
-        .open(r"Keyboard Layout\Preload", Security::Read | Security::Write)
-        .unwrap()
+fn kbd_layout_preload_regkey(is_all_users: bool) -> KeyPath {
+    base_regkey(is_all_users).join(r"Keyboard Layout\Preload")
 }
 
 /// Substitute IDs begin with 0000, then increment to d001, and continue incrementing dXXX.
-fn next_substitute_id(suffix: u16) -> u32 {
-    let prefix: u16 = Hive::CurrentUser
-        .open(r"Keyboard Layout\Substitutes", Security::Read)
-        .unwrap()
-        .values()
-        .fold(0u16, |acc, x| {
-            let name = x.unwrap().name().to_string_lossy();
+fn next_substitute_id(reg: &dyn RegistryBackend, suffix: u16) -> Result<u32, Error> {
+    let prefix: u16 =
+        reg.values(&kbd_layout_sub_regkey(false))?
+            .into_iter()
+            .fold(0u16, |acc, (name, _)| {
+                if let Ok(val) = u32::from_str_radix(&name, 16) {
+                    if (val as u16) == suffix {
+                        // Move high bits down
+                        let v = (val >> 16) as u16;
 
-            if let Ok(val) = u32::from_str_radix(&name, 16) {
-                if (val as u16) == suffix {
-                    // Move high bits down
-                    let v = (val >> 16) as u16;
-
-                    if v >= acc {
-                        return if v == 0 { 0xd001 } else { v + 1 };
+                        if v >= acc {
+                            return if v == 0 { 0xd001 } else { v + 1 };
+                        }
                     }
-                }
 
-                acc
-            } else {
-                acc
-            }
-        });
+                    acc
+                } else {
+                    acc
+                }
+            });
 
-    ((prefix as u32) << 16) + (suffix as u32)
+    Ok(((prefix as u32) << 16) + (suffix as u32))
 }
 
 #[cfg(feature = "legacy")]
-fn next_preload_id(is_all_users: bool) -> u32 {
-    base_regkey(is_all_users)
-        .open(r"Keyboard Layout\Preload", Security::Read)
-        .unwrap()
-        .values()
-        .fold(1u32, |acc, x| {
-            let name = x.unwrap().name().to_string_lossy();
-
+fn next_preload_id(reg: &dyn RegistryBackend, is_all_users: bool) -> Result<u32, Error> {
+    Ok(reg
+        .values(&kbd_layout_preload_regkey(is_all_users))?
+        .into_iter()
+        .fold(1u32, |acc, (name, _)| {
             if let Ok(v) = u32::from_str_radix(&name, 10) {
                 if v >= acc {
                     v + 1
@@ -93,5 +96,5 @@ fn next_preload_id(is_all_users: bool) -> u32 {
             } else {
                 acc
             }
-        })
+        }))
 }
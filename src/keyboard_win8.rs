@@ -1,128 +1,257 @@
-use crate::keyboard::{Error, KeyboardRegKey};
+#[cfg(windows)]
+use crate::backend::HiveFile;
+use crate::backend::{Data, Hive, KeyPath, RegistryBackend};
+#[cfg(windows)]
+use crate::dry_run::DryRunRegistry;
+#[cfg(windows)]
+use crate::journal::Transaction;
+use crate::keyboard::KeyboardRegKey;
+#[cfg(windows)]
 use crate::language::LanguageRegKey;
+#[cfg(windows)]
 use crate::platform::*;
+#[cfg(windows)]
+use crate::profiles::{self, UserKeys, UserResult, ValueResult};
 use crate::types::*;
+use crate::Error;
+#[cfg(windows)]
+use crate::LanguageTag;
+#[cfg(windows)]
 use indexmap::IndexMap;
-use registry::{Data, Hive, RegKey, Security};
-use std::convert::{TryFrom, TryInto};
+use serde::Serialize;
+use std::convert::TryFrom;
+use std::fmt;
+#[cfg(windows)]
 use winapi::um::winnt::WinLocalSystemSid;
-use windows_permissions::{Sid, utilities::current_process_sid};
+#[cfg(windows)]
+use windows_permissions::{utilities::current_process_sid, Sid};
 
-fn enabled_input_methods() -> InputList {
-    let langs = crate::enabled_languages().unwrap();
-    let mut imes: Vec<String> = vec![];
-    for lang in langs {
-        imes.append(&mut bcp47langs::get_user_language_input_methods(&lang).unwrap());
+pub(crate) fn user_profile_key() -> KeyPath {
+    KeyPath::new(
+        Hive::CurrentUser,
+        r"Control Panel\International\User Profile",
+    )
+}
+
+pub(crate) fn substitutes_key() -> KeyPath {
+    KeyPath::new(Hive::CurrentUser, r"Keyboard Layout\Substitutes")
+}
+
+pub(crate) fn preload_key() -> KeyPath {
+    KeyPath::new(Hive::CurrentUser, r"Keyboard Layout\Preload")
+}
+
+#[cfg(windows)]
+fn log_key_values(reg: &dyn RegistryBackend, key: &KeyPath) {
+    log::trace!("{}", key);
+    for (name, data) in reg.values(key).unwrap_or_default() {
+        log::trace!("  '{}' = '{}'", name, data);
     }
-    InputList::try_from(imes).unwrap()
+    log::trace!("");
 }
 
-fn log_important_regkeys() {
+#[cfg(windows)]
+fn log_important_regkeys(reg: &dyn RegistryBackend) {
     log::trace!("  == REGKEY WATCH ==");
     log::trace!("");
 
-    let substitutes_key = Hive::CurrentUser
-        .open(r"Keyboard Layout\Substitutes", Security::Read)
-        .unwrap();
+    let user_profile_key = user_profile_key();
 
-    let user_profile_key = Hive::CurrentUser
-        .create(r"Control Panel\International\User Profile", Security::Read)
-        .unwrap();
+    for subkey in reg.subkeys(&user_profile_key).unwrap_or_default() {
+        log_key_values(reg, &user_profile_key.join(&subkey));
+    }
 
-    let preload_key = Hive::CurrentUser
-        .open(r"Keyboard Layout\Preload", Security::Read)
-        .unwrap();
+    log_key_values(reg, &user_profile_key);
+    log_key_values(reg, &preload_key());
+    log_key_values(reg, &substitutes_key());
+
+    log::trace!("  == If you see a suspicious REGKEY in your neighbourhood, call 112 ==")
+}
 
-    let user_profile_subkeys = user_profile_key
-        .keys()
-        .filter_map(Result::ok)
-        .map(|x| x.open(Security::Read))
-        .filter_map(Result::ok);
+#[cfg(windows)]
+pub fn enable(
+    reg: &dyn RegistryBackend,
+    tag: &LanguageTag,
+    product_code: &str,
+    lang_name: Option<&str>,
+) -> Result<(), Error> {
+    log::info!("Enabling '{}' with product code '{}'", tag, product_code);
+    log::info!("Lang name: {:?}", lang_name);
 
-    for key in user_profile_subkeys {
-        log::trace!("{}", key);
-        for value in key.values().filter_map(Result::ok) {
-            let (inner_name, inner_data) = value.into_inner();
+    let tx = Transaction::begin(reg);
+    enable_all(&tx, &[(tag, product_code)])?;
+    tx.commit();
+    Ok(())
+}
 
-            let name = inner_name.to_string_lossy().to_string();
-            let data = format!("{}", inner_data);
-            log::trace!("  '{}' = '{}'", name, data);
-        }
-        log::trace!("");
+/// Enables each keyboard, given as a language tag and product code, for the current user. The
+/// input methods of every language are installed with a single call to `InstallLayoutOrTip`, as
+/// part of `tx`.
+#[cfg(windows)]
+pub fn enable_all(tx: &Transaction, keyboards: &[(&LanguageTag, &str)]) -> Result<(), Error> {
+    let mut records = vec![];
+    for (tag, product_code) in keyboards.iter() {
+        records.push((*tag, Tip::Layout(layout_klid(tx, product_code)?)));
     }
 
-    log::trace!("{}", user_profile_key);
-    for value in user_profile_key.values().filter_map(Result::ok) {
-        let (inner_name, inner_data) = value.into_inner();
+    enable_tips(tx, &records)
+}
 
-        let name = inner_name.to_string_lossy().to_string();
-        let data = format!("{}", inner_data);
-        log::trace!("  '{}' = '{}'", name, data);
+/// Enables a registered text service profile for `tag` for the current user.
+#[cfg(windows)]
+pub fn enable_text_service(
+    reg: &dyn RegistryBackend,
+    tag: &LanguageTag,
+    clsid: Guid,
+    profile: Guid,
+) -> Result<(), Error> {
+    log::info!("Enabling '{}' with text service {}{}", tag, clsid, profile);
+
+    let lcid = crate::lcid(reg, tag)?;
+    if !crate::text_service::find(reg, clsid, profile)?
+        .iter()
+        .any(|x| x.lang_id == lcid)
+    {
+        return Err(Error::NotInstalled(format!("{}{}", clsid, profile)));
     }
-    log::trace!("");
 
-    log::trace!("{}", preload_key);
-    for value in preload_key.values().filter_map(Result::ok) {
-        let (inner_name, inner_data) = value.into_inner();
+    let tx = Transaction::begin(reg);
+    enable_tips(&tx, &[(tag, Tip::TextService { clsid, profile })])?;
+    tx.commit();
+    Ok(())
+}
 
-        let name = inner_name.to_string_lossy().to_string();
-        let data = format!("{}", inner_data);
-        log::trace!("  '{}' = '{}'", name, data);
-    }
-    log::trace!("");
+#[cfg(windows)]
+fn layout_klid(reg: &dyn RegistryBackend, product_code: &str) -> Result<u32, Error> {
+    let record = match KeyboardRegKey::find_by_product_code(reg, product_code)? {
+        Some(v) => v,
+        None => return Err(Error::NotInstalled(product_code.to_string())),
+    };
+    u32::from_str_radix(record.regkey_id(), 16)
+        .map_err(|_| Error::InvalidInputMethods(record.regkey_id().to_owned()))
+}
 
-    log::trace!("{}", substitutes_key);
-    for value in substitutes_key.values().filter_map(Result::ok) {
-        let (inner_name, inner_data) = value.into_inner();
+/// Enables the keyboard for `tag` for every user loaded under `HKEY_USERS`, other than service
+/// accounts, by writing their `User Profile`, `Preload` and `Substitutes` keys. The current
+/// user's key for the language, if they have it enabled, is used as a template for its values.
+#[cfg(windows)]
+pub fn enable_all_users(
+    reg: &dyn RegistryBackend,
+    tag: &LanguageTag,
+    product_code: &str,
+) -> Result<Vec<UserResult>, Error> {
+    log::info!(
+        "Enabling '{}' with product code '{}' for all users",
+        tag,
+        product_code
+    );
+
+    let input_method = InputListItem {
+        lang_id: crate::lcid(reg, tag)?,
+        tip: Tip::Layout(layout_klid(reg, product_code)?),
+    };
+    let template = match LanguageRegKey::find_by_tag(reg, tag) {
+        Some(v) => reg.values(&v.regkey)?,
+        None => vec![],
+    };
 
-        let name = inner_name.to_string_lossy().to_string();
-        let data = format!("{}", inner_data);
-        log::trace!("  '{}' = '{}'", name, data);
-    }
-    log::trace!("");
+    profiles::for_loaded_users(reg, |reg, keys| {
+        profiles::enable_for_user(reg, keys, tag, &input_method, &template)
+    })
+}
 
-    log::trace!("  == If you see a suspicious REGKEY in your neighbourhood, call 112 ==")
+/// Removes the keyboard from every language of the current user.
+#[cfg(windows)]
+pub fn disable(reg: &dyn RegistryBackend, product_code: &str) -> Result<(), Error> {
+    log::info!("Disabling product code '{}'", product_code);
+    let tip = Tip::Layout(layout_klid(reg, product_code)?);
+
+    let tx = Transaction::begin(reg);
+    let original_keyboards = crate::win8::enabled_keyboards()?;
+    let keyboards: Vec<(String, Vec<String>)> = original_keyboards
+        .iter()
+        .map(|(tag, imes)| {
+            let imes = imes
+                .iter()
+                .filter(|x| !matches!(InputListItem::try_from(&***x), Ok(item) if item.tip == tip))
+                .cloned()
+                .collect();
+            (tag.to_owned(), imes)
+        })
+        .collect();
+    tx.on_rollback("restore enabled languages and input methods", move || {
+        crate::win8::restore_keyboards(reg, &original_keyboards).map_err(|e| e.to_string())
+    });
+
+    crate::win8::restore_keyboards(&tx, &keyboards)?;
+    regenerate_registry(&tx)?;
+
+    tx.commit();
+    Ok(())
 }
 
-pub fn enable(tag: &str, product_code: &str, lang_name: Option<&str>) -> Result<(), Error> {
-    log::info!("Enabling '{}' with product code '{}'", tag, product_code);
-    log::info!("Lang name: {:?}", lang_name);
+/// Removes the keyboard from every user loaded under `HKEY_USERS`, other than service accounts.
+#[cfg(windows)]
+pub fn disable_all_users(
+    reg: &dyn RegistryBackend,
+    product_code: &str,
+) -> Result<Vec<UserResult>, Error> {
+    log::info!("Disabling product code '{}' for all users", product_code);
+    let klid = format!("{:08x}", layout_klid(reg, product_code)?);
+
+    profiles::for_loaded_users(reg, |reg, keys| {
+        Ok(!profiles::purge_klid(reg, keys, &klid)?.is_empty())
+    })
+}
+
+/// Adds each TIP to the input methods of its language and installs them all at once, as part of
+/// `tx`.
+#[cfg(windows)]
+fn enable_tips<'a>(tx: &Transaction<'a>, records: &[(&LanguageTag, Tip)]) -> Result<(), Error> {
+    let inner = tx.inner();
+    log_important_regkeys(inner);
 
-    log_important_regkeys();
+    let original_keyboards = crate::win8::enabled_keyboards()?;
+    tx.on_rollback("restore enabled languages and input methods", move || {
+        crate::win8::restore_keyboards(inner, &original_keyboards).map_err(|e| e.to_string())
+    });
+    let reg: &dyn RegistryBackend = tx;
 
     log::info!("Regenerating registry for keyboards, just in case.");
-    regenerate_registry();
+    regenerate_registry(reg)?;
 
     let original_layout = winuser::current_keyboard();
-    let record = match KeyboardRegKey::find_by_product_code(product_code) {
-        Some(v) => v,
-        None => return Err(Error::NotFound),
-    };
 
     // Check language is enabled or LCID check will fail
-    log::info!("Enabling language by tag");
-    crate::enable_language(tag).unwrap();
+    log::info!("Enabling languages by tag");
+    let tags: Vec<&LanguageTag> = records.iter().map(|(tag, _)| *tag).collect();
+    crate::win8::enable_languages(reg, &tags)?;
 
     // Get all languages and keyboards
-    let mut keyboards = crate::win8::enabled_keyboards()
-        .unwrap()
+    let mut keyboards = crate::win8::enabled_keyboards()?
         .into_iter()
         .collect::<IndexMap<_, _>>();
     log::trace!("Keyboards: {:?}", &keyboards);
 
-    log::trace!("bcp47langs::lcid_from_bcp47(tag).unwrap()");
-    let lcid = bcp47langs::lcid_from_bcp47(tag).unwrap();
-    let tip = format!("{:04X}:{}", lcid, record.regkey_id());
+    for (tag, tip) in records.iter() {
+        let lcid = crate::lcid(reg, tag)?;
+        log::trace!("LCID of {}: {:04x}", tag, lcid);
+        let tip = InputListItem {
+            lang_id: lcid,
+            tip: *tip,
+        }
+        .to_string();
 
-    log::debug!("Injecting into keyboard list: {}", &tip);
+        log::debug!("Injecting into keyboard list: {}", &tip);
 
-    keyboards
-        .entry(tag.to_string())
-        .and_modify(|x| x.push(tip.clone()))
-        .or_insert(vec![tip.clone()]);
+        let tips = keyboards.entry(tag.to_string()).or_insert(vec![]);
+        if !tips.iter().any(|x| x.eq_ignore_ascii_case(&tip)) {
+            tips.push(tip);
+        }
+    }
 
     log::debug!("Keyboard list: {:?}", &keyboards);
-    log_important_regkeys();
+    log_important_regkeys(reg);
 
     // Remove all inputs internal
     // log::trace!("bcp47langs::remove_inputs_for_all_languages().unwrap();");
@@ -130,216 +259,535 @@ pub fn enable(tag: &str, product_code: &str, lang_name: Option<&str>) -> Result<
     // log_important_regkeys();
 
     // Build input method list
-    let mut first = true;
+    let mut all_tips = vec![];
     for (lang_tag, tips) in keyboards {
-        let _lcid = match bcp47langs::lcid_from_bcp47(&lang_tag) {
-            Some(v) => v,
-            None => {
-                log::error!("No LCID for {}; continuing!", &lang_tag);
-                continue;
-            }
-        };
+        // Languages given a transient LCID above have none Windows will report yet.
+        let is_ours = records
+            .iter()
+            .any(|(tag, _)| tag.as_str().eq_ignore_ascii_case(&lang_tag));
+        if !is_ours && bcp47langs::lcid_from_bcp47(&lang_tag)?.is_none() {
+            log::error!("No LCID for {}; continuing!", &lang_tag);
+            continue;
+        }
 
         log::debug!("Tip for {}: {:?}", lang_tag, &tips);
-        let inputs = InputList::try_from(tips).unwrap();
-        log::debug!("Input list for {}: {:?}", lang_tag, &inputs);
+        all_tips.extend(tips);
+    }
 
-        // Flag 256 seems to clear everything.
-        // let flag = if first { 256 } else { 0 };
-        let flag = 0;
-        first = false;
+    let inputs = InputList::try_from(all_tips.clone())
+        .map_err(|_| Error::InvalidInputMethods(all_tips.join(";")))?;
+    log::debug!("Input list: {:?}", &inputs);
 
-        input::install_layout(inputs, flag).unwrap();
-    }
-    log_important_regkeys();
+    // Flag 256 seems to clear everything.
+    input::install_layout(inputs, 0)?;
+    log_important_regkeys(reg);
 
     log::info!("Regenerating registry for keyboards");
-    regenerate_registry();
-    log_important_regkeys();
+    regenerate_registry(reg)?;
+    log_important_regkeys(reg);
 
     log::info!("Resetting current active keyboard");
     winuser::set_active_keyboard(original_layout);
 
-    coreglobconfig::sync_language_data();
-    std::thread::sleep_ms(10000);
+    coreglobconfig::sync_language_data()?;
+    if !crate::dry_run::is_active() {
+        std::thread::sleep(std::time::Duration::from_secs(10));
+    }
     log::info!("Done saving lang to cloud");
-    
+
+    Ok(())
+}
+
+/// Drops the input methods of the current user that cannot be read, or whose keyboard or text
+/// service is no longer installed, and disables the languages left without any.
+#[cfg(windows)]
+pub fn remove_invalid_kbids(reg: &dyn RegistryBackend) -> Result<(), Error> {
+    let keyboards = crate::enabled_keyboards()?;
+    let valid = valid_keyboards(reg, &keyboards)?;
+    if valid != keyboards {
+        crate::restore_keyboards(reg, &valid)?;
+    }
     Ok(())
 }
 
-pub fn remove_invalid_kbids() {
-    let installed_imes: Vec<String> = KeyboardRegKey::installed()
+/// The languages of `keyboards`, as returned by `enabled_keyboards`, with only their input
+/// methods that still refer to something installed. Windows' own layouts are always kept, kbdi's
+/// only while their KLID is installed, and text services while a profile of theirs is registered.
+/// Languages left without input methods are dropped.
+pub fn valid_keyboards(
+    reg: &dyn RegistryBackend,
+    keyboards: &[(String, Vec<String>)],
+) -> Result<Vec<(String, Vec<String>)>, Error> {
+    let installed_imes: Vec<String> = if reg.key_exists(&crate::keyboard::keyboard_layouts_key()) {
+        KeyboardRegKey::installed(reg)?
+            .iter()
+            .map(|x| x.regkey_id().to_owned())
+            .collect()
+    } else {
+        vec![]
+    };
+
+    let registered_tips: Vec<Tip> = crate::text_service::installed(reg)?
         .iter()
-        .map(|x| x.regkey_id().to_owned())
+        .map(|x| x.tip())
         .collect();
 
-    let enabled_imes = enabled_input_methods();
-    let filtered_imes: Vec<InputListItem> = enabled_imes
-        .into_inner()
-        .into_iter()
-        .filter(|i| {
-            let kbid = i.kbid().to_string().to_lowercase();
-            // Only handle custom keyboards
-            if kbid.starts_with("a") {
-                return true;
+    let is_valid = |ime: &str| {
+        let item = match InputListItem::try_from(ime) {
+            Ok(v) => v,
+            Err(e) => {
+                log::warn!("Dropping input method {:?}: {}", ime, e);
+                return false;
             }
-            installed_imes.contains(&kbid)
+        };
+        if let Tip::TextService { .. } = item.tip {
+            return registered_tips.contains(&item.tip);
+        }
+
+        let kbid = item.kbid().to_lowercase();
+        // Only handle custom keyboards
+        if !kbid.starts_with('a') {
+            return true;
+        }
+        installed_imes.iter().any(|x| x.eq_ignore_ascii_case(&kbid))
+    };
+
+    Ok(keyboards
+        .iter()
+        .map(|(tag, imes)| {
+            let imes: Vec<String> = imes.iter().filter(|x| is_valid(x)).cloned().collect();
+            (tag.to_owned(), imes)
         })
-        .collect();
+        .filter(|(_, imes)| !imes.is_empty())
+        .collect())
+}
 
-    bcp47langs::remove_inputs_for_all_languages().unwrap();
-    input::install_layout(InputList::from(filtered_imes), 0).unwrap();
+#[cfg(windows)]
+fn is_local_system() -> Result<bool, Error> {
+    let current_sid = current_process_sid().map_err(|source| Error::Win32 {
+        function: "GetTokenInformation",
+        source,
+    })?;
+    let nt_auth_system = Sid::well_known_sid(WinLocalSystemSid).map_err(|source| Error::Win32 {
+        function: "CreateWellKnownSid",
+        source,
+    })?;
+    log::debug!("Running as {:?}", current_sid);
+    log::debug!("nta is {:?}", nt_auth_system);
+    Ok(current_sid == nt_auth_system)
 }
 
-pub fn regenerate_registry() {
-    let user_profile_key = Hive::CurrentUser
-        .open(r"Control Panel\International\User Profile", Security::Read)
-        .unwrap();
-    let substitutes_key = Hive::CurrentUser
-        .open(
-            r"Keyboard Layout\Substitutes",
-            Security::Read | Security::Write,
-        )
-        .unwrap();
-    let preload_key = Hive::CurrentUser
-        .open(r"Keyboard Layout\Preload", Security::Read | Security::Write)
-        .unwrap();
+#[cfg(windows)]
+pub fn regenerate_registry(reg: &dyn RegistryBackend) -> Result<(), Error> {
+    if is_local_system()? {
+        log::debug!("Not refreshing because we're running at NT Authority/System");
+        return Ok(());
+    }
 
-    regenerate_given_registry(user_profile_key, substitutes_key, preload_key);
+    regenerate_given_registry(reg, &user_profile_key(), &substitutes_key(), &preload_key())
 }
 
-fn enable_default_user_lang(tag: &str) {
-    let default_user_registry = registry::Hive::load_file(
-        r"C:\Users\Default\NTUSER.DAT",
-        Security::Read | Security::Write,
+/// Enables the keyboard for accounts created from now on, by writing its entries into the default
+/// profile's `NTUSER.DAT`. The hive is loaded only while writing, and unloaded even if that fails.
+#[cfg(windows)]
+pub fn enable_default_user(
+    reg: &dyn RegistryBackend,
+    tag: &LanguageTag,
+    product_code: &str,
+) -> Result<Vec<ValueResult>, Error> {
+    log::info!(
+        "Enabling '{}' with product code '{}' for the default user",
+        tag,
+        product_code
+    );
+
+    let input_method = InputListItem {
+        lang_id: crate::lcid(reg, tag)?,
+        tip: Tip::Layout(layout_klid(reg, product_code)?),
+    };
+    let template = match LanguageRegKey::find_by_tag(reg, tag) {
+        Some(v) => reg.values(&v.regkey)?,
+        None => vec![],
+    };
+
+    let path = profiles::profiles(reg)?
+        .into_iter()
+        .find(|x| x.sid == "Default")
+        .and_then(|x| x.hive_path())
+        .ok_or_else(|| Error::InvalidArgument("there is no default user profile".into()))?;
+    log::info!("Loading {}", path.display());
+    let hive = HiveFile::load(&path)?;
+    let dry_run_hive = DryRunRegistry::new(&hive);
+    let target: &dyn RegistryBackend = match crate::dry_run::is_active() {
+        true => &dry_run_hive,
+        false => &hive,
+    };
+
+    profiles::enable_for_default_user(
+        target,
+        &UserKeys::current_user(),
+        tag,
+        &input_method,
+        &template,
     )
-    .unwrap();
-    let language_key = LanguageRegKey::find_by_tag(tag).unwrap().regkey;
-    let substitutes_key = Hive::CurrentUser
-        .open(
-            r"Keyboard Layout\Substitutes",
-            Security::Read | Security::Write,
-        )
-        .unwrap();
+}
 
-    let default_user_profile_key = default_user_registry
-        .create(
-            format!(r"Control Panel\International\User Profile\{}", tag),
-            Security::Read | Security::Write,
-        )
-        .unwrap();
-    let default_substitutes_key = default_user_registry
-        .open(
-            r"Keyboard Layout\Substitutes",
-            Security::Read | Security::Write,
-        )
-        .unwrap();
+/// The changes that regenerating makes to `Keyboard Layout\Substitutes` and `Preload`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Regeneration {
+    /// Substitutes, as name and KLID, whose layout is not an input method of any language.
+    pub delete_substitutes: Vec<(String, String)>,
+    /// The new `Preload` list. Each entry is a KLID, or the name of a substitute for it.
+    pub preload: Vec<String>,
+    /// The `Preload` values before regenerating, as name and value.
+    pub current_preload: Vec<(String, String)>,
+}
 
-    for value in language_key.values() {
-        let value = value.unwrap();
-        default_user_profile_key
-            .set_value(value.name(), value.data())
-            .unwrap();
+impl Regeneration {
+    /// Whether regenerating would leave both keys as they are.
+    pub fn is_empty(&self) -> bool {
+        let new_preload: Vec<(String, String)> = self
+            .preload
+            .iter()
+            .enumerate()
+            .map(|(i, value)| ((i + 1).to_string(), value.to_owned()))
+            .collect();
+        self.delete_substitutes.is_empty() && new_preload == self.current_preload
     }
 
-    for value in substitutes_key.values() {
-        let value = value.unwrap();
-        default_substitutes_key
-            .set_value(value.name(), value.data())
-            .unwrap();
-    }
+    fn apply(
+        &self,
+        reg: &dyn RegistryBackend,
+        substitutes_key: &KeyPath,
+        preload_key: &KeyPath,
+    ) -> Result<(), Error> {
+        for (name, _) in self.delete_substitutes.iter() {
+            log::debug!("Deleting substitute: {:?}", name);
+            reg.delete_value(substitutes_key, name)?;
+        }
 
-    let preload_key = default_user_registry
-        .open(r"Keyboard Layout\Preload", Security::Read | Security::Write)
-        .unwrap();
-    regenerate_given_registry(default_user_profile_key, substitutes_key, preload_key);
+        let new_names: Vec<String> = (1..=self.preload.len()).map(|i| i.to_string()).collect();
+        for (name, _) in self.current_preload.iter() {
+            if !new_names.contains(name) {
+                reg.delete_value(preload_key, name)?;
+            }
+        }
+
+        for (name, value) in new_names.iter().zip(self.preload.iter()) {
+            reg.set_value(preload_key, name, &Data::String(value.to_owned()))?;
+        }
+
+        Ok(())
+    }
 }
 
-fn regenerate_given_registry(
-    user_profile_key: RegKey,
-    substitutes_key: RegKey,
-    preload_key: RegKey,
-) {
-    let current_sid = current_process_sid().expect("Failed to get current SID");
-    let nt_auth_system = Sid::well_known_sid(WinLocalSystemSid).unwrap();
-    log::debug!("Running as {:?}", current_sid);
-    log::debug!("nta is {:?}", nt_auth_system);
-    if current_sid == nt_auth_system {
-        log::debug!("Not refreshing because we're running at NT Authority/System");
-        return
+impl fmt::Display for Regeneration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Substitutes:")?;
+        if self.delete_substitutes.is_empty() {
+            writeln!(f, "  (unchanged)")?;
+        }
+        for (name, klid) in self.delete_substitutes.iter() {
+            writeln!(f, "  - {} = {}", name, klid)?;
+        }
+
+        writeln!(f, "Preload:")?;
+        for (i, value) in self.preload.iter().enumerate() {
+            let name = (i + 1).to_string();
+            match self.current_preload.iter().find(|(n, _)| *n == name) {
+                Some((_, v)) if v == value => writeln!(f, "    {} = {}", name, value)?,
+                Some((_, v)) => writeln!(f, "  ~ {} = {} (was {})", name, value, v)?,
+                None => writeln!(f, "  + {} = {}", name, value)?,
+            }
+        }
+        for (name, value) in self.current_preload.iter() {
+            if name
+                .parse::<usize>()
+                .map_or(true, |i| i == 0 || i > self.preload.len())
+            {
+                writeln!(f, "  - {} = {}", name, value)?;
+            }
+        }
+
+        Ok(())
     }
+}
 
-    log::debug!("regenerate_given_registry");
-    let lang_keys: Vec<_> = user_profile_key
-        .keys()
-        .map(|k| k.unwrap().open(Security::Read).unwrap())
+/// Works out the new `Preload` list and the stale `Substitutes` from the input methods the user
+/// has enabled.
+///
+/// Layouts are preloaded in order of language id, then KLID, each only once. A layout with a
+/// substitute for its language is preloaded by the substitute's name. Substitutes for layouts
+/// that are not enabled for any language are deleted. Text services are ignored, as neither key
+/// lists them.
+pub fn plan_regeneration(
+    input_methods: &[InputListItem],
+    substitutes: &[(String, String)],
+    preload: &[(String, String)],
+) -> Regeneration {
+    let mut layouts: Vec<(u16, u32)> = input_methods
+        .iter()
+        .filter_map(|x| Some((x.lang_id, x.klid()?)))
         .collect();
+    layouts.sort();
+    layouts.dedup();
 
-    log::trace!("Lang keys: {:?}", lang_keys);
+    let delete_substitutes = substitutes
+        .iter()
+        .filter(|(_, klid)| {
+            !layouts
+                .iter()
+                .any(|(_, x)| klid.eq_ignore_ascii_case(&format!("{:08x}", x)))
+        })
+        .cloned()
+        .collect();
 
-    // Get known keyboard ids from Control Panel configured language list
-    let mut keyboard_ids: Vec<_> = lang_keys
+    let new_preload = layouts
         .iter()
-        .flat_map(|k| k.values())
-        .map(|v| v.unwrap().name().to_string_lossy())
-        .filter(|n| n.contains(":"))
-        .map(|v| InputListItem::try_from(&*v))
-        // .map(|n| n.split(":").last().unwrap().to_string())
-        .filter_map(Result::ok)
+        .map(|(lang_id, klid)| {
+            let lcid = format!("{:04x}", lang_id);
+            let klid = format!("{:08x}", klid);
+
+            // A substitute is named for the language it serves, in its low word.
+            substitutes
+                .iter()
+                .find(|(name, value)| {
+                    value.eq_ignore_ascii_case(&klid)
+                        && matches!(name.get(4..), Some(x) if x.eq_ignore_ascii_case(&lcid))
+                })
+                .map(|(name, _)| name.to_owned())
+                .unwrap_or(klid)
+        })
         .collect();
 
-    keyboard_ids.sort_by(|a, b| a.tip_id.cmp(&b.tip_id));
-    keyboard_ids.sort_by(|a, b| a.lang_id.cmp(&b.lang_id));
+    Regeneration {
+        delete_substitutes,
+        preload: new_preload,
+        current_preload: preload.to_vec(),
+    }
+}
 
-    log::trace!("Keyboard IDs: {:?}", &keyboard_ids);
+/// Reads what regenerating `Preload` and `Substitutes` from the language subkeys of
+/// `user_profile_key` would change, without changing it.
+pub fn given_registry_regeneration(
+    reg: &dyn RegistryBackend,
+    user_profile_key: &KeyPath,
+    substitutes_key: &KeyPath,
+    preload_key: &KeyPath,
+) -> Result<Regeneration, Error> {
+    let lang_keys: Vec<KeyPath> = reg
+        .subkeys(user_profile_key)?
+        .iter()
+        .map(|k| user_profile_key.join(k))
+        .collect();
 
-    // Get all substitutes into a list
-    let subs = substitutes_key
-        .values()
-        .filter_map(Result::ok)
-        .map(|x| {
-            let x = x.into_inner();
-            (x.0.to_string_lossy(), x.1.to_string())
-        })
-        .collect::<Vec<_>>();
+    log::trace!("Lang keys: {:?}", lang_keys);
 
-    log::trace!("Substitutions: {:?}", &subs);
+    // Get known keyboard ids from Control Panel configured language list
+    let mut input_methods: Vec<InputListItem> = vec![];
+    for key in lang_keys.iter() {
+        input_methods.extend(
+            reg.values(key)?
+                .into_iter()
+                .filter(|(name, _)| name.contains(':'))
+                .filter_map(|(name, _)| InputListItem::try_from(&*name).ok()),
+        );
+    }
 
-    // Clean up all invalid substitutions
-    for (value_id, kbd_id) in subs.iter() {
-        if keyboard_ids
-            .iter()
-            .find(|x| &format!("{:08x}", x.tip_id) == kbd_id)
-            .is_none()
-        {
-            log::debug!("Deleting substitute: {:?}", value_id);
-            substitutes_key.delete_value(value_id).unwrap();
+    log::trace!("Input methods: {:?}", &input_methods);
+
+    let string_values = |key: &KeyPath| -> Result<Vec<(String, String)>, Error> {
+        if !reg.key_exists(key) {
+            return Ok(vec![]);
         }
-    }
 
-    // Delete all preload values
-    for value in preload_key.values() {
-        let name = value.unwrap().name().to_owned();
-        preload_key.delete_value(name).unwrap();
-    }
+        Ok(reg
+            .values(key)?
+            .into_iter()
+            .map(|(name, data)| (name, data.to_string()))
+            .collect())
+    };
+
+    let substitutes = string_values(substitutes_key)?;
+    log::trace!("Substitutions: {:?}", &substitutes);
+    let preload = string_values(preload_key)?;
 
-    log::trace!("Cleared all preload keys");
+    Ok(plan_regeneration(&input_methods, &substitutes, &preload))
+}
 
-    // Check if substitutes contains lang_id
-    for (i, item) in keyboard_ids.iter().enumerate() {
-        let lcid = format!("{:08x}", item.lang_id);
-        let tip = format!("{:08x}", item.tip_id);
+/// What [`regenerate_registry`] would change for the current user.
+pub fn registry_regeneration(reg: &dyn RegistryBackend) -> Result<Regeneration, Error> {
+    given_registry_regeneration(reg, &user_profile_key(), &substitutes_key(), &preload_key())
+}
 
-        let value = if let Some(sub) = subs.iter().filter(|sub| sub.1 == tip && sub.0[4..] == lcid[4..]).nth(0) {
-            log::trace!("{}: Adding substitute lcid: {}", i + 1, &sub.0);
-            sub.0.clone().try_into().unwrap()
-        } else {
-            log::trace!("{}: Adding TIP: {}", i + 1, &tip);
-            tip.try_into().unwrap()
-        };
+/// Rebuilds `Preload` and prunes `Substitutes` from the input methods listed under the language
+/// subkeys of `user_profile_key`.
+pub fn regenerate_given_registry(
+    reg: &dyn RegistryBackend,
+    user_profile_key: &KeyPath,
+    substitutes_key: &KeyPath,
+    preload_key: &KeyPath,
+) -> Result<(), Error> {
+    log::debug!("regenerate_given_registry");
+    let plan = given_registry_regeneration(reg, user_profile_key, substitutes_key, preload_key)?;
+    log::trace!("Regeneration: {:?}", &plan);
 
-        preload_key
-            .set_value((i + 1).to_string(), &Data::String(value))
-            .unwrap();
+    if !plan.preload.is_empty() && !reg.key_exists(preload_key) {
+        reg.create_key(preload_key)?;
     }
+    plan.apply(reg, substitutes_key, preload_key)
+}
+
+#[test]
+fn test_regenerate_given_registry() {
+    use crate::backend::MemoryRegistry;
+
+    let reg = MemoryRegistry::new();
+    let user_profile = KeyPath::new(
+        Hive::CurrentUser,
+        r"Control Panel\International\User Profile",
+    );
+    let substitutes = KeyPath::new(Hive::CurrentUser, r"Keyboard Layout\Substitutes");
+    let preload = KeyPath::new(Hive::CurrentUser, r"Keyboard Layout\Preload");
+
+    let se = user_profile.join("se-NO");
+    let en = user_profile.join("en-US");
+    reg.create_key(&se).unwrap();
+    reg.create_key(&en).unwrap();
+    reg.create_key(&substitutes).unwrap();
+    reg.create_key(&preload).unwrap();
+
+    reg.set_value(&se, "043B:A000043B", &Data::U32(1)).unwrap();
+    reg.set_value(&se, "CachedLanguageName", &Data::String("Sami".into()))
+        .unwrap();
+    reg.set_value(&en, "0409:00000409", &Data::U32(1)).unwrap();
+    reg.set_value(&substitutes, "d001043b", &Data::String("a000043b".into()))
+        .unwrap();
+    reg.set_value(&substitutes, "d0010407", &Data::String("a0000407".into()))
+        .unwrap();
+    reg.set_value(&preload, "1", &Data::String("00000407".into()))
+        .unwrap();
+    reg.set_value(&preload, "7", &Data::String("00000407".into()))
+        .unwrap();
+
+    regenerate_given_registry(&reg, &user_profile, &substitutes, &preload).unwrap();
+
+    assert_eq!(
+        reg.values(&substitutes).unwrap(),
+        vec![("d001043b".to_string(), Data::String("a000043b".into()))]
+    );
+    assert_eq!(
+        reg.values(&preload).unwrap(),
+        vec![
+            ("1".to_string(), Data::String("00000409".into())),
+            ("2".to_string(), Data::String("d001043b".into())),
+        ]
+    );
+}
+
+#[test]
+fn test_plan_regeneration() {
+    let items = |list: &str| InputList::try_from(list.to_string()).unwrap().into_inner();
+    let pairs = |list: &[(&str, &str)]| -> Vec<(String, String)> {
+        list.iter()
+            .map(|(a, b)| (a.to_string(), b.to_string()))
+            .collect()
+    };
+
+    // Two substitutes for the same language, one of them in upper case, and one too short to
+    // name a language.
+    let plan = plan_regeneration(
+        &items(concat!(
+            "043B:A001043B;043B:A000043B;0409:00000409;043B:A000043B;",
+            "0411:{03B5835F-F03C-411B-9CE2-AA23E1171E36}{A76C93D9-5523-4E90-AAFA-4DB112F9AC76}"
+        )),
+        &pairs(&[
+            ("d001043b", "a000043b"),
+            ("D002043B", "A001043B"),
+            ("d00", "a001043b"),
+            ("d0010407", "a0000407"),
+        ]),
+        &pairs(&[("1", "00000409"), ("2", "d001043b"), ("9", "00000407")]),
+    );
+
+    assert_eq!(plan.preload, vec!["00000409", "d001043b", "D002043B"]);
+    assert_eq!(plan.delete_substitutes, pairs(&[("d0010407", "a0000407")]));
+    assert!(!plan.is_empty());
+    assert_eq!(
+        plan.to_string(),
+        concat!(
+            "Substitutes:\n",
+            "  - d0010407 = a0000407\n",
+            "Preload:\n",
+            "    1 = 00000409\n",
+            "    2 = d001043b\n",
+            "  + 3 = D002043B\n",
+            "  - 9 = 00000407\n",
+        )
+    );
+
+    // The same layout for another language is not served by a substitute for the first.
+    let plan = plan_regeneration(
+        &items("0409:A000043B"),
+        &pairs(&[("d001043b", "a000043b")]),
+        &pairs(&[("1", "a000043b")]),
+    );
+    assert_eq!(plan.preload, vec!["a000043b"]);
+    assert!(plan.is_empty());
+
+    let plan = plan_regeneration(&[], &pairs(&[("d001043b", "a000043b")]), &[]);
+    assert_eq!(plan.preload, Vec::<String>::new());
+    assert_eq!(plan.delete_substitutes.len(), 1);
+}
+
+#[test]
+fn test_valid_keyboards() {
+    use crate::backend::MemoryRegistry;
+    use crate::text_service::{self, TextServiceProfile};
+
+    let reg = MemoryRegistry::new();
+    let layouts = crate::keyboard::keyboard_layouts_key();
+    reg.create_key(&layouts.join("a000043b")).unwrap();
+    let clsid: Guid = "{03B5835F-F03C-411B-9CE2-AA23E1171E36}".parse().unwrap();
+    let profile: Guid = "{A76C93D9-5523-4E90-AAFA-4DB112F9AC76}".parse().unwrap();
+    let ime = TextServiceProfile {
+        clsid,
+        profile,
+        lang_id: 0x043b,
+        description: None,
+        icon_file: None,
+        icon_index: 0,
+    };
+    ime.register(&reg).unwrap();
+
+    let keyboards = |list: &[(&str, &[&str])]| -> Vec<(String, Vec<String>)> {
+        list.iter()
+            .map(|(tag, imes)| {
+                (
+                    tag.to_string(),
+                    imes.iter().map(|x| x.to_string()).collect(),
+                )
+            })
+            .collect()
+    };
+    let tip = ime.input_list_item().to_string();
+    let enabled = keyboards(&[
+        ("en-US", &["0409:00000409", "0409:A0000409"]),
+        ("se-NO", &["043B:A000043B", &tip, "nonsense"]),
+        ("fi-FI", &["040B:A000040B"]),
+    ]);
+    assert_eq!(
+        valid_keyboards(&reg, &enabled).unwrap(),
+        keyboards(&[
+            ("en-US", &["0409:00000409"]),
+            ("se-NO", &["043B:A000043B", &tip]),
+        ])
+    );
+
+    // As `text_service_uninstall` does, before cleaning up.
+    text_service::unregister(&reg, clsid, profile).unwrap();
+    assert_eq!(
+        valid_keyboards(&reg, &enabled).unwrap(),
+        keyboards(&[("en-US", &["0409:00000409"]), ("se-NO", &["043B:A000043B"]),])
+    );
 }
@@ -0,0 +1,253 @@
+//! Allocation of the `Keyboard Layouts` key name (KLID) and `Layout Id` for a new keyboard.
+//!
+//! Custom layouts use KLIDs of the form `aXXXllll`, where `llll` is the LCID, so there are 4096
+//! of them per LCID. A `Layout Id` is 12 bits wide, as it becomes the low bits of the `F0nn`
+//! device handle of the HKL, and Microsoft's own variant layouts occupy the ids below 0x100.
+//!
+//! Languages Windows has no LCID for are given one of twelve transient LCIDs instead, recorded
+//! as `TransientLangId` in the language's `User Profile` subkey. Each language keeps its
+//! transient LCID, and two languages never share one.
+
+use crate::LanguageTag;
+use std::collections::BTreeSet;
+use std::ops::RangeInclusive;
+
+pub const KLID_PREFIXES: RangeInclusive<u16> = 0xa000..=0xafff;
+pub const LAYOUT_IDS: RangeInclusive<u16> = 0x0100..=0x0fff;
+pub const TRANSIENT_LCIDS: [u16; 12] = [
+    0x2000, 0x2400, 0x2800, 0x2c00, 0x3000, 0x3400, 0x3800, 0x3c00, 0x4000, 0x4400, 0x4800, 0x4c00,
+];
+/// What `LocaleNameToLCID` returns for a locale that has no LCID of its own.
+pub const LOCALE_CUSTOM_UNSPECIFIED: u32 = 0x1000;
+
+/// A subkey of `Keyboard Layouts` and its `Layout Id` value, if it has one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstalledLayout {
+    pub klid: String,
+    pub layout_id: Option<String>,
+}
+
+/// A language using a transient LCID, from its `User Profile` subkey or an installed layout.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransientLcid {
+    pub tag: String,
+    pub lcid: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Allocation {
+    pub klid: String,
+    pub layout_id: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum Error {
+    #[error("All keyboard layout ids for LCID {0:04x} are in use")]
+    KlidsExhausted(u16),
+
+    #[error("All layout ids are in use")]
+    LayoutIdsExhausted,
+
+    #[error("All transient LCIDs are in use by other languages")]
+    TransientLcidsExhausted,
+}
+
+fn parse_klid(klid: &str) -> Option<u32> {
+    if klid.len() != 8 {
+        return None;
+    }
+
+    u32::from_str_radix(klid, 16).ok()
+}
+
+/// Returns the lowest free `aXXXllll` KLID for `lcid`.
+pub fn next_klid(layouts: &[InstalledLayout], lcid: u16) -> Result<String, Error> {
+    let used: BTreeSet<u32> = layouts.iter().filter_map(|x| parse_klid(&x.klid)).collect();
+
+    KLID_PREFIXES
+        .map(|prefix| ((prefix as u32) << 16) | lcid as u32)
+        .find(|klid| !used.contains(klid))
+        .map(|klid| format!("{:08x}", klid))
+        .ok_or(Error::KlidsExhausted(lcid))
+}
+
+/// Returns the lowest free `Layout Id` outside of the range reserved by Microsoft.
+pub fn next_layout_id(layouts: &[InstalledLayout]) -> Result<String, Error> {
+    let used: BTreeSet<u16> = layouts
+        .iter()
+        .filter_map(|x| x.layout_id.as_ref())
+        .filter_map(|x| u16::from_str_radix(x.trim(), 16).ok())
+        .collect();
+
+    LAYOUT_IDS
+        .into_iter()
+        .find(|id| !used.contains(id))
+        .map(|id| format!("{:04x}", id))
+        .ok_or(Error::LayoutIdsExhausted)
+}
+
+pub fn is_transient_lcid(lcid: u32) -> bool {
+    TRANSIENT_LCIDS.iter().any(|x| *x as u32 == lcid)
+}
+
+/// Returns the transient LCID `tag` already uses, or else the lowest one no other language uses.
+pub fn next_transient_lcid(used: &[TransientLcid], tag: &LanguageTag) -> Result<u16, Error> {
+    let is_free = |lcid: u16| {
+        used.iter()
+            .all(|x| x.lcid != lcid || x.tag.eq_ignore_ascii_case(tag.as_str()))
+    };
+
+    used.iter()
+        .filter(|x| x.tag.eq_ignore_ascii_case(tag.as_str()) && is_transient_lcid(x.lcid as u32))
+        .map(|x| x.lcid)
+        .find(|x| is_free(*x))
+        .or_else(|| TRANSIENT_LCIDS.iter().copied().find(|x| is_free(*x)))
+        .ok_or(Error::TransientLcidsExhausted)
+}
+
+/// Returns the LCID Windows reported for `tag`, or a transient LCID if it has no LCID of its own.
+pub fn resolve_lcid(
+    windows_lcid: Option<u32>,
+    used: &[TransientLcid],
+    tag: &LanguageTag,
+) -> Result<u16, Error> {
+    match windows_lcid {
+        Some(lcid)
+            if lcid != 0
+                && lcid != LOCALE_CUSTOM_UNSPECIFIED
+                && lcid <= 0xffff
+                && !is_transient_lcid(lcid) =>
+        {
+            Ok(lcid as u16)
+        }
+        _ => next_transient_lcid(used, tag),
+    }
+}
+
+pub fn allocate(layouts: &[InstalledLayout], lcid: u16) -> Result<Allocation, Error> {
+    Ok(Allocation {
+        klid: next_klid(layouts, lcid)?,
+        layout_id: next_layout_id(layouts)?,
+    })
+}
+
+#[cfg(test)]
+fn layout(klid: &str, layout_id: Option<&str>) -> InstalledLayout {
+    InstalledLayout {
+        klid: klid.to_string(),
+        layout_id: layout_id.map(str::to_string),
+    }
+}
+
+#[test]
+fn test_allocate_empty() {
+    assert_eq!(
+        allocate(&[], 0x043b),
+        Ok(Allocation {
+            klid: "a000043b".into(),
+            layout_id: "0100".into(),
+        })
+    );
+}
+
+#[test]
+fn test_allocate_reuses_gaps() {
+    let layouts = vec![
+        layout("00000409", None),
+        layout("00020409", Some("0010")),
+        layout("a000043b", Some("0100")),
+        layout("A002043B", Some("0102")),
+        layout("a0010409", Some("0101")),
+    ];
+
+    assert_eq!(next_klid(&layouts, 0x043b), Ok("a001043b".into()));
+    assert_eq!(next_klid(&layouts, 0x0409), Ok("a0000409".into()));
+    assert_eq!(next_layout_id(&layouts), Ok("0103".into()));
+}
+
+#[test]
+fn test_allocate_exhausted() {
+    let layouts: Vec<_> = KLID_PREFIXES
+        .map(|p| layout(&format!("{:04x}043b", p), None))
+        .collect();
+    assert_eq!(
+        next_klid(&layouts, 0x043b),
+        Err(Error::KlidsExhausted(0x043b))
+    );
+    assert_eq!(next_klid(&layouts, 0x0c3b), Ok("a0000c3b".into()));
+
+    let layouts: Vec<_> = LAYOUT_IDS
+        .map(|id| layout("00000409", Some(&format!("{:04X}", id))))
+        .collect();
+    assert_eq!(next_layout_id(&layouts), Err(Error::LayoutIdsExhausted));
+}
+
+#[test]
+fn test_transient_lcids() {
+    let used = |entries: &[(&str, u16)]| -> Vec<TransientLcid> {
+        entries
+            .iter()
+            .map(|(tag, lcid)| TransientLcid {
+                tag: tag.to_string(),
+                lcid: *lcid,
+            })
+            .collect()
+    };
+    let tag = |x: &str| -> LanguageTag { x.parse().unwrap() };
+
+    assert_eq!(next_transient_lcid(&[], &tag("sjd-RU")), Ok(0x2000));
+    let taken = used(&[("sje-SE", 0x2000), ("sju-SE", 0x2800)]);
+    assert_eq!(next_transient_lcid(&taken, &tag("sjd-RU")), Ok(0x2400));
+    assert_eq!(next_transient_lcid(&taken, &tag("SJU-se")), Ok(0x2800));
+
+    let all: Vec<(String, u16)> = TRANSIENT_LCIDS
+        .iter()
+        .map(|x| (format!("x-{:x}", x), *x))
+        .collect();
+    let all: Vec<(&str, u16)> = all.iter().map(|(tag, x)| (&**tag, *x)).collect();
+    assert_eq!(
+        next_transient_lcid(&used(&all), &tag("sjd-RU")),
+        Err(Error::TransientLcidsExhausted)
+    );
+    assert_eq!(next_transient_lcid(&used(&all), &tag("x-4c00")), Ok(0x4c00));
+
+    assert_eq!(
+        resolve_lcid(Some(0x043b), &taken, &tag("se-NO")),
+        Ok(0x043b)
+    );
+    assert_eq!(
+        resolve_lcid(Some(0x1000), &taken, &tag("sjd-RU")),
+        Ok(0x2400)
+    );
+    assert_eq!(
+        resolve_lcid(Some(0x2800), &taken, &tag("sjd-RU")),
+        Ok(0x2400)
+    );
+    assert_eq!(resolve_lcid(None, &taken, &tag("sju-SE")), Ok(0x2800));
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn prop_allocation_is_free_and_in_range(
+        prefixes in proptest::collection::btree_set(0x9f00u16..0xb100, 0..64),
+        ids in proptest::collection::btree_set(0u16..0x1100, 0..64),
+        lcid in proptest::sample::select(vec![0x0409u16, 0x043b, 0x2000, 0x2400]),
+    ) {
+        let layouts: Vec<_> = prefixes
+            .iter()
+            .zip(ids.iter().map(Some).chain(std::iter::repeat(None)))
+            .map(|(p, id)| layout(&format!("{:04x}{:04x}", p, lcid), id.map(|x| format!("{:04x}", x)).as_deref()))
+            .collect();
+
+        let allocation = allocate(&layouts, lcid).unwrap();
+        let klid = u32::from_str_radix(&allocation.klid, 16).unwrap();
+        let layout_id = u16::from_str_radix(&allocation.layout_id, 16).unwrap();
+
+        proptest::prop_assert!(KLID_PREFIXES.contains(&((klid >> 16) as u16)));
+        proptest::prop_assert_eq!(klid as u16, lcid);
+        proptest::prop_assert!(layouts.iter().all(|x| !x.klid.eq_ignore_ascii_case(&allocation.klid)));
+        proptest::prop_assert!(LAYOUT_IDS.contains(&layout_id));
+        proptest::prop_assert!(!layouts.iter().any(|x| x.layout_id.as_deref() == Some(&*allocation.layout_id)));
+    }
+}
@@ -0,0 +1,447 @@
+//! BCP 47 language tags (RFC 5646), parsed and put into canonical form before they reach Windows.
+//!
+//! Canonical form lowercases every subtag except the script (title case) and region (upper case),
+//! replaces an extended language subtag and its prefix with the extlang alone (`zh-yue` becomes
+//! `yue`), replaces deprecated languages and regions with their preferred values, and sorts
+//! extensions by their singleton. Grandfathered tags are replaced with their preferred value, and
+//! refused if they have none.
+
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum Error {
+    #[error("Language tag is empty")]
+    Empty,
+
+    #[error("Language tag {tag:?} has an invalid subtag {subtag:?}")]
+    InvalidSubtag { tag: String, subtag: String },
+
+    #[error("Language tag {tag:?} repeats the subtag {subtag:?}")]
+    DuplicateSubtag { tag: String, subtag: String },
+
+    #[error("Language tag {tag:?} ends its {singleton:?} extension or private use section early")]
+    EmptyExtension { tag: String, singleton: char },
+
+    #[error("Language tag {0:?} is grandfathered and has no modern equivalent")]
+    Grandfathered(String),
+}
+
+/// Grandfathered tags (RFC 5646 section 2.2.8) and their preferred values.
+const GRANDFATHERED: &[(&str, Option<&str>)] = &[
+    ("art-lojban", Some("jbo")),
+    ("cel-gaulish", None),
+    ("en-gb-oed", Some("en-GB-oxendict")),
+    ("i-ami", Some("ami")),
+    ("i-bnn", Some("bnn")),
+    ("i-default", None),
+    ("i-enochian", None),
+    ("i-hak", Some("hak")),
+    ("i-klingon", Some("tlh")),
+    ("i-lux", Some("lb")),
+    ("i-mingo", None),
+    ("i-navajo", Some("nv")),
+    ("i-pwn", Some("pwn")),
+    ("i-tao", Some("tao")),
+    ("i-tay", Some("tay")),
+    ("i-tsu", Some("tsu")),
+    ("no-bok", Some("nb")),
+    ("no-nyn", Some("nn")),
+    ("sgn-be-fr", Some("sfb")),
+    ("sgn-be-nl", Some("vgt")),
+    ("sgn-ch-de", Some("sgg")),
+    ("zh-guoyu", Some("cmn")),
+    ("zh-hakka", Some("hak")),
+    ("zh-min", None),
+    ("zh-min-nan", Some("nan")),
+    ("zh-xiang", Some("hsn")),
+];
+
+/// Deprecated language subtags from the IANA registry and their preferred values.
+const LANGUAGE_ALIASES: &[(&str, &str)] = &[
+    ("in", "id"),
+    ("iw", "he"),
+    ("ji", "yi"),
+    ("jw", "jv"),
+    ("mo", "ro"),
+    ("aam", "aas"),
+    ("adp", "dz"),
+    ("aue", "ktz"),
+    ("ayx", "nun"),
+    ("bjd", "drl"),
+    ("ccq", "rki"),
+    ("cjr", "mom"),
+    ("cka", "cmr"),
+    ("cmk", "xch"),
+    ("drh", "khk"),
+    ("drw", "prs"),
+    ("gav", "dev"),
+    ("hrr", "jal"),
+    ("ibi", "opa"),
+    ("kgh", "kml"),
+    ("lcq", "ppr"),
+    ("mst", "mry"),
+    ("myt", "mry"),
+    ("sca", "hle"),
+    ("tie", "ras"),
+    ("tkk", "twm"),
+    ("tlw", "weo"),
+    ("tnf", "prs"),
+    ("ybd", "rki"),
+    ("yma", "lrr"),
+];
+
+/// Deprecated region subtags from the IANA registry and their preferred values.
+const REGION_ALIASES: &[(&str, &str)] = &[
+    ("BU", "MM"),
+    ("DD", "DE"),
+    ("FX", "FR"),
+    ("TP", "TL"),
+    ("YD", "YE"),
+    ("ZR", "CD"),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Extension {
+    pub singleton: char,
+    pub subtags: Vec<String>,
+}
+
+/// A language tag in canonical form.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct LanguageTag {
+    tag: String,
+    language: String,
+    script: Option<String>,
+    region: Option<String>,
+    variants: Vec<String>,
+    extensions: Vec<Extension>,
+    private_use: Vec<String>,
+}
+
+fn is_alpha(s: &str, len: std::ops::RangeInclusive<usize>) -> bool {
+    len.contains(&s.len()) && s.bytes().all(|x| x.is_ascii_alphabetic())
+}
+
+fn is_digit(s: &str, len: usize) -> bool {
+    s.len() == len && s.bytes().all(|x| x.is_ascii_digit())
+}
+
+fn is_alphanum(s: &str, len: std::ops::RangeInclusive<usize>) -> bool {
+    len.contains(&s.len()) && s.bytes().all(|x| x.is_ascii_alphanumeric())
+}
+
+fn is_variant(s: &str) -> bool {
+    is_alphanum(s, 5..=8)
+        || (s.len() == 4 && s.as_bytes()[0].is_ascii_digit() && is_alphanum(s, 4..=4))
+}
+
+fn title_case(s: &str) -> String {
+    s[..1].to_ascii_uppercase() + &s[1..]
+}
+
+fn alias<'a>(table: &[(&str, &'a str)], value: &str) -> Option<&'a str> {
+    table
+        .iter()
+        .find(|(from, _)| *from == value)
+        .map(|(_, to)| *to)
+}
+
+impl LanguageTag {
+    pub fn parse(tag: &str) -> Result<LanguageTag, Error> {
+        if tag.is_empty() {
+            return Err(Error::Empty);
+        }
+
+        let lower = tag.to_ascii_lowercase();
+        if let Some((_, preferred)) = GRANDFATHERED.iter().find(|(x, _)| *x == lower) {
+            return match preferred {
+                Some(v) => LanguageTag::parse(v),
+                None => Err(Error::Grandfathered(tag.to_owned())),
+            };
+        }
+
+        let invalid = |subtag: &str| Error::InvalidSubtag {
+            tag: tag.to_owned(),
+            subtag: subtag.to_owned(),
+        };
+
+        let subtags: Vec<&str> = lower.split('-').collect();
+        if let Some(subtag) = subtags.iter().find(|x| !is_alphanum(x, 1..=8)) {
+            return Err(invalid(subtag));
+        }
+
+        let mut rest = &subtags[..];
+        let mut next = |matches: &dyn Fn(&str) -> bool| match rest.first() {
+            Some(x) if matches(x) => {
+                rest = &rest[1..];
+                Some(x.to_string())
+            }
+            _ => None,
+        };
+
+        let mut language = String::new();
+        let mut script = None;
+        let mut region = None;
+        let mut variants: Vec<String> = vec![];
+
+        // A tag may be private use alone, such as `x-whatever`.
+        if subtags[0] != "x" {
+            language = next(&|x| is_alpha(x, 2..=3) || is_alpha(x, 5..=8))
+                .ok_or_else(|| invalid(subtags[0]))?;
+
+            // The extlang replaces its prefix in canonical form. Only one may be given, as the
+            // second and third positions allowed by the ABNF are permanently reserved.
+            if language.len() <= 3 {
+                if let Some(extlang) = next(&|x| is_alpha(x, 3..=3)) {
+                    language = extlang;
+                }
+            }
+
+            script = next(&|x| is_alpha(x, 4..=4)).map(|x| title_case(&x));
+            region =
+                next(&|x| is_alpha(x, 2..=2) || is_digit(x, 3)).map(|x| x.to_ascii_uppercase());
+
+            while let Some(variant) = next(&is_variant) {
+                if variants.contains(&variant) {
+                    return Err(Error::DuplicateSubtag {
+                        tag: tag.to_owned(),
+                        subtag: variant,
+                    });
+                }
+                variants.push(variant);
+            }
+        }
+
+        let mut extensions: Vec<Extension> = vec![];
+        let mut private_use = vec![];
+        while let Some(singleton) = rest.first() {
+            if singleton.len() != 1 {
+                return Err(invalid(singleton));
+            }
+
+            let singleton = singleton.chars().next().unwrap_or('x');
+            let is_private = singleton == 'x';
+            let min_len = if is_private { 1 } else { 2 };
+            let len = rest[1..]
+                .iter()
+                .take_while(|x| is_private || (x.len() >= min_len))
+                .count();
+            if len == 0 {
+                return Err(Error::EmptyExtension {
+                    tag: tag.to_owned(),
+                    singleton,
+                });
+            }
+
+            let section: Vec<String> = rest[1..=len].iter().map(|x| x.to_string()).collect();
+            rest = &rest[len + 1..];
+
+            if is_private {
+                private_use = section;
+            } else if extensions.iter().any(|x| x.singleton == singleton) {
+                return Err(Error::DuplicateSubtag {
+                    tag: tag.to_owned(),
+                    subtag: singleton.to_string(),
+                });
+            } else {
+                extensions.push(Extension {
+                    singleton,
+                    subtags: section,
+                });
+            }
+        }
+
+        if let Some(v) = alias(LANGUAGE_ALIASES, &language) {
+            language = v.to_owned();
+        }
+        if let Some(v) = region.as_deref().and_then(|x| alias(REGION_ALIASES, x)) {
+            region = Some(v.to_owned());
+        }
+        extensions.sort_by_key(|x| x.singleton);
+
+        let mut canonical: Vec<&str> = vec![];
+        canonical.extend(Some(&*language).filter(|x| !x.is_empty()));
+        canonical.extend(script.as_deref());
+        canonical.extend(region.as_deref());
+        canonical.extend(variants.iter().map(|x| &**x));
+        let singletons: Vec<String> = extensions.iter().map(|x| x.singleton.to_string()).collect();
+        for (extension, singleton) in extensions.iter().zip(singletons.iter()) {
+            canonical.push(singleton);
+            canonical.extend(extension.subtags.iter().map(|x| &**x));
+        }
+        if !private_use.is_empty() {
+            canonical.push("x");
+            canonical.extend(private_use.iter().map(|x| &**x));
+        }
+
+        Ok(LanguageTag {
+            tag: canonical.join("-"),
+            language,
+            script,
+            region,
+            variants,
+            extensions,
+            private_use,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.tag
+    }
+
+    /// The primary language subtag, which is empty for a tag that is only private use.
+    pub fn language(&self) -> &str {
+        &self.language
+    }
+
+    pub fn script(&self) -> Option<&str> {
+        self.script.as_deref()
+    }
+
+    pub fn region(&self) -> Option<&str> {
+        self.region.as_deref()
+    }
+
+    pub fn variants(&self) -> &[String] {
+        &self.variants
+    }
+
+    pub fn extensions(&self) -> &[Extension] {
+        &self.extensions
+    }
+
+    pub fn private_use(&self) -> &[String] {
+        &self.private_use
+    }
+}
+
+impl FromStr for LanguageTag {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        LanguageTag::parse(s)
+    }
+}
+
+impl TryFrom<String> for LanguageTag {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        LanguageTag::parse(&s)
+    }
+}
+
+impl From<LanguageTag> for String {
+    fn from(tag: LanguageTag) -> String {
+        tag.tag
+    }
+}
+
+impl AsRef<str> for LanguageTag {
+    fn as_ref(&self) -> &str {
+        &self.tag
+    }
+}
+
+impl fmt::Display for LanguageTag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.tag)
+    }
+}
+
+#[test]
+fn test_canonical_form() {
+    let canonical = |tag: &str| LanguageTag::parse(tag).unwrap().to_string();
+
+    assert_eq!(canonical("SMA-latn-no"), "sma-Latn-NO");
+    assert_eq!(canonical("se-NO"), "se-NO");
+    assert_eq!(canonical("es-419"), "es-419");
+    assert_eq!(canonical("zh-yue-HK"), "yue-HK");
+    assert_eq!(canonical("iw-IL"), "he-IL");
+    assert_eq!(canonical("de-DD"), "de-DE");
+    assert_eq!(canonical("i-klingon"), "tlh");
+    assert_eq!(canonical("EN-gb-OED"), "en-GB-oxendict");
+    assert_eq!(canonical("sl-rozaj-biske-1994"), "sl-rozaj-biske-1994");
+    assert_eq!(
+        canonical("en-u-ca-gregory-a-xyz-x-Private"),
+        "en-a-xyz-u-ca-gregory-x-private"
+    );
+    assert_eq!(canonical("X-kbdi"), "x-kbdi");
+
+    let tag = LanguageTag::parse("sma-Latn-NO-x-test").unwrap();
+    assert_eq!(tag.language(), "sma");
+    assert_eq!(tag.script(), Some("Latn"));
+    assert_eq!(tag.region(), Some("NO"));
+    assert_eq!(tag.private_use(), ["test".to_string()]);
+}
+
+#[test]
+fn test_invalid_tags() {
+    let invalid = |tag: &str, subtag: &str| Error::InvalidSubtag {
+        tag: tag.to_owned(),
+        subtag: subtag.to_owned(),
+    };
+
+    assert_eq!(LanguageTag::parse(""), Err(Error::Empty));
+    assert_eq!(LanguageTag::parse("se_NO"), Err(invalid("se_NO", "se_no")));
+    assert_eq!(LanguageTag::parse("se--NO"), Err(invalid("se--NO", "")));
+    assert_eq!(LanguageTag::parse("s-NO"), Err(invalid("s-NO", "s")));
+    assert_eq!(
+        LanguageTag::parse("se-NO-ab"),
+        Err(invalid("se-NO-ab", "ab"))
+    );
+    assert_eq!(
+        LanguageTag::parse("zh-yue-cmn"),
+        Err(invalid("zh-yue-cmn", "cmn"))
+    );
+    assert_eq!(
+        LanguageTag::parse("verylongtag"),
+        Err(invalid("verylongtag", "verylongtag"))
+    );
+    assert_eq!(
+        LanguageTag::parse("sl-rozaj-rozaj"),
+        Err(Error::DuplicateSubtag {
+            tag: "sl-rozaj-rozaj".into(),
+            subtag: "rozaj".into()
+        })
+    );
+    assert_eq!(
+        LanguageTag::parse("en-u-ca-u-nu"),
+        Err(Error::DuplicateSubtag {
+            tag: "en-u-ca-u-nu".into(),
+            subtag: "u".into()
+        })
+    );
+    assert_eq!(
+        LanguageTag::parse("en-u"),
+        Err(Error::EmptyExtension {
+            tag: "en-u".into(),
+            singleton: 'u'
+        })
+    );
+    assert_eq!(
+        LanguageTag::parse("i-default"),
+        Err(Error::Grandfathered("i-default".into()))
+    );
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn prop_canonical_form_is_stable(
+        tag in "[a-zA-Z]{2,3}(-[a-zA-Z]{3})?(-[a-zA-Z]{4})?(-([a-zA-Z]{2}|[0-9]{3}))?(-[a-z0-9]{5,8})?(-[a-wyzA-WYZ](-[a-z0-9]{2,8}){1,2})*(-x-[a-z0-9]{1,8})?",
+    ) {
+        if let Ok(parsed) = LanguageTag::parse(&tag) {
+            proptest::prop_assert_eq!(LanguageTag::parse(parsed.as_str()), Ok(parsed.clone()));
+        }
+    }
+
+    #[test]
+    fn prop_parse_never_panics(tag in "\\PC{0,40}") {
+        let _ = LanguageTag::parse(&tag);
+    }
+}
@@ -1,42 +1,72 @@
 #[macro_use]
 extern crate log;
 
+pub mod backend;
+pub mod deploy;
+#[cfg(not(feature = "legacy"))]
+pub mod diff;
+#[cfg(not(feature = "legacy"))]
+pub mod doctor;
+pub mod dry_run;
+mod error;
+pub mod journal;
 pub mod keyboard;
-#[cfg(feature = "legacy")]
+#[cfg(all(windows, feature = "legacy"))]
 mod keyboard_legacy;
 #[cfg(not(feature = "legacy"))]
 mod keyboard_win8;
+pub mod klid;
+#[cfg(all(windows, not(feature = "legacy")))]
 mod language;
+pub mod language_tag;
+pub mod manifest;
+pub mod output;
+pub mod pe;
+#[cfg(windows)]
 pub mod platform;
+pub mod profiles;
+#[cfg(not(feature = "legacy"))]
+pub mod reg_file;
+pub mod regf;
+#[cfg(not(feature = "legacy"))]
+pub mod snapshot;
+pub mod text_service;
 mod types;
+#[cfg(windows)]
 mod winrust;
 
-#[cfg(not(feature = "legacy"))]
+#[cfg(all(windows, not(feature = "legacy")))]
 mod win8;
-#[cfg(not(feature = "legacy"))]
+#[cfg(all(windows, not(feature = "legacy")))]
 pub use self::win8::*;
 
-#[cfg(feature = "legacy")]
+#[cfg(all(windows, feature = "legacy"))]
 mod win7;
-#[cfg(feature = "legacy")]
+#[cfg(all(windows, feature = "legacy"))]
 pub use self::win7::*;
 
-pub fn lcid(tag: &str) -> u32 {
-    crate::platform::winnls::locale_name_to_lcid(&tag)
-        .map(|x| if x == 0x1000 { 0x2000 } else { x })
-        .unwrap_or(0x2000)
-}
+pub use self::error::Error;
+pub use self::language_tag::LanguageTag;
 
-#[derive(Debug, thiserror::Error)]
-pub enum Error {
-    #[error("IO error")]
-    Io(#[from] std::io::Error),
+/// The LCID of `tag`, or the transient LCID it uses, or will be given, if Windows has none for it.
+#[cfg(windows)]
+pub fn lcid(reg: &dyn backend::RegistryBackend, tag: &LanguageTag) -> Result<u16, Error> {
+    let lcid = crate::platform::winnls::locale_name_to_lcid(tag).ok();
+    let used = keyboard::transient_lcids(reg)?;
+    Ok(klid::resolve_lcid(lcid, &used, tag)?)
+}
 
-    #[error("Path error")]
-    Path(#[from] pathos::Error),
+/// The file transactions are journaled to while they run, in the same app data directory as the
+/// logs of [`setup_logger`].
+pub fn journal_path() -> Result<std::path::PathBuf, Error> {
+    let dir = if whoami::username() == "SYSTEM" {
+        pathos::system::app_data_dir("kbdi")
+    } else {
+        pathos::user::app_data_dir("kbdi")?
+    };
 
-    #[error("Set logger error")]
-    SetLoggerError(#[from] log::SetLoggerError),
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir.join("journal.jsonl"))
 }
 
 pub fn setup_logger() -> Result<(), Error> {
@@ -0,0 +1,181 @@
+//! Manifests listing several keyboards to install in one run of `kbdi apply`.
+//!
+//! A manifest is TOML or JSON, chosen by the file extension. Each keyboard takes the same
+//! fields as the flags of `keyboard_install`:
+//!
+//! ```toml
+//! [[keyboards]]
+//! tag = "se-NO"
+//! layout = "Northern Sami (Norway)"
+//! guid = "{42c3de12-2811-4d32-9ef6-c23d4d3c1d4d}"
+//! dll = "kbdse01.dll"
+//! lang = "Davvisámegiella"
+//! enable = true
+//! ```
+
+use crate::{Error, LanguageTag};
+use serde::Deserialize;
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    pub keyboards: Vec<KeyboardEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeyboardEntry {
+    /// Language tag in BCP 47 format
+    pub tag: LanguageTag,
+    /// Layout name
+    pub layout: String,
+    /// Product code GUID
+    pub guid: String,
+    /// Name of the keyboard DLL
+    pub dll: String,
+    /// Native language name, if required
+    #[serde(default)]
+    pub lang: Option<String>,
+    /// Enable the keyboard for the user after installing
+    #[serde(default)]
+    pub enable: bool,
+}
+
+impl Manifest {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Manifest, Error> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)?;
+
+        match path.extension().and_then(|x| x.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("json") => Manifest::from_json(&text),
+            Some(ext) if ext.eq_ignore_ascii_case("toml") => Manifest::from_toml(&text),
+            _ => Err(Error::InvalidManifest(format!(
+                "{}: expected a .toml or .json file",
+                path.display()
+            ))),
+        }
+    }
+
+    pub fn from_toml(text: &str) -> Result<Manifest, Error> {
+        let manifest: Manifest =
+            toml::from_str(text).map_err(|e| Error::InvalidManifest(e.to_string()))?;
+        manifest.validate()?;
+        Ok(manifest)
+    }
+
+    pub fn from_json(text: &str) -> Result<Manifest, Error> {
+        let manifest: Manifest =
+            serde_json::from_str(text).map_err(|e| Error::InvalidManifest(e.to_string()))?;
+        manifest.validate()?;
+        Ok(manifest)
+    }
+
+    /// A manifest of keyboards gathered from elsewhere, such as an imported `.reg` file.
+    pub fn from_keyboards(keyboards: Vec<KeyboardEntry>) -> Result<Manifest, Error> {
+        let manifest = Manifest { keyboards };
+        manifest.validate()?;
+        Ok(manifest)
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.keyboards.is_empty() {
+            return Err(Error::InvalidManifest("no keyboards listed".into()));
+        }
+
+        for (i, keyboard) in self.keyboards.iter().enumerate() {
+            let fields = [
+                ("layout", &keyboard.layout),
+                ("guid", &keyboard.guid),
+                ("dll", &keyboard.dll),
+            ];
+            if let Some((name, _)) = fields.iter().find(|(_, value)| value.trim().is_empty()) {
+                return Err(Error::InvalidManifest(format!(
+                    "keyboard {} has an empty {}",
+                    i + 1,
+                    name
+                )));
+            }
+
+            if self.keyboards[..i]
+                .iter()
+                .any(|x| x.guid.eq_ignore_ascii_case(&keyboard.guid))
+            {
+                return Err(Error::InvalidManifest(format!(
+                    "product code {} is listed more than once",
+                    keyboard.guid
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_manifest_formats() {
+    let toml = Manifest::from_toml(
+        r#"
+        [[keyboards]]
+        tag = "se-NO"
+        layout = "Northern Sami (Norway)"
+        guid = "{1}"
+        dll = "kbdse01.dll"
+        lang = "Davvisámegiella"
+        enable = true
+
+        [[keyboards]]
+        tag = "sma-NO"
+        layout = "Southern Sami (Norway)"
+        guid = "{2}"
+        dll = "kbdsma01.dll"
+        "#,
+    )
+    .unwrap();
+
+    let json = Manifest::from_json(
+        r#"{
+            "keyboards": [
+                {
+                    "tag": "se-NO",
+                    "layout": "Northern Sami (Norway)",
+                    "guid": "{1}",
+                    "dll": "kbdse01.dll",
+                    "lang": "Davvisámegiella",
+                    "enable": true
+                },
+                {
+                    "tag": "sma-NO",
+                    "layout": "Southern Sami (Norway)",
+                    "guid": "{2}",
+                    "dll": "kbdsma01.dll"
+                }
+            ]
+        }"#,
+    )
+    .unwrap();
+
+    assert_eq!(toml, json);
+    assert_eq!(toml.keyboards[1].tag.as_str(), "sma-NO");
+    assert_eq!(toml.keyboards[0].lang.as_deref(), Some("Davvisámegiella"));
+    assert!(toml.keyboards[0].enable);
+    assert_eq!(toml.keyboards[1].lang, None);
+    assert!(!toml.keyboards[1].enable);
+}
+
+#[test]
+fn test_manifest_validation() {
+    let keyboard = |guid: &str, dll: &str| {
+        format!(
+            "[[keyboards]]\ntag = \"se-NO\"\nlayout = \"x\"\nguid = \"{}\"\ndll = \"{}\"\n",
+            guid, dll
+        )
+    };
+
+    assert!(Manifest::from_toml("keyboards = []").is_err());
+    assert!(Manifest::from_toml(&keyboard("{1}", "")).is_err());
+    assert!(Manifest::from_toml(&keyboard("{1}", "a.dll").replace("se-NO", "se_NO")).is_err());
+    assert!(Manifest::from_toml(&(keyboard("{a}", "a.dll") + &keyboard("{A}", "b.dll"))).is_err());
+    assert!(Manifest::from_toml(&(keyboard("{a}", "a.dll") + "colour = \"red\"\n")).is_err());
+    assert!(Manifest::from_toml(&(keyboard("{a}", "a.dll") + &keyboard("{b}", "b.dll"))).is_ok());
+}
@@ -0,0 +1,180 @@
+//! Records printed by the query commands, and the formats they can be printed in.
+//!
+//! The JSON form of each record is documented in the README; fields may be added, but existing
+//! fields keep their names and types.
+
+use crate::keyboard::{KeyboardRegKey, Ownership};
+use crate::text_service::TextServiceProfile;
+use serde::Serialize;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown format {:?}, expected text or json", s)),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Format::Text => f.write_str("text"),
+            Format::Json => f.write_str("json"),
+        }
+    }
+}
+
+/// A keyboard installed under `Keyboard Layouts`, as listed by `keyboard_list`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct KeyboardRecord {
+    pub klid: String,
+    pub layout_name: Option<String>,
+    pub language_name: Option<String>,
+    pub layout_file: Option<String>,
+    pub layout_id: Option<String>,
+    pub product_code: Option<String>,
+    pub owner: Option<Ownership>,
+}
+
+impl From<&KeyboardRegKey> for KeyboardRecord {
+    fn from(key: &KeyboardRegKey) -> Self {
+        KeyboardRecord {
+            klid: key.regkey_id().to_owned(),
+            layout_name: key.layout_name(),
+            language_name: key.language_name(),
+            layout_file: key.layout_file(),
+            layout_id: key.id(),
+            product_code: key.product_code(),
+            owner: key.owner(),
+        }
+    }
+}
+
+/// A text service language profile registered under `CTF\TIP`, as listed by
+/// `text_service_list`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TextServiceRecord {
+    pub input_method: String,
+    pub clsid: String,
+    pub profile: String,
+    pub lang_id: u16,
+    pub description: Option<String>,
+    pub icon_file: Option<String>,
+    pub icon_index: u32,
+}
+
+impl From<&TextServiceProfile> for TextServiceRecord {
+    fn from(profile: &TextServiceProfile) -> Self {
+        TextServiceRecord {
+            input_method: profile.input_list_item().to_string(),
+            clsid: profile.clsid.to_string(),
+            profile: profile.profile.to_string(),
+            lang_id: profile.lang_id,
+            description: profile.description.clone(),
+            icon_file: profile.icon_file.clone(),
+            icon_index: profile.icon_index,
+        }
+    }
+}
+
+/// A language enabled for the user and its input methods, as listed by `keyboard_enabled`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct EnabledLanguageRecord {
+    pub tag: String,
+    pub input_methods: Vec<String>,
+}
+
+impl From<(String, Vec<String>)> for EnabledLanguageRecord {
+    fn from((tag, input_methods): (String, Vec<String>)) -> Self {
+        EnabledLanguageRecord { tag, input_methods }
+    }
+}
+
+/// What Windows knows about a language tag, as shown by `language_query`.
+///
+/// The names are only known on Windows 8 and later, and are `null` when the tag is unsupported.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LanguageRecord {
+    pub tag: String,
+    pub supported: bool,
+    pub name: Option<String>,
+    pub english_name: Option<String>,
+    pub native_name: Option<String>,
+    pub script_name: Option<String>,
+    pub lcid: Option<u32>,
+}
+
+pub fn to_json<T: Serialize + ?Sized>(value: &T) -> Result<String, crate::Error> {
+    Ok(serde_json::to_string_pretty(value)?)
+}
+
+#[test]
+fn test_keyboard_record_json() {
+    use crate::backend::MemoryRegistry;
+    use crate::backend::RegistryBackend;
+
+    let reg = MemoryRegistry::new();
+    reg.create_key(&crate::keyboard::keyboard_layouts_key())
+        .unwrap();
+    let owner = Ownership {
+        installer: "kbdi".into(),
+        version: "0.5.1".into(),
+        installed: "2026-10-18T12:00:00Z".into(),
+        source: Some("keyboards.toml".into()),
+    };
+    KeyboardRegKey::create(
+        &reg,
+        &"se-NO".parse().unwrap(),
+        0x043b,
+        "Davvisámegiella",
+        "{42c3de12-2811-4d32-9ef6-c23d4d3c1d4d}",
+        "kbdse01.dll",
+        "Northern Sami (Norway)",
+        &owner,
+    )
+    .unwrap();
+
+    let records: Vec<KeyboardRecord> = crate::keyboard::installed(&reg)
+        .unwrap()
+        .iter()
+        .map(KeyboardRecord::from)
+        .collect();
+
+    assert_eq!(
+        serde_json::to_value(&records).unwrap(),
+        serde_json::json!([{
+            "klid": "a000043b",
+            "layout_name": "Northern Sami (Norway)",
+            "language_name": "Davvisámegiella",
+            "layout_file": "kbdse01.dll",
+            "layout_id": "0100",
+            "product_code": "{42c3de12-2811-4d32-9ef6-c23d4d3c1d4d}",
+            "owner": {
+                "installer": "kbdi",
+                "version": "0.5.1",
+                "installed": "2026-10-18T12:00:00Z",
+                "source": "keyboards.toml",
+            },
+        }])
+    );
+}
+
+#[test]
+fn test_format_from_str() {
+    assert_eq!("json".parse(), Ok(Format::Json));
+    assert_eq!("text".parse(), Ok(Format::Text));
+    assert!("yaml".parse::<Format>().is_err());
+}
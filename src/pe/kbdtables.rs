@@ -0,0 +1,758 @@
+//! The key map of a layout DLL, read from the `KBDTABLES` that `KbdLayerDescriptor` returns.
+//!
+//! The descriptor is never run. Its instructions are decoded to find the tables, which are then
+//! walked as `kbd.h` lays them out. Pointers in the tables are virtual addresses at the DLL's
+//! preferred base, where relocating them changes nothing, so they are made relative to the image
+//! base. 32-bit DLLs built for WOW64 use 64-bit pointers, and are recognised by them.
+
+use super::{Error, LayoutDll, Machine, LAYOUT_DESCRIPTOR};
+use serde::Serialize;
+use std::fmt;
+
+const WCH_NONE: u16 = 0xf000;
+const WCH_DEAD: u16 = 0xf001;
+const WCH_LGTR: u16 = 0xf002;
+const SHFT_INVALID: u8 = 0x0f;
+const CAPLOK: u8 = 0x01;
+const KLLF_ALTGR: u32 = 0x0001;
+const DKF_DEAD: u16 = 0x0001;
+const KBDSHIFT: u8 = 0x01;
+const KBDCTRL: u8 = 0x02;
+const KBDALT: u8 = 0x04;
+const VK_NONE: u8 = 0xff;
+/// Tables without a terminator within this many entries are treated as corrupt.
+const MAX_ENTRIES: u32 = 4096;
+
+/// What a key produces in one shift state.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", content = "text", rename_all = "snake_case")]
+pub enum Output {
+    Char(String),
+    /// A dead key, as its accent
+    Dead(String),
+    Ligature(String),
+}
+
+impl Output {
+    pub fn text(&self) -> &str {
+        match self {
+            Output::Char(x) | Output::Dead(x) | Output::Ligature(x) => x,
+        }
+    }
+}
+
+/// `text` with control and space characters written as code points, so they can be seen.
+pub fn printable(text: &str) -> String {
+    text.chars()
+        .map(|c| match c.is_control() || c.is_whitespace() {
+            true => format!("U+{:04X}", c as u32),
+            false => c.to_string(),
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ShiftState {
+    /// The modifiers, eg. `Shift+AltGr`, or `Base` for none
+    pub name: String,
+    /// The lowest modifier bits giving this state, or `null` if none do
+    pub bits: Option<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Key {
+    /// Scancode, with `0xe0` in the high byte for extended keys
+    pub scancode: u16,
+    pub vk: u8,
+    pub vk_name: String,
+    /// Whether Caps Lock acts as Shift
+    pub caps_lock: bool,
+    /// The output in each of the key map's shift states
+    pub outputs: Vec<Option<Output>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DeadKey {
+    pub accent: String,
+    pub base: String,
+    pub composed: String,
+    /// Whether `composed` is itself a dead key
+    pub chained: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct KeyMap {
+    pub machine: Machine,
+    /// Whether the right Alt key is AltGr
+    pub alt_gr: bool,
+    pub shift_states: Vec<ShiftState>,
+    /// Keys producing characters, in scancode order
+    pub keys: Vec<Key>,
+    pub dead_keys: Vec<DeadKey>,
+}
+
+/// The name of a virtual key, without its `VK_` prefix.
+pub fn vk_name(vk: u8) -> String {
+    let name = match vk {
+        0x03 => "CANCEL",
+        0x08 => "BACK",
+        0x09 => "TAB",
+        0x0d => "RETURN",
+        0x1b => "ESCAPE",
+        0x20 => "SPACE",
+        b'0'..=b'9' | b'A'..=b'Z' => return (vk as char).to_string(),
+        0x60..=0x69 => return format!("NUMPAD{}", vk - 0x60),
+        0x6a => "MULTIPLY",
+        0x6b => "ADD",
+        0x6c => "SEPARATOR",
+        0x6d => "SUBTRACT",
+        0x6e => "DECIMAL",
+        0x6f => "DIVIDE",
+        0xba => "OEM_1",
+        0xbb => "OEM_PLUS",
+        0xbc => "OEM_COMMA",
+        0xbd => "OEM_MINUS",
+        0xbe => "OEM_PERIOD",
+        0xbf => "OEM_2",
+        0xc0 => "OEM_3",
+        0xc1 => "ABNT_C1",
+        0xc2 => "ABNT_C2",
+        0xdb => "OEM_4",
+        0xdc => "OEM_5",
+        0xdd => "OEM_6",
+        0xde => "OEM_7",
+        0xdf => "OEM_8",
+        0xe2 => "OEM_102",
+        _ => return format!("0x{:02X}", vk),
+    };
+    name.to_owned()
+}
+
+fn scancode_name(scancode: u16) -> String {
+    match scancode >> 8 {
+        0 => format!("{:02x}", scancode),
+        prefix => format!("{:02x}{:02x}", prefix, scancode & 0xff),
+    }
+}
+
+fn shift_state_name(bits: u8, alt_gr: bool) -> String {
+    let mut parts = vec![];
+    if bits & KBDSHIFT != 0 {
+        parts.push("Shift");
+    }
+    if alt_gr && bits & (KBDCTRL | KBDALT) == KBDCTRL | KBDALT {
+        parts.push("AltGr");
+    } else {
+        if bits & KBDCTRL != 0 {
+            parts.push("Ctrl");
+        }
+        if bits & KBDALT != 0 {
+            parts.push("Alt");
+        }
+    }
+    for (bit, name) in [
+        (0x08, "Kana"),
+        (0x10, "Roya"),
+        (0x20, "Loya"),
+        (0x80, "GrpSelTap"),
+    ]
+    .iter()
+    {
+        if bits & bit != 0 {
+            parts.push(name);
+        }
+    }
+
+    match parts.is_empty() {
+        true => "Base".into(),
+        false => parts.join("+"),
+    }
+}
+
+fn invalid(rva: u32, message: &str) -> Error {
+    Error::InvalidTables {
+        rva,
+        message: message.to_owned(),
+    }
+}
+
+/// The RVA of entry `index` of a table of `size`-byte entries at `table`, failing if it is past
+/// the end of the address space.
+fn table_entry(table: u32, index: u32, size: u32) -> Result<u32, Error> {
+    super::entry_rva(table, index, size)
+        .ok_or_else(|| invalid(table, "table runs past the end of the address space"))
+}
+
+/// Finds the tables `KbdLayerDescriptor` returns by decoding its instructions. Compilers emit it
+/// as a single load of the tables' address, then a return.
+fn tables_rva(dll: &LayoutDll) -> Result<u32, Error> {
+    let function = dll
+        .export(LAYOUT_DESCRIPTOR)
+        .ok_or(Error::MissingLayoutDescriptor)?;
+
+    match dll.machine() {
+        // mov eax, imm32; ret
+        Machine::X86 => match dll.bytes_at(function, 6) {
+            Some([0xb8, a, b, c, d, 0xc3]) => (u32::from_le_bytes([*a, *b, *c, *d]) as u64)
+                .checked_sub(dll.image_base())
+                .map(|x| x as u32)
+                .ok_or(Error::UnrecognizedDescriptor),
+            _ => Err(Error::UnrecognizedDescriptor),
+        },
+        // lea rax, [rip + disp32]; ret
+        Machine::X64 => match dll.bytes_at(function, 8) {
+            Some([0x48, 0x8d, 0x05, a, b, c, d, 0xc3]) => {
+                let disp = i32::from_le_bytes([*a, *b, *c, *d]);
+                Ok(function.wrapping_add(7).wrapping_add(disp as u32))
+            }
+            _ => Err(Error::UnrecognizedDescriptor),
+        },
+        // adrp x0, page; add x0, x0, #offset; ret
+        Machine::Arm64 => {
+            let ins = |i: u32| dll.u32_at(super::entry_rva(function, i, 4)?);
+            match (ins(0), ins(1), ins(2)) {
+                (Some(adrp), Some(add), Some(0xd65f_03c0))
+                    if adrp & 0x9f00_001f == 0x9000_0000 && add & 0xff80_03ff == 0x9100_0000 =>
+                {
+                    let imm = ((adrp >> 5) & 0x7_ffff) << 2 | (adrp >> 29) & 3;
+                    // Sign-extend the 21-bit page count
+                    let pages = ((imm << 11) as i32) >> 11;
+                    let page = (function & !0xfff).wrapping_add((pages << 12) as u32);
+                    let offset = ((add >> 10) & 0xfff) << (12 * ((add >> 22) & 1));
+                    Ok(page.wrapping_add(offset))
+                }
+                _ => Err(Error::UnrecognizedDescriptor),
+            }
+        }
+    }
+}
+
+struct Row {
+    vk: u8,
+    attributes: u8,
+    chars: Vec<u16>,
+    /// The accents of the dead keys in `chars`
+    dead: Option<Vec<u16>>,
+}
+
+struct Ligature {
+    vk: u8,
+    column: u16,
+    text: String,
+}
+
+struct Tables<'a> {
+    dll: &'a LayoutDll,
+    pointer_size: u32,
+}
+
+impl<'a> Tables<'a> {
+    fn u8(&self, rva: u32) -> Result<u8, Error> {
+        self.dll
+            .bytes_at(rva, 1)
+            .map(|x| x[0])
+            .ok_or_else(|| invalid(rva, "out of bounds"))
+    }
+
+    fn u16(&self, rva: u32) -> Result<u16, Error> {
+        self.dll
+            .u16_at(rva)
+            .ok_or_else(|| invalid(rva, "out of bounds"))
+    }
+
+    fn u32(&self, rva: u32) -> Result<u32, Error> {
+        self.dll
+            .u32_at(rva)
+            .ok_or_else(|| invalid(rva, "out of bounds"))
+    }
+
+    fn pointer(&self, rva: u32) -> Result<Option<u32>, Error> {
+        let va = match self.pointer_size {
+            4 => self.u32(rva)? as u64,
+            _ => self
+                .dll
+                .u64_at(rva)
+                .ok_or_else(|| invalid(rva, "out of bounds"))?,
+        };
+        if va == 0 {
+            return Ok(None);
+        }
+
+        va.checked_sub(self.dll.image_base())
+            .filter(|x| *x <= u32::MAX as u64)
+            .map(|x| Some(x as u32))
+            .ok_or_else(|| invalid(rva, "pointer outside the image"))
+    }
+
+    fn required_pointer(&self, rva: u32, what: &str) -> Result<u32, Error> {
+        self.pointer(rva)?
+            .ok_or_else(|| invalid(rva, &format!("no {}", what)))
+    }
+
+    fn shift_states(&self, modifiers: u32, alt_gr: bool) -> Result<Vec<ShiftState>, Error> {
+        let max_bits_at = table_entry(modifiers, 1, self.pointer_size)?;
+        let max_bits = self.u16(max_bits_at)?.min(0xff) as u8;
+        let mut columns: Vec<Option<u8>> = vec![];
+        for bits in 0..=max_bits {
+            let column = self.u8(table_entry(max_bits_at, 2 + bits as u32, 1)?)?;
+            if column == SHFT_INVALID {
+                continue;
+            }
+            let column = column as usize;
+            if columns.len() <= column {
+                columns.resize(column + 1, None);
+            }
+            columns[column].get_or_insert(bits);
+        }
+
+        Ok(columns
+            .into_iter()
+            .map(|bits| ShiftState {
+                name: bits.map_or_else(|| "-".into(), |x| shift_state_name(x, alt_gr)),
+                bits,
+            })
+            .collect())
+    }
+
+    fn rows(&self, table: u32) -> Result<Vec<Row>, Error> {
+        let mut rows: Vec<Row> = vec![];
+        for i in 0..MAX_ENTRIES {
+            let entry = table_entry(table, i, 2 * self.pointer_size)?;
+            let first = match self.pointer(entry)? {
+                Some(v) => v,
+                None => return Ok(rows),
+            };
+            let count = self.u8(table_entry(entry, self.pointer_size, 1)?)? as u32;
+            let size = self.u8(table_entry(entry, self.pointer_size + 1, 1)?)? as u32;
+            if size < 2 + count * 2 {
+                return Err(invalid(entry, "rows are smaller than their characters"));
+            }
+
+            for j in 0..=MAX_ENTRIES {
+                let row = table_entry(first, j, size)?;
+                if j == MAX_ENTRIES {
+                    return Err(invalid(row, "character table has no end"));
+                }
+                let vk = self.u8(row)?;
+                if vk == 0 {
+                    break;
+                }
+                let chars = (0..count)
+                    .map(|k| self.u16(table_entry(row, 1 + k, 2)?))
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                // The accents of a row's dead keys follow it in a row of their own.
+                if vk == VK_NONE {
+                    if let Some(last) = rows.last_mut() {
+                        last.dead = Some(chars);
+                    }
+                    continue;
+                }
+                rows.push(Row {
+                    vk,
+                    attributes: self.u8(table_entry(row, 1, 1)?)?,
+                    chars,
+                    dead: None,
+                });
+            }
+        }
+
+        Err(invalid(table, "table of character tables has no end"))
+    }
+
+    fn ligatures(&self, table: u32, max: u8, size: u8) -> Result<Vec<Ligature>, Error> {
+        let mut ligatures = vec![];
+        for i in 0..MAX_ENTRIES {
+            let entry = table_entry(table, i, size as u32)?;
+            let vk = self.u8(entry)?;
+            if vk == 0 {
+                return Ok(ligatures);
+            }
+            let units = (0..max as u32)
+                .map(|k| self.u16(table_entry(entry, 2 + k, 2)?))
+                .collect::<Result<Vec<_>, _>>()?;
+            let units: Vec<u16> = units
+                .into_iter()
+                .take_while(|x| *x != WCH_NONE && *x != 0)
+                .collect();
+            ligatures.push(Ligature {
+                vk,
+                column: self.u16(table_entry(entry, 1, 2)?)?,
+                text: String::from_utf16_lossy(&units),
+            });
+        }
+
+        Err(invalid(table, "ligature table has no end"))
+    }
+
+    fn dead_keys(&self, table: u32) -> Result<Vec<DeadKey>, Error> {
+        let text = |x: u16| String::from_utf16_lossy(&[x]);
+        let mut dead_keys = vec![];
+        for i in 0..MAX_ENTRIES {
+            let entry = table_entry(table, i, 8)?;
+            let both = self.u32(entry)?;
+            if both == 0 {
+                return Ok(dead_keys);
+            }
+            dead_keys.push(DeadKey {
+                accent: text((both >> 16) as u16),
+                base: text(both as u16),
+                composed: text(self.u16(table_entry(entry, 2, 2)?)?),
+                chained: self.u16(table_entry(entry, 3, 2)?)? & DKF_DEAD != 0,
+            });
+        }
+
+        Err(invalid(table, "dead key table has no end"))
+    }
+
+    /// Scancodes with the virtual key each gives, first the plain ones, then those prefixed with
+    /// `0xe0`.
+    fn scancodes(&self, plain: u32, max: u8, e0: Option<u32>) -> Result<Vec<(u16, u8)>, Error> {
+        let mut scancodes = vec![];
+        for scancode in 0..max as u32 {
+            let vk = self.u16(table_entry(plain, scancode, 2)?)? as u8;
+            if vk != 0 && vk != VK_NONE {
+                scancodes.push((scancode as u16, vk));
+            }
+        }
+
+        if let Some(table) = e0 {
+            for i in 0..=MAX_ENTRIES {
+                let entry = table_entry(table, i, 4)?;
+                if i == MAX_ENTRIES {
+                    return Err(invalid(table, "extended scancode table has no end"));
+                }
+                let scancode = self.u8(entry)?;
+                if scancode == 0 {
+                    break;
+                }
+                scancodes.push((
+                    0xe000 | scancode as u16,
+                    self.u16(table_entry(entry, 1, 2)?)? as u8,
+                ));
+            }
+        }
+
+        Ok(scancodes)
+    }
+}
+
+impl KeyMap {
+    pub fn read(dll: &LayoutDll) -> Result<KeyMap, Error> {
+        let tables = tables_rva(dll)?;
+        // With 64-bit pointers, the second half of the first is zero where a 32-bit table has
+        // its character tables.
+        let second = super::entry_rva(tables, 1, 4).and_then(|x| dll.u32_at(x));
+        let pointer_size = match dll.machine() {
+            Machine::X86 if second == Some(0) => 8,
+            Machine::X86 => 4,
+            _ => 8,
+        };
+        let t = Tables { dll, pointer_size };
+        let field = |i: u32| table_entry(tables, i, pointer_size);
+
+        let modifiers = t.required_pointer(field(0)?, "modifiers")?;
+        let characters = t.required_pointer(field(1)?, "character tables")?;
+        let dead_keys = t.pointer(field(2)?)?;
+        let plain = t.required_pointer(field(6)?, "scancode table")?;
+        let max_scancode = t.u8(field(7)?)?;
+        let e0 = t.pointer(field(8)?)?;
+        let flags = field(10)?;
+        let locale_flags = t.u32(flags)?;
+        let ligature_max = t.u8(table_entry(flags, 4, 1)?)?;
+        let ligature_size = t.u8(table_entry(flags, 5, 1)?)?;
+        // The ligature table's pointer is aligned after the two bytes above.
+        let ligatures = table_entry(flags, 6, 1)?.div_ceil(pointer_size);
+        let ligatures = t.pointer(table_entry(0, ligatures, pointer_size)?)?;
+        let alt_gr = locale_flags & KLLF_ALTGR != 0;
+
+        let shift_states = t.shift_states(modifiers, alt_gr)?;
+        let rows = t.rows(characters)?;
+        let ligatures = match ligatures {
+            Some(rva) => t.ligatures(rva, ligature_max, ligature_size)?,
+            None => vec![],
+        };
+
+        let output = |row: &Row, column: usize, wch: u16| match wch {
+            WCH_NONE => None,
+            WCH_DEAD => row
+                .dead
+                .as_ref()
+                .and_then(|x| x.get(column))
+                .map(|x| Output::Dead(String::from_utf16_lossy(&[*x]))),
+            WCH_LGTR => ligatures
+                .iter()
+                .find(|x| x.vk == row.vk && x.column as usize == column)
+                .map(|x| Output::Ligature(x.text.to_owned())),
+            x => Some(Output::Char(String::from_utf16_lossy(&[x]))),
+        };
+
+        let mut keys = vec![];
+        for (scancode, vk) in t.scancodes(plain, max_scancode, e0)? {
+            let row = match rows.iter().find(|x| x.vk == vk) {
+                Some(v) => v,
+                None => continue,
+            };
+            let mut outputs: Vec<Option<Output>> = row
+                .chars
+                .iter()
+                .enumerate()
+                .map(|(column, wch)| output(row, column, *wch))
+                .collect();
+            if outputs.iter().all(Option::is_none) {
+                continue;
+            }
+            outputs.resize(shift_states.len().max(outputs.len()), None);
+
+            keys.push(Key {
+                scancode,
+                vk,
+                vk_name: vk_name(vk),
+                caps_lock: row.attributes & CAPLOK != 0,
+                outputs,
+            });
+        }
+
+        Ok(KeyMap {
+            machine: dll.machine(),
+            alt_gr,
+            shift_states,
+            keys,
+            dead_keys: match dead_keys {
+                Some(rva) => t.dead_keys(rva)?,
+                None => vec![],
+            },
+        })
+    }
+
+    /// The key map as an SVG diagram of an ISO keyboard.
+    pub fn to_svg(&self, title: &str) -> String {
+        super::svg::render(self, title)
+    }
+}
+
+impl fmt::Display for KeyMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut table = vec![];
+        let mut header = vec!["SC".to_string(), "VK".to_string()];
+        header.extend(self.shift_states.iter().map(|x| x.name.to_owned()));
+        table.push(header);
+        for key in self.keys.iter() {
+            let mut row = vec![scancode_name(key.scancode), key.vk_name.to_owned()];
+            row.extend(key.outputs.iter().map(|x| match x {
+                Some(Output::Dead(x)) => format!("{}*", printable(x)),
+                Some(x) => printable(x.text()),
+                None => String::new(),
+            }));
+            table.push(row);
+        }
+
+        let columns = table.iter().map(Vec::len).max().unwrap_or(0);
+        let widths: Vec<usize> = (0..columns)
+            .map(|i| {
+                table
+                    .iter()
+                    .filter_map(|row| row.get(i))
+                    .map(|x| x.chars().count())
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect();
+        for row in table.iter() {
+            let line: Vec<String> = row
+                .iter()
+                .zip(widths.iter())
+                .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+                .collect();
+            writeln!(f, "{}", line.join("  ").trim_end())?;
+        }
+
+        if self.dead_keys.is_empty() {
+            return Ok(());
+        }
+        writeln!(f, "\n* dead key\n\nDead keys:")?;
+        for dead_key in self.dead_keys.iter() {
+            writeln!(
+                f,
+                "  {} + {} = {}{}",
+                printable(&dead_key.accent),
+                printable(&dead_key.base),
+                printable(&dead_key.composed),
+                if dead_key.chained { "*" } else { "" }
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Builds a layout DLL whose tables have a letter with a ligature, a dead key and a space bar.
+#[cfg(test)]
+pub(crate) fn fixture(machine: Machine, pointer_size: u32) -> LayoutDll {
+    const FUNCTION: u32 = 0x1028;
+    let image_base: u64 = match machine.is_64_bit() {
+        true => 0x1_8000_0000,
+        false => 0x1000_0000,
+    };
+    let (_, base) = super::fixture(machine, &[LAYOUT_DESCRIPTOR], &[0; 12], &[], &[]);
+
+    let mut data: Vec<u8> = vec![];
+    let rva = |data: &Vec<u8>| base + data.len() as u32;
+    let align = |data: &mut Vec<u8>| data.resize(data.len().div_ceil(8) * 8, 0);
+    let pointer = |data: &mut Vec<u8>, rva: Option<u32>| {
+        let va = rva.map_or(0, |x| image_base + x as u64);
+        data.extend_from_slice(&va.to_le_bytes()[..pointer_size as usize]);
+    };
+    let u16s = |data: &mut Vec<u8>, units: &[u16]| {
+        for x in units.iter() {
+            data.extend_from_slice(&x.to_le_bytes());
+        }
+    };
+
+    let vk_to_bit = rva(&data);
+    data.extend_from_slice(&[0x10, KBDSHIFT, 0x11, KBDCTRL, 0x12, KBDALT, 0, 0]);
+
+    align(&mut data);
+    let modifiers = rva(&data);
+    pointer(&mut data, Some(vk_to_bit));
+    u16s(&mut data, &[7]);
+    data.extend_from_slice(&[0, 1, 15, 15, 15, 15, 2, 3]);
+
+    align(&mut data);
+    let rows = rva(&data);
+    for (vk, attributes, chars) in [
+        (b'A', CAPLOK, ['a' as u16, 'A' as u16, WCH_LGTR, WCH_NONE]),
+        (0xba, 0, [WCH_DEAD, '¨' as u16, WCH_NONE, WCH_NONE]),
+        (VK_NONE, 0, ['´' as u16, WCH_NONE, WCH_NONE, WCH_NONE]),
+        (b' ', 0, [' ' as u16, ' ' as u16, WCH_NONE, WCH_NONE]),
+        (0, 0, [0; 4]),
+    ]
+    .iter()
+    {
+        data.extend_from_slice(&[*vk, *attributes]);
+        u16s(&mut data, chars);
+    }
+
+    align(&mut data);
+    let characters = rva(&data);
+    pointer(&mut data, Some(rows));
+    data.extend_from_slice(&[4, 10]);
+    data.resize(data.len() + pointer_size as usize * 3 - 2, 0);
+
+    align(&mut data);
+    let dead_keys = rva(&data);
+    data.extend_from_slice(&('a' as u32 | ('´' as u32) << 16).to_le_bytes());
+    u16s(&mut data, &['á' as u16, 0, 0, 0, 0, 0]);
+
+    align(&mut data);
+    let plain = rva(&data);
+    for scancode in 0..0x3a {
+        let vk = match scancode {
+            0x10 => b'Q',
+            0x1e => b'A',
+            0x27 => 0xba,
+            0x39 => b' ',
+            _ => VK_NONE,
+        };
+        u16s(&mut data, &[vk as u16]);
+    }
+
+    align(&mut data);
+    let e0 = rva(&data);
+    data.extend_from_slice(&[0x1c, 0, 0x0d, 0x01, 0, 0, 0, 0]);
+
+    let ligatures = rva(&data);
+    data.extend_from_slice(&[b'A', 0]);
+    u16s(&mut data, &[2, 'Ŋ' as u16, 'g' as u16, 0, 0, 0]);
+
+    align(&mut data);
+    let tables = rva(&data);
+    for x in [
+        Some(modifiers),
+        Some(characters),
+        Some(dead_keys),
+        None,
+        None,
+        None,
+    ]
+    .iter()
+    {
+        pointer(&mut data, *x);
+    }
+    pointer(&mut data, Some(plain));
+    data.push(0x3a);
+    data.resize(data.len() + pointer_size as usize - 1, 0);
+    pointer(&mut data, Some(e0));
+    pointer(&mut data, None);
+    data.extend_from_slice(&KLLF_ALTGR.to_le_bytes());
+    data.extend_from_slice(&[2, 8]);
+    align(&mut data);
+    pointer(&mut data, Some(ligatures));
+    data.extend_from_slice(&[0; 8]);
+
+    let mut code = match machine {
+        Machine::X86 => {
+            let mut code = vec![0xb8];
+            code.extend_from_slice(&((image_base as u32) + tables).to_le_bytes());
+            code.push(0xc3);
+            code
+        }
+        Machine::X64 => {
+            let mut code = vec![0x48, 0x8d, 0x05];
+            code.extend_from_slice(&(tables - (FUNCTION + 7)).to_le_bytes());
+            code.push(0xc3);
+            code
+        }
+        Machine::Arm64 => {
+            let pages = (tables >> 12) - (FUNCTION >> 12);
+            let adrp = 0x9000_0000 | (pages & 3) << 29 | (pages >> 2) << 5;
+            let add = 0x9100_0000 | (tables & 0xfff) << 10;
+            [adrp, add, 0xd65f_03c0]
+                .iter()
+                .flat_map(|x| x.to_le_bytes().to_vec())
+                .collect()
+        }
+    };
+    code.resize(12, 0);
+
+    let (bytes, data_rva) = super::fixture(machine, &[LAYOUT_DESCRIPTOR], &code, &data, &[]);
+    assert_eq!(data_rva, base);
+    LayoutDll::from_bytes(bytes).unwrap()
+}
+
+#[test]
+fn test_read_key_map() {
+    let expected = concat!(
+        "SC  VK     Base    Shift   AltGr  Shift+AltGr\n",
+        "1e  A      a       A       Ŋg\n",
+        "27  OEM_1  ´*      ¨\n",
+        "39  SPACE  U+0020  U+0020\n",
+        "\n",
+        "* dead key\n",
+        "\n",
+        "Dead keys:\n",
+        "  ´ + a = á\n",
+    );
+
+    for (machine, pointer_size) in [
+        (Machine::X86, 4),
+        (Machine::X86, 8),
+        (Machine::X64, 8),
+        (Machine::Arm64, 8),
+    ]
+    .iter()
+    {
+        let map = KeyMap::read(&fixture(*machine, *pointer_size)).unwrap();
+        assert_eq!(map.machine, *machine);
+        assert!(map.alt_gr);
+        assert_eq!(map.to_string(), expected);
+    }
+
+    let map = KeyMap::read(&fixture(Machine::X64, 8)).unwrap();
+    assert_eq!(map.keys[0].outputs[2], Some(Output::Ligature("Ŋg".into())));
+    assert!(map.keys[0].caps_lock);
+    assert_eq!(
+        serde_json::to_value(&map.keys[1].outputs[0]).unwrap(),
+        serde_json::json!({"type": "dead", "text": "´"})
+    );
+}
@@ -0,0 +1,630 @@
+//! Keyboard layout DLLs (Portable Executable files), read without Windows.
+//!
+//! [`LayoutDll`] reads a whole DLL into memory and checks that it exports `KbdLayerDescriptor`,
+//! the function Windows calls to get the layout's tables. Its machine type and the string
+//! resources that the `Layout Display Name` and `Custom Language Display Name` registry values
+//! point at can then be read. [`kbdtables`] walks the layout's tables without running any code.
+
+pub mod kbdtables;
+mod svg;
+
+use serde::Serialize;
+use std::fmt;
+use std::path::Path;
+
+const IMAGE_FILE_DLL: u16 = 0x2000;
+const IMAGE_FILE_MACHINE_I386: u16 = 0x014c;
+const IMAGE_FILE_MACHINE_AMD64: u16 = 0x8664;
+const IMAGE_FILE_MACHINE_ARM64: u16 = 0xaa64;
+const PE32_MAGIC: u16 = 0x010b;
+const PE32_PLUS_MAGIC: u16 = 0x020b;
+const EXPORT_DIRECTORY: usize = 0;
+const RESOURCE_DIRECTORY: usize = 2;
+const RT_STRING: u32 = 6;
+const SUBDIRECTORY: u32 = 0x8000_0000;
+
+/// The function Windows calls to get a layout's `KBDTABLES`.
+pub const LAYOUT_DESCRIPTOR: &str = "KbdLayerDescriptor";
+/// The string resource `Layout Display Name` points at.
+pub const LAYOUT_DISPLAY_NAME: u16 = 1000;
+/// The string resource `Custom Language Display Name` points at.
+pub const LANGUAGE_DISPLAY_NAME: u16 = 1100;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Not a PE file")]
+    InvalidSignature,
+
+    #[error("Not a DLL")]
+    NotDll,
+
+    #[error("Unsupported machine type 0x{0:04x}")]
+    UnsupportedMachine(u16),
+
+    #[error("Does not export KbdLayerDescriptor")]
+    MissingLayoutDescriptor,
+
+    #[error("Corrupt PE file at offset 0x{offset:x}: {message}")]
+    Corrupt { offset: usize, message: String },
+
+    #[error("KbdLayerDescriptor does not simply return its tables")]
+    UnrecognizedDescriptor,
+
+    #[error("Invalid keyboard tables at RVA 0x{rva:x}: {message}")]
+    InvalidTables { rva: u32, message: String },
+}
+
+fn corrupt(offset: usize, message: &str) -> Error {
+    Error::Corrupt {
+        offset,
+        message: message.to_owned(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Machine {
+    X86,
+    X64,
+    Arm64,
+}
+
+impl Machine {
+    fn from_u16(value: u16) -> Result<Machine, Error> {
+        match value {
+            IMAGE_FILE_MACHINE_I386 => Ok(Machine::X86),
+            IMAGE_FILE_MACHINE_AMD64 => Ok(Machine::X64),
+            IMAGE_FILE_MACHINE_ARM64 => Ok(Machine::Arm64),
+            x => Err(Error::UnsupportedMachine(x)),
+        }
+    }
+
+    pub fn is_64_bit(self) -> bool {
+        self != Machine::X86
+    }
+}
+
+impl fmt::Display for Machine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Machine::X86 => f.write_str("x86"),
+            Machine::X64 => f.write_str("x64"),
+            Machine::Arm64 => f.write_str("arm64"),
+        }
+    }
+}
+
+fn u16_at(buf: &[u8], at: usize) -> Option<u16> {
+    buf.get(at..at + 2)
+        .map(|x| u16::from_le_bytes([x[0], x[1]]))
+}
+
+fn u32_at(buf: &[u8], at: usize) -> Option<u32> {
+    buf.get(at..at + 4)
+        .map(|x| u32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+}
+
+fn u64_at(buf: &[u8], at: usize) -> Option<u64> {
+    Some(u32_at(buf, at)? as u64 | (u32_at(buf, at + 4)? as u64) << 32)
+}
+
+/// The RVA of entry `index` of a table of `size`-byte entries at `table`, unless it is past the
+/// end of the address space.
+fn entry_rva(table: u32, index: u32, size: u32) -> Option<u32> {
+    table.checked_add(index.checked_mul(size)?)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Section {
+    virtual_address: u32,
+    virtual_size: u32,
+    raw_offset: u32,
+    raw_size: u32,
+}
+
+pub struct LayoutDll {
+    bytes: Vec<u8>,
+    machine: Machine,
+    image_base: u64,
+    sections: Vec<Section>,
+    /// Exported functions, as name and RVA
+    exports: Vec<(String, u32)>,
+    resources: Option<u32>,
+}
+
+impl LayoutDll {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<LayoutDll, Error> {
+        LayoutDll::from_bytes(std::fs::read(path)?)
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<LayoutDll, Error> {
+        if bytes.get(0..2) != Some(b"MZ") {
+            return Err(Error::InvalidSignature);
+        }
+        let pe = u32_at(&bytes, 0x3c).ok_or(Error::InvalidSignature)? as usize;
+        if bytes.get(pe..pe + 4) != Some(b"PE\0\0") {
+            return Err(Error::InvalidSignature);
+        }
+
+        let coff = pe + 4;
+        let header =
+            |at: usize| u16_at(&bytes, coff + at).ok_or_else(|| corrupt(coff, "truncated"));
+        let machine = Machine::from_u16(header(0)?)?;
+        let section_count = header(2)? as usize;
+        let optional_size = header(16)? as usize;
+        if header(18)? & IMAGE_FILE_DLL == 0 {
+            return Err(Error::NotDll);
+        }
+
+        let optional = coff + 20;
+        let (image_base, directories) = match u16_at(&bytes, optional) {
+            Some(PE32_MAGIC) => (u32_at(&bytes, optional + 28).map(u64::from), optional + 96),
+            Some(PE32_PLUS_MAGIC) => (u64_at(&bytes, optional + 24), optional + 112),
+            _ => return Err(corrupt(optional, "unknown optional header")),
+        };
+        let image_base = image_base.ok_or_else(|| corrupt(optional, "truncated"))?;
+        let directory_count =
+            u32_at(&bytes, directories - 4).ok_or_else(|| corrupt(optional, "truncated"))? as usize;
+        let directory = |index: usize| -> Option<(u32, u32)> {
+            if index >= directory_count || directories + index * 8 + 8 > optional + optional_size {
+                return None;
+            }
+            let at = directories + index * 8;
+            match (u32_at(&bytes, at)?, u32_at(&bytes, at + 4)?) {
+                (_, 0) | (0, _) => None,
+                x => Some(x),
+            }
+        };
+        let exports = directory(EXPORT_DIRECTORY);
+        let resources = directory(RESOURCE_DIRECTORY).map(|(rva, _)| rva);
+
+        let section_table = optional + optional_size;
+        let sections = (0..section_count)
+            .map(|i| {
+                let at = section_table + i * 40;
+                let field = |x| u32_at(&bytes, at + x).ok_or_else(|| corrupt(at, "truncated"));
+                Ok(Section {
+                    virtual_size: field(8)?,
+                    virtual_address: field(12)?,
+                    raw_size: field(16)?,
+                    raw_offset: field(20)?,
+                })
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let mut dll = LayoutDll {
+            bytes,
+            machine,
+            image_base,
+            sections,
+            exports: vec![],
+            resources,
+        };
+        if let Some((rva, _)) = exports {
+            dll.exports = dll.read_exports(rva)?;
+        }
+        if dll.export(LAYOUT_DESCRIPTOR).is_none() {
+            return Err(Error::MissingLayoutDescriptor);
+        }
+
+        Ok(dll)
+    }
+
+    fn read_exports(&self, rva: u32) -> Result<Vec<(String, u32)>, Error> {
+        let truncated = || corrupt(self.offset(rva).unwrap_or(0), "truncated export directory");
+        let dir = self.bytes_at(rva, 40).ok_or_else(truncated)?;
+        let field = |at| u32_at(dir, at).unwrap_or(0);
+        let (name_count, functions, names, ordinals) = (field(24), field(28), field(32), field(36));
+        let entry = |table, index, size| {
+            entry_rva(table, index, size).ok_or_else(|| {
+                corrupt(
+                    self.offset(rva).unwrap_or(0),
+                    "export table past the end of the address space",
+                )
+            })
+        };
+
+        (0..name_count)
+            .map(|i| {
+                let name = self.u32_at(entry(names, i, 4)?).ok_or_else(truncated)?;
+                let name = self.c_string_at(name).ok_or_else(truncated)?;
+                let ordinal = self.u16_at(entry(ordinals, i, 2)?).ok_or_else(truncated)?;
+                let function = self
+                    .u32_at(entry(functions, ordinal as u32, 4)?)
+                    .ok_or_else(truncated)?;
+                Ok((name, function))
+            })
+            .collect()
+    }
+
+    pub fn machine(&self) -> Machine {
+        self.machine
+    }
+
+    /// The address the DLL prefers to be loaded at, which pointers in its data are relative to
+    /// until relocated.
+    pub fn image_base(&self) -> u64 {
+        self.image_base
+    }
+
+    /// The RVA of the exported function `name`.
+    pub fn export(&self, name: &str) -> Option<u32> {
+        self.exports
+            .iter()
+            .find(|(x, _)| x == name)
+            .map(|(_, rva)| *rva)
+    }
+
+    /// The file offset of the byte loaded at `rva`, if it is stored in the file.
+    fn offset(&self, rva: u32) -> Option<usize> {
+        self.sections.iter().find_map(|s| {
+            let delta = rva.checked_sub(s.virtual_address)?;
+            if delta < s.raw_size && delta < s.virtual_size.max(s.raw_size) {
+                Some(s.raw_offset as usize + delta as usize)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// `len` bytes loaded at `rva`, if they are all stored in the file.
+    pub fn bytes_at(&self, rva: u32, len: usize) -> Option<&[u8]> {
+        let offset = self.offset(rva)?;
+        self.bytes.get(offset..offset.checked_add(len)?)
+    }
+
+    pub fn u16_at(&self, rva: u32) -> Option<u16> {
+        u16_at(self.bytes_at(rva, 2)?, 0)
+    }
+
+    pub fn u32_at(&self, rva: u32) -> Option<u32> {
+        u32_at(self.bytes_at(rva, 4)?, 0)
+    }
+
+    pub fn u64_at(&self, rva: u32) -> Option<u64> {
+        u64_at(self.bytes_at(rva, 8)?, 0)
+    }
+
+    /// The NUL-terminated ASCII string at `rva`.
+    pub fn c_string_at(&self, rva: u32) -> Option<String> {
+        let offset = self.offset(rva)?;
+        let len = self.bytes.get(offset..)?.iter().position(|&x| x == 0)?;
+        Some(String::from_utf8_lossy(&self.bytes[offset..offset + len]).into_owned())
+    }
+
+    /// The offset of the entry of the resource directory at `dir` with `id`, or of its first
+    /// entry, relative to the start of the resources. Directories past the end of the address
+    /// space have no entries.
+    fn resource_entry(&self, dir: u32, id: Option<u32>) -> Option<u32> {
+        let dir = self.resources?.checked_add(dir)?;
+        let named = self.u16_at(entry_rva(dir, 6, 2)?)? as u32;
+        let ids = self.u16_at(entry_rva(dir, 7, 2)?)? as u32;
+
+        (named..named + ids).find_map(|i| {
+            let entry = entry_rva(dir.checked_add(16)?, i, 8)?;
+            let entry_id = self.u32_at(entry)?;
+            match id {
+                Some(id) if id != entry_id => None,
+                _ => self.u32_at(entry_rva(entry, 1, 4)?),
+            }
+        })
+    }
+
+    /// The string resource `id`, in the first language the DLL has it in. Empty strings are
+    /// treated as missing, as Windows does.
+    pub fn string_resource(&self, id: u16) -> Option<String> {
+        let subdirectory = |entry: u32| match entry & SUBDIRECTORY {
+            0 => None,
+            _ => Some(entry & !SUBDIRECTORY),
+        };
+
+        // Strings are stored in blocks of 16, with the block id starting at 1.
+        let types = subdirectory(self.resource_entry(0, Some(RT_STRING))?)?;
+        let block = subdirectory(self.resource_entry(types, Some(id as u32 / 16 + 1))?)?;
+        let data_entry = self.resource_entry(block, None)?;
+        if data_entry & SUBDIRECTORY != 0 {
+            return None;
+        }
+        let data = self.resources?.checked_add(data_entry)?;
+        let (rva, size) = (self.u32_at(data)?, self.u32_at(entry_rva(data, 1, 4)?)?);
+        let table = self.bytes_at(rva, size as usize)?;
+
+        let mut at = 0;
+        for _ in 0..id % 16 {
+            at += 2 + u16_at(table, at)? as usize * 2;
+        }
+        let len = u16_at(table, at)? as usize;
+        let units: Vec<u16> = table
+            .get(at + 2..at + 2 + len * 2)?
+            .chunks_exact(2)
+            .map(|x| u16::from_le_bytes([x[0], x[1]]))
+            .collect();
+
+        match String::from_utf16_lossy(&units) {
+            s if s.is_empty() => None,
+            s => Some(s),
+        }
+    }
+
+    /// The layout's name, as shown for `Layout Display Name`.
+    pub fn layout_display_name(&self) -> Option<String> {
+        self.string_resource(LAYOUT_DISPLAY_NAME)
+    }
+
+    /// The language's name, as shown for `Custom Language Display Name`.
+    pub fn language_display_name(&self) -> Option<String> {
+        self.string_resource(LANGUAGE_DISPLAY_NAME)
+    }
+}
+
+/// Builds a layout DLL by hand: a single section at RVA 0x1000 holding the exports, `code` as the
+/// body of `KbdLayerDescriptor`, then `data` and the string resources. Returns the DLL and the RVA
+/// of `data`.
+#[cfg(test)]
+pub(crate) fn fixture(
+    machine: Machine,
+    exports: &[&str],
+    code: &[u8],
+    data: &[u8],
+    strings: &[(u16, &str)],
+) -> (Vec<u8>, u32) {
+    const SECTION_RVA: u32 = 0x1000;
+    const FILE_ALIGNMENT: usize = 0x200;
+
+    fn put(buf: &mut Vec<u8>, at: usize, bytes: &[u8]) {
+        if buf.len() < at + bytes.len() {
+            buf.resize(at + bytes.len(), 0);
+        }
+        buf[at..at + bytes.len()].copy_from_slice(bytes);
+    }
+
+    fn align(buf: &mut Vec<u8>, to: usize) {
+        buf.resize(buf.len().div_ceil(to) * to, 0);
+    }
+
+    let mut section = vec![0u8; 40];
+    let rva = |section: &Vec<u8>| SECTION_RVA + section.len() as u32;
+
+    // Exports, each pointing at `code`
+    let code_rva = rva(&section);
+    section.extend_from_slice(code);
+    align(&mut section, 4);
+    let functions = rva(&section);
+    section.extend_from_slice(&code_rva.to_le_bytes());
+    let ordinals = rva(&section);
+    for _ in exports.iter() {
+        section.extend_from_slice(&0u16.to_le_bytes());
+    }
+    align(&mut section, 4);
+    let names = rva(&section);
+    section.resize(section.len() + exports.len() * 4, 0);
+    for (i, name) in exports.iter().enumerate() {
+        let name_rva = rva(&section);
+        section.extend_from_slice(name.as_bytes());
+        section.push(0);
+        put(
+            &mut section,
+            (names - SECTION_RVA) as usize + i * 4,
+            &name_rva.to_le_bytes(),
+        );
+    }
+    put(&mut section, 20, &1u32.to_le_bytes());
+    put(&mut section, 24, &(exports.len() as u32).to_le_bytes());
+    put(&mut section, 28, &functions.to_le_bytes());
+    put(&mut section, 32, &names.to_le_bytes());
+    put(&mut section, 36, &ordinals.to_le_bytes());
+
+    align(&mut section, 8);
+    let data_rva = rva(&section);
+    section.extend_from_slice(data);
+    align(&mut section, 8);
+
+    // String resources: a directory of types, then blocks, then a language for each block
+    let mut blocks: Vec<u16> = strings.iter().map(|(id, _)| id / 16 + 1).collect();
+    blocks.sort();
+    blocks.dedup();
+    let resources = rva(&section);
+    let dir = |entries: &[(u32, u32)]| -> Vec<u8> {
+        let mut dir = vec![0u8; 16];
+        dir[14..16].copy_from_slice(&(entries.len() as u16).to_le_bytes());
+        for (id, offset) in entries.iter() {
+            dir.extend_from_slice(&id.to_le_bytes());
+            dir.extend_from_slice(&offset.to_le_bytes());
+        }
+        dir
+    };
+    let types_offset = 24;
+    let block_offset = |i: usize| types_offset + 16 + 8 * blocks.len() as u32 + i as u32 * 24;
+    let entry_offset = |i: usize| block_offset(blocks.len()) + i as u32 * 16;
+    let tables_offset = entry_offset(blocks.len());
+
+    let mut tree = dir(&[(RT_STRING, SUBDIRECTORY | types_offset)]);
+    let block_entries: Vec<(u32, u32)> = blocks
+        .iter()
+        .enumerate()
+        .map(|(i, id)| (*id as u32, SUBDIRECTORY | block_offset(i)))
+        .collect();
+    tree.extend(dir(&block_entries));
+    for i in 0..blocks.len() {
+        tree.extend(dir(&[(0x0409, entry_offset(i))]));
+    }
+
+    let mut tables = vec![];
+    for (i, block) in blocks.iter().enumerate() {
+        let start = tables.len() as u32;
+        for index in 0..16 {
+            let id = (block - 1) * 16 + index;
+            let units: Vec<u16> = match strings.iter().find(|(x, _)| *x == id) {
+                Some((_, s)) => s.encode_utf16().collect(),
+                None => vec![],
+            };
+            tables.extend_from_slice(&(units.len() as u16).to_le_bytes());
+            for unit in units {
+                tables.extend_from_slice(&unit.to_le_bytes());
+            }
+        }
+        let size = tables.len() as u32 - start;
+        let mut entry = vec![];
+        entry.extend_from_slice(&(resources + tables_offset + start).to_le_bytes());
+        entry.extend_from_slice(&size.to_le_bytes());
+        entry.resize(16, 0);
+        put(&mut tree, entry_offset(i) as usize, &entry);
+    }
+    section.extend(tree);
+    section.extend(tables);
+    let resources_size = rva(&section) - resources;
+    align(&mut section, FILE_ALIGNMENT);
+
+    // Headers
+    let is_64_bit = machine.is_64_bit();
+    let (magic, directories) = if is_64_bit {
+        (PE32_PLUS_MAGIC, 112)
+    } else {
+        (PE32_MAGIC, 96)
+    };
+    let optional_size = directories + 16 * 8;
+    let mut file = vec![0u8; 0x40];
+    file[0..2].copy_from_slice(b"MZ");
+    file[0x3c..0x40].copy_from_slice(&0x40u32.to_le_bytes());
+    file.extend_from_slice(b"PE\0\0");
+    let machine = match machine {
+        Machine::X86 => IMAGE_FILE_MACHINE_I386,
+        Machine::X64 => IMAGE_FILE_MACHINE_AMD64,
+        Machine::Arm64 => IMAGE_FILE_MACHINE_ARM64,
+    };
+    let mut coff = vec![0u8; 20];
+    coff[0..2].copy_from_slice(&machine.to_le_bytes());
+    coff[2..4].copy_from_slice(&1u16.to_le_bytes());
+    coff[16..18].copy_from_slice(&(optional_size as u16).to_le_bytes());
+    coff[18..20].copy_from_slice(&(IMAGE_FILE_DLL | 0x0002).to_le_bytes());
+    file.extend(coff);
+
+    let mut optional = vec![0u8; optional_size];
+    optional[0..2].copy_from_slice(&magic.to_le_bytes());
+    if is_64_bit {
+        optional[24..32].copy_from_slice(&0x1_8000_0000u64.to_le_bytes());
+    } else {
+        optional[28..32].copy_from_slice(&0x1000_0000u32.to_le_bytes());
+    }
+    optional[directories - 4..directories].copy_from_slice(&16u32.to_le_bytes());
+    optional[directories..directories + 4].copy_from_slice(&SECTION_RVA.to_le_bytes());
+    optional[directories + 4..directories + 8].copy_from_slice(&40u32.to_le_bytes());
+    if !blocks.is_empty() {
+        let at = directories + RESOURCE_DIRECTORY * 8;
+        optional[at..at + 4].copy_from_slice(&resources.to_le_bytes());
+        optional[at + 4..at + 8].copy_from_slice(&resources_size.to_le_bytes());
+    }
+    file.extend(optional);
+
+    let mut header = vec![0u8; 40];
+    header[0..6].copy_from_slice(b".rdata");
+    header[8..12].copy_from_slice(&(section.len() as u32).to_le_bytes());
+    header[12..16].copy_from_slice(&SECTION_RVA.to_le_bytes());
+    header[16..20].copy_from_slice(&(section.len() as u32).to_le_bytes());
+    header[20..24].copy_from_slice(&(FILE_ALIGNMENT as u32).to_le_bytes());
+    file.extend(header);
+    align(&mut file, FILE_ALIGNMENT);
+    file.extend(section);
+
+    (file, data_rva)
+}
+
+#[test]
+fn test_layout_dll_fixture() {
+    let strings = [
+        (LAYOUT_DISPLAY_NAME, "Northern Sami (Norway)"),
+        (LANGUAGE_DISPLAY_NAME, "davvisámegiella"),
+        (1002, "Unused"),
+    ];
+
+    for machine in [Machine::X86, Machine::X64, Machine::Arm64].iter() {
+        let (bytes, _) = fixture(*machine, &[LAYOUT_DESCRIPTOR], &[0xc3], &[], &strings);
+        let dll = LayoutDll::from_bytes(bytes).unwrap();
+        assert_eq!(dll.machine(), *machine);
+        assert_eq!(dll.export(LAYOUT_DESCRIPTOR), Some(0x1028));
+        assert_eq!(
+            dll.layout_display_name().as_deref(),
+            Some("Northern Sami (Norway)")
+        );
+        assert_eq!(
+            dll.language_display_name().as_deref(),
+            Some("davvisámegiella")
+        );
+        assert_eq!(dll.string_resource(1001), None);
+        assert_eq!(dll.string_resource(1002).as_deref(), Some("Unused"));
+        assert_eq!(dll.string_resource(2000), None);
+    }
+
+    let (bytes, _) = fixture(Machine::X64, &[LAYOUT_DESCRIPTOR], &[0xc3], &[], &[]);
+    let dll = LayoutDll::from_bytes(bytes).unwrap();
+    assert_eq!(dll.image_base(), 0x1_8000_0000);
+    assert_eq!(dll.layout_display_name(), None);
+    assert_eq!(Machine::Arm64.to_string(), "arm64");
+}
+
+#[test]
+fn test_layout_dll_rejects_bad_files() {
+    let (bytes, _) = fixture(Machine::X64, &["KbdNlsLayerDescriptor"], &[0xc3], &[], &[]);
+    assert!(matches!(
+        LayoutDll::from_bytes(bytes),
+        Err(Error::MissingLayoutDescriptor)
+    ));
+
+    let (mut bytes, _) = fixture(Machine::X86, &[LAYOUT_DESCRIPTOR], &[0xc3], &[], &[]);
+    bytes[0x44] = 0xc4;
+    bytes[0x45] = 0x01;
+    assert!(matches!(
+        LayoutDll::from_bytes(bytes.clone()),
+        Err(Error::UnsupportedMachine(0x01c4))
+    ));
+
+    bytes[0x3c] = 0x80;
+    assert!(matches!(
+        LayoutDll::from_bytes(bytes.clone()),
+        Err(Error::InvalidSignature)
+    ));
+    assert!(matches!(
+        LayoutDll::from_bytes(b"MZ".to_vec()),
+        Err(Error::InvalidSignature)
+    ));
+
+    let (mut bytes, _) = fixture(Machine::X86, &[LAYOUT_DESCRIPTOR], &[0xc3], &[], &[]);
+    bytes[0x56] = 0x02;
+    bytes[0x57] = 0x00;
+    assert!(matches!(LayoutDll::from_bytes(bytes), Err(Error::NotDll)));
+
+    let (mut bytes, _) = fixture(Machine::X86, &[LAYOUT_DESCRIPTOR], &[0xc3], &[], &[]);
+    bytes.truncate(0x210);
+    assert!(matches!(
+        LayoutDll::from_bytes(bytes),
+        Err(Error::Corrupt { .. })
+    ));
+}
+
+#[test]
+fn test_layout_dll_rejects_overflowing_exports() {
+    // The export directory starts the section, at file offset 0x200.
+    let (mut bytes, _) = fixture(Machine::X64, &[LAYOUT_DESCRIPTOR], &[0xc3], &[], &[]);
+    let ordinals = u32_at(&bytes, 0x200 + 36).unwrap() as usize - 0x1000 + 0x200;
+    bytes[0x200 + 28..0x200 + 32].copy_from_slice(&(u32::MAX - 4).to_le_bytes());
+    bytes[ordinals..ordinals + 2].copy_from_slice(&u16::MAX.to_le_bytes());
+    assert!(matches!(
+        LayoutDll::from_bytes(bytes),
+        Err(Error::Corrupt { .. })
+    ));
+
+    let (mut bytes, _) = fixture(Machine::X64, &[LAYOUT_DESCRIPTOR], &[0xc3], &[], &[]);
+    bytes[0x200 + 24..0x200 + 28].copy_from_slice(&u32::MAX.to_le_bytes());
+    bytes[0x200 + 32..0x200 + 36].copy_from_slice(&(u32::MAX - 3).to_le_bytes());
+    assert!(matches!(
+        LayoutDll::from_bytes(bytes),
+        Err(Error::Corrupt { .. })
+    ));
+
+    assert_eq!(entry_rva(u32::MAX - 7, 1, 4), Some(u32::MAX - 3));
+    assert_eq!(entry_rva(u32::MAX - 3, 1, 4), None);
+    assert_eq!(entry_rva(0x1000, u32::MAX, 8), None);
+}
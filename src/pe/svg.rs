@@ -0,0 +1,172 @@
+//! An SVG diagram of a [`KeyMap`] on an ISO keyboard.
+
+use super::kbdtables::{KeyMap, Output};
+
+const UNIT: f32 = 48.0;
+const MARGIN: f32 = 8.0;
+const TITLE_HEIGHT: f32 = 32.0;
+
+/// A key on the diagram: its scancode, its width in keys, and the label drawn instead of its
+/// characters, if any.
+type Cap = (Option<u16>, f32, &'static str);
+
+/// The shift states drawn on each key, with the corner they are drawn in.
+const CORNERS: [(&str, bool, bool); 4] = [
+    ("Shift", false, true),
+    ("Base", false, false),
+    ("Shift+AltGr", true, true),
+    ("AltGr", true, false),
+];
+
+fn rows() -> Vec<Vec<Cap>> {
+    let keys = |first: u16, last: u16| (first..=last).map(|x| (Some(x), 1.0, "")).collect();
+
+    let mut rows: Vec<Vec<Cap>> = vec![
+        vec![(Some(0x29), 1.0, "")],
+        vec![(Some(0x0f), 1.5, "Tab")],
+        vec![(Some(0x3a), 1.75, "Caps")],
+        vec![(Some(0x2a), 1.25, "Shift"), (Some(0x56), 1.0, "")],
+        vec![
+            (Some(0x1d), 1.5, "Ctrl"),
+            (None, 1.0, ""),
+            (Some(0x38), 1.5, "Alt"),
+            (Some(0x39), 7.0, ""),
+            (Some(0xe038), 1.5, "AltGr"),
+            (None, 1.0, ""),
+            (Some(0xe01d), 1.5, "Ctrl"),
+        ],
+    ];
+    rows[0].extend::<Vec<Cap>>(keys(0x02, 0x0d));
+    rows[0].push((Some(0x0e), 2.0, "Backspace"));
+    rows[1].extend::<Vec<Cap>>(keys(0x10, 0x1b));
+    rows[1].push((Some(0x1c), 1.5, "Enter"));
+    rows[2].extend::<Vec<Cap>>(keys(0x1e, 0x28));
+    rows[2].push((Some(0x2b), 1.0, ""));
+    rows[2].push((None, 1.25, ""));
+    rows[3].extend::<Vec<Cap>>(keys(0x2c, 0x35));
+    rows[3].push((Some(0x36), 2.75, "Shift"));
+    rows
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Whether `text` has something to see; spaces and control characters are left off the keys.
+fn is_visible(text: &str) -> bool {
+    text.chars().any(|c| !c.is_control() && !c.is_whitespace())
+}
+
+pub(super) fn render(map: &KeyMap, title: &str) -> String {
+    let rows = rows();
+    let width = 15.0 * UNIT + 2.0 * MARGIN;
+    let height = rows.len() as f32 * UNIT + 2.0 * MARGIN + TITLE_HEIGHT;
+    let corners: Vec<(usize, bool, bool)> = CORNERS
+        .iter()
+        .filter_map(|(name, right, top)| {
+            let column = map.shift_states.iter().position(|x| x.name == *name)?;
+            Some((column, *right, *top))
+        })
+        .collect();
+
+    let mut svg = format!(
+        concat!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" ",
+            "viewBox=\"0 0 {w} {h}\" font-family=\"sans-serif\">\n",
+            "<style>rect {{ fill: #f4f4f4; stroke: #888; }} text {{ font-size: 15px; }} ",
+            ".label {{ font-size: 11px; fill: #666; }} .dead {{ fill: #c00; }}</style>\n",
+            "<text x=\"{m}\" y=\"22\" style=\"font-size: 18px\">{title}</text>\n",
+        ),
+        w = width,
+        h = height,
+        m = MARGIN,
+        title = escape(title)
+    );
+
+    for (i, row) in rows.iter().enumerate() {
+        let y = MARGIN + TITLE_HEIGHT + i as f32 * UNIT;
+        let mut x = MARGIN;
+        for (scancode, keys, label) in row.iter() {
+            let w = keys * UNIT;
+            let key_x = x;
+            x += w;
+            let scancode = match scancode {
+                Some(v) => *v,
+                None => continue,
+            };
+
+            svg.push_str(&format!(
+                "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" rx=\"4\"/>\n",
+                key_x + 2.0,
+                y + 2.0,
+                w - 4.0,
+                UNIT - 4.0
+            ));
+
+            if !label.is_empty() {
+                svg.push_str(&format!(
+                    "<text class=\"label\" x=\"{}\" y=\"{}\">{}</text>\n",
+                    key_x + 8.0,
+                    y + UNIT / 2.0 + 4.0,
+                    label
+                ));
+                continue;
+            }
+
+            let key = match map.keys.iter().find(|x| x.scancode == scancode) {
+                Some(v) => v,
+                None => continue,
+            };
+            for (column, right, top) in corners.iter() {
+                let output = match key.outputs.get(*column) {
+                    Some(Some(v)) if is_visible(v.text()) => v,
+                    _ => continue,
+                };
+                let class = match output {
+                    Output::Dead(_) => " class=\"dead\"",
+                    _ => "",
+                };
+                let (text_x, anchor) = match right {
+                    true => (key_x + w - 8.0, "end"),
+                    false => (key_x + 8.0, "start"),
+                };
+                let text_y = match top {
+                    true => y + 20.0,
+                    false => y + UNIT - 10.0,
+                };
+                svg.push_str(&format!(
+                    "<text{} x=\"{}\" y=\"{}\" text-anchor=\"{}\">{}</text>\n",
+                    class,
+                    text_x,
+                    text_y,
+                    anchor,
+                    escape(output.text())
+                ));
+            }
+        }
+    }
+
+    svg.push_str("</svg>\n");
+    svg
+}
+
+#[test]
+fn test_render_svg() {
+    use super::kbdtables::fixture;
+    use super::Machine;
+
+    let map = KeyMap::read(&fixture(Machine::X64, 8)).unwrap();
+    let svg = render(&map, "Sámi <test>");
+
+    assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"736\""));
+    assert!(svg.contains(">Sámi &lt;test&gt;</text>"));
+    assert!(svg.contains("text-anchor=\"start\">a</text>"));
+    assert!(svg.contains("text-anchor=\"start\">A</text>"));
+    assert!(svg.contains("text-anchor=\"end\">Ŋg</text>"));
+    assert!(svg.contains("<text class=\"dead\""));
+    assert!(!svg.contains("U+0020"));
+    assert!(svg.ends_with("</svg>\n"));
+}
@@ -1,9 +1,9 @@
 use crate::platform::*;
 use crate::winrust::hstring::*;
 use crate::winrust::*;
-use std::io;
+use crate::{Error, LanguageTag};
 
-pub fn get_user_languages() -> Result<Vec<String>, io::Error> {
+pub fn get_user_languages() -> Result<Vec<String>, Error> {
     // let data = registry::Hive::CurrentUser
     //     .open(r"Control Panel\International\User Profile", registry::Security::Read)
     //     .unwrap()
@@ -15,9 +15,12 @@ pub fn get_user_languages() -> Result<Vec<String>, io::Error> {
     // }
     let handle = unsafe {
         let mut hstring = HString::null();
-        let ret = sys::bcp47langs::GetUserLanguages(';' as u16, &mut *hstring);
+        let ret = sys::bcp47langs::GetUserLanguages(';' as u16, &mut *hstring)?;
         if ret < 0 {
-            return Err(io::Error::last_os_error());
+            return Err(Error::Hresult {
+                function: "GetUserLanguages",
+                code: ret,
+            });
         }
         hstring
     };
@@ -26,15 +29,18 @@ pub fn get_user_languages() -> Result<Vec<String>, io::Error> {
     Ok(langs.split(';').map(|x| x.to_owned()).collect())
 }
 
-pub fn get_user_language_input_methods(tag: &str) -> Result<Vec<String>, io::Error> {
-    let wtag = to_wide_string(tag);
+pub fn get_user_language_input_methods(tag: &LanguageTag) -> Result<Vec<String>, Error> {
+    let wtag = to_wide_string(tag.as_str());
 
     let handle = unsafe {
         let mut hstring = HString::null();
         let ret =
-            sys::bcp47langs::GetUserLanguageInputMethods(wtag.as_ptr(), ';' as u16, &mut *hstring);
+            sys::bcp47langs::GetUserLanguageInputMethods(wtag.as_ptr(), ';' as u16, &mut *hstring)?;
         if ret != 0 {
-            return Err(io::Error::last_os_error());
+            return Err(Error::Hresult {
+                function: "GetUserLanguageInputMethods",
+                code: ret,
+            });
         }
         hstring
     };
@@ -46,30 +52,39 @@ pub fn get_user_language_input_methods(tag: &str) -> Result<Vec<String>, io::Err
     Ok(langs.split(';').map(|x| x.to_owned()).collect())
 }
 
-pub fn lcid_from_bcp47(tag: &str) -> Option<u32> {
+/// Returns `None` when Windows has no LCID for the tag.
+pub fn lcid_from_bcp47(tag: &str) -> Result<Option<u32>, Error> {
     let handle = HString::from(tag);
     let mut lcid = 0i32;
 
-    unsafe { sys::bcp47langs::LcidFromBcp47(*handle, &mut lcid) };
+    unsafe { sys::bcp47langs::LcidFromBcp47(*handle, &mut lcid)? };
 
-    match lcid {
+    Ok(match lcid {
         0 => None,
         _ => Some(lcid as u32),
-    }
+    })
 }
 
-pub fn bcp47_get_iso_language_code(tag: &str) -> i32 {
+pub fn bcp47_get_iso_language_code(tag: &str) -> Result<i32, Error> {
     let tag = HString::from(tag);
     let mut handle = unsafe { HString::null() };
 
     unsafe { sys::bcp47langs::Bcp47GetIsoLanguageCode(*tag, &mut *handle) }
 }
 
-pub fn remove_inputs_for_all_languages() -> Result<(), io::Error> {
-    let ret = unsafe { sys::bcp47langs::RemoveInputsForAllLanguagesInternal() };
+pub fn remove_inputs_for_all_languages() -> Result<(), Error> {
+    if crate::dry_run::is_active() {
+        crate::dry_run::record(crate::dry_run::Change::RemoveInputsForAllLanguages);
+        return Ok(());
+    }
+
+    let ret = unsafe { sys::bcp47langs::RemoveInputsForAllLanguagesInternal()? };
 
     if ret < 0 {
-        return Err(io::Error::last_os_error());
+        return Err(Error::Hresult {
+            function: "RemoveInputsForAllLanguagesInternal",
+            code: ret,
+        });
     }
 
     Ok(())
@@ -7,22 +7,32 @@ pub mod winnls;
 
 pub mod input {
     use super::*;
+    use crate::dry_run::Change;
     use crate::types::InputList;
     use crate::winrust::to_wide_string;
-    use std::io;
+    use crate::Error;
     use std::ptr::null;
 
-    pub fn install_layout(inputs: InputList, flag: i32) -> Result<(), io::Error> {
+    pub fn install_layout(inputs: InputList, flag: i32) -> Result<(), Error> {
         log::debug!("install_layout({:?}, {:?})", inputs, flag);
         log::trace!("Input list: {:?}", &inputs);
         let input_string = String::from(inputs);
         log::trace!("Input string: {}", &input_string);
+
+        if crate::dry_run::is_active() {
+            crate::dry_run::record(Change::InstallLayoutOrTip(input_string, flag));
+            return Ok(());
+        }
+
         let winput = to_wide_string(&input_string);
 
         // let ret = unsafe { sys::input::InstallLayoutOrTipUserReg(null(), null(), null(), winput.as_ptr(), flag) };
-        let ret = unsafe { sys::input::InstallLayoutOrTip(winput.as_ptr(), flag) };
+        let ret = unsafe { sys::input::InstallLayoutOrTip(winput.as_ptr(), flag)? };
         if ret < 0 {
-            return Err(io::Error::last_os_error());
+            return Err(Error::Hresult {
+                function: "InstallLayoutOrTip",
+                code: ret,
+            });
         }
 
         Ok(())
@@ -47,6 +57,10 @@ pub mod winuser {
     }
 
     pub fn set_active_keyboard(layout: isize) {
+        if crate::dry_run::is_active() {
+            return;
+        }
+
         unsafe {
             winuser::PostMessageW(
                 winuser::HWND_BROADCAST,
@@ -61,8 +75,14 @@ pub mod winuser {
 #[cfg(not(feature = "legacy"))]
 pub mod coreglobconfig {
     use super::*;
-    
-    pub fn sync_language_data() {
+    use crate::Error;
+
+    pub fn sync_language_data() -> Result<(), Error> {
+        if crate::dry_run::is_active() {
+            crate::dry_run::record(crate::dry_run::Change::SyncLanguageDataToCloud);
+            return Ok(());
+        }
+
         unsafe { sys::coreglobconfig::SyncLanguageDataToCloud() }
     }
 }
@@ -2,10 +2,17 @@
 
 macro_rules! lib_extern {
     ( $($name:ident ( $($arg: ident : $argty: ty),* ) -> $retty: ty);+ ) => {
-        $(pub unsafe fn $name($($arg: $argty),*) -> $retty {
+        $(pub unsafe fn $name($($arg: $argty),*) -> Result<$retty, crate::Error> {
+            let lib = LIB.as_ref().map_err(|e| crate::Error::LibraryLoad {
+                library: LIB_PATH,
+                message: e.clone(),
+            })?;
             let func: Symbol<unsafe extern "stdcall" fn($($arg: $argty),*) -> $retty> =
-                LIB.get(stringify!($name).as_bytes()).unwrap();
-            func($($arg),*)
+                lib.get(stringify!($name).as_bytes()).map_err(|source| crate::Error::Win32 {
+                    function: stringify!($name),
+                    source,
+                })?;
+            Ok(func($($arg),*))
         })+
     };
 }
@@ -20,8 +27,10 @@ pub mod bcp47langs {
         um::winnt::{CHAR, WCHAR},
     };
 
+    const LIB_PATH: &str = r"C:\Windows\System32\BCP47Langs.dll";
+
     lazy_static! {
-        static ref LIB: Library = Library::new(r"C:\Windows\System32\BCP47Langs.dll").unwrap();
+        static ref LIB: Result<Library, String> = Library::new(LIB_PATH).map_err(|e| e.to_string());
     }
 
     lib_extern! {
@@ -43,8 +52,10 @@ pub mod coreglobconfig {
         um::winnt::{CHAR, WCHAR},
     };
 
+    const LIB_PATH: &str = r"C:\Windows\System32\coreglobconfig.dll";
+
     lazy_static! {
-        static ref LIB: Library = Library::new(r"C:\Windows\System32\coreglobconfig.dll").unwrap();
+        static ref LIB: Result<Library, String> = Library::new(LIB_PATH).map_err(|e| e.to_string());
     }
 
     lib_extern! {
@@ -58,8 +69,10 @@ pub mod input {
     use winapi::ctypes::*;
     use winapi::um::winnt::WCHAR;
 
+    const LIB_PATH: &str = r"C:\Windows\System32\input.dll";
+
     lazy_static! {
-        static ref LIB: Library = Library::new(r"C:\Windows\System32\input.dll").unwrap();
+        static ref LIB: Result<Library, String> = Library::new(LIB_PATH).map_err(|e| e.to_string());
     }
 
     lib_extern! {
@@ -79,8 +92,10 @@ pub mod winlangdb {
         um::winnt::{CHAR, WCHAR},
     };
 
+    const LIB_PATH: &str = r"C:\Windows\System32\winlangdb.dll";
+
     lazy_static! {
-        static ref LIB: Library = Library::new(r"C:\Windows\System32\winlangdb.dll").unwrap();
+        static ref LIB: Result<Library, String> = Library::new(LIB_PATH).map_err(|e| e.to_string());
     }
 
     lib_extern! {
@@ -1,9 +1,11 @@
+use crate::dry_run::Change;
 use crate::platform::*;
 use crate::types::*;
 use crate::winrust::hstring::*;
 use crate::winrust::*;
+use crate::{Error, LanguageTag};
+use std::convert::TryFrom;
 use std::fmt;
-use std::{convert::TryFrom, io};
 
 pub struct LanguageData {
     pub tag: String,
@@ -25,7 +27,7 @@ impl fmt::Display for LanguageData {
     }
 }
 
-pub fn get_language_names(tag: &str) -> Option<LanguageData> {
+pub fn get_language_names(tag: &LanguageTag) -> Result<LanguageData, Error> {
     log::debug!("get_language_names({:?})", &tag);
     let mut a = [0u16; 256];
     let mut b = [0u16; 256];
@@ -34,72 +36,85 @@ pub fn get_language_names(tag: &str) -> Option<LanguageData> {
 
     let ret = unsafe {
         sys::winlangdb::GetLanguageNames(
-            to_wide_string(tag).as_ptr(),
+            to_wide_string(tag.as_str()).as_ptr(),
             a.as_mut_ptr(),
             b.as_mut_ptr(),
             c.as_mut_ptr(),
             d.as_mut_ptr(),
-        )
+        )?
     };
 
     if ret != 0 {
-        log::error!(
-            "Error getting language names: {:?}",
-            io::Error::last_os_error()
-        );
-        return None;
+        log::error!("Error getting language names: HRESULT 0x{:08x}", ret);
+        return Err(Error::UnsupportedLanguage(tag.to_string()));
     }
 
-    Some(LanguageData {
-        tag: tag.to_owned(),
-        name: from_wide_string(&a).unwrap(),
-        english_name: from_wide_string(&b).unwrap(),
-        localised_name: from_wide_string(&c).unwrap(),
-        script_name: from_wide_string(&d).unwrap(),
+    Ok(LanguageData {
+        tag: tag.to_string(),
+        name: from_wide_string(&a).unwrap_or_default(),
+        english_name: from_wide_string(&b).unwrap_or_default(),
+        localised_name: from_wide_string(&c).unwrap_or_default(),
+        script_name: from_wide_string(&d).unwrap_or_default(),
     })
 }
 
-pub fn set_user_languages(tags: &[String]) -> Result<(), io::Error> {
+pub fn set_user_languages(tags: &[String]) -> Result<(), Error> {
     if tags.is_empty() {
-        panic!("Tags must not be empty");
+        return Err(Error::InvalidArgument(
+            "the user language list must not be empty".into(),
+        ));
     }
 
     log::debug!("set_user_languages({:?})", tags);
 
+    if crate::dry_run::is_active() {
+        crate::dry_run::record(Change::SetUserLanguages(tags.to_vec()));
+        return Ok(());
+    }
+
     // We duplicate the first item because 32-bit binaries on 64-bit Windows fail to set the first item.
     // DO NOT REMOVE WITHOUT SETTING ASIDE TWO DAYS TO REALISE YOU HAVE MADE A HORRIBLE ERROR.
     let joined = format!("{};{}", &tags[0], tags.join(";"));
     log::trace!("Joined: {:?}", &joined);
     let handle = HString::from(joined);
-    let ret = unsafe { sys::winlangdb::SetUserLanguages(';' as u16, *handle) };
+    let ret = unsafe { sys::winlangdb::SetUserLanguages(';' as u16, *handle)? };
 
     if ret != 0 {
-        let err = io::Error::last_os_error();
-        log::error!("Error setting user languages: {:?}", err);
+        log::error!("Error setting user languages: HRESULT 0x{:08x}", ret);
 
-        return Err(err);
+        return Err(Error::Hresult {
+            function: "SetUserLanguages",
+            code: ret,
+        });
     }
 
     Ok(())
 }
 
-pub fn transform_input_methods(methods: InputList, tag: &str) -> InputList {
+fn parse_input_list(function: &'static str, ret: i32, out: HString) -> Result<InputList, Error> {
+    if ret < 0 {
+        return Err(Error::Hresult {
+            function,
+            code: ret,
+        });
+    }
+
+    let out = String::from(out);
+    InputList::try_from(out.clone()).map_err(|_| Error::InvalidInputMethods(out))
+}
+
+pub fn transform_input_methods(methods: InputList, tag: &LanguageTag) -> Result<InputList, Error> {
     let hmethods = HString::from(String::from(methods));
-    let htag = HString::from(tag);
-    let out = unsafe {
-        let mut out = HString::null();
-        sys::winlangdb::TransformInputMethodsForLanguage(*hmethods, *htag, &mut *out);
-        out
-    };
-    InputList::try_from(String::from(out)).unwrap()
+    let htag = HString::from(tag.as_str());
+    let mut out = unsafe { HString::null() };
+    let ret =
+        unsafe { sys::winlangdb::TransformInputMethodsForLanguage(*hmethods, *htag, &mut *out)? };
+    parse_input_list("TransformInputMethodsForLanguage", ret, out)
 }
 
-pub fn default_input_method(tag: &str) -> InputList {
-    let htag = HString::from(tag);
-    let out = unsafe {
-        let mut out = HString::null();
-        sys::winlangdb::GetDefaultInputMethodForLanguage(*htag, &mut *out);
-        out
-    };
-    InputList::try_from(String::from(out)).unwrap()
+pub fn default_input_method(tag: &LanguageTag) -> Result<InputList, Error> {
+    let htag = HString::from(tag.as_str());
+    let mut out = unsafe { HString::null() };
+    let ret = unsafe { sys::winlangdb::GetDefaultInputMethodForLanguage(*htag, &mut *out)? };
+    parse_input_list("GetDefaultInputMethodForLanguage", ret, out)
 }
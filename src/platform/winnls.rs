@@ -1,44 +1,42 @@
 use crate::winrust::{from_wide_string, to_wide_string};
-use std::io;
+use crate::{Error, LanguageTag};
 use winapi::ctypes::c_int;
 use winapi::um::winnls as sys_winnls;
 
 const MAX_LOCALE_NAME_LEN: usize = 85usize;
 
-// TODO: remove panics
-pub fn resolve_locale_name(tag: &str) -> Option<String> {
+/// Returns `None` when Windows cannot resolve the tag to a locale.
+pub fn resolve_locale_name(tag: &LanguageTag) -> Result<Option<LanguageTag>, Error> {
     let mut buf = vec![0u16; MAX_LOCALE_NAME_LEN];
 
     let ret = unsafe {
         sys_winnls::ResolveLocaleName(
-            to_wide_string(tag).as_ptr(),
+            to_wide_string(tag.as_str()).as_ptr(),
             buf.as_mut_ptr(),
             MAX_LOCALE_NAME_LEN as c_int,
         )
     };
 
     if ret == 0 {
-        let err = io::Error::last_os_error();
-        info!("{:?}", err);
-        panic!();
+        return Err(Error::win32("ResolveLocaleName"));
     }
 
     buf.truncate(ret as usize - 1);
 
     if buf.len() == 0 {
-        return None;
+        return Ok(None);
     }
 
-    Some(from_wide_string(&buf).unwrap())
+    Ok(from_wide_string(&buf).ok().and_then(|x| x.parse().ok()))
 }
 
-pub fn locale_name_to_lcid(locale_name: &str) -> Result<u32, io::Error> {
-    let tag = resolve_locale_name(locale_name).unwrap_or(locale_name.to_owned());
+pub fn locale_name_to_lcid(tag: &LanguageTag) -> Result<u32, Error> {
+    let tag = resolve_locale_name(tag)?.unwrap_or_else(|| tag.clone());
 
-    let ret = unsafe { sys_winnls::LocaleNameToLCID(to_wide_string(&tag).as_ptr(), 0) };
+    let ret = unsafe { sys_winnls::LocaleNameToLCID(to_wide_string(tag.as_str()).as_ptr(), 0) };
 
     match ret {
-        0 => Err(io::Error::last_os_error()),
+        0 => Err(Error::win32("LocaleNameToLCID")),
         _ => Ok(ret),
     }
 }
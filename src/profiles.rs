@@ -0,0 +1,864 @@
+//! The user profiles on the machine, for changes that must reach users other than the current one.
+//!
+//! Profiles are listed under `ProfileList`. A profile whose user is logged in has its hive loaded
+//! under `HKEY_USERS\<SID>`; any other is changed in its `NTUSER.DAT`, loaded with
+//! `RegLoadAppKey` on Windows and with [`crate::regf::Hive`] elsewhere. The default profile, which
+//! new accounts are copied from, is enabled for through Windows instead.
+
+#[cfg(windows)]
+use crate::backend::HiveFile;
+use crate::backend::{Data, Hive, KeyPath, RegistryBackend};
+#[cfg(windows)]
+use crate::dry_run::DryRunRegistry;
+use crate::journal::Transaction;
+#[cfg(not(feature = "legacy"))]
+use crate::keyboard_win8::{given_registry_regeneration, regenerate_given_registry};
+#[cfg(any(not(windows), test))]
+use crate::regf;
+use crate::types::InputListItem;
+use crate::Error;
+#[cfg(not(feature = "legacy"))]
+use crate::LanguageTag;
+use serde::Serialize;
+use std::convert::TryFrom;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+const USER_PROFILE: &str = r"Control Panel\International\User Profile";
+const SUBSTITUTES: &str = r"Keyboard Layout\Substitutes";
+const PRELOAD: &str = r"Keyboard Layout\Preload";
+
+pub(crate) fn profile_list_key() -> KeyPath {
+    KeyPath::new(
+        Hive::LocalMachine,
+        r"SOFTWARE\Microsoft\Windows NT\CurrentVersion\ProfileList",
+    )
+}
+
+/// The keys of one user that list their input methods.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserKeys {
+    pub user_profile: KeyPath,
+    pub substitutes: KeyPath,
+    pub preload: KeyPath,
+}
+
+impl UserKeys {
+    /// The keys under `root`, which is `HKEY_CURRENT_USER`, `HKEY_USERS\<SID>` or the root of a
+    /// hive file.
+    pub fn under(root: &KeyPath) -> UserKeys {
+        UserKeys {
+            user_profile: root.join(USER_PROFILE),
+            substitutes: root.join(SUBSTITUTES),
+            preload: root.join(PRELOAD),
+        }
+    }
+
+    pub fn current_user() -> UserKeys {
+        UserKeys::under(&KeyPath::new(Hive::CurrentUser, ""))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    /// The user's SID, or `Default` for the profile new users are copied from
+    pub sid: String,
+    /// The profile directory holding `NTUSER.DAT`, with environment variables expanded
+    pub path: Option<PathBuf>,
+}
+
+/// Replaces each `%NAME%` with the environment variable, as `ProfileImagePath` is stored
+/// unexpanded. Unknown variables are left as they are.
+fn expand_env_vars(value: &str) -> String {
+    let mut out = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find('%') {
+        let end = match rest[start + 1..].find('%') {
+            Some(i) => start + 1 + i,
+            None => break,
+        };
+        out.push_str(&rest[..start]);
+        match std::env::var(&rest[start + 1..end]) {
+            Ok(v) => out.push_str(&v),
+            Err(_) => out.push_str(&rest[start..=end]),
+        }
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Every profile in `ProfileList`, then the default profile.
+pub fn profiles(reg: &dyn RegistryBackend) -> Result<Vec<Profile>, Error> {
+    let key = profile_list_key();
+    if !reg.key_exists(&key) {
+        return Ok(vec![]);
+    }
+
+    let path_value = |key: &KeyPath, name: &str| match reg.value(key, name) {
+        Ok(Data::String(v)) | Ok(Data::ExpandString(v)) => Some(PathBuf::from(expand_env_vars(&v))),
+        _ => None,
+    };
+
+    let mut profiles: Vec<Profile> = reg
+        .subkeys(&key)?
+        .into_iter()
+        .map(|sid| Profile {
+            path: path_value(&key.join(&sid), "ProfileImagePath"),
+            sid,
+        })
+        .collect();
+
+    if let Some(path) = path_value(&key, "Default") {
+        profiles.push(Profile {
+            sid: "Default".into(),
+            path: Some(path),
+        });
+    }
+
+    Ok(profiles)
+}
+
+impl Profile {
+    fn loaded_root(&self) -> KeyPath {
+        KeyPath::new(Hive::Users, self.sid.as_str())
+    }
+
+    /// Whether the profile's hive is loaded under `HKEY_USERS`.
+    pub fn is_loaded(&self, reg: &dyn RegistryBackend) -> bool {
+        self.sid != "Default" && reg.key_exists(&self.loaded_root())
+    }
+
+    pub fn loaded_keys(&self) -> UserKeys {
+        UserKeys::under(&self.loaded_root())
+    }
+
+    pub fn hive_path(&self) -> Option<PathBuf> {
+        self.path.as_ref().map(|x| x.join("NTUSER.DAT"))
+    }
+}
+
+/// References to a KLID removed from one user's keys.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Purge {
+    /// Input methods, as language tag and input method
+    pub input_methods: Vec<(String, String)>,
+    /// Names of the substitutes for the KLID
+    pub substitutes: Vec<String>,
+    /// `Preload` values naming the KLID or one of its substitutes
+    pub preload: Vec<String>,
+}
+
+impl Purge {
+    pub fn is_empty(&self) -> bool {
+        self.input_methods.is_empty() && self.substitutes.is_empty() && self.preload.is_empty()
+    }
+}
+
+/// Removes every reference to `klid` from a user's language input methods, `Substitutes` and
+/// `Preload`. The remaining `Preload` values are renumbered from 1 in their order.
+pub fn purge_klid(reg: &dyn RegistryBackend, keys: &UserKeys, klid: &str) -> Result<Purge, Error> {
+    let mut purge = Purge::default();
+
+    if reg.key_exists(&keys.user_profile) {
+        for tag in reg.subkeys(&keys.user_profile)? {
+            let lang_key = keys.user_profile.join(&tag);
+            for (name, _) in reg.values(&lang_key)? {
+                let item_klid = InputListItem::try_from(&*name).ok().and_then(|x| x.klid());
+                if matches!(item_klid, Some(x) if format!("{:08x}", x).eq_ignore_ascii_case(klid)) {
+                    reg.delete_value(&lang_key, &name)?;
+                    purge.input_methods.push((tag.to_owned(), name));
+                }
+            }
+        }
+    }
+
+    if reg.key_exists(&keys.substitutes) {
+        for (name, data) in reg.values(&keys.substitutes)? {
+            if data.to_string().eq_ignore_ascii_case(klid) {
+                reg.delete_value(&keys.substitutes, &name)?;
+                purge.substitutes.push(name);
+            }
+        }
+    }
+
+    if reg.key_exists(&keys.preload) {
+        let mut values = reg.values(&keys.preload)?;
+        values.sort_by_key(|(name, _)| name.parse::<u32>().unwrap_or(u32::MAX));
+
+        let (removed, kept): (Vec<_>, Vec<_>) = values.into_iter().partition(|(_, data)| {
+            let value = data.to_string();
+            value.eq_ignore_ascii_case(klid)
+                || purge
+                    .substitutes
+                    .iter()
+                    .any(|x| x.eq_ignore_ascii_case(&value))
+        });
+
+        if !removed.is_empty() {
+            for (name, _) in removed.iter().chain(kept.iter()) {
+                reg.delete_value(&keys.preload, name)?;
+            }
+            for (i, (_, data)) in kept.iter().enumerate() {
+                reg.set_value(&keys.preload, &(i + 1).to_string(), data)?;
+            }
+            purge.preload = removed.into_iter().map(|(name, _)| name).collect();
+        }
+    }
+
+    Ok(purge)
+}
+
+/// A profile that had references to a KLID removed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PurgedProfile {
+    pub sid: String,
+    /// Whether the profile was loaded under `HKEY_USERS`, rather than changed in `NTUSER.DAT`
+    pub loaded: bool,
+    pub removed: Purge,
+}
+
+impl fmt::Display for PurgedProfile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let hive = if self.loaded { "loaded" } else { "NTUSER.DAT" };
+        writeln!(f, "{} ({}):", self.sid, hive)?;
+        for (tag, ime) in self.removed.input_methods.iter() {
+            writeln!(f, "  input method {} {}", tag, ime)?;
+        }
+        for name in self.removed.substitutes.iter() {
+            writeln!(f, "  substitute {}", name)?;
+        }
+        for name in self.removed.preload.iter() {
+            writeln!(f, "  preload {}", name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Removes references to `klid` from the profiles loaded under `HKEY_USERS`.
+pub fn purge_loaded_profiles(
+    reg: &dyn RegistryBackend,
+    klid: &str,
+) -> Result<Vec<PurgedProfile>, Error> {
+    let mut purged = vec![];
+    for profile in profiles(reg)?.iter().filter(|x| x.is_loaded(reg)) {
+        let removed = purge_klid(reg, &profile.loaded_keys(), klid)?;
+        if !removed.is_empty() {
+            log::info!("Purged {} from loaded profile {}", klid, profile.sid);
+            purged.push(PurgedProfile {
+                sid: profile.sid.to_owned(),
+                loaded: true,
+                removed,
+            });
+        }
+    }
+
+    Ok(purged)
+}
+
+/// Removes references to `klid` from the hive file of `profile` at `path`, or returns `None` if it
+/// cannot be opened. The hive is loaded with `RegLoadAppKey` and changed in place, so Windows keeps
+/// its security descriptor, file attributes and transaction logs.
+#[cfg(windows)]
+fn purge_hive_file(profile: &Profile, path: &Path, klid: &str) -> Result<Option<Purge>, Error> {
+    let hive = match HiveFile::load(path) {
+        Ok(v) => v,
+        Err(e) => {
+            log::warn!("Skipping profile {}: {}", profile.sid, e);
+            return Ok(None);
+        }
+    };
+    let dry_run_hive = DryRunRegistry::new(&hive);
+    let target: &dyn RegistryBackend = match crate::dry_run::is_active() {
+        true => &dry_run_hive,
+        false => &hive,
+    };
+
+    purge_klid(target, &UserKeys::current_user(), klid).map(Some)
+}
+
+/// Removes references to `klid` from the hive file of `profile` at `path`, or returns `None` if it
+/// cannot be read. Without Windows to load it, the file is rewritten with [`regf::Hive`].
+#[cfg(not(windows))]
+fn purge_hive_file(profile: &Profile, path: &Path, klid: &str) -> Result<Option<Purge>, Error> {
+    let hive = match regf::Hive::open(path) {
+        Ok(v) => v,
+        Err(e) => {
+            log::warn!(
+                "Skipping profile {}: {}: {}",
+                profile.sid,
+                path.display(),
+                e
+            );
+            return Ok(None);
+        }
+    };
+
+    let removed = purge_klid(&hive, &UserKeys::current_user(), klid)?;
+    if !removed.is_empty() && !crate::dry_run::is_active() {
+        hive.save(path)?;
+    }
+    Ok(Some(removed))
+}
+
+/// Removes references to `klid` from the `NTUSER.DAT` of each profile that is not loaded. A hive
+/// that cannot be read, such as one in use by a service, is skipped with a warning. Nothing is
+/// changed during a dry run.
+pub fn purge_unloaded_profiles(
+    reg: &dyn RegistryBackend,
+    klid: &str,
+) -> Result<Vec<PurgedProfile>, Error> {
+    let mut purged = vec![];
+    for profile in profiles(reg)?.iter().filter(|x| !x.is_loaded(reg)) {
+        let path = match profile.hive_path() {
+            Some(v) if v.exists() => v,
+            _ => continue,
+        };
+
+        let removed = match purge_hive_file(profile, &path, klid)? {
+            Some(v) if !v.is_empty() => v,
+            _ => continue,
+        };
+        log::info!("Purged {} from {}", klid, path.display());
+        purged.push(PurgedProfile {
+            sid: profile.sid.to_owned(),
+            loaded: false,
+            removed,
+        });
+    }
+
+    Ok(purged)
+}
+
+/// Well-known SIDs of the local system, local service and network service accounts. Their hives
+/// are loaded under `HKEY_USERS`, but nobody types with them.
+const SERVICE_ACCOUNTS: [&str; 3] = ["S-1-5-18", "S-1-5-19", "S-1-5-20"];
+
+/// The SIDs of the users whose hives are loaded under `HKEY_USERS`. Service accounts, `.DEFAULT`
+/// and the `_Classes` hives are left out.
+pub fn loaded_users(reg: &dyn RegistryBackend) -> Result<Vec<String>, Error> {
+    Ok(reg
+        .subkeys(&KeyPath::new(Hive::Users, ""))?
+        .into_iter()
+        .filter(|sid| sid.starts_with("S-1-") && !sid.ends_with("_Classes"))
+        .filter(|sid| !SERVICE_ACCOUNTS.contains(&sid.as_str()))
+        .collect())
+}
+
+/// What enabling or disabling a keyboard did for one user.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UserResult {
+    pub sid: String,
+    /// Whether any of the user's keys were changed
+    pub changed: bool,
+    /// Why the user's keys could not be changed. Their changes were rolled back.
+    pub error: Option<String>,
+}
+
+impl fmt::Display for UserResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.error, self.changed) {
+            (Some(e), _) => write!(f, "{}: failed: {}", self.sid, e),
+            (None, true) => write!(f, "{}: changed", self.sid),
+            (None, false) => write!(f, "{}: unchanged", self.sid),
+        }
+    }
+}
+
+/// Runs `change` on the keys of each user in [`loaded_users`], returning whether it changed
+/// anything. Each user's changes are made in their own transaction, so a user that fails is
+/// rolled back and reported without stopping the others.
+pub fn for_loaded_users<F>(reg: &dyn RegistryBackend, change: F) -> Result<Vec<UserResult>, Error>
+where
+    F: Fn(&dyn RegistryBackend, &UserKeys) -> Result<bool, Error>,
+{
+    let mut results = vec![];
+    for sid in loaded_users(reg)? {
+        let tx = Transaction::begin(reg);
+        let keys = UserKeys::under(&KeyPath::new(Hive::Users, sid.as_str()));
+        let result = match change(&tx, &keys) {
+            Ok(changed) => {
+                tx.commit();
+                UserResult {
+                    sid,
+                    changed,
+                    error: None,
+                }
+            }
+            Err(e) => {
+                log::error!("Could not change the keys of {}: {}", sid, e);
+                tx.rollback();
+                UserResult {
+                    sid,
+                    changed: false,
+                    error: Some(e.to_string()),
+                }
+            }
+        };
+        results.push(result);
+    }
+
+    Ok(results)
+}
+
+/// Adds `input_method` to the language `tag` of one user, then regenerates their `Preload` and
+/// `Substitutes`. The language is added to the user's `Languages` if missing, and its subkey
+/// is given any values of `template`, the same language's key of a user who has it enabled,
+/// that it lacks. Returns whether anything changed.
+#[cfg(not(feature = "legacy"))]
+pub fn enable_for_user(
+    reg: &dyn RegistryBackend,
+    keys: &UserKeys,
+    tag: &LanguageTag,
+    input_method: &InputListItem,
+    template: &[(String, Data)],
+) -> Result<bool, Error> {
+    let mut changed = false;
+
+    let mut languages = match reg.value(&keys.user_profile, "Languages") {
+        Ok(Data::MultiString(v)) => v,
+        _ => vec![],
+    };
+    if !languages
+        .iter()
+        .any(|x| x.eq_ignore_ascii_case(tag.as_str()))
+    {
+        languages.push(tag.to_string());
+        reg.create_key(&keys.user_profile)?;
+        reg.set_value(
+            &keys.user_profile,
+            "Languages",
+            &Data::MultiString(languages),
+        )?;
+        changed = true;
+    }
+
+    let lang_key = keys.user_profile.join(tag.as_str());
+    let existing = if reg.key_exists(&lang_key) {
+        reg.values(&lang_key)?
+    } else {
+        reg.create_key(&lang_key)?;
+        changed = true;
+        vec![]
+    };
+    let has_value = |name: &str| existing.iter().any(|(x, _)| x.eq_ignore_ascii_case(name));
+
+    for (name, data) in template.iter() {
+        if !name.contains(':') && !has_value(name) {
+            reg.set_value(&lang_key, name, data)?;
+            changed = true;
+        }
+    }
+
+    let name = input_method.to_string();
+    if !has_value(&name) {
+        let order = existing.iter().filter(|(x, _)| x.contains(':')).count() as u32 + 1;
+        reg.set_value(&lang_key, &name, &Data::U32(order))?;
+        changed = true;
+    }
+
+    let plan =
+        given_registry_regeneration(reg, &keys.user_profile, &keys.substitutes, &keys.preload)?;
+    if !plan.is_empty() {
+        regenerate_given_registry(reg, &keys.user_profile, &keys.substitutes, &keys.preload)?;
+        changed = true;
+    }
+
+    Ok(changed)
+}
+
+/// One value written to a user's keys, and whether writing it succeeded.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ValueResult {
+    pub key: String,
+    pub name: String,
+    pub data: String,
+    /// Why the value could not be written.
+    pub error: Option<String>,
+}
+
+impl fmt::Display for ValueResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} [{}] = {}: ", self.key, self.name, self.data)?;
+        match &self.error {
+            Some(e) => write!(f, "failed: {}", e),
+            None => write!(f, "ok"),
+        }
+    }
+}
+
+/// Adds `input_method` to the language `tag` in the keys of the default profile, which accounts
+/// created from now on start from. Unlike [`enable_for_user`], nothing else is regenerated: only
+/// `Languages`, the language's own subkey, given the values of `template` it lacks, and the
+/// `Preload` and `Substitutes` entries for the layout are written. A substitute is only added if
+/// the layout is for another language. Writing carries on past a value that fails, and every
+/// value written is returned.
+#[cfg(not(feature = "legacy"))]
+pub fn enable_for_default_user(
+    reg: &dyn RegistryBackend,
+    keys: &UserKeys,
+    tag: &LanguageTag,
+    input_method: &InputListItem,
+    template: &[(String, Data)],
+) -> Result<Vec<ValueResult>, Error> {
+    let mut writes: Vec<(KeyPath, String, Data)> = vec![];
+
+    let mut languages = match reg.value(&keys.user_profile, "Languages") {
+        Ok(Data::MultiString(v)) => v,
+        _ => vec![],
+    };
+    if !languages
+        .iter()
+        .any(|x| x.eq_ignore_ascii_case(tag.as_str()))
+    {
+        languages.push(tag.to_string());
+        writes.push((
+            keys.user_profile.clone(),
+            "Languages".into(),
+            Data::MultiString(languages),
+        ));
+    }
+
+    let lang_key = keys.user_profile.join(tag.as_str());
+    let existing = match reg.key_exists(&lang_key) {
+        true => reg.values(&lang_key)?,
+        false => vec![],
+    };
+    let has_value = |name: &str| existing.iter().any(|(x, _)| x.eq_ignore_ascii_case(name));
+    for (name, data) in template.iter() {
+        if !name.contains(':') && !has_value(name) {
+            writes.push((lang_key.clone(), name.to_owned(), data.clone()));
+        }
+    }
+    let name = input_method.to_string();
+    if !has_value(&name) {
+        let order = existing.iter().filter(|(x, _)| x.contains(':')).count() as u32 + 1;
+        writes.push((lang_key.clone(), name, Data::U32(order)));
+    }
+
+    if let Some(klid) = input_method.klid() {
+        let klid = format!("{:08x}", klid);
+        let lcid = format!("{:04x}", input_method.lang_id);
+        let values = |key: &KeyPath| -> Result<Vec<(String, String)>, Error> {
+            match reg.key_exists(key) {
+                true => Ok(reg
+                    .values(key)?
+                    .into_iter()
+                    .map(|(name, data)| (name, data.to_string()))
+                    .collect()),
+                false => Ok(vec![]),
+            }
+        };
+
+        let substitutes = values(&keys.substitutes)?;
+        let substitute = substitutes.iter().find(|(name, value)| {
+            value.eq_ignore_ascii_case(&klid)
+                && matches!(name.get(4..), Some(x) if x.eq_ignore_ascii_case(&lcid))
+        });
+        let preload_entry = match substitute {
+            Some((name, _)) => name.to_owned(),
+            None if klid.ends_with(&lcid) => klid.clone(),
+            None => {
+                let name = (1..0x1000)
+                    .map(|i| format!("d{:03x}{}", i, lcid))
+                    .find(|x| !substitutes.iter().any(|(n, _)| n.eq_ignore_ascii_case(x)))
+                    .ok_or_else(|| {
+                        Error::InvalidArgument(format!("no free substitute for {}", lcid))
+                    })?;
+                writes.push((
+                    keys.substitutes.clone(),
+                    name.to_owned(),
+                    Data::String(klid.clone()),
+                ));
+                name
+            }
+        };
+
+        let preload = values(&keys.preload)?;
+        if !preload
+            .iter()
+            .any(|(_, v)| v.eq_ignore_ascii_case(&preload_entry))
+        {
+            let next = preload
+                .iter()
+                .filter_map(|(name, _)| name.parse::<u32>().ok())
+                .max()
+                .unwrap_or(0)
+                + 1;
+            writes.push((
+                keys.preload.clone(),
+                next.to_string(),
+                Data::String(preload_entry),
+            ));
+        }
+    }
+
+    let mut results = vec![];
+    for (key, name, data) in writes {
+        let result = reg
+            .create_key(&key)
+            .and_then(|_| reg.set_value(&key, &name, &data));
+        if let Err(e) = &result {
+            log::error!("Could not set {} [{}]: {}", key, name, e);
+        }
+        results.push(ValueResult {
+            key: key.to_string(),
+            name,
+            data: match data {
+                Data::MultiString(v) => v.join(", "),
+                data => data.to_string(),
+            },
+            error: result.err().map(|e| e.to_string()),
+        });
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+fn add_input_methods(reg: &dyn RegistryBackend, keys: &UserKeys) {
+    let se = keys.user_profile.join("se-NO");
+    reg.create_key(&se).unwrap();
+    reg.set_value(&se, "043B:A000043B", &Data::U32(1)).unwrap();
+    reg.set_value(&se, "043B:0000043B", &Data::U32(2)).unwrap();
+    reg.create_key(&keys.substitutes).unwrap();
+    reg.set_value(
+        &keys.substitutes,
+        "d001043b",
+        &Data::String("a000043b".into()),
+    )
+    .unwrap();
+    reg.create_key(&keys.preload).unwrap();
+    for (name, value) in [("1", "00000409"), ("2", "d001043b"), ("3", "0000043b")].iter() {
+        reg.set_value(&keys.preload, name, &Data::String(value.to_string()))
+            .unwrap();
+    }
+}
+
+#[test]
+fn test_purge_klid() {
+    let reg = crate::backend::MemoryRegistry::new();
+    let keys = UserKeys::under(&KeyPath::new(Hive::Users, "S-1-5-21-1"));
+    add_input_methods(&reg, &keys);
+
+    let purge = purge_klid(&reg, &keys, "A000043B").unwrap();
+    assert_eq!(
+        purge,
+        Purge {
+            input_methods: vec![("se-NO".into(), "043B:A000043B".into())],
+            substitutes: vec!["d001043b".into()],
+            preload: vec!["2".into()],
+        }
+    );
+    assert_eq!(
+        reg.values(&keys.preload).unwrap(),
+        vec![
+            ("1".to_string(), Data::String("00000409".into())),
+            ("2".to_string(), Data::String("0000043b".into())),
+        ]
+    );
+    assert!(purge_klid(&reg, &keys, "a000043b").unwrap().is_empty());
+}
+
+#[test]
+fn test_purge_profiles() {
+    let dir = std::env::temp_dir().join(format!("kbdi-profiles-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let hive = regf::Hive::new("ROOT");
+    add_input_methods(&hive, &UserKeys::current_user());
+    hive.save(dir.join("NTUSER.DAT")).unwrap();
+
+    let reg = crate::backend::MemoryRegistry::new();
+    let list = profile_list_key();
+    for (sid, path) in [
+        ("S-1-5-18", r"%systemroot%\system32\config\systemprofile"),
+        ("S-1-5-21-1", r"C:\Users\Loaded"),
+        ("S-1-5-21-2", dir.to_str().unwrap()),
+    ]
+    .iter()
+    {
+        reg.create_key(&list.join(sid)).unwrap();
+        reg.set_value(
+            &list.join(sid),
+            "ProfileImagePath",
+            &Data::ExpandString(path.to_string()),
+        )
+        .unwrap();
+    }
+    reg.set_value(&list, "Default", &Data::ExpandString(r"%kbdi-none%".into()))
+        .unwrap();
+
+    let loaded = Profile {
+        sid: "S-1-5-21-1".into(),
+        path: None,
+    };
+    add_input_methods(&reg, &loaded.loaded_keys());
+
+    assert_eq!(profiles(&reg).unwrap().len(), 4);
+    assert_eq!(
+        profiles(&reg).unwrap()[3],
+        Profile {
+            sid: "Default".into(),
+            path: Some(PathBuf::from("%kbdi-none%")),
+        }
+    );
+
+    let purged = purge_loaded_profiles(&reg, "a000043b").unwrap();
+    assert_eq!(purged.len(), 1);
+    assert_eq!(purged[0].sid, "S-1-5-21-1");
+    assert!(purged[0].loaded);
+
+    let purged = purge_unloaded_profiles(&reg, "a000043b").unwrap();
+    assert_eq!(purged.len(), 1);
+    assert_eq!(purged[0].sid, "S-1-5-21-2");
+    assert!(!purged[0].loaded);
+    assert_eq!(purged[0].removed.substitutes, vec!["d001043b"]);
+
+    let saved = regf::Hive::open(dir.join("NTUSER.DAT")).unwrap();
+    assert!(purge_klid(&saved, &UserKeys::current_user(), "a000043b")
+        .unwrap()
+        .is_empty());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(not(feature = "legacy"))]
+#[test]
+fn test_for_loaded_users() {
+    let reg = crate::backend::MemoryRegistry::new();
+    for sid in [
+        "S-1-5-18",
+        "S-1-5-21-1",
+        "S-1-5-21-1_Classes",
+        "S-1-5-21-2",
+        ".DEFAULT",
+    ]
+    .iter()
+    {
+        reg.create_key(&KeyPath::new(Hive::Users, *sid)).unwrap();
+    }
+    let user1 = UserKeys::under(&KeyPath::new(Hive::Users, "S-1-5-21-1"));
+    add_input_methods(&reg, &user1);
+    assert_eq!(
+        loaded_users(&reg).unwrap(),
+        vec!["S-1-5-21-1", "S-1-5-21-2"]
+    );
+
+    let se_no: LanguageTag = "se-NO".parse().unwrap();
+    let item = InputListItem::try_from("043B:A000043B").unwrap();
+    let template = vec![
+        (
+            "CachedLanguageName".to_string(),
+            Data::String("@Winlangdb.dll,-1".into()),
+        ),
+        ("043B:00000409".to_string(), Data::U32(1)),
+    ];
+    let results = for_loaded_users(&reg, |reg, keys| {
+        enable_for_user(reg, keys, &se_no, &item, &template)
+    })
+    .unwrap();
+    assert_eq!(
+        results.iter().map(|x| x.to_string()).collect::<Vec<_>>(),
+        vec!["S-1-5-21-1: changed", "S-1-5-21-2: changed"]
+    );
+
+    let user2 = UserKeys::under(&KeyPath::new(Hive::Users, "S-1-5-21-2"));
+    let se = user2.user_profile.join("se-NO");
+    assert_eq!(
+        reg.values(&se).unwrap(),
+        vec![
+            (
+                "CachedLanguageName".to_string(),
+                Data::String("@Winlangdb.dll,-1".into())
+            ),
+            ("043B:A000043B".to_string(), Data::U32(1)),
+        ]
+    );
+    assert_eq!(
+        reg.value(&user2.user_profile, "Languages").unwrap(),
+        Data::MultiString(vec!["se-NO".into()])
+    );
+    assert_eq!(
+        reg.values(&user2.preload).unwrap(),
+        vec![("1".to_string(), Data::String("a000043b".into()))]
+    );
+
+    // Enabling again changes nothing; a failing user is rolled back and reported
+    let results = for_loaded_users(&reg, |reg, keys| {
+        let changed = enable_for_user(reg, keys, &se_no, &item, &template)?;
+        if keys == &user2 {
+            reg.delete_key(&user2.preload)?;
+            reg.delete_value(&user2.user_profile, "Missing")?;
+        }
+        Ok(changed)
+    })
+    .unwrap();
+    assert_eq!(results[0].to_string(), "S-1-5-21-1: unchanged");
+    assert!(results[1].error.is_some());
+    assert!(reg.key_exists(&user2.preload));
+
+    let results = for_loaded_users(&reg, |reg, keys| {
+        Ok(!purge_klid(reg, keys, "a000043b")?.is_empty())
+    })
+    .unwrap();
+    assert!(results.iter().all(|x| x.changed));
+    assert!(reg.values(&se).unwrap().len() == 1);
+}
+
+#[cfg(not(feature = "legacy"))]
+#[test]
+fn test_enable_for_default_user() {
+    let reg = crate::backend::MemoryRegistry::new();
+    let keys = UserKeys::current_user();
+    reg.create_key(&keys.user_profile).unwrap();
+    reg.set_value(
+        &keys.user_profile,
+        "Languages",
+        &Data::MultiString(vec!["en-US".into()]),
+    )
+    .unwrap();
+    reg.create_key(&keys.preload).unwrap();
+    reg.set_value(&keys.preload, "1", &Data::String("00000409".into()))
+        .unwrap();
+
+    let template = vec![
+        (
+            "CachedLanguageName".to_string(),
+            Data::String("Sami".into()),
+        ),
+        ("0409:00000409".to_string(), Data::U32(1)),
+    ];
+    let se_no: LanguageTag = "se-NO".parse().unwrap();
+    let se = InputListItem::try_from("043B:A000043B").unwrap();
+    let results = enable_for_default_user(&reg, &keys, &se_no, &se, &template).unwrap();
+    let written: Vec<String> = results.iter().map(|x| x.to_string()).collect();
+    assert_eq!(
+        written,
+        vec![
+            format!("{} [Languages] = en-US, se-NO: ok", keys.user_profile),
+            format!(
+                "{}\\se-NO [CachedLanguageName] = Sami: ok",
+                keys.user_profile
+            ),
+            format!("{}\\se-NO [043B:A000043B] = 1: ok", keys.user_profile),
+            format!("{} [2] = a000043b: ok", keys.preload),
+        ]
+    );
+    assert!(enable_for_default_user(&reg, &keys, &se_no, &se, &template)
+        .unwrap()
+        .is_empty());
+
+    // A layout for another language is preloaded through a substitute
+    let smj = InputListItem::try_from("043B:A0000C3B").unwrap();
+    let results = enable_for_default_user(&reg, &keys, &se_no, &smj, &[]).unwrap();
+    let written: Vec<(&str, &str)> = results.iter().map(|x| (&*x.name, &*x.data)).collect();
+    assert_eq!(
+        written,
+        vec![
+            ("043B:A0000C3B", "2"),
+            ("d001043b", "a0000c3b"),
+            ("3", "d001043b"),
+        ]
+    );
+    assert_eq!(reg.subkeys(&keys.user_profile).unwrap(), vec!["se-NO"]);
+}
@@ -0,0 +1,546 @@
+//! `.reg` files in the Windows Registry Editor 5.00 format, for moving the keys kbdi manages
+//! between machines.
+//!
+//! [`RegFile::export`] collects the installed `a*` layouts, the language subkeys of
+//! `User Profile`, `Preload` and `Substitutes`. [`RegFile::to_manifest`] turns such a file back
+//! into keyboards to install, enabling those listed under a language, so importing goes through
+//! the same code as `kbdi apply`. `Preload` and `Substitutes` are regenerated when keyboards are
+//! enabled, so they are only exported for reference.
+
+use crate::backend::{Data, Hive, KeyPath, RegistryBackend};
+use crate::keyboard::{keyboard_layouts_key, KeyboardRegKey};
+use crate::keyboard_win8::{preload_key, substitutes_key, user_profile_key};
+use crate::manifest::{KeyboardEntry, Manifest};
+use crate::regf::{self, REG_BINARY};
+use crate::types::InputListItem;
+use crate::Error;
+use std::convert::TryFrom;
+use std::fmt;
+use std::path::Path;
+
+const HEADER: &str = "Windows Registry Editor Version 5.00";
+
+/// Hex data is wrapped so no line is longer than this, as Registry Editor does.
+const MAX_LINE_LEN: usize = 80;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegFile {
+    pub keys: Vec<RegFileKey>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegFileKey {
+    pub path: KeyPath,
+    pub values: Vec<(String, Data)>,
+}
+
+fn invalid(line: usize, message: &str) -> Error {
+    Error::InvalidRegFile(format!("line {}: {}", line, message))
+}
+
+/// The name of `key` if it is a direct subkey of `parent`.
+fn child_name<'a>(key: &'a KeyPath, parent: &KeyPath) -> Option<&'a str> {
+    let prefix_len = parent.path.len() + 1;
+    if key.hive != parent.hive
+        || key.path.len() <= prefix_len
+        || !key.path.is_char_boundary(prefix_len)
+        || !key.path[..parent.path.len()].eq_ignore_ascii_case(&parent.path)
+        || key.path.as_bytes()[parent.path.len()] != b'\\'
+    {
+        return None;
+    }
+
+    Some(&key.path[prefix_len..]).filter(|x| !x.contains('\\'))
+}
+
+impl RegFile {
+    /// Collects the keys kbdi manages. Keys that do not exist are left out.
+    pub fn export(reg: &dyn RegistryBackend) -> Result<RegFile, Error> {
+        let mut paths = vec![];
+
+        if reg.key_exists(&keyboard_layouts_key()) {
+            for keyboard in KeyboardRegKey::installed(reg)? {
+                paths.push(keyboard_layouts_key().join(keyboard.regkey_id()));
+            }
+        }
+
+        let user_profile = user_profile_key();
+        if reg.key_exists(&user_profile) {
+            for tag in reg.subkeys(&user_profile)? {
+                paths.push(user_profile.join(&tag));
+            }
+        }
+
+        paths.extend(
+            vec![preload_key(), substitutes_key()]
+                .into_iter()
+                .filter(|x| reg.key_exists(x)),
+        );
+
+        let keys = paths
+            .into_iter()
+            .map(|path| {
+                Ok(RegFileKey {
+                    values: reg.values(&path)?,
+                    path,
+                })
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        Ok(RegFile { keys })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<RegFile, Error> {
+        RegFile::from_bytes(&std::fs::read(path)?)
+    }
+
+    /// Parses a file saved by Registry Editor, which is UTF-16 with a byte order mark, or one
+    /// saved as UTF-8.
+    pub fn from_bytes(bytes: &[u8]) -> Result<RegFile, Error> {
+        let text = match bytes {
+            [0xff, 0xfe, rest @ ..] => {
+                let units: Vec<u16> = rest
+                    .chunks_exact(2)
+                    .map(|x| u16::from_le_bytes([x[0], x[1]]))
+                    .collect();
+                String::from_utf16(&units)
+                    .map_err(|_| Error::InvalidRegFile("invalid UTF-16 text".into()))?
+            }
+            _ => String::from_utf8(bytes.to_vec())
+                .map_err(|_| Error::InvalidRegFile("expected UTF-16 or UTF-8 text".into()))?,
+        };
+
+        RegFile::parse(&text)
+    }
+
+    /// Writes the file as UTF-16 with a byte order mark, like Registry Editor.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let mut bytes = vec![0xff, 0xfe];
+        bytes.extend(
+            self.to_string()
+                .encode_utf16()
+                .flat_map(|x| x.to_le_bytes().to_vec()),
+        );
+        std::fs::write(path, bytes)?;
+        Ok(())
+    }
+
+    pub fn parse(text: &str) -> Result<RegFile, Error> {
+        let mut lines = logical_lines(text.trim_start_matches('\u{feff}'))
+            .into_iter()
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with(';'));
+
+        match lines.next() {
+            Some((_, line)) if line == HEADER => {}
+            Some((line, _)) => return Err(invalid(line, &format!("expected {:?}", HEADER))),
+            None => return Err(Error::InvalidRegFile("file is empty".into())),
+        }
+
+        let mut keys: Vec<RegFileKey> = vec![];
+        for (line, text) in lines {
+            if text.starts_with('[') {
+                let path = text
+                    .strip_prefix('[')
+                    .and_then(|x| x.strip_suffix(']'))
+                    .ok_or_else(|| invalid(line, "key name is missing its closing bracket"))?;
+                if path.starts_with('-') {
+                    return Err(invalid(line, "deleting keys is not supported"));
+                }
+
+                keys.push(RegFileKey {
+                    path: parse_key_path(path).map_err(|e| invalid(line, &e))?,
+                    values: vec![],
+                });
+                continue;
+            }
+
+            let key = keys
+                .last_mut()
+                .ok_or_else(|| invalid(line, "value is not under a key"))?;
+            let value = parse_value(&text).map_err(|e| invalid(line, &e))?;
+            key.values.push(value);
+        }
+
+        Ok(RegFile { keys })
+    }
+
+    /// The `a*` layouts in the file as keyboards to install. Those listed under a language in
+    /// `User Profile` are marked to be enabled.
+    pub fn to_manifest(&self) -> Result<Manifest, Error> {
+        let layouts_key = keyboard_layouts_key();
+        let user_profile = user_profile_key();
+
+        let enabled: Vec<String> = self
+            .keys
+            .iter()
+            .filter(|key| child_name(&key.path, &user_profile).is_some())
+            .flat_map(|key| key.values.iter())
+            // Keyboard layouts are listed as `LLLL:KKKKKKKK`, next to text services and other values.
+            .filter_map(|(name, _)| InputListItem::try_from(&**name).ok())
+            .filter_map(|x| x.klid())
+            .map(|klid| format!("{:08x}", klid))
+            .collect();
+
+        let mut keyboards = vec![];
+        for key in self.keys.iter() {
+            let klid = match child_name(&key.path, &layouts_key) {
+                Some(v) if v.starts_with('a') || v.starts_with('A') => v,
+                _ => continue,
+            };
+
+            let value = |name: &str| {
+                key.values
+                    .iter()
+                    .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                    .and_then(|(_, v)| v.as_str())
+                    .map(str::to_owned)
+            };
+            let required = |name: &str| {
+                value(name).ok_or_else(|| {
+                    Error::InvalidRegFile(format!("layout {} has no {:?} value", klid, name))
+                })
+            };
+
+            keyboards.push(KeyboardEntry {
+                tag: required("Layout Locale Name")?.parse()?,
+                layout: required("Layout Text")?,
+                guid: required("Layout Product Code")?,
+                dll: required("Layout File")?,
+                lang: value("Custom Language Name"),
+                enable: enabled.iter().any(|x| x.eq_ignore_ascii_case(klid)),
+            });
+        }
+
+        if keyboards.is_empty() {
+            return Err(Error::InvalidRegFile(
+                "no keyboards installed by kbdi are listed".into(),
+            ));
+        }
+
+        Manifest::from_keyboards(keyboards)
+    }
+}
+
+/// Trimmed lines with their line numbers, joining hex data continued with a trailing backslash.
+fn logical_lines(text: &str) -> Vec<(usize, String)> {
+    let mut lines: Vec<(usize, String)> = vec![];
+    let mut continued = false;
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        let (line, continues) = match line.strip_suffix('\\') {
+            Some(v) if !line.starts_with('[') => (v.trim_end(), true),
+            _ => (line, false),
+        };
+
+        match lines.last_mut() {
+            Some((_, last)) if continued => last.push_str(line),
+            _ => lines.push((i + 1, line.to_owned())),
+        }
+        continued = continues;
+    }
+
+    lines
+}
+
+fn parse_key_path(path: &str) -> Result<KeyPath, String> {
+    let (hive, path) = match path.find('\\') {
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None => (path, ""),
+    };
+
+    [Hive::LocalMachine, Hive::CurrentUser, Hive::Users]
+        .iter()
+        .find(|x| x.name().eq_ignore_ascii_case(hive))
+        .map(|x| KeyPath::new(*x, path.trim_end_matches('\\')))
+        .ok_or_else(|| format!("unsupported hive {:?}", hive))
+}
+
+/// Parses a quoted string at the start of `text`, returning it and the rest of the text.
+fn parse_quoted(text: &str) -> Result<(String, &str), String> {
+    let mut chars = text
+        .strip_prefix('"')
+        .ok_or_else(|| "expected a quoted string".to_owned())?
+        .char_indices();
+    let mut value = String::new();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((value, &text[i + 2..])),
+            '\\' => match chars.next() {
+                Some((_, c)) => value.push(c),
+                None => break,
+            },
+            c => value.push(c),
+        }
+    }
+
+    Err("string is missing its closing quote".into())
+}
+
+fn parse_hex(text: &str) -> Result<Vec<u8>, String> {
+    text.split(',')
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(|x| match x.len() {
+            1 | 2 => u8::from_str_radix(x, 16).map_err(|_| format!("invalid hex byte {:?}", x)),
+            _ => Err(format!("invalid hex byte {:?}", x)),
+        })
+        .collect()
+}
+
+fn parse_value(text: &str) -> Result<(String, Data), String> {
+    let (name, rest) = match text.strip_prefix('@') {
+        Some(rest) => (String::new(), rest),
+        None => parse_quoted(text)?,
+    };
+
+    let data = rest
+        .trim_start()
+        .strip_prefix('=')
+        .ok_or_else(|| "expected = after the value name".to_owned())?
+        .trim();
+
+    let data = if data == "-" {
+        return Err("deleting values is not supported".into());
+    } else if data.starts_with('"') {
+        match parse_quoted(data)? {
+            (value, "") => Data::String(value),
+            _ => return Err("unexpected text after the string".into()),
+        }
+    } else if let Some(v) = data.strip_prefix("dword:") {
+        match v.len() {
+            1..=8 => {
+                Data::U32(u32::from_str_radix(v, 16).map_err(|_| format!("invalid dword {:?}", v))?)
+            }
+            _ => return Err(format!("invalid dword {:?}", v)),
+        }
+    } else if let Some(v) = data.strip_prefix("hex:") {
+        regf::to_data(REG_BINARY, &parse_hex(v)?)
+    } else if let Some(v) = data.strip_prefix("hex(") {
+        let end = v
+            .find("):")
+            .ok_or_else(|| "hex type is missing its closing parenthesis".to_owned())?;
+        let data_type = u32::from_str_radix(&v[..end], 16)
+            .map_err(|_| format!("invalid value type {:?}", &v[..end]))?;
+        regf::to_data(data_type, &parse_hex(&v[end + 2..])?)
+    } else {
+        return Err(format!("unsupported value data {:?}", data));
+    };
+
+    Ok((name, data))
+}
+
+fn quote(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn format_value(name: &str, data: &Data) -> String {
+    let name = match name {
+        "" => "@".to_owned(),
+        name => quote(name),
+    };
+
+    match data {
+        Data::String(v) if !v.contains(&['\r', '\n', '\0'][..]) => {
+            return format!("{}={}", name, quote(v))
+        }
+        Data::U32(v) => return format!("{}=dword:{:08x}", name, v),
+        _ => {}
+    }
+
+    let (data_type, bytes) = regf::from_data(data);
+    let mut text = match data_type {
+        REG_BINARY => format!("{}=hex:", name),
+        ty => format!("{}=hex({:x}):", name, ty),
+    };
+
+    let mut line_len = text.chars().count();
+    for (i, byte) in bytes.iter().enumerate() {
+        if i + 1 == bytes.len() {
+            text.push_str(&format!("{:02x}", byte));
+            break;
+        }
+
+        text.push_str(&format!("{:02x},", byte));
+        line_len += 3;
+        if line_len + 3 > MAX_LINE_LEN - 1 {
+            text.push_str("\\\r\n  ");
+            line_len = 2;
+        }
+    }
+
+    text
+}
+
+impl fmt::Display for RegFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}\r\n", HEADER)?;
+
+        for key in self.keys.iter() {
+            write!(f, "\r\n[{}]\r\n", key.path)?;
+            for (name, data) in key.values.iter() {
+                write!(f, "{}\r\n", format_value(name, data))?;
+            }
+        }
+
+        f.write_str("\r\n")
+    }
+}
+
+#[test]
+fn test_reg_file_round_trip() {
+    let file = RegFile {
+        keys: vec![
+            RegFileKey {
+                path: KeyPath::new(Hive::CurrentUser, r"Keyboard Layout\Substitutes"),
+                values: vec![
+                    ("".into(), Data::String(r#"C:\"quoted""#.into())),
+                    ("d001043b".into(), Data::String("a000043b".into())),
+                    ("Count".into(), Data::U32(0x43b)),
+                    ("Big".into(), Data::U64(1 << 40)),
+                    ("Path".into(), Data::ExpandString("%SystemRoot%".into())),
+                    (
+                        "List".into(),
+                        Data::MultiString(vec!["a".into(), "b".into()]),
+                    ),
+                    ("Lines".into(), Data::String("a\nb".into())),
+                    ("Bytes".into(), Data::Binary((0..100).collect())),
+                    ("Nothing".into(), Data::None),
+                ],
+            },
+            RegFileKey {
+                path: KeyPath::new(Hive::LocalMachine, "SYSTEM"),
+                values: vec![],
+            },
+        ],
+    };
+
+    let text = file.to_string();
+    assert!(text.starts_with("Windows Registry Editor Version 5.00\r\n\r\n[HKEY_CURRENT_USER\\"));
+    assert!(text.contains("\"Count\"=dword:0000043b\r\n"));
+    assert!(text.lines().all(|x| x.len() <= MAX_LINE_LEN));
+    assert_eq!(RegFile::parse(&text).unwrap(), file);
+
+    let mut utf16 = vec![0xff, 0xfe];
+    utf16.extend(text.encode_utf16().flat_map(|x| x.to_le_bytes().to_vec()));
+    assert_eq!(RegFile::from_bytes(&utf16).unwrap(), file);
+}
+
+#[test]
+fn test_reg_file_parse() {
+    let file = RegFile::parse(
+        "Windows Registry Editor Version 5.00\n\
+         ; exported by hand\n\
+         [HKEY_LOCAL_MACHINE\\SYSTEM\\CurrentControlSet\\Control\\Keyboard Layouts\\a000043b]\n\
+         \"Layout Text\" = \"Northern Sami\"\n\
+         \"Layout Id\"=hex(1):30,00,31,00,\\\n  30,00,30,00,00,00\n",
+    )
+    .unwrap();
+
+    assert_eq!(file.keys.len(), 1);
+    assert_eq!(file.keys[0].path.hive, Hive::LocalMachine);
+    assert_eq!(
+        file.keys[0].values,
+        vec![
+            (
+                "Layout Text".to_string(),
+                Data::String("Northern Sami".into())
+            ),
+            ("Layout Id".to_string(), Data::String("0100".into())),
+        ]
+    );
+
+    let error = |text: &str| match RegFile::parse(text) {
+        Err(Error::InvalidRegFile(message)) => message,
+        other => panic!("expected an error, got {:?}", other),
+    };
+    let header = "Windows Registry Editor Version 5.00\n";
+    assert_eq!(
+        error("REGEDIT4\n"),
+        "line 1: expected \"Windows Registry Editor Version 5.00\""
+    );
+    assert_eq!(
+        error(&format!(
+            "{}[-HKEY_CURRENT_USER\\Keyboard Layout]\n",
+            header
+        )),
+        "line 2: deleting keys is not supported"
+    );
+    assert_eq!(
+        error(&format!("{}\n[HKEY_CURRENT_USER\\x]\n\"a\"=-\n", header)),
+        "line 4: deleting values is not supported"
+    );
+    assert_eq!(
+        error(&format!("{}[HKEY_CLASSES_ROOT\\x]\n", header)),
+        "line 2: unsupported hive \"HKEY_CLASSES_ROOT\""
+    );
+    assert_eq!(
+        error(&format!("{}[HKEY_USERS\\x]\n\"a\"=hex:1g\n", header)),
+        "line 3: invalid hex byte \"1g\""
+    );
+    assert_eq!(
+        error(&format!("{}\"a\"=\"b\"\n", header)),
+        "line 2: value is not under a key"
+    );
+}
+
+#[test]
+fn test_reg_file_export_to_manifest() {
+    let reg = crate::backend::MemoryRegistry::new();
+    reg.create_key(&keyboard_layouts_key().join("00000409"))
+        .unwrap();
+    KeyboardRegKey::create(
+        &reg,
+        &"se-NO".parse().unwrap(),
+        0x043b,
+        "Sámi",
+        "{1}",
+        "kbdse01.dll",
+        "Northern Sami",
+        &crate::keyboard::Ownership::new(None),
+    )
+    .unwrap();
+    KeyboardRegKey::create(
+        &reg,
+        &"sma-NO".parse().unwrap(),
+        0x043b,
+        "Sámi",
+        "{2}",
+        "kbdsma01.dll",
+        "Southern Sami",
+        &crate::keyboard::Ownership::new(None),
+    )
+    .unwrap();
+
+    let se = user_profile_key().join("se-NO");
+    reg.create_key(&se).unwrap();
+    reg.set_value(&se, "043B:A000043B", &Data::U32(1)).unwrap();
+    reg.create_key(&preload_key()).unwrap();
+    reg.set_value(&preload_key(), "1", &Data::String("d001043b".into()))
+        .unwrap();
+
+    let file = RegFile::export(&reg).unwrap();
+    let paths: Vec<String> = file.keys.iter().map(|x| x.path.to_string()).collect();
+    assert_eq!(
+        paths,
+        vec![
+            r"HKEY_LOCAL_MACHINE\SYSTEM\CurrentControlSet\Control\Keyboard Layouts\a000043b",
+            r"HKEY_LOCAL_MACHINE\SYSTEM\CurrentControlSet\Control\Keyboard Layouts\a001043b",
+            r"HKEY_CURRENT_USER\Control Panel\International\User Profile\se-NO",
+            r"HKEY_CURRENT_USER\Keyboard Layout\Preload",
+        ]
+    );
+
+    let manifest = RegFile::parse(&file.to_string())
+        .unwrap()
+        .to_manifest()
+        .unwrap();
+    assert_eq!(manifest.keyboards.len(), 2);
+    assert_eq!(manifest.keyboards[0].tag.as_str(), "se-NO");
+    assert_eq!(manifest.keyboards[0].guid, "{1}");
+    assert_eq!(manifest.keyboards[0].lang.as_deref(), Some("Sámi"));
+    assert!(manifest.keyboards[0].enable);
+    assert_eq!(manifest.keyboards[1].dll, "kbdsma01.dll");
+    assert!(!manifest.keyboards[1].enable);
+}
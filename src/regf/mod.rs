@@ -0,0 +1,627 @@
+//! Registry hive files (the `regf` format of `NTUSER.DAT`), read and written without Windows.
+//!
+//! [`Hive`] reads a whole file into memory and implements [`RegistryBackend`], resolving every
+//! [`KeyPath`] relative to the root key of the file, whichever hive it names. [`Hive::save`]
+//! writes a fresh, compacted file with valid checksums. Transaction logs are not replayed, so a
+//! file that was not cleanly unloaded is refused rather than read in an inconsistent state.
+
+mod read;
+mod write;
+
+use crate::backend::{self, Data, KeyPath, RegistryBackend};
+use std::cell::RefCell;
+use std::path::Path;
+
+pub const REG_NONE: u32 = 0;
+pub const REG_SZ: u32 = 1;
+pub const REG_EXPAND_SZ: u32 = 2;
+pub const REG_BINARY: u32 = 3;
+pub const REG_DWORD: u32 = 4;
+pub const REG_DWORD_BIG_ENDIAN: u32 = 5;
+pub const REG_MULTI_SZ: u32 = 7;
+pub const REG_QWORD: u32 = 11;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Not a registry hive file")]
+    InvalidSignature,
+
+    #[error("Unsupported hive format version {0}.{1}")]
+    UnsupportedVersion(u32, u32),
+
+    #[error("Base block checksum is 0x{found:08x}, expected 0x{expected:08x}")]
+    BadChecksum { expected: u32, found: u32 },
+
+    #[error("Hive was not cleanly unloaded; its transaction logs must be replayed first")]
+    Dirty,
+
+    #[error("Corrupt hive at cell 0x{offset:x}: {message}")]
+    Corrupt { offset: u32, message: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Value {
+    pub name: String,
+    pub data_type: u32,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Key {
+    pub name: String,
+    pub class_name: Option<String>,
+    /// FILETIME of the last change.
+    pub last_written: u64,
+    pub flags: u16,
+    /// Self-relative security descriptor. Keys without one inherit their parent's when written.
+    pub security: Option<Vec<u8>>,
+    pub values: Vec<Value>,
+    pub subkeys: Vec<Key>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Header {
+    sequence: u32,
+    minor_version: u32,
+    file_name: Vec<u8>,
+}
+
+pub struct Hive {
+    header: Header,
+    root: RefCell<Key>,
+}
+
+fn names_eq(a: &str, b: &str) -> bool {
+    a.eq_ignore_ascii_case(b) || a.to_uppercase() == b.to_uppercase()
+}
+
+fn now() -> u64 {
+    let now = chrono::Utc::now();
+    (now.timestamp() + 11_644_473_600) as u64 * 10_000_000
+        + now.timestamp_subsec_nanos() as u64 / 100
+}
+
+impl Key {
+    pub fn new(name: &str) -> Key {
+        Key {
+            name: name.to_owned(),
+            class_name: None,
+            last_written: now(),
+            flags: 0,
+            security: None,
+            values: vec![],
+            subkeys: vec![],
+        }
+    }
+
+    pub fn subkey(&self, name: &str) -> Option<&Key> {
+        self.subkeys.iter().find(|x| names_eq(&x.name, name))
+    }
+
+    fn subkey_mut(&mut self, name: &str) -> Option<&mut Key> {
+        self.subkeys.iter_mut().find(|x| names_eq(&x.name, name))
+    }
+
+    pub fn value(&self, name: &str) -> Option<&Value> {
+        self.values.iter().find(|x| names_eq(&x.name, name))
+    }
+}
+
+fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('\\').filter(|x| !x.is_empty())
+}
+
+fn decode_utf16(bytes: &[u8]) -> String {
+    let units: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|x| u16::from_le_bytes([x[0], x[1]]))
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+fn encode_utf16(s: &str) -> Vec<u8> {
+    s.encode_utf16()
+        .flat_map(|x| x.to_le_bytes().to_vec())
+        .collect()
+}
+
+fn decode_string(bytes: &[u8]) -> String {
+    let s = decode_utf16(bytes);
+    match s.find('\0') {
+        Some(i) => s[..i].to_owned(),
+        None => s,
+    }
+}
+
+/// Converts raw value data to the form used by [`RegistryBackend`]. Data that does not fit its
+/// declared type is returned as binary.
+pub fn to_data(data_type: u32, bytes: &[u8]) -> Data {
+    match data_type {
+        REG_NONE if bytes.is_empty() => Data::None,
+        REG_SZ => Data::String(decode_string(bytes)),
+        REG_EXPAND_SZ => Data::ExpandString(decode_string(bytes)),
+        REG_MULTI_SZ => Data::MultiString(
+            decode_utf16(bytes)
+                .split('\0')
+                .filter(|x| !x.is_empty())
+                .map(str::to_owned)
+                .collect(),
+        ),
+        REG_DWORD if bytes.len() == 4 => {
+            Data::U32(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        }
+        REG_DWORD_BIG_ENDIAN if bytes.len() == 4 => {
+            Data::U32(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        }
+        REG_QWORD if bytes.len() == 8 => {
+            let mut x = [0u8; 8];
+            x.copy_from_slice(bytes);
+            Data::U64(u64::from_le_bytes(x))
+        }
+        _ => Data::Binary(bytes.to_vec()),
+    }
+}
+
+pub fn from_data(data: &Data) -> (u32, Vec<u8>) {
+    let string = |s: &str| encode_utf16(&format!("{}\0", s));
+
+    match data {
+        Data::None => (REG_NONE, vec![]),
+        Data::String(v) => (REG_SZ, string(v)),
+        Data::ExpandString(v) => (REG_EXPAND_SZ, string(v)),
+        Data::MultiString(v) => {
+            let mut bytes: Vec<u8> = v.iter().flat_map(|x| string(x)).collect();
+            bytes.extend_from_slice(&[0, 0]);
+            (REG_MULTI_SZ, bytes)
+        }
+        Data::U32(v) => (REG_DWORD, v.to_le_bytes().to_vec()),
+        Data::U64(v) => (REG_QWORD, v.to_le_bytes().to_vec()),
+        Data::Binary(v) => (REG_BINARY, v.clone()),
+    }
+}
+
+impl Hive {
+    /// An empty hive whose root key has the given name.
+    pub fn new(root_name: &str) -> Hive {
+        let mut root = Key::new(root_name);
+        root.flags = write::KEY_HIVE_ENTRY | write::KEY_NO_DELETE;
+
+        Hive {
+            header: Header {
+                sequence: 0,
+                minor_version: 5,
+                file_name: vec![],
+            },
+            root: RefCell::new(root),
+        }
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Hive, Error> {
+        Hive::from_bytes(&std::fs::read(path)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Hive, Error> {
+        let (header, root) = read::read(bytes)?;
+        Ok(Hive {
+            header,
+            root: RefCell::new(root),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        write::write(&self.header, &self.root.borrow())
+    }
+
+    /// Writes the hive to `path`, replacing it only once the whole file has been written.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".kbdi-tmp");
+
+        std::fs::write(&tmp, self.to_bytes())?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    pub fn root(&self) -> Key {
+        self.root.borrow().clone()
+    }
+
+    fn with_key<T>(&self, key: &KeyPath, f: impl FnOnce(&Key) -> T) -> Result<T, backend::Error> {
+        let root = self.root.borrow();
+        let mut current = &*root;
+        for name in components(&key.path) {
+            current = current
+                .subkey(name)
+                .ok_or_else(|| backend::Error::KeyNotFound(key.clone()))?;
+        }
+        Ok(f(current))
+    }
+
+    fn with_key_mut<T>(
+        &self,
+        key: &KeyPath,
+        f: impl FnOnce(&mut Key) -> T,
+    ) -> Result<T, backend::Error> {
+        let mut root = self.root.borrow_mut();
+        let mut current = &mut *root;
+        for name in components(&key.path) {
+            current = current
+                .subkey_mut(name)
+                .ok_or_else(|| backend::Error::KeyNotFound(key.clone()))?;
+        }
+        current.last_written = now();
+        Ok(f(current))
+    }
+}
+
+impl RegistryBackend for Hive {
+    fn key_exists(&self, key: &KeyPath) -> bool {
+        self.with_key(key, |_| ()).is_ok()
+    }
+
+    fn create_key(&self, key: &KeyPath) -> Result<(), backend::Error> {
+        let mut root = self.root.borrow_mut();
+        let mut current = &mut *root;
+        for name in components(&key.path) {
+            let index = match current.subkeys.iter().position(|x| names_eq(&x.name, name)) {
+                Some(i) => i,
+                None => {
+                    current.last_written = now();
+                    current.subkeys.push(Key::new(name));
+                    current.subkeys.len() - 1
+                }
+            };
+            current = &mut current.subkeys[index];
+        }
+        Ok(())
+    }
+
+    fn delete_key(&self, key: &KeyPath) -> Result<(), backend::Error> {
+        let parent = match key.parent() {
+            Some(v) => v,
+            None => {
+                return Err(backend::Error::Access(
+                    key.clone(),
+                    "cannot delete a hive root".into(),
+                ))
+            }
+        };
+
+        let name = key.name();
+        self.with_key_mut(&parent, |parent| {
+            let len = parent.subkeys.len();
+            parent.subkeys.retain(|x| !names_eq(&x.name, name));
+            parent.subkeys.len() != len
+        })
+        .and_then(|deleted| match deleted {
+            true => Ok(()),
+            false => Err(backend::Error::KeyNotFound(key.clone())),
+        })
+    }
+
+    fn subkeys(&self, key: &KeyPath) -> Result<Vec<String>, backend::Error> {
+        self.with_key(key, |k| k.subkeys.iter().map(|x| x.name.clone()).collect())
+    }
+
+    fn values(&self, key: &KeyPath) -> Result<Vec<(String, Data)>, backend::Error> {
+        self.with_key(key, |k| {
+            k.values
+                .iter()
+                .map(|x| (x.name.clone(), to_data(x.data_type, &x.data)))
+                .collect()
+        })
+    }
+
+    fn value(&self, key: &KeyPath, name: &str) -> Result<Data, backend::Error> {
+        self.with_key(key, |k| {
+            k.value(name).map(|x| to_data(x.data_type, &x.data))
+        })?
+        .ok_or_else(|| backend::Error::ValueNotFound(key.clone(), name.to_string()))
+    }
+
+    fn set_value(&self, key: &KeyPath, name: &str, data: &Data) -> Result<(), backend::Error> {
+        let (data_type, data) = from_data(data);
+        self.with_key_mut(key, |k| {
+            match k.values.iter_mut().find(|x| names_eq(&x.name, name)) {
+                Some(v) => {
+                    v.data_type = data_type;
+                    v.data = data;
+                }
+                None => k.values.push(Value {
+                    name: name.to_string(),
+                    data_type,
+                    data,
+                }),
+            }
+        })
+    }
+
+    fn delete_value(&self, key: &KeyPath, name: &str) -> Result<(), backend::Error> {
+        let deleted = self.with_key_mut(key, |k| {
+            let len = k.values.len();
+            k.values.retain(|x| !names_eq(&x.name, name));
+            k.values.len() != len
+        })?;
+
+        match deleted {
+            true => Ok(()),
+            false => Err(backend::Error::ValueNotFound(key.clone(), name.to_string())),
+        }
+    }
+}
+
+/// Builds a hive by hand, using list and name encodings that [`Hive::save`] never writes.
+#[cfg(test)]
+fn fixture() -> Vec<u8> {
+    fn cell(bins: &mut Vec<u8>, data: &[u8]) -> u32 {
+        let offset = bins.len() as u32;
+        let size = (data.len() + 4).div_ceil(8) * 8;
+        bins.extend_from_slice(&(-(size as i32)).to_le_bytes());
+        bins.extend_from_slice(data);
+        bins.resize(offset as usize + size, 0);
+        offset
+    }
+
+    fn nk(
+        name: &[u8],
+        flags: u16,
+        subkeys: (u32, u32),
+        values: (u32, u32),
+        class: Option<&[u8]>,
+    ) -> Vec<u8> {
+        let mut nk = vec![0u8; 0x4c];
+        nk[0..2].copy_from_slice(b"nk");
+        nk[0x02..0x04].copy_from_slice(&flags.to_le_bytes());
+        nk[0x10..0x14].copy_from_slice(&read::INVALID_OFFSET.to_le_bytes());
+        nk[0x14..0x18].copy_from_slice(&subkeys.0.to_le_bytes());
+        nk[0x1c..0x20].copy_from_slice(&subkeys.1.to_le_bytes());
+        nk[0x24..0x28].copy_from_slice(&values.0.to_le_bytes());
+        nk[0x28..0x2c].copy_from_slice(&values.1.to_le_bytes());
+        nk[0x2c..0x30].copy_from_slice(&read::INVALID_OFFSET.to_le_bytes());
+        let (class, class_len) = class.map_or((read::INVALID_OFFSET, 0), |x| {
+            (
+                u32::from_le_bytes([x[0], x[1], x[2], x[3]]),
+                x.len() as u16 - 4,
+            )
+        });
+        nk[0x30..0x34].copy_from_slice(&class.to_le_bytes());
+        nk[0x48..0x4a].copy_from_slice(&(name.len() as u16).to_le_bytes());
+        nk[0x4a..0x4c].copy_from_slice(&class_len.to_le_bytes());
+        nk.extend_from_slice(name);
+        nk
+    }
+
+    let mut bins = vec![0u8; 32];
+    bins[0..4].copy_from_slice(b"hbin");
+
+    // A UTF-16 named key with a class name and an inline REG_DWORD.
+    let mut vk = vec![0u8; 0x14];
+    vk[0..2].copy_from_slice(b"vk");
+    vk[0x02..0x04].copy_from_slice(&5u16.to_le_bytes());
+    vk[0x04..0x08].copy_from_slice(&0x8000_0004u32.to_le_bytes());
+    vk[0x08..0x0c].copy_from_slice(&0x43bu32.to_le_bytes());
+    vk[0x0c..0x10].copy_from_slice(&REG_DWORD.to_le_bytes());
+    vk[0x10..0x12].copy_from_slice(&read::VALUE_COMP_NAME.to_le_bytes());
+    vk.extend_from_slice(b"Value");
+    let vk = cell(&mut bins, &vk);
+    let value_list = cell(&mut bins, &vk.to_le_bytes());
+    let class_name = cell(&mut bins, &encode_utf16("Class"));
+    let mut class = class_name.to_le_bytes().to_vec();
+    class.extend_from_slice(&encode_utf16("Class"));
+    let sami = cell(
+        &mut bins,
+        &nk(
+            &encode_utf16("Sámi ŋ"),
+            0,
+            (0, 0),
+            (1, value_list),
+            Some(&class),
+        ),
+    );
+
+    // Two subkeys under an index root of leaf lists, and one under a fast leaf.
+    let a = cell(
+        &mut bins,
+        &nk(b"a", read::KEY_COMP_NAME, (0, 0), (0, 0), None),
+    );
+    let b = cell(
+        &mut bins,
+        &nk(b"b", read::KEY_COMP_NAME, (0, 0), (0, 0), None),
+    );
+    let mut li = b"li\x01\x00".to_vec();
+    li.extend_from_slice(&a.to_le_bytes());
+    let li_a = cell(&mut bins, &li);
+    let mut li = b"li\x01\x00".to_vec();
+    li.extend_from_slice(&b.to_le_bytes());
+    let li_b = cell(&mut bins, &li);
+    let mut ri = b"ri\x02\x00".to_vec();
+    ri.extend_from_slice(&li_a.to_le_bytes());
+    ri.extend_from_slice(&li_b.to_le_bytes());
+    let ri = cell(&mut bins, &ri);
+    let list = cell(
+        &mut bins,
+        &nk(b"List", read::KEY_COMP_NAME, (2, ri), (0, 0), None),
+    );
+
+    let mut lf = b"lf\x02\x00".to_vec();
+    lf.extend_from_slice(&list.to_le_bytes());
+    lf.extend_from_slice(b"List");
+    lf.extend_from_slice(&sami.to_le_bytes());
+    lf.extend_from_slice(&[0x53, 0, 0xe1, 0]);
+    let lf = cell(&mut bins, &lf);
+    let root = cell(
+        &mut bins,
+        &nk(
+            b"ROOT",
+            read::KEY_COMP_NAME | write::KEY_HIVE_ENTRY,
+            (2, lf),
+            (0, 0),
+            None,
+        ),
+    );
+
+    // The rest of the bin is one free cell.
+    let used = bins.len();
+    bins.resize(used.div_ceil(4096) * 4096, 0);
+    let len = bins.len() as u32;
+    bins[used..used + 4].copy_from_slice(&(len - used as u32).to_le_bytes());
+    bins[0x08..0x0c].copy_from_slice(&len.to_le_bytes());
+
+    let mut base = vec![0u8; read::BASE_BLOCK_SIZE];
+    base[0..4].copy_from_slice(b"regf");
+    base[0x04..0x08].copy_from_slice(&7u32.to_le_bytes());
+    base[0x08..0x0c].copy_from_slice(&7u32.to_le_bytes());
+    base[0x14..0x18].copy_from_slice(&1u32.to_le_bytes());
+    base[0x18..0x1c].copy_from_slice(&3u32.to_le_bytes());
+    base[0x24..0x28].copy_from_slice(&root.to_le_bytes());
+    base[0x28..0x2c].copy_from_slice(&len.to_le_bytes());
+    let sum = read::checksum(&base);
+    base[0x1fc..0x200].copy_from_slice(&sum.to_le_bytes());
+
+    base.extend_from_slice(&bins);
+    base
+}
+
+#[test]
+fn test_hive_fixture() {
+    let hive = Hive::from_bytes(&fixture()).unwrap();
+    let root = hive.root();
+
+    assert_eq!(root.name, "ROOT");
+    let names: Vec<&str> = root.subkeys.iter().map(|x| &*x.name).collect();
+    assert_eq!(names, vec!["List", "Sámi ŋ"]);
+
+    let list = root.subkey("list").unwrap();
+    let names: Vec<&str> = list.subkeys.iter().map(|x| &*x.name).collect();
+    assert_eq!(names, vec!["a", "b"]);
+
+    let sami = root.subkey("SÁMI Ŋ").unwrap();
+    assert_eq!(sami.class_name.as_deref(), Some("Class"));
+    assert_eq!(
+        hive.value(&KeyPath::new(backend::Hive::Users, "Sámi ŋ"), "value")
+            .unwrap(),
+        Data::U32(0x43b)
+    );
+
+    // Saving rewrites every list as an lh list, and gives each key a security descriptor.
+    fn strip(key: &mut Key) {
+        key.security = None;
+        key.flags &= !write::KEY_NO_DELETE;
+        key.subkeys.iter_mut().for_each(strip);
+    }
+
+    let mut saved = Hive::from_bytes(&hive.to_bytes()).unwrap().root();
+    assert!(saved.security.is_some());
+    strip(&mut saved);
+    assert_eq!(saved, root);
+}
+
+#[test]
+fn test_hive_rejects_bad_files() {
+    let mut bytes = fixture();
+    bytes[0x30] = b'x';
+    assert!(matches!(
+        Hive::from_bytes(&bytes),
+        Err(Error::BadChecksum { .. })
+    ));
+
+    let mut bytes = fixture();
+    bytes[0x08] += 1;
+    let sum = read::checksum(&bytes[..read::BASE_BLOCK_SIZE]);
+    bytes[0x1fc..0x200].copy_from_slice(&sum.to_le_bytes());
+    assert!(matches!(Hive::from_bytes(&bytes), Err(Error::Dirty)));
+
+    assert!(matches!(
+        Hive::from_bytes(b"not a hive"),
+        Err(Error::InvalidSignature)
+    ));
+
+    let mut bytes = fixture();
+    let len = bytes.len();
+    bytes.truncate(len - 4096);
+    assert!(Hive::from_bytes(&bytes).is_err());
+}
+
+#[test]
+fn test_hive_round_trip() {
+    let hive = Hive::new("ROOT");
+    let key = KeyPath::new(backend::Hive::CurrentUser, r"Keyboard Layout\Preload");
+    hive.create_key(&key).unwrap();
+    hive.set_value(&key, "1", &Data::String("d001043b".into()))
+        .unwrap();
+    hive.set_value(&key, "Empty", &Data::None).unwrap();
+    hive.set_value(&key, "Dword", &Data::U32(7)).unwrap();
+    hive.set_value(&key, "Qword", &Data::U64(u64::MAX)).unwrap();
+    hive.set_value(
+        &key,
+        "Multi",
+        &Data::MultiString(vec!["a".into(), "b".into()]),
+    )
+    .unwrap();
+    hive.set_value(&key, "Big", &Data::Binary(vec![0xab; 40_000]))
+        .unwrap();
+
+    let many = KeyPath::new(backend::Hive::CurrentUser, "Many");
+    for i in 0..600 {
+        hive.create_key(&many.join(&format!("key {}", i))).unwrap();
+    }
+
+    let bytes = hive.to_bytes();
+    assert_eq!(bytes.len() % 4096, 0);
+    assert_eq!(
+        read::u32_at(&bytes, 0x1fc),
+        Some(read::checksum(&bytes[..read::BASE_BLOCK_SIZE]))
+    );
+
+    let saved = Hive::from_bytes(&bytes).unwrap();
+    assert_eq!(saved.values(&key).unwrap(), hive.values(&key).unwrap());
+    assert_eq!(saved.subkeys(&many).unwrap().len(), 600);
+    assert!(saved.key_exists(&many.join("KEY 599")));
+    assert_eq!(saved.root().name, "ROOT");
+
+    // Saving again only bumps the sequence numbers.
+    let again = Hive::from_bytes(&saved.to_bytes()).unwrap();
+    assert_eq!(again.root(), saved.root());
+    assert_eq!(again.header.sequence, saved.header.sequence + 1);
+}
+
+#[cfg(not(feature = "legacy"))]
+#[test]
+fn test_hive_default_profile() {
+    use crate::keyboard_win8::regenerate_given_registry;
+
+    let user_profile = KeyPath::new(
+        backend::Hive::CurrentUser,
+        r"Control Panel\International\User Profile",
+    );
+    let substitutes = KeyPath::new(backend::Hive::CurrentUser, r"Keyboard Layout\Substitutes");
+    let preload = KeyPath::new(backend::Hive::CurrentUser, r"Keyboard Layout\Preload");
+
+    let hive = Hive::new("ROOT");
+    hive.create_key(&user_profile.join("en-US")).unwrap();
+    hive.create_key(&substitutes).unwrap();
+    hive.create_key(&preload).unwrap();
+    hive.set_value(&user_profile.join("en-US"), "0409:00000409", &Data::U32(1))
+        .unwrap();
+    hive.set_value(&preload, "1", &Data::String("00000409".into()))
+        .unwrap();
+    let hive = Hive::from_bytes(&hive.to_bytes()).unwrap();
+
+    hive.create_key(&user_profile.join("se-NO")).unwrap();
+    hive.set_value(&user_profile.join("se-NO"), "043B:A000043B", &Data::U32(1))
+        .unwrap();
+    regenerate_given_registry(&hive, &user_profile, &substitutes, &preload).unwrap();
+
+    let saved = Hive::from_bytes(&hive.to_bytes()).unwrap();
+    assert_eq!(
+        saved.values(&preload).unwrap(),
+        vec![
+            ("1".to_string(), Data::String("00000409".into())),
+            ("2".to_string(), Data::String("a000043b".into())),
+        ]
+    );
+}
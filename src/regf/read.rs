@@ -0,0 +1,295 @@
+use super::{decode_utf16, Error, Header, Key, Value};
+
+pub(super) const BASE_BLOCK_SIZE: usize = 4096;
+pub(super) const INVALID_OFFSET: u32 = 0xffff_ffff;
+pub(super) const KEY_COMP_NAME: u16 = 0x0020;
+pub(super) const VALUE_COMP_NAME: u16 = 0x0001;
+pub(super) const BIG_DATA_SEGMENT: usize = 16344;
+
+/// Keys nested deeper than this are treated as a cycle in a corrupt file.
+const MAX_DEPTH: usize = 512;
+
+pub(super) fn u16_at(buf: &[u8], at: usize) -> Option<u16> {
+    buf.get(at..at + 2)
+        .map(|x| u16::from_le_bytes([x[0], x[1]]))
+}
+
+pub(super) fn u32_at(buf: &[u8], at: usize) -> Option<u32> {
+    buf.get(at..at + 4)
+        .map(|x| u32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+}
+
+fn u64_at(buf: &[u8], at: usize) -> Option<u64> {
+    Some(u32_at(buf, at)? as u64 | (u32_at(buf, at + 4)? as u64) << 32)
+}
+
+/// The XOR of the first 127 dwords of the base block.
+pub(super) fn checksum(base: &[u8]) -> u32 {
+    let sum = (0..127).fold(0u32, |acc, i| acc ^ u32_at(base, i * 4).unwrap_or(0));
+
+    match sum {
+        0 => 1,
+        0xffff_ffff => 0xffff_fffe,
+        x => x,
+    }
+}
+
+fn decode_name(bytes: &[u8], compressed: bool) -> String {
+    match compressed {
+        // Compressed names are Latin-1.
+        true => bytes.iter().map(|&x| x as char).collect(),
+        false => decode_utf16(bytes),
+    }
+}
+
+pub(super) fn read(bytes: &[u8]) -> Result<(Header, Key), Error> {
+    if bytes.len() < BASE_BLOCK_SIZE || &bytes[0..4] != b"regf" {
+        return Err(Error::InvalidSignature);
+    }
+
+    let base = &bytes[..BASE_BLOCK_SIZE];
+    let field = |at| u32_at(base, at).unwrap_or(0);
+
+    let (major, minor) = (field(0x14), field(0x18));
+    if major != 1 || !(2..=6).contains(&minor) {
+        return Err(Error::UnsupportedVersion(major, minor));
+    }
+
+    let expected = checksum(base);
+    let found = field(0x1fc);
+    if expected != found {
+        return Err(Error::BadChecksum { expected, found });
+    }
+
+    if field(0x04) != field(0x08) {
+        return Err(Error::Dirty);
+    }
+
+    let bins_size = field(0x28) as usize;
+    let bins = bytes
+        .get(BASE_BLOCK_SIZE..BASE_BLOCK_SIZE + bins_size)
+        .filter(|x| x.starts_with(b"hbin"))
+        .ok_or_else(|| corrupt(0, "hive bins are missing or truncated"))?;
+
+    let reader = Reader { bins, minor };
+    let root = reader.key(field(0x24), 0)?;
+
+    Ok((
+        Header {
+            sequence: field(0x04),
+            minor_version: minor,
+            file_name: base[0x30..0x70].to_vec(),
+        },
+        root,
+    ))
+}
+
+fn corrupt(offset: u32, message: &str) -> Error {
+    Error::Corrupt {
+        offset,
+        message: message.to_owned(),
+    }
+}
+
+struct Reader<'a> {
+    bins: &'a [u8],
+    minor: u32,
+}
+
+impl<'a> Reader<'a> {
+    /// The data of the cell at `offset`, without its size field.
+    fn cell(&self, offset: u32) -> Result<&'a [u8], Error> {
+        let start = offset as usize;
+        let size = match u32_at(self.bins, start) {
+            Some(v) if offset != INVALID_OFFSET => (v as i32).wrapping_abs() as u32 as usize,
+            _ => return Err(corrupt(offset, "cell offset is out of bounds")),
+        };
+
+        if size < 4 {
+            return Err(corrupt(offset, "cell is too small"));
+        }
+
+        self.bins
+            .get(start + 4..start + size)
+            .ok_or_else(|| corrupt(offset, "cell extends past the end of the hive"))
+    }
+
+    fn signed_cell(
+        &self,
+        offset: u32,
+        signature: &[u8],
+        min_len: usize,
+    ) -> Result<&'a [u8], Error> {
+        let cell = self.cell(offset)?;
+
+        if !cell.starts_with(signature) {
+            return Err(corrupt(
+                offset,
+                &format!("expected a {} cell", String::from_utf8_lossy(signature)),
+            ));
+        }
+
+        if cell.len() < min_len {
+            return Err(corrupt(offset, "cell is truncated"));
+        }
+
+        Ok(cell)
+    }
+
+    fn key(&self, offset: u32, depth: usize) -> Result<Key, Error> {
+        if depth > MAX_DEPTH {
+            return Err(corrupt(offset, "keys are nested too deeply"));
+        }
+
+        let nk = self.signed_cell(offset, b"nk", 0x4c)?;
+        let field = |at| u32_at(nk, at).unwrap_or(0);
+        let flags = u16_at(nk, 0x02).unwrap_or(0);
+        let name_len = u16_at(nk, 0x48).unwrap_or(0) as usize;
+        let class_len = u16_at(nk, 0x4a).unwrap_or(0) as usize;
+
+        let name = nk
+            .get(0x4c..0x4c + name_len)
+            .ok_or_else(|| corrupt(offset, "key name is truncated"))?;
+
+        let class_name = match field(0x30) {
+            INVALID_OFFSET => None,
+            _ if class_len == 0 => None,
+            class => Some(decode_utf16(
+                self.cell(class)?
+                    .get(..class_len)
+                    .ok_or_else(|| corrupt(class, "class name is truncated"))?,
+            )),
+        };
+
+        let security = match field(0x2c) {
+            INVALID_OFFSET => None,
+            sk => Some(self.security(sk)?),
+        };
+
+        let values = match field(0x24) {
+            0 => vec![],
+            count => {
+                let list = self.cell(field(0x28))?;
+                (0..count as usize)
+                    .map(|i| {
+                        let value = u32_at(list, i * 4)
+                            .ok_or_else(|| corrupt(field(0x28), "value list is truncated"))?;
+                        self.value(value)
+                    })
+                    .collect::<Result<Vec<_>, _>>()?
+            }
+        };
+
+        let mut subkey_offsets = vec![];
+        if field(0x14) > 0 {
+            self.subkey_offsets(field(0x1c), &mut subkey_offsets, 0)?;
+        }
+
+        let subkeys = subkey_offsets
+            .into_iter()
+            .map(|x| self.key(x, depth + 1))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Key {
+            name: decode_name(name, flags & KEY_COMP_NAME != 0),
+            class_name,
+            last_written: u64_at(nk, 0x04).unwrap_or(0),
+            flags,
+            security,
+            values,
+            subkeys,
+        })
+    }
+
+    fn subkey_offsets(&self, offset: u32, out: &mut Vec<u32>, depth: usize) -> Result<(), Error> {
+        let list = self.cell(offset)?;
+        let count = u16_at(list, 0x02).unwrap_or(0) as usize;
+        let entry_size = match list.get(0..2) {
+            Some(b"lf") | Some(b"lh") => 8,
+            Some(b"li") | Some(b"ri") => 4,
+            _ => return Err(corrupt(offset, "unknown subkey list type")),
+        };
+
+        for i in 0..count {
+            let entry = u32_at(list, 4 + i * entry_size)
+                .ok_or_else(|| corrupt(offset, "subkey list is truncated"))?;
+
+            if list.starts_with(b"ri") {
+                if depth > 0 {
+                    return Err(corrupt(offset, "index roots are nested"));
+                }
+                self.subkey_offsets(entry, out, depth + 1)?;
+            } else {
+                out.push(entry);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn value(&self, offset: u32) -> Result<Value, Error> {
+        let vk = self.signed_cell(offset, b"vk", 0x14)?;
+        let name_len = u16_at(vk, 0x02).unwrap_or(0) as usize;
+        let size = u32_at(vk, 0x04).unwrap_or(0);
+        let data_offset = u32_at(vk, 0x08).unwrap_or(0);
+        let flags = u16_at(vk, 0x10).unwrap_or(0);
+
+        let name = vk
+            .get(0x14..0x14 + name_len)
+            .ok_or_else(|| corrupt(offset, "value name is truncated"))?;
+
+        let data = if size & 0x8000_0000 != 0 {
+            // Up to four bytes are stored in place of the data offset.
+            let len = ((size & 0x7fff_ffff) as usize).min(4);
+            vk[0x08..0x08 + len].to_vec()
+        } else if size == 0 {
+            vec![]
+        } else {
+            self.data(data_offset, size as usize)?
+        };
+
+        Ok(Value {
+            name: decode_name(name, flags & VALUE_COMP_NAME != 0),
+            data_type: u32_at(vk, 0x0c).unwrap_or(0),
+            data,
+        })
+    }
+
+    fn data(&self, offset: u32, len: usize) -> Result<Vec<u8>, Error> {
+        let cell = self.cell(offset)?;
+
+        if self.minor >= 4 && len > BIG_DATA_SEGMENT && cell.starts_with(b"db") {
+            let count = u16_at(cell, 0x02).unwrap_or(0) as usize;
+            let list_offset = u32_at(cell, 0x04).unwrap_or(INVALID_OFFSET);
+            let list = self.cell(list_offset)?;
+            let mut data = Vec::with_capacity(len);
+
+            for i in 0..count {
+                let segment = u32_at(list, i * 4)
+                    .ok_or_else(|| corrupt(list_offset, "big data list is truncated"))?;
+                let segment = self.cell(segment)?;
+                let take = (len - data.len()).min(segment.len()).min(BIG_DATA_SEGMENT);
+                data.extend_from_slice(&segment[..take]);
+            }
+
+            if data.len() != len {
+                return Err(corrupt(offset, "big data is truncated"));
+            }
+
+            return Ok(data);
+        }
+
+        cell.get(..len)
+            .map(|x| x.to_vec())
+            .ok_or_else(|| corrupt(offset, "value data is truncated"))
+    }
+
+    fn security(&self, offset: u32) -> Result<Vec<u8>, Error> {
+        let sk = self.signed_cell(offset, b"sk", 0x14)?;
+        let len = u32_at(sk, 0x10).unwrap_or(0) as usize;
+
+        sk.get(0x14..0x14 + len)
+            .map(|x| x.to_vec())
+            .ok_or_else(|| corrupt(offset, "security descriptor is truncated"))
+    }
+}
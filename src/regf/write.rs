@@ -0,0 +1,334 @@
+use super::read::{
+    checksum, BASE_BLOCK_SIZE, BIG_DATA_SEGMENT, INVALID_OFFSET, KEY_COMP_NAME, VALUE_COMP_NAME,
+};
+use super::{encode_utf16, now, Header, Key, Value};
+
+pub(super) const KEY_HIVE_ENTRY: u16 = 0x0004;
+pub(super) const KEY_NO_DELETE: u16 = 0x0008;
+/// Flags that only make sense in a loaded hive, or that are recomputed when writing.
+const KEY_TRANSIENT_FLAGS: u16 = 0x0001 | 0x0002 | KEY_COMP_NAME;
+
+const BIN_SIZE: usize = 4096;
+const BIN_HEADER_SIZE: usize = 32;
+/// Subkey lists longer than this are split under an index root.
+const MAX_LIST_ENTRIES: usize = 512;
+
+/// A self-relative security descriptor with no DACL, for hives created from scratch.
+const DEFAULT_SECURITY: [u8; 20] = [
+    1, 0, 0x00, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+];
+
+/// Names made only of Latin-1 characters are stored one byte per character.
+fn encode_name(name: &str) -> (Vec<u8>, bool) {
+    if name.chars().all(|c| (c as u32) < 0x100) {
+        (name.chars().map(|c| c as u8).collect(), true)
+    } else {
+        (encode_utf16(name), false)
+    }
+}
+
+fn sort_key(name: &str) -> Vec<u16> {
+    name.to_uppercase().encode_utf16().collect()
+}
+
+pub(super) fn name_hash(name: &str) -> u32 {
+    sort_key(name)
+        .into_iter()
+        .fold(0u32, |hash, c| hash.wrapping_mul(37).wrapping_add(c as u32))
+}
+
+fn put_u16(buf: &mut [u8], at: usize, value: u16) {
+    buf[at..at + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut [u8], at: usize, value: u32) {
+    buf[at..at + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(buf: &mut [u8], at: usize, value: u64) {
+    buf[at..at + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+struct SecurityCell {
+    descriptor: Vec<u8>,
+    offset: u32,
+    references: u32,
+}
+
+struct Writer {
+    bins: Vec<u8>,
+    /// Start of the free space in the last bin.
+    used: usize,
+    minor_version: u32,
+    timestamp: u64,
+    security: Vec<SecurityCell>,
+}
+
+impl Writer {
+    /// Marks the rest of the last bin as a free cell.
+    fn close_bin(&mut self) {
+        let end = self.bins.len();
+        if self.used < end {
+            put_u32(&mut self.bins, self.used, (end - self.used) as u32);
+            self.used = end;
+        }
+    }
+
+    fn new_bin(&mut self, cell_size: usize) {
+        self.close_bin();
+
+        let start = self.bins.len();
+        let size = (BIN_HEADER_SIZE + cell_size).div_ceil(BIN_SIZE) * BIN_SIZE;
+        self.bins.resize(start + size, 0);
+
+        let header = &mut self.bins[start..start + BIN_HEADER_SIZE];
+        header[0..4].copy_from_slice(b"hbin");
+        put_u32(header, 0x04, start as u32);
+        put_u32(header, 0x08, size as u32);
+        put_u64(header, 0x14, self.timestamp);
+        self.used = start + BIN_HEADER_SIZE;
+    }
+
+    /// Allocates a cell for `len` bytes of data and returns its offset.
+    fn alloc(&mut self, len: usize) -> u32 {
+        let size = (len + 4).div_ceil(8) * 8;
+        if self.used + size > self.bins.len() {
+            self.new_bin(size);
+        }
+
+        let offset = self.used;
+        put_u32(&mut self.bins, offset, (-(size as i32)) as u32);
+        self.used += size;
+        offset as u32
+    }
+
+    fn cell_mut(&mut self, offset: u32) -> &mut [u8] {
+        &mut self.bins[offset as usize + 4..]
+    }
+
+    fn write_cell(&mut self, data: &[u8]) -> u32 {
+        let offset = self.alloc(data.len());
+        self.cell_mut(offset)[..data.len()].copy_from_slice(data);
+        offset
+    }
+
+    fn write_security(&mut self, descriptor: &[u8]) -> u32 {
+        if let Some(cell) = self
+            .security
+            .iter_mut()
+            .find(|x| x.descriptor == descriptor)
+        {
+            cell.references += 1;
+            return cell.offset;
+        }
+
+        let offset = self.alloc(0x14 + descriptor.len());
+        let cell = self.cell_mut(offset);
+        cell[0..2].copy_from_slice(b"sk");
+        put_u32(cell, 0x10, descriptor.len() as u32);
+        cell[0x14..0x14 + descriptor.len()].copy_from_slice(descriptor);
+
+        self.security.push(SecurityCell {
+            descriptor: descriptor.to_vec(),
+            offset,
+            references: 1,
+        });
+        offset
+    }
+
+    /// Links the security cells into the circular list the format requires.
+    fn finish_security(&mut self) {
+        let offsets: Vec<(u32, u32)> = self
+            .security
+            .iter()
+            .map(|x| (x.offset, x.references))
+            .collect();
+
+        for (i, &(offset, references)) in offsets.iter().enumerate() {
+            let flink = offsets[(i + 1) % offsets.len()].0;
+            let blink = offsets[(i + offsets.len() - 1) % offsets.len()].0;
+            let cell = self.cell_mut(offset);
+            put_u32(cell, 0x04, flink);
+            put_u32(cell, 0x08, blink);
+            put_u32(cell, 0x0c, references);
+        }
+    }
+
+    fn write_data(&mut self, data: &[u8]) -> u32 {
+        if self.minor_version < 4 || data.len() <= BIG_DATA_SEGMENT {
+            return self.write_cell(data);
+        }
+
+        let segments: Vec<u8> = data
+            .chunks(BIG_DATA_SEGMENT)
+            .flat_map(|x| self.write_cell(x).to_le_bytes().to_vec())
+            .collect();
+        let list = self.write_cell(&segments);
+
+        let mut db = [0u8; 12];
+        db[0..2].copy_from_slice(b"db");
+        put_u16(&mut db, 0x02, (segments.len() / 4) as u16);
+        put_u32(&mut db, 0x04, list);
+        self.write_cell(&db)
+    }
+
+    fn write_value(&mut self, value: &Value) -> u32 {
+        let (name, compressed) = encode_name(&value.name);
+        let (size, data) = match value.data.len() {
+            0 => (0, INVALID_OFFSET),
+            len @ 1..=4 => {
+                let mut inline = [0u8; 4];
+                inline[..len].copy_from_slice(&value.data);
+                (len as u32 | 0x8000_0000, u32::from_le_bytes(inline))
+            }
+            len => (len as u32, self.write_data(&value.data)),
+        };
+
+        let offset = self.alloc(0x14 + name.len());
+        let vk = self.cell_mut(offset);
+        vk[0..2].copy_from_slice(b"vk");
+        put_u16(vk, 0x02, name.len() as u16);
+        put_u32(vk, 0x04, size);
+        put_u32(vk, 0x08, data);
+        put_u32(vk, 0x0c, value.data_type);
+        put_u16(vk, 0x10, if compressed { VALUE_COMP_NAME } else { 0 });
+        vk[0x14..0x14 + name.len()].copy_from_slice(&name);
+        offset
+    }
+
+    fn write_hash_list(&mut self, entries: &[(&str, u32)]) -> u32 {
+        let mut lh = vec![0u8; 4 + entries.len() * 8];
+        lh[0..2].copy_from_slice(b"lh");
+        put_u16(&mut lh, 0x02, entries.len() as u16);
+        for (i, (name, offset)) in entries.iter().enumerate() {
+            put_u32(&mut lh, 4 + i * 8, *offset);
+            put_u32(&mut lh, 8 + i * 8, name_hash(name));
+        }
+        self.write_cell(&lh)
+    }
+
+    fn write_subkey_list(&mut self, entries: &[(&str, u32)]) -> u32 {
+        if entries.len() <= MAX_LIST_ENTRIES {
+            return self.write_hash_list(entries);
+        }
+
+        let lists: Vec<u32> = entries
+            .chunks(MAX_LIST_ENTRIES)
+            .map(|x| self.write_hash_list(x))
+            .collect();
+
+        let mut ri = vec![0u8; 4 + lists.len() * 4];
+        ri[0..2].copy_from_slice(b"ri");
+        put_u16(&mut ri, 0x02, lists.len() as u16);
+        for (i, list) in lists.iter().enumerate() {
+            put_u32(&mut ri, 4 + i * 4, *list);
+        }
+        self.write_cell(&ri)
+    }
+
+    fn write_key(&mut self, key: &Key, parent: u32, inherited_security: &[u8]) -> u32 {
+        let (name, compressed) = encode_name(&key.name);
+        let offset = self.alloc(0x4c + name.len());
+
+        let security = key.security.as_deref().unwrap_or(inherited_security);
+        let sk = self.write_security(security);
+
+        let values: Vec<u8> = key
+            .values
+            .iter()
+            .flat_map(|x| self.write_value(x).to_le_bytes().to_vec())
+            .collect();
+        let value_list = match values.is_empty() {
+            true => INVALID_OFFSET,
+            false => self.write_cell(&values),
+        };
+
+        let class_name = key.class_name.as_deref().map(encode_utf16);
+        let class = match &class_name {
+            Some(v) => self.write_cell(v),
+            None => INVALID_OFFSET,
+        };
+
+        let mut subkeys: Vec<(&str, u32)> = key
+            .subkeys
+            .iter()
+            .map(|x| (&*x.name, self.write_key(x, offset, security)))
+            .collect();
+        subkeys.sort_by_key(|(name, _)| sort_key(name));
+        let subkey_list = match subkeys.is_empty() {
+            true => INVALID_OFFSET,
+            false => self.write_subkey_list(&subkeys),
+        };
+
+        let utf16_len = |s: &str| s.encode_utf16().count() as u32 * 2;
+        let max_subkey_name = key.subkeys.iter().map(|x| utf16_len(&x.name)).max();
+        let max_subkey_class = key
+            .subkeys
+            .iter()
+            .map(|x| x.class_name.as_deref().map_or(0, utf16_len))
+            .max();
+        let max_value_name = key.values.iter().map(|x| utf16_len(&x.name)).max();
+        let max_value_data = key.values.iter().map(|x| x.data.len() as u32).max();
+
+        let mut flags = key.flags & !KEY_TRANSIENT_FLAGS;
+        if compressed {
+            flags |= KEY_COMP_NAME;
+        }
+
+        let nk = self.cell_mut(offset);
+        nk[0..2].copy_from_slice(b"nk");
+        put_u16(nk, 0x02, flags);
+        put_u64(nk, 0x04, key.last_written);
+        put_u32(nk, 0x10, parent);
+        put_u32(nk, 0x14, key.subkeys.len() as u32);
+        put_u32(nk, 0x1c, subkey_list);
+        put_u32(nk, 0x20, INVALID_OFFSET);
+        put_u32(nk, 0x24, key.values.len() as u32);
+        put_u32(nk, 0x28, value_list);
+        put_u32(nk, 0x2c, sk);
+        put_u32(nk, 0x30, class);
+        put_u32(nk, 0x34, max_subkey_name.unwrap_or(0));
+        put_u32(nk, 0x38, max_subkey_class.unwrap_or(0));
+        put_u32(nk, 0x3c, max_value_name.unwrap_or(0));
+        put_u32(nk, 0x40, max_value_data.unwrap_or(0));
+        put_u16(nk, 0x48, name.len() as u16);
+        put_u16(nk, 0x4a, class_name.map_or(0, |x| x.len() as u16));
+        nk[0x4c..0x4c + name.len()].copy_from_slice(&name);
+        offset
+    }
+}
+
+pub(super) fn write(header: &Header, root: &Key) -> Vec<u8> {
+    let mut writer = Writer {
+        bins: vec![],
+        used: 0,
+        minor_version: header.minor_version,
+        timestamp: now(),
+        security: vec![],
+    };
+
+    let mut root = root.clone();
+    root.flags |= KEY_HIVE_ENTRY | KEY_NO_DELETE;
+    let root_offset = writer.write_key(&root, INVALID_OFFSET, &DEFAULT_SECURITY);
+    writer.finish_security();
+    writer.close_bin();
+
+    let sequence = header.sequence.wrapping_add(1);
+    let mut base = vec![0u8; BASE_BLOCK_SIZE];
+    base[0..4].copy_from_slice(b"regf");
+    put_u32(&mut base, 0x04, sequence);
+    put_u32(&mut base, 0x08, sequence);
+    put_u64(&mut base, 0x0c, writer.timestamp);
+    put_u32(&mut base, 0x14, 1);
+    put_u32(&mut base, 0x18, header.minor_version);
+    put_u32(&mut base, 0x24, root_offset);
+    put_u32(&mut base, 0x28, writer.bins.len() as u32);
+    put_u32(&mut base, 0x2c, 1);
+    let file_name_len = header.file_name.len().min(64);
+    base[0x30..0x30 + file_name_len].copy_from_slice(&header.file_name[..file_name_len]);
+    let sum = checksum(&base);
+    put_u32(&mut base, 0x1fc, sum);
+
+    base.extend_from_slice(&writer.bins);
+    base
+}
@@ -0,0 +1,253 @@
+//! Snapshots of a user's complete input configuration, for `kbdi snapshot save` and `restore`.
+//!
+//! A snapshot holds the enabled languages in order with their input methods, and the
+//! `User Profile`, `Keyboard Layout\Preload` and `Substitutes` keys of the current user. It is
+//! saved as JSON. The installed `a*` keyboard layouts are captured too, so `kbdi diff` can show
+//! them, but they are machine-wide and are not restored.
+
+use crate::backend::{Data, Hive, KeyPath, RegistryBackend};
+#[cfg(windows)]
+use crate::journal::Transaction;
+use crate::keyboard::{keyboard_layouts_key, KeyboardRegKey};
+use crate::keyboard_win8::{preload_key, substitutes_key, user_profile_key};
+use crate::Error;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Snapshot {
+    /// The enabled languages, in the user's order.
+    pub languages: Vec<LanguageSnapshot>,
+    /// Each captured key, parents before children.
+    pub keys: Vec<KeySnapshot>,
+    /// The installed `a*` keyboard layouts.
+    #[serde(default)]
+    pub layouts: Vec<KeySnapshot>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LanguageSnapshot {
+    pub tag: String,
+    pub input_methods: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeySnapshot {
+    /// Path under `HKEY_CURRENT_USER`, or `HKEY_LOCAL_MACHINE` for layouts
+    pub path: String,
+    pub values: Vec<(String, Data)>,
+}
+
+fn captured_keys() -> Vec<KeyPath> {
+    vec![user_profile_key(), preload_key(), substitutes_key()]
+}
+
+fn capture_key(
+    reg: &dyn RegistryBackend,
+    key: &KeyPath,
+    keys: &mut Vec<KeySnapshot>,
+) -> Result<(), Error> {
+    keys.push(KeySnapshot {
+        path: key.path.to_owned(),
+        values: reg.values(key)?,
+    });
+
+    for subkey in reg.subkeys(key)? {
+        capture_key(reg, &key.join(&subkey), keys)?;
+    }
+
+    Ok(())
+}
+
+impl Snapshot {
+    /// Captures the registry keys, alongside `languages` as returned by `enabled_keyboards`.
+    /// Keys that do not exist are left out.
+    pub fn capture(
+        reg: &dyn RegistryBackend,
+        languages: Vec<(String, Vec<String>)>,
+    ) -> Result<Snapshot, Error> {
+        let mut keys = vec![];
+        for key in captured_keys() {
+            if reg.key_exists(&key) {
+                capture_key(reg, &key, &mut keys)?;
+            }
+        }
+
+        let mut layouts = vec![];
+        if reg.key_exists(&keyboard_layouts_key()) {
+            for keyboard in KeyboardRegKey::installed(reg)? {
+                let key = keyboard_layouts_key().join(keyboard.regkey_id());
+                capture_key(reg, &key, &mut layouts)?;
+            }
+        }
+
+        Ok(Snapshot {
+            languages: languages
+                .into_iter()
+                .map(|(tag, input_methods)| LanguageSnapshot { tag, input_methods })
+                .collect(),
+            keys,
+            layouts,
+        })
+    }
+
+    /// The languages and their input methods, as taken by `restore_keyboards`.
+    pub fn keyboards(&self) -> Vec<(String, Vec<String>)> {
+        self.languages
+            .iter()
+            .map(|x| (x.tag.to_owned(), x.input_methods.to_owned()))
+            .collect()
+    }
+
+    /// Replaces the captured keys with their contents in the snapshot. Keys and values that
+    /// were not in the snapshot are deleted.
+    pub fn restore_registry(&self, reg: &dyn RegistryBackend) -> Result<(), Error> {
+        for key in captured_keys() {
+            if reg.key_exists(&key) {
+                reg.delete_key(&key)?;
+            }
+        }
+
+        for key in self.keys.iter() {
+            let path = KeyPath::new(Hive::CurrentUser, key.path.as_str());
+            reg.create_key(&path)?;
+            for (name, data) in key.values.iter() {
+                reg.set_value(&path, name, data)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Snapshot, Error> {
+        let text = std::fs::read_to_string(path)?;
+        Snapshot::from_json(&text)
+    }
+
+    pub fn from_json(text: &str) -> Result<Snapshot, Error> {
+        let snapshot: Snapshot =
+            serde_json::from_str(text).map_err(|e| Error::InvalidSnapshot(e.to_string()))?;
+
+        let is_under = |key: &KeySnapshot, roots: &[KeyPath]| {
+            roots.iter().any(|root| {
+                let path = key.path.to_ascii_lowercase();
+                let root = root.path.to_ascii_lowercase();
+                path == root || path.starts_with(&format!("{}\\", root))
+            })
+        };
+        if let Some(key) = snapshot
+            .keys
+            .iter()
+            .find(|key| !is_under(key, &captured_keys()))
+            .or_else(|| {
+                snapshot
+                    .layouts
+                    .iter()
+                    .find(|key| !is_under(key, &[keyboard_layouts_key()]))
+            })
+        {
+            return Err(Error::InvalidSnapshot(format!(
+                "key {:?} is not one kbdi snapshots",
+                key.path
+            )));
+        }
+
+        Ok(snapshot)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+/// Captures the current user's languages, input methods and keys.
+#[cfg(windows)]
+pub fn save(reg: &dyn RegistryBackend) -> Result<Snapshot, Error> {
+    Snapshot::capture(reg, crate::enabled_keyboards()?)
+}
+
+/// Puts the current user back into the state captured in `snapshot`: the languages and input
+/// methods are set through Windows, then the keys are overwritten with their captured contents.
+#[cfg(windows)]
+pub fn restore(reg: &dyn RegistryBackend, snapshot: &Snapshot) -> Result<(), Error> {
+    let tx = Transaction::begin(reg);
+    let original_keyboards = crate::enabled_keyboards()?;
+    tx.on_rollback("restore enabled languages and input methods", move || {
+        crate::restore_keyboards(reg, &original_keyboards).map_err(|e| e.to_string())
+    });
+
+    log::info!("Restoring {} languages", snapshot.languages.len());
+    crate::restore_keyboards(&tx, &snapshot.keyboards())?;
+
+    log::info!("Restoring {} registry keys", snapshot.keys.len());
+    snapshot.restore_registry(&tx)?;
+
+    tx.commit();
+    Ok(())
+}
+
+#[test]
+fn test_snapshot_round_trip() {
+    use crate::backend::MemoryRegistry;
+
+    let reg = MemoryRegistry::new();
+    let se = user_profile_key().join("se-NO");
+    reg.create_key(&se).unwrap();
+    reg.set_value(&se, "043B:A000043B", &Data::U32(1)).unwrap();
+    reg.set_value(&se, "TransientLangId", &Data::U32(0x2000))
+        .unwrap();
+    reg.set_value(
+        &user_profile_key(),
+        "Languages",
+        &Data::MultiString(vec!["se-NO".into(), "en-US".into()]),
+    )
+    .unwrap();
+    reg.create_key(&preload_key()).unwrap();
+    reg.set_value(&preload_key(), "1", &Data::String("d001043b".into()))
+        .unwrap();
+    reg.create_key(&substitutes_key()).unwrap();
+    reg.set_value(
+        &substitutes_key(),
+        "d001043b",
+        &Data::String("a000043b".into()),
+    )
+    .unwrap();
+
+    let languages = vec![
+        ("se-NO".to_string(), vec!["043B:A000043B".to_string()]),
+        ("en-US".to_string(), vec!["0409:00000409".to_string()]),
+    ];
+    let snapshot = Snapshot::capture(&reg, languages.clone()).unwrap();
+    assert_eq!(snapshot.keyboards(), languages);
+    assert_eq!(
+        Snapshot::from_json(&serde_json::to_string(&snapshot).unwrap()).unwrap(),
+        snapshot
+    );
+
+    // Undo everything an enable could have done, then restore
+    let en = user_profile_key().join("en-US");
+    reg.create_key(&en).unwrap();
+    reg.delete_value(&se, "TransientLangId").unwrap();
+    reg.set_value(&preload_key(), "2", &Data::String("00000409".into()))
+        .unwrap();
+    reg.delete_key(&substitutes_key()).unwrap();
+
+    snapshot.restore_registry(&reg).unwrap();
+    assert_eq!(Snapshot::capture(&reg, languages).unwrap(), snapshot);
+    assert!(!reg.key_exists(&en));
+
+    let foreign = r#"{"languages": [], "keys": [{"path": "Software\\Foo", "values": []}]}"#;
+    assert!(matches!(
+        Snapshot::from_json(foreign),
+        Err(Error::InvalidSnapshot(_))
+    ));
+    let foreign = r#"{"languages": [], "keys": [], "layouts": [{"path": "SYSTEM", "values": []}]}"#;
+    assert!(matches!(
+        Snapshot::from_json(foreign),
+        Err(Error::InvalidSnapshot(_))
+    ));
+}
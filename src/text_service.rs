@@ -0,0 +1,300 @@
+//! TSF text services (IMEs), registered per language under `SOFTWARE\Microsoft\CTF\TIP`.
+//!
+//! The COM class and categories of a text service are registered by its own DLL. kbdi only adds
+//! and removes the language profiles that tell Windows which languages it serves, and enables
+//! them as input methods alongside keyboard layouts.
+
+use crate::backend::{Data, Hive, KeyPath, RegistryBackend};
+#[cfg(windows)]
+use crate::journal::Transaction;
+pub use crate::types::Guid;
+use crate::types::{InputListItem, Tip};
+use crate::Error;
+#[cfg(windows)]
+use crate::LanguageTag;
+use std::fmt;
+
+/// One language profile of a text service.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextServiceProfile {
+    pub clsid: Guid,
+    pub profile: Guid,
+    pub lang_id: u16,
+    pub description: Option<String>,
+    pub icon_file: Option<String>,
+    pub icon_index: u32,
+}
+
+pub(crate) fn tip_key() -> KeyPath {
+    KeyPath::new(Hive::LocalMachine, r"SOFTWARE\Microsoft\CTF\TIP")
+}
+
+fn language_profile_key(clsid: Guid) -> KeyPath {
+    tip_key().join(&clsid.to_string()).join("LanguageProfile")
+}
+
+fn lang_id_key_name(lang_id: u16) -> String {
+    format!("0x{:08x}", lang_id)
+}
+
+fn parse_lang_id_key_name(name: &str) -> Option<u16> {
+    let digits = name
+        .strip_prefix("0x")
+        .or_else(|| name.strip_prefix("0X"))?;
+    u32::from_str_radix(digits, 16)
+        .ok()
+        .filter(|x| *x <= 0xffff)
+        .map(|x| x as u16)
+}
+
+impl TextServiceProfile {
+    fn key(&self) -> KeyPath {
+        language_profile_key(self.clsid)
+            .join(&lang_id_key_name(self.lang_id))
+            .join(&self.profile.to_string())
+    }
+
+    pub(crate) fn tip(&self) -> Tip {
+        Tip::TextService {
+            clsid: self.clsid,
+            profile: self.profile,
+        }
+    }
+
+    pub(crate) fn input_list_item(&self) -> InputListItem {
+        InputListItem {
+            lang_id: self.lang_id,
+            tip: self.tip(),
+        }
+    }
+
+    /// Writes the profile, as `ITfInputProcessorProfiles::AddLanguageProfile` would, and marks it
+    /// enabled. An existing profile is overwritten.
+    pub fn register(&self, reg: &dyn RegistryBackend) -> Result<(), Error> {
+        let key = self.key();
+        reg.create_key(&key)?;
+
+        let description = self.description.clone().unwrap_or_default();
+        let icon_file = self.icon_file.clone().unwrap_or_default();
+        reg.set_value(&key, "Description", &Data::String(description))?;
+        reg.set_value(&key, "IconFile", &Data::String(icon_file))?;
+        reg.set_value(&key, "IconIndex", &Data::U32(self.icon_index))?;
+        reg.set_value(&key, "Enable", &Data::U32(1))?;
+        Ok(())
+    }
+
+    fn open(
+        reg: &dyn RegistryBackend,
+        clsid: Guid,
+        lang_id: u16,
+        profile: Guid,
+    ) -> Result<TextServiceProfile, Error> {
+        let mut record = TextServiceProfile {
+            clsid,
+            profile,
+            lang_id,
+            description: None,
+            icon_file: None,
+            icon_index: 0,
+        };
+
+        for (name, data) in reg.values(&record.key())? {
+            match (&*name.to_ascii_lowercase(), data) {
+                ("description", Data::String(v)) => record.description = Some(v),
+                ("iconfile", Data::String(v)) => record.icon_file = Some(v),
+                ("iconindex", Data::U32(v)) => record.icon_index = v,
+                _ => {}
+            }
+        }
+
+        Ok(record)
+    }
+}
+
+/// Every language profile of every registered text service. Keys that are not GUIDs or language
+/// ids are skipped.
+pub fn installed(reg: &dyn RegistryBackend) -> Result<Vec<TextServiceProfile>, Error> {
+    let mut profiles = vec![];
+    if !reg.key_exists(&tip_key()) {
+        return Ok(profiles);
+    }
+
+    for clsid in reg.subkeys(&tip_key())? {
+        let clsid = match Guid::parse(&clsid) {
+            Some(v) => v,
+            None => continue,
+        };
+        let langs_key = language_profile_key(clsid);
+        if !reg.key_exists(&langs_key) {
+            continue;
+        }
+
+        for lang in reg.subkeys(&langs_key)? {
+            let lang_id = match parse_lang_id_key_name(&lang) {
+                Some(v) => v,
+                None => continue,
+            };
+
+            for profile in reg.subkeys(&langs_key.join(&lang))? {
+                if let Some(profile) = Guid::parse(&profile) {
+                    profiles.push(TextServiceProfile::open(reg, clsid, lang_id, profile)?);
+                }
+            }
+        }
+    }
+
+    Ok(profiles)
+}
+
+/// The languages a text service profile is registered for.
+pub fn find(
+    reg: &dyn RegistryBackend,
+    clsid: Guid,
+    profile: Guid,
+) -> Result<Vec<TextServiceProfile>, Error> {
+    Ok(installed(reg)?
+        .into_iter()
+        .filter(|x| x.clsid == clsid && x.profile == profile)
+        .collect())
+}
+
+/// Removes the profile for every language it is registered for, and any language keys left
+/// empty. The text service's own keys are left for its DLL to unregister.
+pub fn unregister(reg: &dyn RegistryBackend, clsid: Guid, profile: Guid) -> Result<(), Error> {
+    let records = find(reg, clsid, profile)?;
+    if records.is_empty() {
+        return Err(Error::NotInstalled(format!("{}{}", clsid, profile)));
+    }
+
+    for record in records {
+        let key = record.key();
+        reg.delete_key(&key)?;
+
+        if let Some(lang_key) = key.parent() {
+            if reg.subkeys(&lang_key)?.is_empty() && reg.values(&lang_key)?.is_empty() {
+                reg.delete_key(&lang_key)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Registers a text service profile for `tag`, using its LCID or transient LCID.
+#[cfg(windows)]
+pub fn install(
+    reg: &dyn RegistryBackend,
+    tag: &LanguageTag,
+    clsid: Guid,
+    profile: Guid,
+    description: &str,
+    icon_file: Option<&str>,
+    icon_index: u32,
+) -> Result<TextServiceProfile, Error> {
+    let lang_id = crate::lcid(reg, tag)?;
+    log::info!(
+        "Registering text service {}{} for {} ({:04x})",
+        clsid,
+        profile,
+        tag,
+        lang_id
+    );
+
+    let record = TextServiceProfile {
+        clsid,
+        profile,
+        lang_id,
+        description: Some(description.to_owned()),
+        icon_file: icon_file.map(str::to_owned),
+        icon_index,
+    };
+
+    let tx = Transaction::begin(reg);
+    record.register(&tx)?;
+    tx.commit();
+    Ok(record)
+}
+
+/// Unregisters a text service profile, then removes it from the user's input methods.
+#[cfg(windows)]
+pub fn uninstall(reg: &dyn RegistryBackend, clsid: Guid, profile: Guid) -> Result<(), Error> {
+    let tx = Transaction::begin(reg);
+    #[cfg(not(feature = "legacy"))]
+    {
+        let original_keyboards = crate::enabled_keyboards()?;
+        tx.on_rollback("restore enabled languages and input methods", move || {
+            crate::restore_keyboards(reg, &original_keyboards).map_err(|e| e.to_string())
+        });
+    }
+
+    unregister(&tx, clsid, profile)?;
+    crate::clean(&tx, false)?;
+    tx.commit();
+    Ok(())
+}
+
+impl fmt::Display for TextServiceProfile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Input Method:   {}", self.input_list_item())?;
+        writeln!(f, "CLSID:          {}", self.clsid)?;
+        writeln!(f, "Profile:        {}", self.profile)?;
+        writeln!(
+            f,
+            "Description:    {}",
+            self.description.as_deref().unwrap_or("")
+        )?;
+        writeln!(
+            f,
+            "Icon:           {},{}",
+            self.icon_file.as_deref().unwrap_or(""),
+            self.icon_index
+        )?;
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_register_text_services() {
+    let reg = crate::backend::MemoryRegistry::new();
+    assert_eq!(installed(&reg).unwrap(), vec![]);
+
+    let clsid: Guid = "{03B5835F-F03C-411B-9CE2-AA23E1171E36}".parse().unwrap();
+    let profile: Guid = "{A76C93D9-5523-4E90-AAFA-4DB112F9AC76}".parse().unwrap();
+    let ime = |lang_id| TextServiceProfile {
+        clsid,
+        profile,
+        lang_id,
+        description: Some("Sami IME".into()),
+        icon_file: Some(r"C:\Program Files\Sami IME\ime.dll".into()),
+        icon_index: 2,
+    };
+
+    ime(0x043b).register(&reg).unwrap();
+    ime(0x2000).register(&reg).unwrap();
+    reg.create_key(&tip_key().join("NotAGuid")).unwrap();
+
+    let key = KeyPath::new(
+        Hive::LocalMachine,
+        r"SOFTWARE\Microsoft\CTF\TIP\{03B5835F-F03C-411B-9CE2-AA23E1171E36}\LanguageProfile\0x0000043b\{A76C93D9-5523-4E90-AAFA-4DB112F9AC76}",
+    );
+    assert_eq!(reg.value(&key, "Enable").unwrap(), Data::U32(1));
+
+    assert_eq!(
+        find(&reg, clsid, profile).unwrap(),
+        vec![ime(0x043b), ime(0x2000)]
+    );
+    assert_eq!(
+        ime(0x043b).input_list_item().to_string(),
+        "043B:{03B5835F-F03C-411B-9CE2-AA23E1171E36}{A76C93D9-5523-4E90-AAFA-4DB112F9AC76}"
+    );
+
+    unregister(&reg, clsid, profile).unwrap();
+    assert_eq!(installed(&reg).unwrap(), vec![]);
+    assert!(!reg.key_exists(&key.parent().unwrap()));
+    assert!(reg.key_exists(&language_profile_key(clsid)));
+    assert!(matches!(
+        unregister(&reg, clsid, profile),
+        Err(Error::NotInstalled(_))
+    ));
+}
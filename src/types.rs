@@ -1,4 +1,24 @@
-use std::{convert::TryFrom, fmt::Debug};
+use std::{convert::TryFrom, fmt, fmt::Debug, str::FromStr};
+
+/// An input method string, as returned by `GetUserLanguageInputMethods` or passed to
+/// `InstallLayoutOrTip`, failed to parse.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ParseError {
+    #[error("Input method {0:?} has no ':' between its language and its TIP")]
+    MissingSeparator(String),
+
+    #[error("Input method {input:?} has an invalid language id {lang_id:?}")]
+    InvalidLangId { input: String, lang_id: String },
+
+    #[error("Input method {input:?} has an invalid keyboard layout id {klid:?}")]
+    InvalidLayout { input: String, klid: String },
+
+    #[error("Input method {input:?} has an invalid text service {tip:?}")]
+    InvalidTextService { input: String, tip: String },
+
+    #[error("{0:?} is not a GUID in braces")]
+    InvalidGuid(String),
+}
 
 #[derive(PartialEq, Eq, Clone)]
 pub struct InputList {
@@ -11,51 +31,177 @@ impl Debug for InputList {
     }
 }
 
+/// A GUID, formatted in braces like `{4518B9B5-7112-4855-B64F-2EC0DD2831E6}`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Guid(pub u128);
+
+impl Guid {
+    /// Parses a braced GUID, ignoring case.
+    pub fn parse(s: &str) -> Option<Guid> {
+        let inner = s.strip_prefix('{')?.strip_suffix('}')?;
+        let groups: Vec<&str> = inner.split('-').collect();
+        let lens: Vec<usize> = groups.iter().map(|x| x.len()).collect();
+        if lens != [8, 4, 4, 4, 12] || !groups.iter().all(|x| is_hex(x)) {
+            return None;
+        }
+
+        u128::from_str_radix(&groups.concat(), 16).ok().map(Guid)
+    }
+}
+
+impl FromStr for Guid {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Guid, ParseError> {
+        Guid::parse(s).ok_or_else(|| ParseError::InvalidGuid(s.to_owned()))
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let hex = format!("{:032X}", self.0);
+        write!(
+            f,
+            "{{{}-{}-{}-{}-{}}}",
+            &hex[0..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..32]
+        )
+    }
+}
+
+impl Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// The part of an input method after the language id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Tip {
+    /// A keyboard layout, by its KLID, such as `A0010409`.
+    Layout(u32),
+    /// A TSF text service, by its CLSID and the GUID of one of its language profiles.
+    TextService { clsid: Guid, profile: Guid },
+}
+
+impl fmt::Display for Tip {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Tip::Layout(klid) => write!(f, "{:08X}", klid),
+            Tip::TextService { clsid, profile } => write!(f, "{}{}", clsid, profile),
+        }
+    }
+}
+
+/// One input method, written `0409:A0010409` for a layout or `0409:{CLSID}{ProfileGUID}` for a
+/// text service. Either part may have a `0x` prefix when parsed.
 #[derive(PartialEq, Eq, Clone)]
 pub struct InputListItem {
     pub lang_id: u16,
-    pub tip_id: u32,
+    pub tip: Tip,
 }
 
 impl Debug for InputListItem {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!("{{{:04x}:{:08x}}}", self.lang_id, self.tip_id))
+        write!(f, "{{{}}}", self)
     }
 }
 
-use std::u16;
-use std::u32;
+impl fmt::Display for InputListItem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04X}:{}", self.lang_id, self.tip)
+    }
+}
+
+fn is_hex(s: &str) -> bool {
+    s.bytes().all(|x| x.is_ascii_hexdigit())
+}
+
+fn strip_hex_prefix(s: &str) -> &str {
+    s.strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s)
+}
 
 impl TryFrom<&str> for InputListItem {
-    type Error = ();
+    type Error = ParseError;
 
-    fn try_from(string: &str) -> Result<InputListItem, ()> {
+    fn try_from(string: &str) -> Result<InputListItem, ParseError> {
         log::trace!("InputListItem try_from: {}", &string);
 
-        let lang_id = u16::from_str_radix(&string[0..4], 16).map_err(|_| ())?;
-        let tip_id = u32::from_str_radix(&string[5..13], 16).map_err(|_| ())?;
+        let input = string.trim();
+        let (lang_id, tip) = match input.find(':') {
+            Some(i) => (&input[..i], &input[i + 1..]),
+            None => return Err(ParseError::MissingSeparator(string.to_owned())),
+        };
+
+        let lang_digits = strip_hex_prefix(lang_id);
+        let lang_id = match lang_digits.len() {
+            1..=4 if is_hex(lang_digits) => u16::from_str_radix(lang_digits, 16).ok(),
+            _ => None,
+        }
+        .ok_or_else(|| ParseError::InvalidLangId {
+            input: string.to_owned(),
+            lang_id: lang_id.to_owned(),
+        })?;
+
+        if tip.starts_with('{') {
+            let guids = match tip.len() {
+                76 if tip.is_char_boundary(38) => Guid::parse(&tip[..38])
+                    .and_then(|clsid| Some((clsid, Guid::parse(&tip[38..])?))),
+                _ => None,
+            };
 
-        Ok(InputListItem { lang_id, tip_id })
+            return match guids {
+                Some((clsid, profile)) => Ok(InputListItem {
+                    lang_id,
+                    tip: Tip::TextService { clsid, profile },
+                }),
+                None => Err(ParseError::InvalidTextService {
+                    input: string.to_owned(),
+                    tip: tip.to_owned(),
+                }),
+            };
+        }
+
+        let klid_digits = strip_hex_prefix(tip);
+        match klid_digits.len() {
+            1..=8 if is_hex(klid_digits) => u32::from_str_radix(klid_digits, 16).ok(),
+            _ => None,
+        }
+        .map(|klid| InputListItem {
+            lang_id,
+            tip: Tip::Layout(klid),
+        })
+        .ok_or_else(|| ParseError::InvalidLayout {
+            input: string.to_owned(),
+            klid: tip.to_owned(),
+        })
     }
 }
 
+/// Parses a `;` separated list. Empty entries, such as from a trailing `;`, are skipped.
 impl TryFrom<String> for InputList {
-    type Error = ();
+    type Error = ParseError;
 
-    fn try_from(string: String) -> Result<InputList, ()> {
+    fn try_from(string: String) -> Result<InputList, ParseError> {
         Ok(InputList {
             __inner: string
                 .split(";")
-                .map(|s| InputListItem::try_from(s))
+                .filter(|s| !s.trim().is_empty())
+                .map(InputListItem::try_from)
                 .collect::<Result<Vec<_>, _>>()?,
         })
     }
 }
 
 impl TryFrom<Vec<String>> for InputList {
-    type Error = ();
+    type Error = ParseError;
 
-    fn try_from(str_vec: Vec<String>) -> Result<InputList, ()> {
+    fn try_from(str_vec: Vec<String>) -> Result<InputList, ParseError> {
         Ok(InputList {
             __inner: str_vec
                 .into_iter()
@@ -73,18 +219,14 @@ impl From<Vec<InputListItem>> for InputList {
 
 impl From<InputList> for String {
     fn from(input_list: InputList) -> String {
-        let x: Vec<String> = input_list
-            .__inner
-            .into_iter()
-            .map(|i| String::from(i))
-            .collect();
+        let x: Vec<String> = input_list.__inner.into_iter().map(String::from).collect();
         x.join(";")
     }
 }
 
 impl From<InputListItem> for String {
     fn from(input_item: InputListItem) -> String {
-        format!("0x{:04X}:{:08X}", input_item.lang_id, input_item.tip_id)
+        input_item.to_string()
     }
 }
 
@@ -107,7 +249,122 @@ impl InputListItem {
         format!("{:04X}", self.lang_id)
     }
 
+    /// The TIP as written after the `:`.
     pub fn kbid(&self) -> String {
-        format!("{:08X}", self.tip_id)
+        self.tip.to_string()
+    }
+
+    /// The KLID, if this is a keyboard layout rather than a text service.
+    pub fn klid(&self) -> Option<u32> {
+        match self.tip {
+            Tip::Layout(klid) => Some(klid),
+            Tip::TextService { .. } => None,
+        }
+    }
+}
+
+#[test]
+fn test_parse_input_list_items() {
+    let item = |s: &str| InputListItem::try_from(s);
+    let layout = |lang_id, klid| InputListItem {
+        lang_id,
+        tip: Tip::Layout(klid),
+    };
+
+    assert_eq!(item("0409:A0010409"), Ok(layout(0x0409, 0xa001_0409)));
+    assert_eq!(item("0x0409:0xa0010409"), Ok(layout(0x0409, 0xa001_0409)));
+    assert_eq!(item(" 043b:0000043B "), Ok(layout(0x043b, 0x043b)));
+
+    let ime = "0411:{03B5835F-F03C-411B-9CE2-AA23E1171E36}{A76C93D9-5523-4E90-AAFA-4DB112F9AC76}";
+    let parsed = item(ime).unwrap();
+    assert_eq!(
+        parsed.tip,
+        Tip::TextService {
+            clsid: Guid(0x03B5835F_F03C_411B_9CE2_AA23E1171E36),
+            profile: Guid(0xA76C93D9_5523_4E90_AAFA_4DB112F9AC76),
+        }
+    );
+    assert_eq!(parsed.to_string(), ime);
+    assert_eq!(parsed.klid(), None);
+    assert_eq!(item(&ime.to_lowercase()), Ok(parsed));
+
+    assert_eq!(
+        item("0409"),
+        Err(ParseError::MissingSeparator("0409".into()))
+    );
+    assert_eq!(
+        item("+409:00000409"),
+        Err(ParseError::InvalidLangId {
+            input: "+409:00000409".into(),
+            lang_id: "+409".into()
+        })
+    );
+    assert_eq!(
+        item("0409:A00104091"),
+        Err(ParseError::InvalidLayout {
+            input: "0409:A00104091".into(),
+            klid: "A00104091".into()
+        })
+    );
+    assert!(matches!(
+        item("0411:{03B5835F-F03C-411B-9CE2-AA23E1171E36}"),
+        Err(ParseError::InvalidTextService { .. })
+    ));
+
+    let list = InputList::try_from("0409:00000409;0x043B:0xA000043B;".to_string()).unwrap();
+    assert_eq!(String::from(list), "0409:00000409;043B:A000043B");
+    assert_eq!(
+        InputList::try_from(String::new()),
+        Ok(InputList::from(vec![]))
+    );
+}
+
+#[cfg(test)]
+fn arb_input_list_item() -> impl proptest::strategy::Strategy<Value = InputListItem> {
+    use proptest::prelude::*;
+
+    let tip = prop_oneof![
+        any::<u32>().prop_map(Tip::Layout),
+        (any::<u128>(), any::<u128>()).prop_map(|(clsid, profile)| Tip::TextService {
+            clsid: Guid(clsid),
+            profile: Guid(profile),
+        }),
+    ];
+
+    (any::<u16>(), tip).prop_map(|(lang_id, tip)| InputListItem { lang_id, tip })
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn prop_input_list_item_round_trips(
+        item in arb_input_list_item(),
+        lowercase: bool,
+        prefix: bool,
+    ) {
+        let mut text = item.to_string();
+        if lowercase {
+            text = text.to_lowercase();
+        }
+        if prefix {
+            text = format!("0x{}", text.replacen(":", ":0x", usize::from(item.klid().is_some())));
+        }
+
+        proptest::prop_assert_eq!(InputListItem::try_from(&*text), Ok(item.clone()));
+        proptest::prop_assert_eq!(
+            InputListItem::try_from(&*item.to_string()).map(|x| x.to_string()),
+            Ok(item.to_string())
+        );
+    }
+
+    #[test]
+    fn prop_input_list_round_trips(items in proptest::collection::vec(arb_input_list_item(), 0..8)) {
+        let list = InputList::from(items);
+        proptest::prop_assert_eq!(InputList::try_from(String::from(list.clone())), Ok(list));
+    }
+
+    #[test]
+    fn prop_input_list_item_parse_never_panics(text in "\\PC{0,90}") {
+        let _ = InputListItem::try_from(&*text);
     }
 }
@@ -1,7 +1,10 @@
+use crate::backend::RegistryBackend;
+use crate::output::LanguageRecord;
 use crate::platform::winnls;
+use crate::{Error, LanguageTag};
 
-pub fn query_language(tag: &str) -> String {
-    let id = winnls::resolve_locale_name(tag).unwrap_or(tag.to_owned());
+pub fn query_language(tag: &LanguageTag) -> Result<String, Error> {
+    let id = winnls::resolve_locale_name(tag)?.unwrap_or_else(|| tag.clone());
 
     let a = format!("Tag:  {}", id);
 
@@ -10,10 +13,24 @@ pub fn query_language(tag: &str) -> String {
         Err(_) => format!("LCID: undefined"),
     };
 
-    format!("{}\n{}", a, b)
+    Ok(format!("{}\n{}", a, b))
 }
 
-pub fn clean() -> Result<(), String> {
-    crate::keyboard::remove_invalid();
-    Ok(())
+pub fn language_info(tag: &LanguageTag) -> Result<LanguageRecord, Error> {
+    let id = winnls::resolve_locale_name(tag)?.unwrap_or_else(|| tag.clone());
+    let lcid = winnls::locale_name_to_lcid(&id).ok();
+
+    Ok(LanguageRecord {
+        tag: id.to_string(),
+        supported: lcid.is_some(),
+        name: None,
+        english_name: None,
+        native_name: None,
+        script_name: None,
+        lcid,
+    })
+}
+
+pub fn clean(reg: &dyn RegistryBackend, include_foreign: bool) -> Result<(), Error> {
+    crate::keyboard::remove_invalid(reg, include_foreign)
 }
@@ -1,124 +1,172 @@
-use registry::{Hive, Security};
-
+use crate::backend::{Data, Hive, KeyPath, RegistryBackend};
+use crate::output::LanguageRecord;
 use crate::platform::*;
-use std::io;
-
-pub fn query_language(tag: &str) -> String {
-    let id = winnls::resolve_locale_name(tag).unwrap_or(tag.to_owned());
-
-    match winlangdb::get_language_names(&id) {
-        None => format!("{}: Unsupported tag.\n", &id),
-        Some(v) => {
-            let lcid = match bcp47langs::lcid_from_bcp47(&tag) {
+use crate::types::InputList;
+use crate::{Error, LanguageTag};
+use std::convert::TryFrom;
+
+pub fn query_language(tag: &LanguageTag) -> Result<String, Error> {
+    let id = winnls::resolve_locale_name(tag)?.unwrap_or_else(|| tag.clone());
+
+    Ok(match winlangdb::get_language_names(&id) {
+        Err(Error::UnsupportedLanguage(_)) => format!("{}: Unsupported tag.\n", &id),
+        Err(e) => return Err(e),
+        Ok(v) => {
+            let lcid = match bcp47langs::lcid_from_bcp47(tag.as_str())? {
                 Some(lcid) => format!("LCID:          0x{:08x}", lcid),
                 None => format!("LCID:          undefined"),
             };
             format!("{}{}", v, lcid)
         }
-    }
+    })
 }
 
-pub fn enabled_languages() -> Result<Vec<String>, io::Error> {
+pub fn language_info(tag: &LanguageTag) -> Result<LanguageRecord, Error> {
+    let id = winnls::resolve_locale_name(tag)?.unwrap_or_else(|| tag.clone());
+    let lcid = bcp47langs::lcid_from_bcp47(tag.as_str())?;
+
+    Ok(match winlangdb::get_language_names(&id) {
+        Ok(v) => LanguageRecord {
+            tag: v.tag,
+            supported: true,
+            name: Some(v.name),
+            english_name: Some(v.english_name),
+            native_name: Some(v.localised_name),
+            script_name: Some(v.script_name),
+            lcid,
+        },
+        Err(Error::UnsupportedLanguage(_)) => LanguageRecord {
+            tag: id.to_string(),
+            supported: false,
+            name: None,
+            english_name: None,
+            native_name: None,
+            script_name: None,
+            lcid,
+        },
+        Err(e) => return Err(e),
+    })
+}
+
+pub fn enabled_languages() -> Result<Vec<String>, Error> {
     // winlangdb::ensure_language_profile_exists()?;
     bcp47langs::get_user_languages()
 }
 
 type LangKeyboards = (String, Vec<String>);
 
-pub fn enabled_keyboards() -> Result<Vec<LangKeyboards>, io::Error> {
+pub fn enabled_keyboards() -> Result<Vec<LangKeyboards>, Error> {
     log::debug!("enabled_keyboards()");
     let langs = enabled_languages()?;
-    Ok(langs
+    langs
         .into_iter()
         .map(|lang| {
-            let imes = bcp47langs::get_user_language_input_methods(&lang).unwrap();
-            (lang, imes)
+            let imes = bcp47langs::get_user_language_input_methods(&lang.parse()?)?;
+            Ok((lang, imes))
         })
-        .collect())
+        .collect()
 }
 
 // TODO: reimplement support for adding native language name, optionally
-pub fn enable_language(tag: &str) -> Result<(), io::Error> {
-    log::debug!("enable_languages({:?})", tag);
+pub fn enable_language(reg: &dyn RegistryBackend, tag: &LanguageTag) -> Result<(), Error> {
+    enable_languages(reg, &[tag])
+}
+
+/// Appends any of `tags` that are not yet enabled to the user's languages, in one call.
+pub fn enable_languages(reg: &dyn RegistryBackend, tags: &[&LanguageTag]) -> Result<(), Error> {
+    log::debug!("enable_languages({:?})", tags);
     let mut langs = enabled_languages()?;
     log::trace!("Enabled languages: {:?}", langs);
-    let lang = tag.to_owned();
+    let original_len = langs.len();
 
-    if langs.contains(&lang) {
-        log::debug!("Lang found in langs, doing nothing.");
-        return Ok(());
+    for tag in tags {
+        if !langs.iter().any(|x| x.eq_ignore_ascii_case(tag.as_str())) {
+            langs.push(tag.to_string());
+        }
     }
 
-    langs.push(lang);
+    if langs.len() == original_len {
+        log::debug!("All langs found in langs, doing nothing.");
+        return Ok(());
+    }
 
-    set_user_languages(&langs).unwrap();
+    set_user_languages(reg, &langs)?;
 
     // winlangdb::ensure_language_profile_exists()?;
     //    .or_else(|_| Err("Error while setting languages.".to_owned()))
     Ok(())
 }
 
-fn set_user_languages(tags: &[String]) -> Result<(), String> {
+/// Puts the user's languages and input methods back to a list returned by [`enabled_keyboards`].
+pub fn restore_keyboards(
+    reg: &dyn RegistryBackend,
+    keyboards: &[LangKeyboards],
+) -> Result<(), Error> {
+    log::debug!("restore_keyboards({:?})", keyboards);
+    let tags: Vec<String> = keyboards.iter().map(|(tag, _)| tag.to_owned()).collect();
+    set_user_languages(reg, &tags)?;
+
+    bcp47langs::remove_inputs_for_all_languages()?;
+    for (tag, imes) in keyboards.iter().filter(|(_, imes)| !imes.is_empty()) {
+        let inputs = InputList::try_from(imes.clone())
+            .map_err(|_| Error::InvalidInputMethods(format!("{}: {}", tag, imes.join(";"))))?;
+        input::install_layout(inputs, 0)?;
+    }
+
+    Ok(())
+}
+
+fn set_user_languages(reg: &dyn RegistryBackend, tags: &[String]) -> Result<(), Error> {
     log::debug!("set_user_languages({:?})", &tags);
-    let valid_tags: Vec<String> = tags
-        .iter()
-        .flat_map(|t| winlangdb::get_language_names(t))
-        .map(|t| t.tag)
-        .collect();
+    let mut valid_tags: Vec<String> = vec![];
+    for tag in tags {
+        match LanguageTag::parse(tag)
+            .map_err(Error::from)
+            .and_then(|x| winlangdb::get_language_names(&x))
+        {
+            Ok(v) => valid_tags.push(v.tag),
+            Err(Error::UnsupportedLanguage(_)) | Err(Error::InvalidLanguageTag(_)) => {
+                log::warn!("Skipping unsupported language tag {:?}", tag)
+            }
+            Err(e) => return Err(e),
+        }
+    }
 
     log::trace!("valid_tags: {:?}", &valid_tags);
 
-    winlangdb::set_user_languages(&valid_tags)
-        .or_else(|_| Err("Failed enabling languages".to_owned()))?;
+    winlangdb::set_user_languages(&valid_tags)?;
 
     // Workaround for bug in Windows 10 20H2
-    win10_20h2_workaround()?;
+    win10_20h2_workaround(reg)?;
 
     Ok(())
 }
 
-fn win10_20h2_workaround() -> Result<(), String> {
-    let user_profile_key = Hive::CurrentUser
-        .open(
-            r"Control Panel\International\User Profile",
-            Security::Read | Security::Write,
-        )
-        .unwrap();
-
-    for subkey in user_profile_key
-        .keys()
-        .filter_map(Result::ok)
-        .map(|x| x.open(Security::Read | Security::Write))
-        .filter_map(Result::ok)
+fn win10_20h2_workaround(reg: &dyn RegistryBackend) -> Result<(), Error> {
+    let user_profile_key = KeyPath::new(
+        Hive::CurrentUser,
+        r"Control Panel\International\User Profile",
+    );
+
+    for subkey in reg
+        .subkeys(&user_profile_key)?
+        .iter()
+        .map(|x| user_profile_key.join(x))
     {
-        if subkey.value("FeaturesToInstall").is_err() {
-            log::debug!("20H2 Workaround: setting FeaturesToInstall to 0xe3 for {}", subkey.to_string());
-            subkey
-                .set_value("FeaturesToInstall", &registry::Data::U32(0xe3))
-                .map_err(|e| format!("{:?}", e))?;
+        if reg.value(&subkey, "FeaturesToInstall").is_err() {
+            log::debug!(
+                "20H2 Workaround: setting FeaturesToInstall to 0xe3 for {}",
+                subkey
+            );
+            reg.set_value(&subkey, "FeaturesToInstall", &Data::U32(0xe3))?;
         }
     }
 
     Ok(())
 }
 
-fn disable_empty_languages() -> Result<(), io::Error> {
-    let langs = enabled_languages()?;
-    let filtered_langs: Vec<String> = langs
-        .into_iter()
-        .filter(|tag| {
-            let imes = bcp47langs::get_user_language_input_methods(&tag).unwrap_or(vec![]);
-            imes.len() > 0
-        })
-        .collect();
-
-    set_user_languages(&filtered_langs).unwrap();
-    Ok(())
-    //.or_else(|_| Err("Error while setting languages.".to_owned()))
-}
-
-pub fn clean() -> Result<(), String> {
-    crate::keyboard::remove_invalid();
-    disable_empty_languages().unwrap();
-    Ok(())
+/// Removes broken keyboards kbdi owns, or any keyboard if `include_foreign` is set, and
+/// disables languages left without input methods.
+pub fn clean(reg: &dyn RegistryBackend, include_foreign: bool) -> Result<(), Error> {
+    crate::keyboard::remove_invalid(reg, include_foreign)
 }